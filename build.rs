@@ -0,0 +1,60 @@
+//! Assembles the boot and trap entry code in kernel/*.S with NASM and links the
+//! kernel executable with kernel/kernel.ld.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let kernel_dir = manifest_dir.join("kernel");
+    let linker_script = kernel_dir.join("kernel.ld");
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=NASM");
+
+    // The other architectures have no boot code yet.
+    if env::var("CARGO_CFG_TARGET_ARCH").unwrap() != "x86_64" {
+        return;
+    }
+
+    // Host builds of the library, for unit tests and fuzzing, have no use for it either.
+    if env::var("CARGO_CFG_TARGET_OS").unwrap() != "none" {
+        return;
+    }
+
+    let nasm = env::var_os("NASM").unwrap_or_else(|| "nasm".into());
+
+    let mut sources: Vec<PathBuf> = fs::read_dir(&kernel_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|x| x == "S"))
+        .collect();
+    sources.sort();
+
+    for source in sources {
+        let object = out_dir
+            .join(source.file_name().unwrap())
+            .with_extension("S.o");
+
+        let status = Command::new(&nasm)
+            .args(["-f", "elf64", "-Wall", "-F", "dwarf", "-g"])
+            .arg(&source)
+            .arg("-o")
+            .arg(&object)
+            .status()
+            .unwrap_or_else(|e| panic!("failed to run {nasm:?}: {e}"));
+
+        if !status.success() {
+            panic!("failed to assemble {}: {status}", source.display());
+        }
+
+        println!("cargo:rerun-if-changed={}", source.display());
+        println!("cargo:rustc-link-arg-bins={}", object.display());
+    }
+
+    println!("cargo:rerun-if-changed={}", linker_script.display());
+    println!("cargo:rustc-link-arg-bins=-T{}", linker_script.display());
+}
@@ -0,0 +1,9 @@
+//! Feeds arbitrary bytes to the parser of the memory map the bootloader passes.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    lithium::fuzz::multiboot_mmap(data);
+});
@@ -0,0 +1,10 @@
+//! Feeds arbitrary bytes to the parsers of PCI configuration spaces and the virtio
+//! capabilities in them.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    lithium::fuzz::pci_caps(data);
+});
@@ -0,0 +1,406 @@
+use spin::Once;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+use crate::log;
+use crate::memory;
+use crate::memory::{PhysRegion, ReservationKind};
+use crate::power;
+use crate::timer;
+use crate::trap;
+use crate::workqueue;
+use crate::workqueue::Work;
+
+/// Signature of the root system description pointer.
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// Where the BIOS keeps the segment of the extended BIOS data area.
+const EBDA_SEGMENT_POINTER: u64 = 0x40E;
+
+/// Read-only BIOS area searched for the RSDP after the first KiB of the EBDA.
+const BIOS_AREA: (u64, u64) = (0xE0000, 0x100000);
+
+/// Size of the header every system description table starts with.
+const HEADER_SIZE: usize = 36;
+
+// Offsets of the fields of the FADT, see 5.2.9 "Fixed ACPI Description Table".
+const FADT_DSDT: usize = 40;
+const FADT_SCI_INT: usize = 46;
+const FADT_SMI_CMD: usize = 48;
+const FADT_ACPI_ENABLE: usize = 52;
+const FADT_PM1A_EVT_BLK: usize = 56;
+const FADT_PM1B_EVT_BLK: usize = 60;
+const FADT_PM1A_CNT_BLK: usize = 64;
+const FADT_PM1B_CNT_BLK: usize = 68;
+const FADT_PM1_EVT_LEN: usize = 88;
+const FADT_X_DSDT: usize = 140;
+
+/// Bit of the PM1 status and enable registers for the power button.
+const PWRBTN: u16 = 1 << 8;
+
+// Bits of the PM1 control register.
+const SCI_EN: u16 = 1 << 0;
+const SLP_EN: u16 = 1 << 13;
+const SLP_TYP_SHIFT: u16 = 10;
+
+/// How long firmware may take to switch to ACPI mode.
+const ENABLE_TIMEOUT: core::time::Duration = core::time::Duration::from_millis(500);
+
+// AML opcodes of the `Name(_S5_, Package() {...})` object.
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+
+// Power management registers found in the FADT.
+static PM: Once<PowerManagement> = Once::new();
+
+// Power button presses are handled on the worker thread.
+static POWER_BUTTON_WORK: Work = Work::new(power_button_pressed);
+
+/// The fixed hardware registers used for sleep states and the power button.
+#[derive(Debug, Clone, Copy)]
+struct PowerManagement {
+    pm1a_event: u16,
+    pm1b_event: Option<u16>,
+    // Length of each PM1 event block, the status register is its first half.
+    event_len: u16,
+    pm1a_control: u16,
+    pm1b_control: Option<u16>,
+    sci: u8,
+    // SLP_TYPa and SLP_TYPb of the S5 sleep state, from the DSDT.
+    s5: Option<(u8, u8)>,
+}
+
+/// Returns `len` bytes of physical memory at `pa` through the direct map.
+fn physical(pa: u64, len: usize) -> Option<&'static [u8]> {
+    let end = pa.checked_add(len.checked_sub(1)? as u64)?;
+    memory::direct_map(PhysAddr::new(end))?;
+
+    let va = memory::direct_map(PhysAddr::new(pa))?;
+    Some(unsafe { core::slice::from_raw_parts(va.as_ptr(), len) })
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+fn checksum_ok(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, x| sum.wrapping_add(*x)) == 0
+}
+
+/// Finds the RSDP in the first KiB of the EBDA or the BIOS area, see 5.2.5.1 "Finding
+/// the RSDP on IA-PC Systems". Returns the physical address of the RSDT or XSDT and
+/// whether it is the XSDT.
+fn find_root_table() -> Option<(u64, bool)> {
+    let ebda = read_u16(physical(EBDA_SEGMENT_POINTER, 2)?, 0)? as u64 * 16;
+    let areas = [(ebda, ebda + 1024), BIOS_AREA];
+
+    for (start, end) in areas {
+        let Some(area) = physical(start, (end - start) as usize) else {
+            continue;
+        };
+
+        // The RSDP sits on a 16 byte boundary.
+        for rsdp in (0..area.len()).step_by(16).map(|x| &area[x..]) {
+            if !rsdp.starts_with(RSDP_SIGNATURE) || !checksum_ok(rsdp.get(..20)?) {
+                continue;
+            }
+
+            let revision = rsdp[15];
+            if revision >= 2 {
+                let length = read_u32(rsdp, 20)? as usize;
+                if let Some(xsdt) = rsdp
+                    .get(..length)
+                    .filter(|x| checksum_ok(x))
+                    .and_then(|x| read_u64(x, 24))
+                {
+                    return Some((xsdt, true));
+                }
+            }
+
+            return Some((read_u32(rsdp, 16)? as u64, false));
+        }
+    }
+
+    None
+}
+
+/// Returns the system description table at `pa` if its checksum is correct.
+fn table(pa: u64) -> Option<&'static [u8]> {
+    let header = physical(pa, HEADER_SIZE)?;
+    let length = read_u32(header, 4)? as usize;
+
+    if length < HEADER_SIZE {
+        return None;
+    }
+
+    physical(pa, length).filter(|x| checksum_ok(x))
+}
+
+/// Finds the table with `signature` listed in the RSDT or XSDT.
+fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let (root, extended) = find_root_table()?;
+    let root = table(root)?;
+    let entry_size = if extended { 8 } else { 4 };
+
+    root[HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .filter_map(|x| match extended {
+            true => read_u64(x, 0),
+            false => read_u32(x, 0).map(|x| x as u64),
+        })
+        .filter_map(table)
+        .find(|x| x.starts_with(signature))
+}
+
+/// Keeps the RSDT or XSDT, every table it lists and the DSDT from ever being handed
+/// out by the frame allocator. Firmware usually marks them in the memory map already,
+/// this covers the ones that do not.
+fn reserve_tables() {
+    // Reserves the table at `pa` if it is valid, and returns it.
+    let reserve = |pa: u64| {
+        let data = table(pa)?;
+        let region = PhysRegion::new(PhysAddr::new(pa), data.len());
+        memory::add_reservation(region, ReservationKind::Acpi);
+        Some(data)
+    };
+
+    let Some((root, extended)) = find_root_table() else {
+        return;
+    };
+
+    let Some(root) = reserve(root) else {
+        return;
+    };
+
+    let entry_size = if extended { 8 } else { 4 };
+
+    for entry in root[HEADER_SIZE..].chunks_exact(entry_size) {
+        let pa = match extended {
+            true => read_u64(entry, 0),
+            false => read_u32(entry, 0).map(|x| x as u64),
+        };
+
+        let Some(data) = pa.and_then(reserve) else {
+            continue;
+        };
+
+        if data.starts_with(b"FACP") {
+            dsdt_address(data).and_then(reserve);
+        }
+    }
+}
+
+/// Decodes an AML integer that is a constant or a byte, the only forms SLP_TYP values
+/// take in practice. Returns the value and its length.
+fn aml_byte(aml: &[u8]) -> Option<(u8, usize)> {
+    match *aml.first()? {
+        AML_ZERO_OP => Some((0, 1)),
+        AML_ONE_OP => Some((1, 1)),
+        AML_BYTE_PREFIX => Some((*aml.get(1)?, 2)),
+        _ => None,
+    }
+}
+
+/// Finds the SLP_TYPa and SLP_TYPb values of the S5 sleep state in the `\_S5_` package
+/// of the DSDT, without interpreting the rest of the AML.
+fn find_s5(dsdt: &[u8]) -> Option<(u8, u8)> {
+    let at = dsdt.windows(4).position(|x| x == b"_S5_")?;
+
+    // The name may be preceded by a root or parent prefix.
+    let named = at > 0 && dsdt[at - 1] == AML_NAME_OP
+        || at > 1 && dsdt[at - 2] == AML_NAME_OP && dsdt[at - 1] == b'\\';
+    if !named || *dsdt.get(at + 4)? != AML_PACKAGE_OP {
+        return None;
+    }
+
+    // The top two bits of the package length tell how many more bytes it takes, then
+    // comes the number of elements.
+    let pkg_length_bytes = (*dsdt.get(at + 5)? >> 6) as usize;
+    let mut pos = at + 5 + 1 + pkg_length_bytes + 1;
+
+    let (a, len) = aml_byte(dsdt.get(pos..)?)?;
+    pos += len;
+    let (b, _) = aml_byte(dsdt.get(pos..)?)?;
+
+    Some((a, b))
+}
+
+/// Returns the I/O port of a register block, `None` for an absent one.
+fn port(fadt: &[u8], offset: usize) -> Option<u16> {
+    read_u32(fadt, offset)
+        .filter(|&x| x != 0)
+        .and_then(|x| u16::try_from(x).ok())
+}
+
+/// Returns the physical address of the DSDT, preferring the 64-bit field.
+fn dsdt_address(fadt: &[u8]) -> Option<u64> {
+    read_u64(fadt, FADT_X_DSDT)
+        .filter(|&x| x != 0)
+        .or_else(|| read_u32(fadt, FADT_DSDT).map(|x| x as u64))
+}
+
+fn parse_fadt(fadt: &[u8]) -> Option<PowerManagement> {
+    let dsdt = dsdt_address(fadt).and_then(table);
+
+    Some(PowerManagement {
+        pm1a_event: port(fadt, FADT_PM1A_EVT_BLK)?,
+        pm1b_event: port(fadt, FADT_PM1B_EVT_BLK),
+        event_len: *fadt.get(FADT_PM1_EVT_LEN)? as u16,
+        pm1a_control: port(fadt, FADT_PM1A_CNT_BLK)?,
+        pm1b_control: port(fadt, FADT_PM1B_CNT_BLK),
+        sci: read_u16(fadt, FADT_SCI_INT)? as u8,
+        s5: dsdt.and_then(find_s5),
+    })
+}
+
+/// Switches the machine from legacy to ACPI mode through the SMI command port, unless
+/// firmware already did so.
+fn enable(fadt: &[u8], pm: &PowerManagement) -> bool {
+    let mut control = Port::<u16>::new(pm.pm1a_control);
+
+    if unsafe { control.read() } & SCI_EN != 0 {
+        return true;
+    }
+
+    let (Some(smi_command), Some(&acpi_enable)) = (
+        read_u32(fadt, FADT_SMI_CMD).filter(|&x| x != 0),
+        fadt.get(FADT_ACPI_ENABLE).filter(|&&x| x != 0),
+    ) else {
+        return false;
+    };
+
+    unsafe { Port::<u8>::new(smi_command as u16).write(acpi_enable) };
+    timer::wait_until(|| unsafe { control.read() } & SCI_EN != 0, ENABLE_TIMEOUT).is_ok()
+}
+
+/// Returns the status and enable registers of the PM1 event blocks.
+fn event_registers(pm: &PowerManagement) -> impl Iterator<Item = (Port<u16>, Port<u16>)> {
+    let half = pm.event_len / 2;
+
+    [Some(pm.pm1a_event), pm.pm1b_event]
+        .into_iter()
+        .flatten()
+        .map(move |x| (Port::new(x), Port::new(x + half)))
+}
+
+fn power_button_pressed() {
+    log!("acpi::power_button_pressed(): power button pressed");
+    power::poweroff()
+}
+
+/// Handles the system control interrupt, which ACPI raises for fixed events like the
+/// power button.
+fn sci_interrupt(_irq: u8) {
+    let Some(pm) = PM.get() else {
+        return;
+    };
+
+    for (mut status, _) in event_registers(pm) {
+        // Status bits are cleared by writing ones.
+        if unsafe { status.read() } & PWRBTN != 0 {
+            unsafe { status.write(PWRBTN) };
+            workqueue::schedule(&POWER_BUTTON_WORK);
+        }
+    }
+}
+
+/// Enters the S5 (soft off) sleep state through the PM1 control registers. Returns if
+/// the state is unknown or the machine did not turn off.
+pub fn enter_s5() {
+    let Some(pm) = PM.get() else {
+        return;
+    };
+
+    let Some((slp_typa, slp_typb)) = pm.s5 else {
+        return;
+    };
+
+    let controls = [
+        (Some(pm.pm1a_control), slp_typa),
+        (pm.pm1b_control, slp_typb),
+    ];
+
+    for (port, slp_typ) in controls {
+        let Some(port) = port else {
+            continue;
+        };
+
+        let mut control = Port::<u16>::new(port);
+        unsafe {
+            let value = control.read() & !(0x7 << SLP_TYP_SHIFT);
+            control.write(value | (slp_typ as u16) << SLP_TYP_SHIFT | SLP_EN);
+        }
+    }
+}
+
+/// Initializes ACPI power management, which lets the machine be turned off on real
+/// hardware and other hypervisors than QEMU, and shuts it down cleanly when the power
+/// button is pressed, e.g. by `virsh shutdown` or a cloud provider stopping the VM.
+///
+/// The FADT gives the power management registers and the DSDT the value that selects
+/// the S5 sleep state, which [`power::power_off`] enters. The power button raises the
+/// system control interrupt, which runs the shutdown hooks through
+/// [`power::poweroff`].
+pub fn init() {
+    reserve_tables();
+
+    let Some(fadt) = find_table(b"FACP") else {
+        log!("acpi::init(): no FADT found, power button disabled");
+        return;
+    };
+
+    let Some(pm) = parse_fadt(fadt) else {
+        log!("acpi::init(): FADT lacks the PM1 registers, power button disabled");
+        return;
+    };
+
+    let pm = *PM.call_once(|| pm);
+
+    if pm.s5.is_none() {
+        log!("acpi::init(): no S5 sleep state in the DSDT");
+    }
+
+    if !enable(fadt, &pm) {
+        log!("acpi::init(): failed to switch to ACPI mode, power button disabled");
+        return;
+    }
+
+    // Clear a press from before boot, then enable only the power button.
+    for (mut status, mut enable) in event_registers(&pm) {
+        unsafe {
+            status.write(PWRBTN);
+            enable.write(PWRBTN);
+        }
+    }
+
+    trap::set_level_triggered(pm.sci);
+    if !trap::register_irq_handler(pm.sci, sci_interrupt) {
+        log!(
+            "acpi::init(): IRQ {} is taken, power button disabled",
+            pm.sci
+        );
+        return;
+    }
+
+    log!(
+        "acpi::init(): power button on IRQ {} [ \x1b[0;32mOK\x1b[0m ]",
+        pm.sci
+    );
+}
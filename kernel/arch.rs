@@ -0,0 +1,79 @@
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(target_arch = "riscv64")]
+mod riscv64;
+#[cfg(target_arch = "x86_64")]
+mod x86;
+
+#[cfg(target_arch = "aarch64")]
+pub use self::aarch64::Aarch64 as Current;
+#[cfg(target_arch = "riscv64")]
+pub use self::riscv64::Riscv64 as Current;
+#[cfg(target_arch = "x86_64")]
+pub use self::x86::X86 as Current;
+
+/// Masking and waiting for interrupts on the current processor.
+pub trait Interrupts {
+    fn enable();
+    fn disable();
+    fn are_enabled() -> bool;
+    /// Enables interrupts and waits for the next one, without a window in between in
+    /// which an interrupt could be missed.
+    fn enable_and_wait();
+}
+
+/// Translation between the addresses the kernel uses and physical addresses.
+pub trait Mmu {
+    /// Returns the kernel address of physical memory at `pa`, if it is mapped.
+    fn direct_map(pa: u64) -> Option<u64>;
+    /// Returns the physical address kernel address `va` is mapped to.
+    fn translate(va: u64) -> Option<u64>;
+
+    /// Returns the kernel address of the `size` bytes of device registers at `pa`,
+    /// mapping them first if need be.
+    ///
+    /// # Safety
+    /// `pa` must be device memory, not RAM.
+    unsafe fn map_device(pa: u64, size: u64) -> Option<u64> {
+        Self::direct_map(pa.checked_add(size.max(1) - 1)?)?;
+        Self::direct_map(pa)
+    }
+}
+
+/// Free-running counter of the current processor.
+pub trait Timer {
+    fn counter() -> u64;
+    /// Returns how fast the counter runs in hertz, if known.
+    fn counter_frequency() -> Option<u64>;
+}
+
+/// Early console, usable before any driver is up and from panics.
+pub trait Console {
+    fn write_bytes(data: &[u8]);
+}
+
+/// virtio-mmio device slot a machine always has, whether or not a device sits in it.
+#[derive(Debug, Clone, Copy)]
+pub struct MmioSlot {
+    pub base: u64,
+    pub size: u64,
+    pub irq: u8,
+}
+
+/// Everything the portable parts of the kernel need from an architecture.
+///
+/// [`Current`] is the implementation for the architecture being built. Code outside
+/// `arch` that is not yet behind this boundary still uses the x86_64 crate directly,
+/// which keeps the kernel x86_64-only for now.
+pub trait Arch: Interrupts + Mmu + Timer + Console {
+    const NAME: &'static str;
+
+    /// Returns the virtio-mmio slots of the machine, which are probed in addition to
+    /// the devices given on the command line.
+    fn virtio_mmio_slots() -> &'static [MmioSlot] {
+        &[]
+    }
+
+    /// Stops the processor for good.
+    fn halt() -> !;
+}
@@ -0,0 +1,154 @@
+use core::arch::asm;
+
+use super::{Arch, Console, Interrupts, Mmu, Timer};
+use crate::mmio::Region;
+
+/// PL011 UART of the QEMU `virt` machine.
+const PL011_BASE: u64 = 0x0900_0000;
+const PL011_SIZE: u64 = 0x1000;
+
+// PL011 registers and flags, see the PrimeCell UART (PL011) TRM 3.2.
+const UARTDR: u64 = 0x00;
+const UARTFR: u64 = 0x18;
+const UARTFR_TXFF: u32 = 1 << 5;
+
+/// GICv2 distributor and CPU interface of the QEMU `virt` machine.
+const GICD_BASE: u64 = 0x0800_0000;
+const GICC_BASE: u64 = 0x0801_0000;
+const GIC_SIZE: u64 = 0x1000;
+
+// GICv2 registers, see the GIC architecture specification 4.1.2 and 4.1.3.
+const GICD_CTLR: u64 = 0x000;
+const GICD_ISENABLER: u64 = 0x100;
+const GICD_ICENABLER: u64 = 0x180;
+const GICC_CTLR: u64 = 0x00;
+const GICC_PMR: u64 = 0x04;
+const GICC_IAR: u64 = 0x0C;
+const GICC_EOIR: u64 = 0x10;
+
+/// Interrupt identifier the GIC returns when nothing is pending.
+pub const GIC_SPURIOUS: u32 = 1023;
+
+/// `DAIF.I`, interrupts are masked while it is set.
+const DAIF_I: u64 = 1 << 7;
+
+// The MMU is still off this early, so device memory is reached at its physical
+// address.
+const PL011: Region = unsafe { Region::new(PL011_BASE, PL011_SIZE) };
+const GICD: Region = unsafe { Region::new(GICD_BASE, GIC_SIZE) };
+const GICC: Region = unsafe { Region::new(GICC_BASE, GIC_SIZE) };
+
+/// AArch64 on the QEMU `virt` machine with a GICv2 and a PL011 UART.
+///
+/// Only the pieces behind [`Arch`] exist so far. The kernel runs with the MMU off and
+/// identity-mapped, there is no exception vector table yet, so the GIC can be set up
+/// but interrupts cannot be handled.
+#[derive(Debug)]
+pub struct Aarch64;
+
+impl Interrupts for Aarch64 {
+    #[inline]
+    fn enable() {
+        unsafe { asm!("msr daifclr, #2", options(nomem, nostack)) };
+    }
+
+    #[inline]
+    fn disable() {
+        unsafe { asm!("msr daifset, #2", options(nomem, nostack)) };
+    }
+
+    #[inline]
+    fn are_enabled() -> bool {
+        let daif: u64;
+        unsafe { asm!("mrs {}, daif", out(reg) daif, options(nomem, nostack)) };
+        daif & DAIF_I == 0
+    }
+
+    #[inline]
+    fn enable_and_wait() {
+        // A pending interrupt wakes up `wfi` even while masked, so unmasking first
+        // cannot lose it.
+        unsafe { asm!("msr daifclr, #2", "wfi", options(nomem, nostack)) };
+    }
+}
+
+impl Mmu for Aarch64 {
+    fn direct_map(pa: u64) -> Option<u64> {
+        Some(pa)
+    }
+
+    fn translate(va: u64) -> Option<u64> {
+        Some(va)
+    }
+}
+
+impl Timer for Aarch64 {
+    #[inline]
+    fn counter() -> u64 {
+        let count: u64;
+        unsafe { asm!("isb", "mrs {}, cntvct_el0", out(reg) count, options(nomem, nostack)) };
+        count
+    }
+
+    fn counter_frequency() -> Option<u64> {
+        let freq: u64;
+        unsafe { asm!("mrs {}, cntfrq_el0", out(reg) freq, options(nomem, nostack)) };
+        Some(freq).filter(|&x| x != 0)
+    }
+}
+
+impl Console for Aarch64 {
+    fn write_bytes(data: &[u8]) {
+        for &byte in data {
+            while PL011.read::<u32>(UARTFR) & UARTFR_TXFF != 0 {
+                core::hint::spin_loop();
+            }
+
+            PL011.write::<u32>(UARTDR, byte as u32);
+        }
+    }
+}
+
+impl Arch for Aarch64 {
+    const NAME: &'static str = "aarch64";
+
+    fn halt() -> ! {
+        loop {
+            Self::disable();
+            unsafe { asm!("wfi", options(nomem, nostack)) };
+        }
+    }
+}
+
+/// Enables the GIC distributor and this processor's CPU interface with every
+/// interrupt masked, and lets interrupts of any priority through.
+pub fn gic_init() {
+    for i in 0..32 {
+        GICD.write::<u32>(GICD_ICENABLER + 4 * i, u32::MAX);
+    }
+
+    GICD.write::<u32>(GICD_CTLR, 1);
+    GICC.write::<u32>(GICC_PMR, 0xFF);
+    GICC.write::<u32>(GICC_CTLR, 1);
+}
+
+/// Unmasks interrupt `id` at the distributor.
+pub fn gic_enable(id: u32) {
+    GICD.write::<u32>(GICD_ISENABLER + 4 * (id / 32) as u64, 1 << (id % 32));
+}
+
+/// Masks interrupt `id` at the distributor.
+pub fn gic_disable(id: u32) {
+    GICD.write::<u32>(GICD_ICENABLER + 4 * (id / 32) as u64, 1 << (id % 32));
+}
+
+/// Acknowledges the highest priority pending interrupt and returns its identifier,
+/// [`GIC_SPURIOUS`] if there is none.
+pub fn gic_acknowledge() -> u32 {
+    GICC.read::<u32>(GICC_IAR) & 0x3FF
+}
+
+/// Signals the end of interrupt `id`.
+pub fn gic_end_of_interrupt(id: u32) {
+    GICC.write::<u32>(GICC_EOIR, id);
+}
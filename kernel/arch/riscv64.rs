@@ -0,0 +1,261 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::{Arch, Console, Interrupts, MmioSlot, Mmu, Timer};
+use crate::mmio::Region;
+
+/// Rate `time` counts at on the QEMU `virt` machine, its `timebase-frequency`.
+const TIMEBASE_HZ: u64 = 10_000_000;
+
+// SBI extensions, see the RISC-V SBI specification. The console uses the legacy
+// extensions, which every SBI implementation still provides.
+const SBI_CONSOLE_PUTCHAR: usize = 0x01;
+const SBI_TIME: usize = 0x5449_4D45;
+const SBI_TIME_SET_TIMER: usize = 0;
+
+/// `sstatus.SIE`, supervisor interrupts are enabled while it is set.
+const SSTATUS_SIE: usize = 1 << 1;
+
+/// PLIC of the QEMU `virt` machine.
+const PLIC_BASE: u64 = 0x0C00_0000;
+const PLIC_SIZE: u64 = 0x40_0000;
+
+// PLIC registers, see the RISC-V PLIC specification chapter 3.
+const PLIC_PRIORITY: u64 = 0x0000;
+const PLIC_ENABLE: u64 = 0x2000;
+const PLIC_ENABLE_STRIDE: u64 = 0x80;
+const PLIC_THRESHOLD: u64 = 0x20_0000;
+const PLIC_CLAIM: u64 = 0x20_0004;
+const PLIC_CONTEXT_STRIDE: u64 = 0x1000;
+
+// The PLIC is reached where it is, which stays mapped once paging is on.
+const PLIC: Region = unsafe { Region::new(PLIC_BASE, PLIC_SIZE) };
+
+/// virtio-mmio slots of the QEMU `virt` machine, with the PLIC source of each.
+const VIRTIO_MMIO_SLOTS: [MmioSlot; 8] = {
+    let mut slots = [MmioSlot {
+        base: 0,
+        size: 0,
+        irq: 0,
+    }; 8];
+
+    let mut i = 0;
+    while i < slots.len() {
+        slots[i] = MmioSlot {
+            base: 0x1000_1000 + 0x1000 * i as u64,
+            size: 0x1000,
+            irq: 1 + i as u8,
+        };
+        i += 1;
+    }
+
+    slots
+};
+
+// Bits of an Sv39 page table entry, see the privileged specification 4.4.1.
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_G: u64 = 1 << 5;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+const PTE_PPN_SHIFT: u64 = 10;
+
+const SATP_MODE_SV39: u64 = 8 << 60;
+const PAGE_SHIFT: u64 = 12;
+const GIGAPAGE_SHIFT: u64 = 30;
+
+/// Start of the upper half of the Sv39 address space, where physical memory is mapped.
+const DIRECT_MAP_BASE: u64 = 0xFFFF_FFC0_0000_0000;
+/// Amount of physical memory mapped, which covers all of the devices as well.
+const DIRECT_MAP_SIZE: u64 = 4 << GIGAPAGE_SHIFT;
+
+/// Sv39 page table, one page of 512 entries.
+#[repr(C, align(4096))]
+struct PageTable([u64; 512]);
+
+// Root page table. The lower half maps physical memory where it is, so the kernel keeps
+// running when paging is turned on, and the upper half maps it again at
+// DIRECT_MAP_BASE.
+static mut ROOT: PageTable = PageTable([0; 512]);
+
+// Whether paging is on and DIRECT_MAP_BASE can be used.
+static PAGING: AtomicBool = AtomicBool::new(false);
+
+/// Calls into the SBI implementation, e.g. OpenSBI, running in machine mode. Returns
+/// the error and value registers.
+unsafe fn sbi_call(extension: usize, function: usize, arg0: usize) -> (isize, usize) {
+    let (error, value): (isize, usize);
+    asm!(
+        "ecall",
+        inlateout("a0") arg0 => error,
+        lateout("a1") value,
+        in("a6") function,
+        in("a7") extension,
+        options(nostack),
+    );
+    (error, value)
+}
+
+/// RISC-V with 64-bit supervisor mode on the QEMU `virt` machine: an SBI console and
+/// timer, the PLIC and Sv39 paging.
+///
+/// There is no trap vector yet, so the PLIC and the timer can be programmed but their
+/// interrupts are not handled.
+#[derive(Debug)]
+pub struct Riscv64;
+
+impl Interrupts for Riscv64 {
+    #[inline]
+    fn enable() {
+        unsafe { asm!("csrsi sstatus, 2", options(nomem, nostack)) };
+    }
+
+    #[inline]
+    fn disable() {
+        unsafe { asm!("csrci sstatus, 2", options(nomem, nostack)) };
+    }
+
+    #[inline]
+    fn are_enabled() -> bool {
+        let sstatus: usize;
+        unsafe { asm!("csrr {}, sstatus", out(reg) sstatus, options(nomem, nostack)) };
+        sstatus & SSTATUS_SIE != 0
+    }
+
+    #[inline]
+    fn enable_and_wait() {
+        // `wfi` wakes up for pending interrupts even while they are disabled, so
+        // enabling them first cannot lose one.
+        unsafe { asm!("csrsi sstatus, 2", "wfi", options(nomem, nostack)) };
+    }
+}
+
+impl Mmu for Riscv64 {
+    fn direct_map(pa: u64) -> Option<u64> {
+        if !PAGING.load(Ordering::Acquire) {
+            return Some(pa);
+        }
+
+        (pa < DIRECT_MAP_SIZE).then(|| DIRECT_MAP_BASE + pa)
+    }
+
+    fn translate(va: u64) -> Option<u64> {
+        if !PAGING.load(Ordering::Acquire) {
+            return Some(va);
+        }
+
+        // Only gigapages are mapped so far.
+        let index = (va >> GIGAPAGE_SHIFT) as usize & 0x1FF;
+        let pte = unsafe { (*core::ptr::addr_of!(ROOT)).0[index] };
+
+        if pte & PTE_V == 0 {
+            return None;
+        }
+
+        let base = (pte >> PTE_PPN_SHIFT) << PAGE_SHIFT;
+        Some(base + (va & ((1 << GIGAPAGE_SHIFT) - 1)))
+    }
+}
+
+impl Timer for Riscv64 {
+    #[inline]
+    fn counter() -> u64 {
+        let time: u64;
+        unsafe { asm!("rdtime {}", out(reg) time, options(nomem, nostack)) };
+        time
+    }
+
+    fn counter_frequency() -> Option<u64> {
+        Some(TIMEBASE_HZ)
+    }
+}
+
+impl Console for Riscv64 {
+    fn write_bytes(data: &[u8]) {
+        for &byte in data {
+            unsafe { sbi_call(SBI_CONSOLE_PUTCHAR, 0, byte as usize) };
+        }
+    }
+}
+
+impl Arch for Riscv64 {
+    const NAME: &'static str = "riscv64";
+
+    fn virtio_mmio_slots() -> &'static [MmioSlot] {
+        &VIRTIO_MMIO_SLOTS
+    }
+
+    fn halt() -> ! {
+        loop {
+            Self::disable();
+            unsafe { asm!("wfi", options(nomem, nostack)) };
+        }
+    }
+}
+
+/// Asks the SBI implementation for a timer interrupt once `time` reaches `deadline`.
+pub fn set_timer(deadline: u64) {
+    unsafe { sbi_call(SBI_TIME, SBI_TIME_SET_TIMER, deadline as usize) };
+}
+
+/// Turns on Sv39 paging with physical memory mapped both where it is and at
+/// [`DIRECT_MAP_BASE`], using gigapages only.
+///
+/// # Safety
+/// Must run once, with paging off, before anything holds an address from
+/// [`Mmu::direct_map`].
+pub unsafe fn paging_init() {
+    let root = &mut *core::ptr::addr_of_mut!(ROOT);
+    let flags = PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+
+    for i in 0..(DIRECT_MAP_SIZE >> GIGAPAGE_SHIFT) {
+        let pte = ((i << GIGAPAGE_SHIFT) >> PAGE_SHIFT) << PTE_PPN_SHIFT | flags;
+        let high = ((DIRECT_MAP_BASE >> GIGAPAGE_SHIFT) & 0x1FF) + i;
+
+        root.0[i as usize] = pte;
+        root.0[high as usize] = pte | PTE_G;
+    }
+
+    // Paging is off, so the table is at its physical address.
+    let satp = SATP_MODE_SV39 | (root as *mut PageTable as u64) >> PAGE_SHIFT;
+    asm!("csrw satp, {}", "sfence.vma", in(reg) satp, options(nostack));
+
+    PAGING.store(true, Ordering::Release);
+}
+
+/// PLIC context of supervisor mode on `hart`.
+fn plic_context(hart: u64) -> u64 {
+    2 * hart + 1
+}
+
+/// Lets source `irq` interrupt supervisor mode on `hart`.
+pub fn plic_enable(hart: u64, irq: u32) {
+    let enable = PLIC_ENABLE + PLIC_ENABLE_STRIDE * plic_context(hart) + 4 * (irq / 32) as u64;
+
+    PLIC.write::<u32>(PLIC_PRIORITY + 4 * irq as u64, 1);
+    PLIC.write::<u32>(enable, PLIC.read::<u32>(enable) | 1 << (irq % 32));
+}
+
+/// Stops source `irq` from interrupting supervisor mode on `hart`.
+pub fn plic_disable(hart: u64, irq: u32) {
+    let enable = PLIC_ENABLE + PLIC_ENABLE_STRIDE * plic_context(hart) + 4 * (irq / 32) as u64;
+    PLIC.write::<u32>(enable, PLIC.read::<u32>(enable) & !(1 << (irq % 32)));
+}
+
+/// Lets sources of any nonzero priority through to supervisor mode on `hart`.
+pub fn plic_init(hart: u64) {
+    PLIC.write::<u32>(PLIC_THRESHOLD + PLIC_CONTEXT_STRIDE * plic_context(hart), 0);
+}
+
+/// Claims the highest priority pending source on `hart`, `None` if there is none.
+pub fn plic_claim(hart: u64) -> Option<u32> {
+    let irq = PLIC.read::<u32>(PLIC_CLAIM + PLIC_CONTEXT_STRIDE * plic_context(hart));
+    (irq != 0).then_some(irq)
+}
+
+/// Signals that source `irq` claimed on `hart` was handled.
+pub fn plic_complete(hart: u64, irq: u32) {
+    PLIC.write::<u32>(PLIC_CLAIM + PLIC_CONTEXT_STRIDE * plic_context(hart), irq);
+}
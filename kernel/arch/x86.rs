@@ -0,0 +1,83 @@
+use x86_64::instructions;
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{Arch, Console, Interrupts, Mmu, Timer};
+use crate::console::uart;
+use crate::cpu;
+use crate::log;
+use crate::memory;
+
+/// x86_64 with the legacy PICs, a 16550 UART and 4-level paging.
+#[derive(Debug)]
+pub struct X86;
+
+impl Interrupts for X86 {
+    #[inline]
+    fn enable() {
+        interrupts::enable();
+    }
+
+    #[inline]
+    fn disable() {
+        interrupts::disable();
+    }
+
+    #[inline]
+    fn are_enabled() -> bool {
+        interrupts::are_enabled()
+    }
+
+    #[inline]
+    fn enable_and_wait() {
+        interrupts::enable_and_hlt();
+    }
+}
+
+impl Mmu for X86 {
+    fn direct_map(pa: u64) -> Option<u64> {
+        memory::direct_map(PhysAddr::try_new(pa).ok()?).map(|x| x.as_u64())
+    }
+
+    fn translate(va: u64) -> Option<u64> {
+        memory::translate(VirtAddr::try_new(va).ok()?).map(|(pa, _)| pa.as_u64())
+    }
+
+    unsafe fn map_device(pa: u64, size: u64) -> Option<u64> {
+        match memory::map_device(PhysAddr::try_new(pa).ok()?, size) {
+            Ok(va) => Some(va.as_u64()),
+            Err(e) => {
+                log!("arch::map_device(): cannot map {pa:#016x}: {e}");
+                None
+            }
+        }
+    }
+}
+
+impl Timer for X86 {
+    #[inline]
+    fn counter() -> u64 {
+        unsafe { core::arch::x86_64::_rdtsc() }
+    }
+
+    fn counter_frequency() -> Option<u64> {
+        Some(unsafe { cpu::current().get_frequency() }).filter(|&x| x != 0)
+    }
+}
+
+impl Console for X86 {
+    fn write_bytes(data: &[u8]) {
+        uart::write_bytes(data);
+    }
+}
+
+impl Arch for X86 {
+    const NAME: &'static str = "x86_64";
+
+    fn halt() -> ! {
+        loop {
+            interrupts::disable();
+            instructions::hlt();
+        }
+    }
+}
@@ -0,0 +1,336 @@
+/// Allocation state of a run of equally sized blocks, one bit per block.
+///
+/// This only does the bookkeeping, in terms of block indices, and leaves addresses
+/// and where the bits are stored to its user. It depends on nothing but `core`, so
+/// the same code can be exercised outside the kernel.
+#[derive(Debug)]
+pub struct BlockBitmap<'a> {
+    bits: &'a mut [u8],
+    blocks: usize,
+    reserved: usize,
+    free: usize,
+    // Every block below this one is in use, so searches start here.
+    next_free: usize,
+}
+
+impl<'a> BlockBitmap<'a> {
+    /// Returns the number of bytes needed to track `blocks` blocks.
+    pub const fn bytes_for(blocks: usize) -> usize {
+        blocks.div_ceil(8)
+    }
+
+    /// Creates a bitmap of `blocks` blocks stored in `bits`, with the first `reserved`
+    /// blocks in use for good. Returns `None` if `bits` is too small or more blocks are
+    /// reserved than there are.
+    pub fn new(bits: &'a mut [u8], blocks: usize, reserved: usize) -> Option<Self> {
+        if bits.len() < Self::bytes_for(blocks) || reserved > blocks {
+            return None;
+        }
+
+        bits.fill(0);
+
+        let mut bitmap = Self {
+            bits,
+            blocks,
+            reserved,
+            free: blocks - reserved,
+            next_free: reserved,
+        };
+
+        for block in 0..reserved {
+            bitmap.set(block, true);
+        }
+
+        Some(bitmap)
+    }
+
+    /// Gets the number of blocks that are not in use.
+    pub const fn free(&self) -> usize {
+        self.free
+    }
+
+    /// Gets the bits, in which bit `i` is set if block `i` is in use.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits[..Self::bytes_for(self.blocks)]
+    }
+
+    /// Stores the bits in `bits` from now on, which must already hold them, e.g. the same
+    /// memory reached through another mapping.
+    pub fn rebind(&mut self, bits: &'a mut [u8]) {
+        debug_assert!(bits.len() >= Self::bytes_for(self.blocks));
+        self.bits = bits;
+    }
+
+    /// Checks whether block `i` is in use.
+    pub fn is_used(&self, i: usize) -> bool {
+        self.bits[i >> 3] & (1 << (i & 7)) != 0
+    }
+
+    fn set(&mut self, i: usize, used: bool) {
+        if used {
+            self.bits[i >> 3] |= 1 << (i & 7);
+        } else {
+            self.bits[i >> 3] &= !(1 << (i & 7));
+        }
+    }
+
+    /// Marks the first run of `count` free blocks as used and returns the index of its
+    /// first block.
+    ///
+    /// The search starts at the lowest block that may be free rather than at the start,
+    /// so taking blocks one at a time costs about the same for every block.
+    pub fn allocate(&mut self, count: usize) -> Option<usize> {
+        self.allocate_aligned(count, 1, 0)
+    }
+
+    /// Like [`allocate`](Self::allocate), but the run starts at a block `i` for which
+    /// `offset + i` is a multiple of `align`, which must be a power of two.
+    pub fn allocate_aligned(&mut self, count: usize, align: usize, offset: usize) -> Option<usize> {
+        debug_assert!(align.is_power_of_two());
+
+        if count == 0 || count > self.free {
+            return None;
+        }
+
+        let mut run = 0;
+        let mut first_free = None;
+        let mut i = self.next_free;
+
+        while i < self.blocks {
+            // Skip over whole bytes of used blocks.
+            if i & 7 == 0 && self.bits[i >> 3] == 0xff {
+                run = 0;
+                i += 8;
+                continue;
+            }
+
+            if self.is_used(i) {
+                run = 0;
+                i += 1;
+                continue;
+            }
+
+            first_free.get_or_insert(i);
+
+            // A run can only start at an aligned block.
+            if run == 0 && (offset + i) & (align - 1) != 0 {
+                i += 1;
+                continue;
+            }
+
+            run += 1;
+
+            if run == count {
+                let start = i + 1 - count;
+                for j in start..=i {
+                    self.set(j, true);
+                }
+
+                self.free -= count;
+
+                // Blocks before the run that were skipped are still free.
+                self.next_free = match first_free {
+                    Some(x) if x < start => x,
+                    _ => i + 1,
+                };
+
+                return Some(start);
+            }
+
+            i += 1;
+        }
+
+        self.next_free = first_free.unwrap_or(self.blocks);
+        None
+    }
+
+    /// Marks `count` blocks starting at block `start` as free again. Returns false if
+    /// they are not all outside the reserved blocks and inside the bitmap.
+    ///
+    /// Panics if one of the blocks was not in use.
+    pub fn deallocate(&mut self, start: usize, count: usize) -> bool {
+        let Some(end) = start.checked_add(count) else {
+            return false;
+        };
+
+        if start < self.reserved || end > self.blocks {
+            return false;
+        }
+
+        for block in start..end {
+            assert!(
+                self.is_used(block),
+                "Deallocating block that was not held before."
+            );
+
+            self.set(block, false);
+        }
+
+        self.free += count;
+        self.next_free = self.next_free.min(start);
+
+        true
+    }
+
+    /// Marks `count` blocks starting at block `start` as used, whether they were free or
+    /// not, and returns how many of them were in use already. Blocks past the end of the
+    /// bitmap are left out.
+    pub fn claim(&mut self, start: usize, count: usize) -> usize {
+        let end = start.saturating_add(count).min(self.blocks);
+        let mut used = 0;
+
+        for block in start.min(end)..end {
+            if self.is_used(block) {
+                used += 1;
+            } else {
+                self.set(block, true);
+                self.free -= 1;
+            }
+        }
+
+        used
+    }
+
+    /// Checks that the count of free blocks agrees with the bits, that the reserved
+    /// blocks are still in use and that no free block is below where searches start.
+    pub fn is_consistent(&self) -> bool {
+        let free = (0..self.blocks).filter(|&i| !self.is_used(i)).count();
+        free == self.free
+            && (0..self.reserved).all(|i| self.is_used(i))
+            && (0..self.next_free.min(self.blocks)).all(|i| self.is_used(i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockBitmap;
+    use proptest::prelude::*;
+
+    const MAX_BLOCKS: usize = 200;
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Allocate {
+            count: usize,
+            align: usize,
+            offset: usize,
+        },
+        // Frees the allocation at this index, modulo the number of live ones.
+        Deallocate(usize),
+        Claim {
+            start: usize,
+            count: usize,
+        },
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            4 => (1..20usize, 0..5u32, 0..16usize).prop_map(|(count, shift, offset)| {
+                Op::Allocate { count, align: 1 << shift, offset }
+            }),
+            3 => any::<usize>().prop_map(Op::Deallocate),
+            1 => (0..MAX_BLOCKS + 10, 0..10usize)
+                .prop_map(|(start, count)| Op::Claim { start, count }),
+        ]
+    }
+
+    // First block of the lowest run of `count` free blocks in `used` that starts at a
+    // block `i` for which `offset + i` is a multiple of `align`.
+    fn first_fit(used: &[bool], count: usize, align: usize, offset: usize) -> Option<usize> {
+        (0..used.len())
+            .filter(|i| (offset + i) & (align - 1) == 0)
+            .find(|&i| i + count <= used.len() && used[i..i + count].iter().all(|x| !x))
+    }
+
+    fn check(bitmap: &BlockBitmap, used: &[bool]) {
+        assert!(bitmap.is_consistent());
+        assert_eq!(bitmap.free(), used.iter().filter(|x| !**x).count());
+        for (i, &x) in used.iter().enumerate() {
+            assert_eq!(bitmap.is_used(i), x, "block {i}");
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn matches_shadow_model(
+            blocks in 1..MAX_BLOCKS,
+            reserved in 0..32usize,
+            ops in prop::collection::vec(op(), 1..200),
+        ) {
+            let reserved = reserved.min(blocks);
+            let mut bits = [0xaa; BlockBitmap::bytes_for(MAX_BLOCKS)];
+            let mut bitmap = BlockBitmap::new(&mut bits, blocks, reserved).unwrap();
+
+            let mut used = vec![false; blocks];
+            used[..reserved].fill(true);
+            let mut live: Vec<(usize, usize)> = Vec::new();
+            check(&bitmap, &used);
+
+            for op in ops {
+                match op {
+                    Op::Allocate { count, align, offset } => {
+                        let expected = first_fit(&used, count, align, offset);
+                        let start = bitmap.allocate_aligned(count, align, offset);
+                        prop_assert_eq!(start, expected);
+
+                        if let Some(start) = start {
+                            used[start..start + count].fill(true);
+                            live.push((start, count));
+                        }
+                    }
+                    Op::Deallocate(_) if live.is_empty() => {
+                        // Reserved blocks are never given back.
+                        if reserved > 0 {
+                            prop_assert!(!bitmap.deallocate(0, reserved));
+                        }
+                    }
+                    Op::Deallocate(i) => {
+                        let (start, count) = live.swap_remove(i % live.len());
+                        prop_assert!(bitmap.deallocate(start, count));
+                        used[start..start + count].fill(false);
+                    }
+                    Op::Claim { start, count } => {
+                        let end = (start + count).min(blocks);
+                        let range = start.min(end)..end;
+                        let expected = used[range.clone()].iter().filter(|x| **x).count();
+                        prop_assert_eq!(bitmap.claim(start, count), expected);
+                        used[range].fill(true);
+                    }
+                }
+
+                check(&bitmap, &used);
+            }
+        }
+    }
+
+    #[test]
+    fn reserved_blocks_stay_in_use() {
+        let mut bits = [0; 2];
+        let mut bitmap = BlockBitmap::new(&mut bits, 16, 3).unwrap();
+
+        assert_eq!(bitmap.free(), 13);
+        assert_eq!(bitmap.allocate(1), Some(3));
+        assert!(!bitmap.deallocate(2, 1));
+        assert!(!bitmap.deallocate(2, 2));
+        assert!(bitmap.deallocate(3, 1));
+        assert_eq!(bitmap.allocate_aligned(4, 4, 0), Some(4));
+        assert_eq!(bitmap.allocate_aligned(2, 4, 1), Some(11));
+        assert_eq!(bitmap.allocate(1), Some(3));
+        assert!(bitmap.is_consistent());
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        let mut bits = [0; 2];
+        assert!(BlockBitmap::new(&mut bits, 17, 0).is_none());
+        assert!(BlockBitmap::new(&mut bits, 16, 17).is_none());
+
+        let mut bitmap = BlockBitmap::new(&mut bits, 16, 0).unwrap();
+        assert_eq!(bitmap.allocate(0), None);
+        assert_eq!(bitmap.allocate(17), None);
+        assert!(!bitmap.deallocate(15, 2));
+        assert!(!bitmap.deallocate(usize::MAX, 2));
+        assert_eq!(bitmap.claim(14, 10), 0);
+        assert_eq!(bitmap.free(), 14);
+    }
+}
@@ -0,0 +1,192 @@
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::arch::{Arch, Current, Timer};
+use crate::log;
+use crate::metrics;
+use crate::metrics::{Metric, MetricKind, Sample};
+use crate::shell;
+use crate::shell::{Command, CommandError};
+
+/// Maximum number of boot phases that are recorded.
+const MAX_PHASES: usize = 32;
+
+// Timestamp counter when the kernel was entered.
+static START: AtomicU64 = AtomicU64::new(0);
+
+// Boot phases in the order they ran.
+static PHASES: Mutex<[Option<Phase>; MAX_PHASES]> = Mutex::new([None; MAX_PHASES]);
+
+/// A boot phase, with the timestamp counter at its start and end.
+#[derive(Clone, Copy)]
+struct Phase {
+    name: &'static str,
+    start: u64,
+    end: u64,
+}
+
+/// Records the time the kernel was entered. Called first thing during boot.
+pub fn start() {
+    START.store(Current::counter(), Ordering::Relaxed);
+}
+
+/// Returns the timestamp counter when the kernel was entered.
+pub fn start_timestamp() -> u64 {
+    START.load(Ordering::Relaxed)
+}
+
+/// Runs `f` as the boot phase `name` and records how long it took.
+///
+/// This only reads the timestamp counter, so it can time phases that run before the
+/// processor or the heap are initialized.
+pub fn phase<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+    let start = Current::counter();
+    let result = f();
+    let end = Current::counter();
+
+    let mut phases = PHASES.lock();
+    if let Some(slot) = phases.iter_mut().find(|x| x.is_none()) {
+        *slot = Some(Phase { name, start, end });
+    }
+
+    result
+}
+
+/// Converts timestamp counter cycles into microseconds, or 0 while the frequency of
+/// the counter is not known.
+fn cycles_to_us(cycles: u64) -> u64 {
+    let Some(hz) = Current::counter_frequency() else {
+        return 0;
+    };
+    (cycles as u128 * 1_000_000 / hz as u128) as u64
+}
+
+/// Returns the microseconds between entering the kernel and the end of the last phase.
+pub fn total_us() -> u64 {
+    let start = START.load(Ordering::Relaxed);
+    let end = PHASES
+        .lock()
+        .iter()
+        .flatten()
+        .map(|x| x.end)
+        .max()
+        .unwrap_or(start);
+
+    cycles_to_us(end.saturating_sub(start))
+}
+
+/// Calls `f` with the name, start and duration in microseconds of every boot phase, in
+/// the order they ran. Starts are relative to entering the kernel.
+pub fn for_each_phase(mut f: impl FnMut(&'static str, u64, u64)) {
+    let start = START.load(Ordering::Relaxed);
+    let phases = *PHASES.lock();
+
+    for phase in phases.iter().flatten() {
+        f(
+            phase.name,
+            cycles_to_us(phase.start.saturating_sub(start)),
+            cycles_to_us(phase.end - phase.start),
+        );
+    }
+}
+
+/// Writes the boot timeline, one phase per line with a bar proportional to its time.
+pub fn timeline(out: &mut dyn Write) -> fmt::Result {
+    const BAR_WIDTH: u64 = 40;
+
+    let total = total_us();
+    let mut result = Ok(());
+
+    for_each_phase(|name, start, duration| {
+        if result.is_err() {
+            return;
+        }
+
+        result = write!(out, "{name:<12} {start:>10} us {duration:>10} us ");
+        for _ in 0..(duration * BAR_WIDTH).div_ceil(total.max(1)) {
+            result = result.and_then(|_| out.write_char('#'));
+        }
+        result = result.and_then(|_| writeln!(out));
+    });
+    result?;
+
+    writeln!(out, "{:<12} {:>10} us", "total", total)
+}
+
+/// Writes the boot phases on one line of `key=value` pairs, for scripts that track
+/// cold start latency.
+pub fn summary(out: &mut dyn Write) -> fmt::Result {
+    write!(out, "total_us={}", total_us())?;
+
+    let mut result = Ok(());
+    for_each_phase(|name, _, duration| {
+        if result.is_ok() {
+            result = write!(out, " {name}_us={duration}");
+        }
+    });
+    result
+}
+
+// Formats the summary as part of a log line.
+struct Summary;
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        summary(f)
+    }
+}
+
+fn boot_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    match args {
+        [] => timeline(out)?,
+        ["summary"] => {
+            summary(out)?;
+            writeln!(out)?;
+        }
+        _ => return Err(CommandError::Usage),
+    }
+
+    Ok(())
+}
+
+/// Logs how long each boot phase took, and a summary line that is easy to parse.
+///
+/// Called at the end of boot, once the shell and metrics exist. The `boot` shell
+/// command shows the same timeline later on, and the total is exported as the
+/// `lithium_boot_microseconds` metric.
+pub fn report() {
+    for_each_phase(|name, start, duration| {
+        log!("boot::report(): {name:<12} started at {start:>8} us, took {duration:>8} us");
+    });
+
+    log!("boot::report(): {}", Summary);
+
+    assert!(
+        shell::register(Command {
+            name: "boot",
+            usage: "[summary]",
+            help: "show how long each boot phase took",
+            run: boot_command,
+        }),
+        "boot::report(): failed to register shell command"
+    );
+
+    assert!(
+        metrics::register(Metric {
+            name: "lithium_boot_microseconds",
+            help: "Time from entering the kernel to the end of boot.",
+            kind: MetricKind::Gauge,
+            sample: Sample::Value(total_us),
+        }),
+        "boot::report(): failed to register metric"
+    );
+
+    log!(
+        "boot::report(): booted {} in {} us [ \x1b[0;32mOK\x1b[0m ]",
+        Current::NAME,
+        total_us()
+    );
+}
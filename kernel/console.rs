@@ -1,14 +1,26 @@
 #![allow(dead_code)]
 
 pub mod uart {
-    use crate::spin_until;
+    use crate::critical::{self, CriticalSection, IrqMutex};
+    use crate::inspect::parse_u64;
+    use crate::timer;
     use bitflags::bitflags;
     use core::fmt::Write;
-    use spin::Mutex;
-    use x86_64::instructions::{interrupts, port::Port};
+    use core::sync::atomic::{AtomicU16, AtomicU8, Ordering};
+    use core::time::Duration;
+    use x86_64::instructions::port::Port;
 
     pub const COM1: u16 = 0x3F8;
 
+    /// Base ports and IRQ lines of the standard serial ports `ttyS0` to `ttyS3`.
+    const PORTS: [(u16, u8); 4] = [(COM1, 4), (0x2F8, 3), (0x3E8, 4), (0x2E8, 3)];
+
+    /// Fastest baud rate, every other one is this divided by an integer.
+    const MAX_BAUD: u32 = 115200;
+
+    /// How long to wait for room in the transmit buffer before dropping a byte.
+    const TRANSMIT_TIMEOUT: Duration = Duration::from_millis(10);
+
     bitflags! {
         pub struct InterruptEnableFlags: u8 {
             const RECEIVED = 1 << 0;
@@ -33,22 +45,133 @@ pub mod uart {
     pub const BACKSPACE: u8 = ctrl(b'H');
     pub const DELETE: u8 = 0x7F;
 
-    static mut UART: Mutex<Uart> = Mutex::new(Uart(COM1));
+    static UART: IrqMutex<Uart> = IrqMutex::new(Uart(COM1));
 
-    pub fn init() {
-        unsafe {
-            UART.lock().init();
+    // Base port of the UART, readable without the lock for emergency output.
+    static BASE: AtomicU16 = AtomicU16::new(COM1);
+
+    // IRQ line of the UART.
+    static IRQ: AtomicU8 = AtomicU8::new(PORTS[0].1);
+
+    /// Which serial port the console is on and how fast it runs.
+    #[derive(Debug, Clone, Copy)]
+    pub struct Config {
+        pub base: u16,
+        pub irq: u8,
+        pub baud: u32,
+    }
+
+    impl Config {
+        /// COM1 at 38400 baud, what QEMU and most machines provide.
+        pub const DEFAULT: Config = Config {
+            base: COM1,
+            irq: PORTS[0].1,
+            baud: 38400,
+        };
+
+        /// Applies a kernel command line argument. Returns false if the argument is
+        /// meant for the console but malformed, leaving the configuration unchanged.
+        ///
+        /// `console=ttyS<n>[,<baud>]` selects one of the standard serial ports,
+        /// `console.base=<port>` and `console.irq=<line>` then override where it is,
+        /// for firmware that moved the UART.
+        pub fn parse_arg(&mut self, arg: &str) -> bool {
+            if let Some(value) = arg.strip_prefix("console=") {
+                let (port, baud) = match value.split_once(',') {
+                    Some((port, baud)) => (port, Some(baud)),
+                    None => (value, None),
+                };
+
+                let Some(&(base, irq)) = port
+                    .strip_prefix("ttyS")
+                    .and_then(|x| x.parse::<usize>().ok())
+                    .and_then(|x| PORTS.get(x))
+                else {
+                    return false;
+                };
+
+                let baud = match baud.map(|x| x.parse::<u32>().ok()) {
+                    None => self.baud,
+                    Some(Some(baud)) if valid_baud(baud) => baud,
+                    Some(_) => return false,
+                };
+
+                *self = Config { base, irq, baud };
+                return true;
+            }
+
+            if let Some(value) = arg.strip_prefix("console.base=") {
+                return match parse_u64(value).and_then(|x| u16::try_from(x).ok()) {
+                    Some(base) => {
+                        self.base = base;
+                        true
+                    }
+                    None => false,
+                };
+            }
+
+            if let Some(value) = arg.strip_prefix("console.irq=") {
+                // The timer and the cascade are not available.
+                return match parse_u64(value) {
+                    Some(irq @ (1 | 3..=15)) => {
+                        self.irq = irq as u8;
+                        true
+                    }
+                    _ => false,
+                };
+            }
+
+            true
         }
     }
 
+    fn valid_baud(baud: u32) -> bool {
+        baud != 0 && baud <= MAX_BAUD && (MAX_BAUD / baud) * baud == MAX_BAUD
+    }
+
+    pub fn init(config: Config) {
+        BASE.store(config.base, Ordering::Relaxed);
+        IRQ.store(config.irq, Ordering::Relaxed);
+
+        UART.with(|uart| {
+            *uart = Uart::new(config.base);
+            uart.init(config.baud);
+        });
+    }
+
+    /// Returns the IRQ line of the console UART.
+    pub fn irq() -> u8 {
+        IRQ.load(Ordering::Relaxed)
+    }
+
     pub fn print(args: core::fmt::Arguments) {
-        interrupts::without_interrupts(|| unsafe {
-            UART.lock().write_fmt(args).unwrap();
+        UART.with(|uart| uart.write_fmt(args).unwrap());
+    }
+
+    /// Prints without waiting for the UART lock, for panic and exception handlers that
+    /// may have interrupted its holder. If the lock is taken the ports are written
+    /// directly, so the output can interleave with the print that was interrupted.
+    pub fn emergency_print(args: core::fmt::Arguments) {
+        critical::with(|cs| {
+            let _ = match UART.try_lock(cs) {
+                Some(mut uart) => uart.write_fmt(args),
+                None => Uart::new(BASE.load(Ordering::Relaxed)).write_fmt(args),
+            };
+        });
+    }
+
+    /// Writes bytes to the serial port without any translation.
+    pub fn write_bytes(data: &[u8]) {
+        UART.with(|uart| {
+            for &byte in data {
+                uart.send_raw(byte);
+            }
         });
     }
 
-    pub fn read() -> Option<u8> {
-        unsafe { UART.lock().receive() }
+    /// Takes a received byte from the UART, if there is one.
+    pub fn read(cs: CriticalSection) -> Option<u8> {
+        UART.lock(cs).receive()
     }
 
     fn outb(port: u16, v: u8) {
@@ -97,16 +220,17 @@ pub mod uart {
             Self(base)
         }
 
-        pub fn init(&mut self) {
+        pub fn init(&mut self, baud: u32) {
             // Disable interrupts from serial port.
             outb(self.port_intr_enable(), 0x00);
 
             // Enable DLAB.
             outb(self.port_line_ctrl(), 0x80);
 
-            // Set maximum speed to 38400 bps by configuring DLL and DLM.
-            outb(self.port_data(), 0x03);
-            outb(self.port_intr_enable(), 0x00);
+            // Set the speed by configuring the divisor in DLL and DLM.
+            let divisor = (MAX_BAUD / baud) as u16;
+            outb(self.port_data(), divisor as u8);
+            outb(self.port_intr_enable(), (divisor >> 8) as u8);
 
             // Disable DLAB and set data word length to 8 bits.
             outb(self.port_line_ctrl(), 0x03);
@@ -130,23 +254,20 @@ pub mod uart {
         fn send(&mut self, data: u8) {
             match data {
                 BACKSPACE | DELETE => {
-                    spin_until!(self.line_status().contains(LineStatusFlags::OUTPUT_EMPTY));
-                    outb(self.port_data(), b'\x08');
-                    spin_until!(self.line_status().contains(LineStatusFlags::OUTPUT_EMPTY));
-                    outb(self.port_data(), b' ');
-                    spin_until!(self.line_status().contains(LineStatusFlags::OUTPUT_EMPTY));
-                    outb(self.port_data(), b'\x08');
-                }
-                _ => {
-                    spin_until!(self.line_status().contains(LineStatusFlags::OUTPUT_EMPTY));
-                    outb(self.port_data(), data);
+                    self.send_raw(b'\x08');
+                    self.send_raw(b' ');
+                    self.send_raw(b'\x08');
                 }
+                _ => self.send_raw(data),
             }
         }
 
         fn send_raw(&mut self, data: u8) {
-            spin_until!(self.line_status().contains(LineStatusFlags::OUTPUT_EMPTY));
-            outb(self.port_data(), data);
+            // A stuck UART must not hang every thread that logs, so drop the byte.
+            let empty = || self.line_status().contains(LineStatusFlags::OUTPUT_EMPTY);
+            if timer::wait_until(empty, TRANSMIT_TIMEOUT).is_ok() {
+                outb(self.port_data(), data);
+            }
         }
 
         fn receive(&mut self) -> Option<u8> {
@@ -168,91 +289,301 @@ pub mod uart {
     }
 }
 
-use crate::trap;
-use spin::Mutex;
-
-pub struct ConsoleInputBuffer {
-    buffer: [char; 256],
-    read_index: usize,
-    write_index: usize,
-    edit_index: usize,
-    echo: bool,
+pub mod ansi {
+    use core::fmt::Write;
+
+    /// A key press decoded from the input stream.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Key {
+        Char(u8),
+        Up,
+        Down,
+        Right,
+        Left,
+        Home,
+        End,
+        Delete,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum State {
+        Ground,
+        // Received ESC.
+        Escape,
+        // Received ESC [ and possibly a numeric parameter.
+        Csi(u8),
+        // Received ESC O, sent by some terminals for home and end.
+        Ss3,
+    }
+
+    /// Incremental decoder for the VT100/xterm sequences terminals send for editing
+    /// keys.
+    ///
+    /// Sequences that are not understood are swallowed rather than inserted into the
+    /// line as garbage.
+    #[derive(Debug)]
+    pub struct Parser {
+        state: State,
+    }
+
+    impl Parser {
+        pub const fn new() -> Self {
+            Self {
+                state: State::Ground,
+            }
+        }
+
+        /// Feeds one input byte, returning a key once a complete one was received.
+        pub fn feed(&mut self, ch: u8) -> Option<Key> {
+            let (state, key) = match (self.state, ch) {
+                (State::Ground, 0x1B) => (State::Escape, None),
+                (State::Ground, _) => (State::Ground, Some(Key::Char(ch))),
+                (State::Escape, b'[') => (State::Csi(0), None),
+                (State::Escape, b'O') => (State::Ss3, None),
+                (State::Escape, _) => (State::Ground, None),
+                (State::Csi(n), b'0'..=b'9') => (
+                    State::Csi(n.saturating_mul(10).saturating_add(ch - b'0')),
+                    None,
+                ),
+                // Modifiers such as ESC [ 1 ; 5 C are ignored.
+                (State::Csi(_), b';') => (State::Csi(0), None),
+                (State::Csi(_), b'A') => (State::Ground, Some(Key::Up)),
+                (State::Csi(_), b'B') => (State::Ground, Some(Key::Down)),
+                (State::Csi(_), b'C') => (State::Ground, Some(Key::Right)),
+                (State::Csi(_), b'D') => (State::Ground, Some(Key::Left)),
+                (State::Csi(_), b'H') => (State::Ground, Some(Key::Home)),
+                (State::Csi(_), b'F') => (State::Ground, Some(Key::End)),
+                (State::Csi(1 | 7), b'~') => (State::Ground, Some(Key::Home)),
+                (State::Csi(4 | 8), b'~') => (State::Ground, Some(Key::End)),
+                (State::Csi(3), b'~') => (State::Ground, Some(Key::Delete)),
+                (State::Csi(_), _) => (State::Ground, None),
+                (State::Ss3, b'H') => (State::Ground, Some(Key::Home)),
+                (State::Ss3, b'F') => (State::Ground, Some(Key::End)),
+                (State::Ss3, _) => (State::Ground, None),
+            };
+
+            self.state = state;
+            key
+        }
+    }
+
+    /// Moves the terminal cursor `n` columns to the left.
+    pub fn move_left(out: &mut dyn Write, n: usize) {
+        if n > 0 {
+            let _ = write!(out, "\x1b[{n}D");
+        }
+    }
+
+    /// Moves the terminal cursor `n` columns to the right.
+    pub fn move_right(out: &mut dyn Write, n: usize) {
+        if n > 0 {
+            let _ = write!(out, "\x1b[{n}C");
+        }
+    }
+
+    /// Erases from the terminal cursor to the end of the line.
+    pub fn erase_to_end_of_line(out: &mut dyn Write) {
+        let _ = out.write_str("\x1b[K");
+    }
 }
 
-static mut INPUT_BUFFER: Mutex<ConsoleInputBuffer> = Mutex::new(ConsoleInputBuffer {
-    buffer: ['\x00'; 256],
-    read_index: 0,
-    write_index: 0,
-    edit_index: 0,
-    echo: false,
-});
+use crate::critical::CriticalSection;
+use crate::initcall::InitGuard;
+use crate::logger;
+use crate::logger::Level;
+use crate::mpsc;
+use crate::multiboot;
+use crate::mux;
+use crate::replay;
+use crate::replay::Source;
+use crate::timer;
+use crate::trap;
+use crate::tty;
+use crate::tty::Mode;
+use crate::tty::ReadError;
+use crate::workqueue;
+use crate::workqueue::Work;
+
+/// Most fields a log line can carry for the context of the thread to be added.
+const MAX_FIELDS: usize = 8;
+
+/// Number of received bytes that can wait for [`process_input`].
+const RX_QUEUE_SIZE: usize = 64;
+
+// Raw bytes drained from the UART by the interrupt handler.
+static RX_QUEUE: mpsc::Queue<u8, RX_QUEUE_SIZE> = mpsc::Queue::new();
+
+// Deferred line discipline processing for received bytes.
+static INPUT_WORK: Work = Work::new(process_input);
 
+// Initialization of the serial console, which everything that logs needs.
+pub static INIT: InitGuard = InitGuard::new("console");
+
+/// Initializes the serial console.
+///
+/// The console is on COM1 at 38400 baud unless the kernel command line says otherwise,
+/// see [`uart::Config::parse_arg`], and so is the format of log lines, see
+/// [`logger::Format::parse_arg`].
 pub fn init() {
-    uart::init();
-    crate::print!("\x1bc"); // clears the screen
-    crate::println!();
+    let _init = INIT.start();
+
+    let mut config = uart::Config::DEFAULT;
+    let mut format = logger::Format::DEFAULT;
+    let malformed = multiboot::args()
+        .filter(|arg| !config.parse_arg(arg) | !format.parse_arg(arg))
+        .last();
+
+    uart::init(config);
+    logger::set_format(format);
+
+    // Tools reading JSON lines expect nothing else on the console.
+    if format.style == logger::Style::Text {
+        crate::print!("\x1bc"); // clears the screen
+        crate::println!();
+    }
+
+    if let Some(arg) = malformed {
+        crate::log!("console::init(): ignoring malformed {arg}");
+    }
+
+    crate::log!(
+        "console::init(): serial port {:#x}, irq {}, {} baud",
+        config.base,
+        config.irq,
+        config.baud
+    );
     crate::log!("console::init(): booting lithium... [ \x1b[0;32mOK\x1b[0m ]");
 }
 
+/// Returns the IRQ line of the serial console.
+pub fn irq() -> u8 {
+    uart::irq()
+}
+
 pub fn print(args: core::fmt::Arguments) {
     uart::print(args);
 }
 
-pub fn interrupt() {
-    unsafe {
-        // let ch = uart::read() as char;
-        let mut buf = INPUT_BUFFER.lock();
-
-        const CTRL_U: u8 = uart::ctrl(b'U');
-
-        while let Some(mut ch) = uart::read() {
-            match ch {
-                CTRL_U => {
-                    while {
-                        let e = buf.edit_index;
-                        let w = buf.write_index;
-                        e != w && buf.buffer[(e - 1) % 256] != '\n'
-                    } {
-                        buf.edit_index -= 1;
-
-                        if buf.echo {
-                            crate::print!("{}", uart::BACKSPACE as char);
-                        }
-                    }
-                }
-                _ => {
-                    if ch != b'\x00' && (buf.edit_index - buf.read_index) < 256 {
-                        ch = if ch == b'\r' { b'\n' } else { ch };
-                        let e = buf.edit_index;
-                        buf.buffer[e] = ch as char;
-                        buf.edit_index = buf.edit_index.wrapping_add(1) % 256;
-
-                        if buf.echo {
-                            crate::print!("{}", ch as char);
-                        }
-
-                        if ch == b'\n'
-                            || ch == uart::ctrl(b'D')
-                            || buf.edit_index == buf.read_index + 256
-                        {
-                            buf.write_index = buf.edit_index;
-                        }
-                    }
-                }
-            };
+/// Prints a log line and records it in the log ring. Called by the logging macros.
+pub fn log(file: &'static str, line: u32, level: Level, args: core::fmt::Arguments) {
+    log_fields(file, line, level, &[], args);
+}
+
+/// Like [`log`], with fields after the message, see [`logger::Field`].
+pub fn log_fields(
+    file: &'static str,
+    line: u32,
+    level: Level,
+    fields: &[logger::Field],
+    args: core::fmt::Arguments,
+) {
+    // The context of the thread goes after the fields given, if there is room.
+    let context = logger::context();
+    let mut all = [logger::Field {
+        key: "",
+        value: &"",
+    }; MAX_FIELDS];
+    let fields = match &context {
+        Some((key, value)) if fields.len() < MAX_FIELDS => {
+            all[..fields.len()].copy_from_slice(fields);
+            all[fields.len()] = logger::Field { key, value };
+            &all[..=fields.len()]
         }
+        _ => fields,
+    };
+
+    let format = logger::format();
+    let record = logger::Line {
+        file,
+        line,
+        level,
+        uptime: timer::uptime(),
+        args,
+        fields,
+    };
+    let _ = logger::write_line(&mut Console, &format, &record);
+
+    if fields.is_empty() {
+        logger::record(file, line, level, args);
+    } else {
+        let fields = logger::Fields(fields);
+        logger::record(file, line, level, format_args!("{args}{fields}"));
+    }
+}
+
+/// Writer for the console, for code that formats into a `core::fmt::Write`.
+pub struct Console;
+
+impl core::fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        print(format_args!("{s}"));
+        Ok(())
+    }
+}
+
+/// Handles the serial receive interrupt.
+///
+/// Only drains the UART here, line editing and echo are deferred to the work queue.
+pub fn interrupt(cs: CriticalSection) {
+    // Input is dropped when the bottom half falls too far behind.
+    while let Some(ch) = uart::read(cs) {
+        let _ = RX_QUEUE.push(ch);
+    }
+
+    workqueue::schedule(&INPUT_WORK);
+}
+
+/// Queues bytes received from an input device other than the serial port, such as
+/// the keyboard, as if they had been typed on the serial console.
+pub fn receive(data: &[u8]) {
+    for &ch in data {
+        let _ = RX_QUEUE.push(ch);
     }
+
+    workqueue::schedule(&INPUT_WORK);
+}
+
+/// Hands the bytes received by [`interrupt`] and [`receive`] to the serial
+/// multiplexer, through the input recorder.
+fn process_input() {
+    let rx =
+        core::iter::from_fn(|| RX_QUEUE.pop()).filter(|&ch| replay::input(Source::Console, &[ch]));
+    mux::input(rx);
+}
+
+/// Reads one line of console input into `line`, without the trailing newline, and
+/// returns its length. Blocks the calling thread until a complete line is available.
+///
+/// See [`tty::Tty::read_line`] for end-of-file and cancelled lines.
+pub fn read_line(line: &mut [u8]) -> Result<usize, ReadError> {
+    tty::console().read_line(line)
+}
+
+/// Like [`read_line`], but returns [`ReadError::WouldBlock`] instead of blocking.
+pub fn try_read_line(line: &mut [u8]) -> Result<usize, ReadError> {
+    tty::console().try_read_line(line)
+}
+
+/// Reads one byte of console input, blocking the calling thread until one is available.
+pub fn read_char() -> Result<u8, ReadError> {
+    tty::console().read_char()
+}
+
+/// Like [`read_char`], but returns [`ReadError::WouldBlock`] instead of blocking.
+pub fn try_read_char() -> Result<u8, ReadError> {
+    tty::console().try_read_char()
 }
 
 pub fn enable_interrupts() {
     // let _ = uart::read();
-    trap::enable_irq(trap::IRQ_COM1);
+    trap::enable_irq(irq());
 }
 
 pub fn enable_echo(v: bool) {
-    unsafe {
-        INPUT_BUFFER.lock().echo = v;
-    }
+    let console = tty::console();
+    let mut mode = console.mode();
+    mode.set(Mode::ECHO, v);
+    console.set_mode(mode);
 }
 
 #[macro_export]
@@ -269,29 +600,65 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Like `print!`, but never blocks on the UART lock. Only meant for panic and
+/// exception handlers.
 #[macro_export]
-macro_rules! log {
-    ($($arg:tt)*) => ({
-        unsafe {
-            use $crate::cpu;
-            const ANSI_FOREGROUND_YELLOW: &str = "\x1b[33m";
-            const ANSI_CLEAR: &str = "\x1b[0m";
-            const ANSI_FOREGROUND_CYAN: &str = "\x1b[36m";
-            let ticks = cpu::ticks();
-            $crate::print!("{ANSI_FOREGROUND_YELLOW}[{ticks: >13.6}]{ANSI_CLEAR} ");
-            $crate::print!("{ANSI_FOREGROUND_CYAN}");
-            $crate::print!("{0: <20} | line {1: <5} | ", file!(), line!());
-            $crate::print!("{ANSI_CLEAR}");
-            $crate::println!(" {}", format_args!($($arg)*));
+macro_rules! emergency_print {
+    ($($args:tt)*) => ({
+        $crate::console::uart::emergency_print(format_args!($($args)*));
+    })
+}
+
+/// Logs at `level`. Fields for structured output go in front of the message, e.g.
+/// `log_at!(Level::Info, irq = irq, port = base; "serial port up")`.
+#[macro_export]
+macro_rules! log_at {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($arg:tt)*) => ({
+        let level = $level;
+        if $crate::logger::enabled(file!(), level) {
+            $crate::console::log_fields(
+                file!(),
+                line!(),
+                level,
+                &[$($crate::logger::Field { key: stringify!($key), value: &$value }),+],
+                format_args!($($arg)*),
+            );
+        }
+    });
+    ($level:expr, $($arg:tt)*) => ({
+        let level = $level;
+        if $crate::logger::enabled(file!(), level) {
+            $crate::console::log(file!(), line!(), level, format_args!($($arg)*));
         }
     })
 }
 
 #[macro_export]
-macro_rules! spin_until {
-    ($cond:expr) => {
-        while !$cond {
-            core::hint::spin_loop();
+macro_rules! log {
+    ($($arg:tt)*) => ($crate::log_at!($crate::logger::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log_at!($crate::logger::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log_at!($crate::logger::Level::Debug, $($arg)*));
+}
+
+/// Logs at trace level, but only while the named tracepoint is enabled.
+#[macro_export]
+macro_rules! tracepoint {
+    ($name:literal, $($arg:tt)*) => ({
+        if $crate::logger::tracepoint_enabled($name) {
+            $crate::console::log(
+                file!(),
+                line!(),
+                $crate::logger::Level::Trace,
+                format_args!("{}: {}", $name, format_args!($($arg)*)),
+            );
         }
-    };
+    })
 }
@@ -1,5 +1,8 @@
 use core::arch::asm;
+use core::fmt::Write;
+use core::ops::{Deref, DerefMut};
 use core::sync::atomic;
+use core::sync::atomic::{AtomicBool, AtomicU8};
 
 use x86_64::instructions::interrupts;
 use x86_64::instructions::tables::load_tss;
@@ -8,18 +11,43 @@ use x86_64::registers::segmentation::{Segment, Segment64, CS, DS, ES, GS, SS};
 use x86_64::structures::gdt::Descriptor;
 use x86_64::structures::gdt::GlobalDescriptorTable;
 use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::structures::paging::PageTableFlags;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 use raw_cpuid::native_cpuid::CpuIdReaderNative;
 use raw_cpuid::CpuId;
 
+use crate::critical::{self, InterruptGuard};
+use crate::memory;
+use crate::shell;
+use crate::shell::{Command, CommandError};
+use crate::{assert_offset, assert_size};
+
 /// Number of total CPUs that are currently supported.
 pub const CPU_COUNT: usize = 1;
 
 /// Size of the trap handler stack.
 pub const TRAP_STACK_SIZE: usize = 4096 * 5;
 
+/// Size of the stack NMIs are handled on.
+pub const NMI_STACK_SIZE: usize = 4096 * 2;
+
+/// Interrupt stack table entry used for NMIs.
+pub const NMI_IST_INDEX: u16 = 2;
+
+/// Interrupt stack table entry used for double faults.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+/// Interrupt stack table entry used for machine checks.
+pub const MACHINE_CHECK_IST_INDEX: u16 = 3;
+
+/// Size of the stacks exceptions other than NMIs are handled on.
+pub const EXCEPTION_STACK_SIZE: usize = 4096 * 4;
+
+/// Marks a per-cpu data structure set up by [`init`], "lithcpu" in ASCII.
+const CPU_MAGIC: u64 = 0x0075_7063_6874_696c;
+
 // This structure should be protected by a spinlock but locks require
 // access to this structure to track the level of interrupt nesting.
 // Sort of a chicken-and-egg problem..
@@ -28,7 +56,55 @@ static mut CPUS: [Cpu; CPU_COUNT] = [Cpu::new(); CPU_COUNT];
 // For now, we are just hard coding a large array in .bss
 // to handle for the stack. Ideally we would have allocated this
 // page, but again sort of a chicken-and-egg problem with the spinlocks.
-static TRAP_STACK: [u8; TRAP_STACK_SIZE] = [0; TRAP_STACK_SIZE];
+// Each processor gets its own slot so that they never share a trap stack.
+static mut TRAP_STACKS: [[u8; TRAP_STACK_SIZE]; CPU_COUNT] = [[0; TRAP_STACK_SIZE]; CPU_COUNT];
+
+// NMIs can arrive while the trap stack is in use, even with interrupts disabled, so
+// they are handled on a stack of their own.
+static mut NMI_STACKS: [[u8; NMI_STACK_SIZE]; CPU_COUNT] = [[0; NMI_STACK_SIZE]; CPU_COUNT];
+
+// Lifecycle state of each processor. This lives outside of [`Cpu`] so that other
+// processors can observe it without touching the per-cpu structure.
+static CPU_STATES: [AtomicU8; CPU_COUNT] =
+    [const { AtomicU8::new(CpuState::Offline as u8) }; CPU_COUNT];
+
+// Whether the per-cpu data structure of each processor is handed out by
+// [`current_mut`], to catch a second mutable reference in debug builds.
+static BORROWED: [AtomicBool; CPU_COUNT] = [const { AtomicBool::new(false) }; CPU_COUNT];
+
+/// Lifecycle state of a logical processor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CpuState {
+    /// Processor has not been initialized.
+    Offline = 0,
+    /// Processor is running kernel code and accepting work.
+    Online = 1,
+}
+
+impl CpuState {
+    const fn from_u8(v: u8) -> Self {
+        match v {
+            1 => Self::Online,
+            _ => Self::Offline,
+        }
+    }
+}
+
+/// Reasons an interrupt stack is refused by [`set_interrupt_stack`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptStackError {
+    /// The index is not one of the seven interrupt stack table entries.
+    InvalidIndex,
+    /// The entry already holds a stack.
+    InUse,
+    /// The top of the stack is not aligned to 16 bytes.
+    Misaligned,
+    /// Part of the stack is not mapped writable.
+    Unmapped,
+    /// The page below the stack is mapped, so an overflow would go unnoticed.
+    NoGuardPage,
+}
 
 /// Data and provenance for CPU TSC frequency.
 ///
@@ -40,6 +116,9 @@ pub enum CpuFrequency {
     /// Measured processor frequency from the TSC info MSR.
     CpuIdTscInfo { hz: u64 },
 
+    /// Processor frequency reported by the hypervisor.
+    Hypervisor { hz: u64 },
+
     /// No valid way to measure processor frequency.
     Invalid,
 }
@@ -50,6 +129,7 @@ impl CpuFrequency {
 
         match *self {
             CpuIdTscInfo { hz } => hz,
+            Hypervisor { hz } => hz,
             Invalid => 2000000000, // we guess the value at 2GHz
         }
     }
@@ -60,33 +140,52 @@ impl CpuFrequency {
 #[allow(unused)]
 #[repr(C, align(16))]
 pub struct Cpu {
+    magic: u64,                        // CPU_MAGIC once initialized
     id: usize,                         // logical identifier of core
+    apic_id: u32,                      // local APIC identifier of core
     freq: CpuFrequency,                // frequency which timestamp counter runs at
     pub tss: TaskStateSegment,         // task state segment
     pub gdt: GlobalDescriptorTable,    // global descriptor table
     pub idt: InterruptDescriptorTable, // interrupt descriptor table
-    pub irq_mask: u16,                 // current interrupt mask
 }
 
+// Loaded into the processor as they are, see the Intel SDM 8.7 and 6.10.
+assert_size!(TaskStateSegment, 104);
+assert_offset!(TaskStateSegment, interrupt_stack_table, 36);
+assert_size!(InterruptDescriptorTable, 4096);
+
 impl Cpu {
     /// Creates a new per-cpu kernel data structure.
     pub const fn new() -> Self {
         Self {
+            magic: 0,
             id: 0,
+            apic_id: 0,
             freq: CpuFrequency::Invalid,
             tss: TaskStateSegment::new(),
             gdt: GlobalDescriptorTable::new(),
             idt: InterruptDescriptorTable::new(),
-            irq_mask: 0xffffu16,
         }
     }
 
+    /// Returns the logical identifier of the processor.
+    #[inline]
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
     /// Returns the processor frequency in megahertz (MHz).
     #[inline]
     pub fn get_frequency(&self) -> u64 {
         self.freq.frequency()
     }
 
+    /// Overrides the processor frequency with one from a more accurate source.
+    #[inline]
+    pub fn set_frequency(&mut self, freq: CpuFrequency) {
+        self.freq = freq;
+    }
+
     // TODO(kosinw): Actually use CPUID to check if rdtsc is available on machine.
     /// Returns the timestamp of the current processor.
     #[inline]
@@ -100,12 +199,65 @@ impl Cpu {
             u64::from(hi) << 32 | u64::from(lo)
         }
     }
+}
 
-    /// Returns the timer ticks with 1 microsecond resolution.
-    #[inline]
-    pub fn get_timer_ticks(&self) -> f64 {
-        (self.get_timestamp() as f64) / (self.get_frequency() as f64)
+/// Returns the top of the NMI stack of a processor.
+pub fn nmi_stack_top(id: usize) -> VirtAddr {
+    VirtAddr::from_ptr(unsafe { core::ptr::addr_of!(NMI_STACKS[id]) }) + NMI_STACK_SIZE
+}
+
+/// Installs the stack from `top - size` to `top` as interrupt stack table entry `index`
+/// of the current processor, for exception handlers that must not run on the stack
+/// that was interrupted. The IDT entries using it are set up separately.
+///
+/// The stack is checked before it is installed, since a bad one is only noticed once
+/// the exception it is for happens: it must be aligned, mapped and writable, and have
+/// an unmapped guard page below it, like the stacks [`crate::heap::alloc_guarded_stack`]
+/// returns.
+pub fn set_interrupt_stack(
+    index: u16,
+    top: VirtAddr,
+    size: usize,
+) -> Result<(), InterruptStackError> {
+    const PAGE_SIZE: u64 = 4096;
+    const IST_ENTRIES: usize = 7;
+
+    if index as usize >= IST_ENTRIES {
+        return Err(InterruptStackError::InvalidIndex);
     }
+
+    if !top.is_aligned(16u64) || size == 0 {
+        return Err(InterruptStackError::Misaligned);
+    }
+
+    let bottom = top - size as u64;
+    let writable = |va: VirtAddr| {
+        memory::translate(va).is_some_and(|(_, flags)| flags.contains(PageTableFlags::WRITABLE))
+    };
+
+    if !(bottom.as_u64()..top.as_u64())
+        .step_by(PAGE_SIZE as usize)
+        .all(|va| writable(VirtAddr::new(va)))
+    {
+        return Err(InterruptStackError::Unmapped);
+    }
+
+    if memory::translate(bottom - 1u64).is_some() {
+        return Err(InterruptStackError::NoGuardPage);
+    }
+
+    // The checks above take locks, which look at the per-cpu data structure, so it is
+    // only borrowed now.
+    let mut cpu = current_mut();
+
+    // The TSS is packed, so its entries are copied out rather than borrowed.
+    let entries = cpu.tss.interrupt_stack_table;
+    if !entries[index as usize].is_null() {
+        return Err(InterruptStackError::InUse);
+    }
+
+    cpu.tss.interrupt_stack_table[index as usize] = top;
+    Ok(())
 }
 
 /// Initializes per-cpu kernel data structure for a given logical core number.
@@ -117,18 +269,24 @@ impl Cpu {
 /// Other CPUID features are detected during this initialization sequence such as the
 /// frequency of the TSC register.
 ///
-/// This function must only be called once per AP and with ID 0 for the bootstrap processor.
+/// This function must only be called once per processor and with ID 0 for the
+/// bootstrap processor.
 pub fn init(id: usize) {
     assert!(id < CPU_COUNT);
+    assert!(
+        state(id) == CpuState::Offline,
+        "cpu::init(): cpu {id} is already initialized"
+    );
 
     unsafe {
         CPUS[id] = Cpu {
+            magic: CPU_MAGIC,
             id,
+            apic_id: 0,
             freq: CpuFrequency::Invalid,
             gdt: GlobalDescriptorTable::new(),
             tss: TaskStateSegment::new(),
             idt: InterruptDescriptorTable::new(),
-            irq_mask: 0xffffu16,
         };
 
         let cpu = &mut CPUS[id];
@@ -138,10 +296,12 @@ pub fn init(id: usize) {
         // TODO(kosinw): Come up with another way for multiprocessor support in the future
         // Each proecssor should have their own trap stack.
         cpu.tss.interrupt_stack_table[1] = {
-            let stack_start = VirtAddr::from_ptr(TRAP_STACK.as_ptr());
+            let stack_start = VirtAddr::from_ptr(core::ptr::addr_of!(TRAP_STACKS[id]));
             stack_start + TRAP_STACK_SIZE
         };
 
+        cpu.tss.interrupt_stack_table[NMI_IST_INDEX as usize] = nmi_stack_top(id);
+
         let cs = cpu.gdt.add_entry(Descriptor::kernel_code_segment());
         let ds = cpu.gdt.add_entry(Descriptor::kernel_data_segment());
         let ts = cpu
@@ -162,6 +322,10 @@ pub fn init(id: usize) {
         // for now just assume that the cpu has the tschz MSR.
         let cpuid: CpuId<CpuIdReaderNative> = CpuId::new();
 
+        cpu.apic_id = cpuid
+            .get_feature_info()
+            .map_or(0, |x| x.initial_local_apic_id() as u32);
+
         cpu.freq = cpuid
             .get_tsc_info()
             .and_then(|x| x.tsc_frequency())
@@ -177,34 +341,145 @@ pub fn init(id: usize) {
         let ptr = &CPUS[id] as *const Cpu;
         GsBase::write(VirtAddr::from_ptr(ptr));
     }
+
+    set_state(id, CpuState::Online);
+
+    if id == 0 {
+        assert!(
+            shell::register(Command {
+                name: "cpu",
+                usage: "",
+                help: "list the processors and their state",
+                run: cpu_command,
+            }),
+            "cpu::init(): failed to register shell command"
+        );
+    }
+}
+
+fn cpu_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    for id in 0..CPU_COUNT {
+        writeln!(out, "cpu {id}: {:?}", state(id))?;
+    }
+
+    Ok(())
+}
+
+/// Returns the local APIC identifier of a processor.
+pub fn apic_id(id: usize) -> u32 {
+    unsafe { CPUS[id].apic_id }
+}
+
+/// Returns the frequency of a processor in megahertz (MHz).
+pub fn frequency(id: usize) -> u64 {
+    unsafe { CPUS[id].freq }.frequency()
+}
+
+/// Returns the lifecycle state of a processor.
+pub fn state(id: usize) -> CpuState {
+    CpuState::from_u8(CPU_STATES[id].load(atomic::Ordering::Acquire))
+}
+
+fn set_state(id: usize, state: CpuState) {
+    CPU_STATES[id].store(state as u8, atomic::Ordering::Release);
+}
+
+// Returns the per-cpu data structure GSBASE points at, checking that it was set up.
+fn current_ptr() -> *mut Cpu {
+    let ptr = GS::read_base().as_mut_ptr::<Cpu>();
+
+    assert!(
+        !ptr.is_null(),
+        "cpu::current(): GSBASE is not set, cpu::init() has not run"
+    );
+
+    debug_assert!(
+        (0..CPU_COUNT).any(|i| core::ptr::eq(unsafe { core::ptr::addr_of!(CPUS[i]) }, ptr)),
+        "cpu::current(): GSBASE {ptr:p} is not a per-cpu data structure"
+    );
+
+    debug_assert_eq!(
+        unsafe { (*ptr).magic },
+        CPU_MAGIC,
+        "cpu::current(): per-cpu data structure at {ptr:p} is not initialized"
+    );
+
+    ptr
 }
 
 /// Gets a reference to the per-cpu data structure for the current processor.
 ///
+/// Panics if [`init`] has not run on the processor yet, and in debug builds also if
+/// GSBASE does not point at an initialized per-cpu data structure.
+///
 /// # Safety
-/// This function is potentially unsafe because it requires initializing the
-/// GSBASE register during the [`crate::cpu::init`] routine. If this routine is called
-/// before cpu::init, then potential invalid data will be read and this function is
-/// unsafe.
+/// The reference must not be held across a [`current_mut`] of the same processor,
+/// which may change the fields it reads.
 pub unsafe fn current() -> &'static Cpu {
-    GS::read_base().as_ptr::<Cpu>().as_ref().unwrap()
+    &*current_ptr()
+}
+
+/// Mutable access to the per-cpu data structure of the current processor, see
+/// [`current_mut`]. Interrupts stay disabled while it is held.
+pub struct CpuGuard {
+    cpu: *mut Cpu,
+    // Dropped after the borrow is released.
+    _interrupts: InterruptGuard,
 }
 
-/// Gets a mutable reference to the per-cpu data structure for the current processor.
+impl Deref for CpuGuard {
+    type Target = Cpu;
+
+    fn deref(&self) -> &Cpu {
+        unsafe { &*self.cpu }
+    }
+}
+
+impl DerefMut for CpuGuard {
+    fn deref_mut(&mut self) -> &mut Cpu {
+        unsafe { &mut *self.cpu }
+    }
+}
+
+impl Drop for CpuGuard {
+    fn drop(&mut self) {
+        BORROWED[self.id].store(false, atomic::Ordering::Release);
+    }
+}
+
+/// Borrows the per-cpu data structure of the current processor mutably.
 ///
-/// # Safety
-/// This function is potentially unsafe because it requires initializing the
-/// GSBASE register during the cpu::init routine. If this routine is called
-/// before cpu::init, then potential invalid data will be read and this function is
-/// unsafe.
-pub unsafe fn current_mut() -> &'static mut Cpu {
-    GS::read_base().as_mut_ptr::<Cpu>().as_mut().unwrap()
+/// Interrupts are disabled until the guard is dropped, so that an interrupt handler
+/// cannot borrow it as well and the thread cannot move to another processor. Debug
+/// builds panic if it is borrowed twice. Panics if [`init`] has not run on the
+/// processor yet.
+pub fn current_mut() -> CpuGuard {
+    let interrupts = critical::disable();
+
+    let cpu = current_ptr();
+    let id = unsafe { (*cpu).id };
+
+    let borrowed = BORROWED[id].swap(true, atomic::Ordering::Acquire);
+    debug_assert!(
+        !borrowed,
+        "cpu::current_mut(): cpu {id} is already borrowed"
+    );
+
+    CpuGuard {
+        cpu,
+        _interrupts: interrupts,
+    }
 }
 
-/// Gets the ticks of the current processor.
+/// Gets a pointer to the per-cpu data structure of the current processor for the NMI
+/// handler, which can interrupt a holder of [`current_mut`] and so cannot borrow it.
 ///
 /// # Safety
-/// This function is potentially unsafe for the stame reasons that [`crate::cpu::current`] is also unsafe.
-pub unsafe fn ticks() -> f64 {
-    current().get_timer_ticks()
+/// Only fields no one else changes may be accessed through the pointer.
+pub unsafe fn current_nmi() -> *mut Cpu {
+    current_ptr()
 }
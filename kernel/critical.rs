@@ -0,0 +1,111 @@
+use core::marker::PhantomData;
+
+use spin::{Mutex, MutexGuard};
+
+use crate::arch::{Current, Interrupts};
+
+/// Proof that interrupts are disabled on this processor for the lifetime `'cs`.
+///
+/// Functions that must not be interrupted take one instead of disabling interrupts
+/// themselves, and locks shared with interrupt handlers hand out their guards only
+/// for as long as it lives, see [`IrqMutex::lock`]. It cannot be sent to another
+/// processor, where it would prove nothing.
+#[derive(Debug, Clone, Copy)]
+pub struct CriticalSection<'cs> {
+    _marker: PhantomData<(&'cs (), *const ())>,
+}
+
+impl CriticalSection<'_> {
+    /// Creates the proof without checking it, e.g. in an interrupt handler, which runs
+    /// with interrupts disabled.
+    ///
+    /// # Safety
+    /// Interrupts must stay disabled for as long as the proof lives.
+    pub unsafe fn new() -> Self {
+        debug_assert!(
+            !Current::are_enabled(),
+            "critical::CriticalSection::new(): interrupts are enabled"
+        );
+
+        Self {
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Keeps interrupts disabled on this processor until it is dropped, then restores
+/// whether they were enabled before. Nests.
+#[derive(Debug)]
+pub struct InterruptGuard {
+    enabled: bool,
+    // Interrupts are only disabled on the processor that created the guard.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl InterruptGuard {
+    /// Returns the proof of being in a critical section for as long as the guard lives.
+    pub fn token(&self) -> CriticalSection<'_> {
+        CriticalSection {
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.enabled {
+            Current::enable();
+        }
+    }
+}
+
+/// Disables interrupts on this processor until the returned guard is dropped.
+pub fn disable() -> InterruptGuard {
+    let enabled = Current::are_enabled();
+    Current::disable();
+
+    InterruptGuard {
+        enabled,
+        _not_send: PhantomData,
+    }
+}
+
+/// Runs `f` with interrupts disabled, restoring them afterwards if they were enabled.
+pub fn with<R>(f: impl FnOnce(CriticalSection) -> R) -> R {
+    let guard = disable();
+    f(guard.token())
+}
+
+/// Spinlock shared with interrupt handlers.
+///
+/// Taking it needs a [`CriticalSection`] and the guard cannot outlive it, so the lock
+/// is never held with interrupts enabled, when an interrupt handler taking it on the
+/// same processor would spin forever.
+#[derive(Debug)]
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+        }
+    }
+
+    /// Takes the lock for as long as the critical section lasts.
+    pub fn lock<'a>(&'a self, _cs: CriticalSection<'a>) -> MutexGuard<'a, T> {
+        self.inner.lock()
+    }
+
+    /// Takes the lock if it is free, e.g. on paths that may have interrupted its
+    /// holder.
+    pub fn try_lock<'a>(&'a self, _cs: CriticalSection<'a>) -> Option<MutexGuard<'a, T>> {
+        self.inner.try_lock()
+    }
+
+    /// Runs `f` with the lock taken and interrupts disabled.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        with(|cs| f(&mut self.lock(cs)))
+    }
+}
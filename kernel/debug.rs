@@ -0,0 +1,206 @@
+use core::arch::asm;
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::emergency_print;
+use crate::log;
+use crate::memory;
+use crate::multiboot;
+use crate::sched;
+use crate::shell;
+use crate::shell::{Command, CommandError};
+use crate::trap;
+use crate::trap::TrapFrame;
+
+/// Most frames recorded in a backtrace.
+const MAX_FRAMES: usize = 32;
+
+/// Return addresses of a call stack, innermost first.
+///
+/// Backtraces are found by following the chain of saved frame pointers, which the
+/// target specification keeps in every function. Recording one takes no locks and
+/// allocates nothing, so it works in panic and exception handlers.
+#[derive(Clone, Copy)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Follows the frame pointer chain starting at `rbp`.
+    ///
+    /// Every frame is checked to be mapped before it is read, so a corrupt chain, like
+    /// that of a frame interrupted by an exception, ends the walk instead of faulting.
+    ///
+    /// # Safety
+    /// The stack must stay valid while it is walked.
+    pub unsafe fn from_frame_pointer(mut rbp: u64) -> Self {
+        let mut backtrace = Self {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+        };
+
+        while rbp != 0 && rbp & 7 == 0 && backtrace.len < MAX_FRAMES {
+            if !is_stack(rbp) || !is_stack(rbp + 8) {
+                break;
+            }
+
+            let frame = rbp as *const u64;
+            let (next, ret) = (frame.read(), frame.add(1).read());
+
+            if ret == 0 {
+                break;
+            }
+
+            backtrace.frames[backtrace.len] = ret;
+            backtrace.len += 1;
+
+            // Callers are further up the stack, anything else is a corrupt chain.
+            if next <= rbp {
+                break;
+            }
+
+            rbp = next;
+        }
+
+        backtrace
+    }
+
+    /// Return addresses, innermost first.
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+// Whether the word at `va` can be read as part of a stack: mapped, and not device
+// memory, where reading may have side effects.
+fn is_stack(va: u64) -> bool {
+    let Ok(va) = VirtAddr::try_new(va) else {
+        return false;
+    };
+
+    memory::probe(va).is_some_and(|x| !x.contains(PageTableFlags::NO_CACHE))
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, frame) in self.frames().iter().enumerate() {
+            writeln!(f, "{i:>4}: {frame:#016x}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the call stack of the caller.
+#[inline(never)]
+pub fn backtrace() -> Backtrace {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack));
+        Backtrace::from_frame_pointer(rbp)
+    }
+}
+
+// Whether breakpoints nobody claimed resume execution instead of panicking.
+static RESUME: AtomicBool = AtomicBool::new(true);
+
+/// Stops at a breakpoint in debug builds, does nothing in release builds.
+///
+/// The breakpoint is logged and execution resumes, unless that was turned off with
+/// [`set_resume`].
+#[macro_export]
+macro_rules! debug_break {
+    () => {
+        #[cfg(debug_assertions)]
+        unsafe {
+            core::arch::asm!("int3", options(nomem, nostack));
+        }
+    };
+}
+
+/// Chooses whether breakpoints without a debugger resume execution or panic.
+pub fn set_resume(resume: bool) {
+    RESUME.store(resume, Ordering::Relaxed);
+}
+
+/// Handles a breakpoint. Called by the trap handler.
+pub fn breakpoint(frame: &mut TrapFrame) {
+    // The breakpoint may have been hit with any lock held, so only print without one.
+    let rip = frame.rip - 1;
+    if !RESUME.load(Ordering::Relaxed) {
+        panic!("debug::breakpoint(): breakpoint at {rip:#016x}");
+    }
+
+    emergency_print!("debug::breakpoint(): breakpoint at {rip:#016x}, resuming\n");
+}
+
+// Shows where the kernel was when an NMI arrived. NMIs without a hardware error are
+// injected from outside, like with the `nmi` command of the QEMU monitor, to find out
+// where a kernel that stopped responding is stuck.
+fn nmi(frame: &TrapFrame) -> bool {
+    // The NMI may have interrupted any lock holder, so only print without one.
+    let backtrace = unsafe { Backtrace::from_frame_pointer(frame.rbp) };
+    emergency_print!(
+        "debug::nmi(): NMI at {:#016x}, called from:\n{backtrace}",
+        frame.rip
+    );
+
+    true
+}
+
+fn bt_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    for (id, name, backtrace) in sched::backtraces() {
+        writeln!(out, "{id:?} ({name}):")?;
+        write!(out, "{backtrace}")?;
+    }
+
+    Ok(())
+}
+
+/// Initializes kernel debugging facilities.
+///
+/// Breakpoints raised with `int3`, for example by [`debug_break!`], do not bring the
+/// kernel down: they are logged and execution continues after the breakpoint, unless
+/// `debug.break=panic` on the kernel command line asks for a panic instead. The `bt`
+/// shell command shows where every kernel thread is, and an NMI shows where the
+/// processor is.
+pub fn init() {
+    for value in multiboot::arg("debug.break") {
+        match value {
+            "resume" => set_resume(true),
+            "panic" => set_resume(false),
+            _ => log!("debug::init(): ignoring malformed debug.break={value}"),
+        }
+    }
+
+    assert!(
+        shell::register(Command {
+            name: "bt",
+            usage: "",
+            help: "show the call stacks of all kernel threads",
+            run: bt_command,
+        }),
+        "debug::init(): failed to register shell command"
+    );
+
+    assert!(
+        trap::register_nmi_handler(nmi),
+        "debug::init(): failed to register NMI handler"
+    );
+
+    let action = if RESUME.load(Ordering::Relaxed) {
+        "resume after logging"
+    } else {
+        "panic"
+    };
+    log!("debug::init(): breakpoints {action} [ \x1b[0;32mOK\x1b[0m ]");
+}
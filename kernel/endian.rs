@@ -0,0 +1,72 @@
+use core::fmt;
+
+// Integers kept in the byte order a specification defines, converted on every access,
+// so structures shared with devices or sent over the network stay correct whatever
+// the byte order of the processor.
+macro_rules! endian_type {
+    ($(#[$attr:meta])* $name:ident($ty:ty), $to:ident, $from:ident, $to_bytes:ident, $from_bytes:ident) => {
+        $(#[$attr])*
+        #[repr(transparent)]
+        #[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+        pub struct $name($ty);
+
+        impl $name {
+            pub const fn new(value: $ty) -> Self {
+                Self(value.$to())
+            }
+
+            /// Returns the value in native byte order.
+            pub const fn get(self) -> $ty {
+                <$ty>::$from(self.0)
+            }
+
+            /// Reads the value out of the bytes of a packet or descriptor.
+            pub const fn from_bytes(bytes: [u8; core::mem::size_of::<$ty>()]) -> Self {
+                Self::new(<$ty>::$from_bytes(bytes))
+            }
+
+            pub const fn to_bytes(self) -> [u8; core::mem::size_of::<$ty>()] {
+                self.get().$to_bytes()
+            }
+        }
+
+        impl From<$ty> for $name {
+            fn from(value: $ty) -> Self {
+                Self::new(value)
+            }
+        }
+
+        impl From<$name> for $ty {
+            fn from(value: $name) -> Self {
+                value.get()
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Debug::fmt(&self.get(), f)
+            }
+        }
+    };
+}
+
+endian_type!(
+    /// Little-endian `u16`, the byte order of virtio structures.
+    Le16(u16), to_le, from_le, to_le_bytes, from_le_bytes
+);
+endian_type!(
+    /// Little-endian `u32`.
+    Le32(u32), to_le, from_le, to_le_bytes, from_le_bytes
+);
+endian_type!(
+    /// Little-endian `u64`.
+    Le64(u64), to_le, from_le, to_le_bytes, from_le_bytes
+);
+endian_type!(
+    /// Big-endian `u16`, network byte order of protocol headers.
+    Be16(u16), to_be, from_be, to_be_bytes, from_be_bytes
+);
+endian_type!(
+    /// Big-endian `u32`.
+    Be32(u32), to_be, from_be, to_be_bytes, from_be_bytes
+);
@@ -0,0 +1,169 @@
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::inspect::parse_u64;
+use crate::log;
+use crate::multiboot;
+use crate::shell;
+use crate::shell::{Command, CommandError};
+use crate::timer;
+
+// Every how many allocations one fails, zero if none do.
+static ALLOC_FAIL_EVERY: AtomicU64 = AtomicU64::new(0);
+
+// Number of allocations seen while allocation failures are injected.
+static ALLOC_COUNT: AtomicU64 = AtomicU64::new(0);
+
+// Percentage of network packets dropped.
+static PACKET_DROP_PERCENT: AtomicU64 = AtomicU64::new(0);
+
+// Microseconds every virtio completion is held back.
+static VIRTIO_DELAY_US: AtomicU64 = AtomicU64::new(0);
+
+// State of the xorshift generator deciding which packets are dropped.
+static RANDOM: AtomicU64 = AtomicU64::new(0x2545_F491_4F6C_DD1D);
+
+/// Kind of fault that can be injected, with the name it has on the command line and in
+/// the shell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fault {
+    AllocFailEvery,
+    PacketDropPercent,
+    VirtioDelayUs,
+}
+
+impl Fault {
+    const ALL: [Fault; 3] = [
+        Fault::AllocFailEvery,
+        Fault::PacketDropPercent,
+        Fault::VirtioDelayUs,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Fault::AllocFailEvery => "alloc_fail_every",
+            Fault::PacketDropPercent => "packet_drop_percent",
+            Fault::VirtioDelayUs => "virtio_delay_us",
+        }
+    }
+
+    fn value(self) -> &'static AtomicU64 {
+        match self {
+            Fault::AllocFailEvery => &ALLOC_FAIL_EVERY,
+            Fault::PacketDropPercent => &PACKET_DROP_PERCENT,
+            Fault::VirtioDelayUs => &VIRTIO_DELAY_US,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Fault> {
+        Fault::ALL.into_iter().find(|x| x.name() == name)
+    }
+
+    fn set(self, value: u64) -> bool {
+        if self == Fault::PacketDropPercent && value > 100 {
+            return false;
+        }
+
+        if self == Fault::AllocFailEvery {
+            ALLOC_COUNT.store(0, Ordering::Relaxed);
+        }
+
+        self.value().store(value, Ordering::Relaxed);
+        true
+    }
+}
+
+/// Checks whether the current allocation should fail. Called by the allocator.
+pub fn fail_alloc() -> bool {
+    let every = ALLOC_FAIL_EVERY.load(Ordering::Relaxed);
+    every != 0 && ALLOC_COUNT.fetch_add(1, Ordering::Relaxed) % every == every - 1
+}
+
+/// Checks whether a network packet should be dropped. Called by network drivers for
+/// every packet they send or receive.
+pub fn drop_packet() -> bool {
+    let percent = PACKET_DROP_PERCENT.load(Ordering::Relaxed);
+    percent != 0 && random() % 100 < percent
+}
+
+/// Holds back a virtio completion for the configured delay, as if the device was
+/// slow. Called by virtqueues before handing out a used buffer.
+pub fn delay_completion() {
+    let delay = Duration::from_micros(VIRTIO_DELAY_US.load(Ordering::Relaxed));
+    if !delay.is_zero() {
+        let _ = timer::wait_until(|| false, delay);
+    }
+}
+
+fn random() -> u64 {
+    let mut x = RANDOM.load(Ordering::Relaxed);
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    RANDOM.store(x, Ordering::Relaxed);
+    x
+}
+
+fn fault_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            for fault in Fault::ALL {
+                writeln!(
+                    out,
+                    "{} {}",
+                    fault.name(),
+                    fault.value().load(Ordering::Relaxed)
+                )?;
+            }
+        }
+        [name, value] => {
+            let fault = Fault::from_name(name).ok_or(CommandError::Failed("unknown fault"))?;
+            let value = parse_u64(value).ok_or(CommandError::Usage)?;
+            if !fault.set(value) {
+                return Err(CommandError::Failed("value out of range"));
+            }
+        }
+        _ => return Err(CommandError::Usage),
+    }
+
+    Ok(())
+}
+
+/// Initializes fault injection.
+///
+/// Faults make the allocator fail every Nth allocation, drop a percentage of network
+/// packets or delay virtio completions, so that error handling paths actually run.
+/// They start out disabled, and are turned on with `fault.<name>=<value>` arguments on
+/// the kernel command line or with the `fault` shell command. Injected allocation
+/// failures are handled like real ones, according to the [`crate::heap::OomPolicy`].
+/// This module only exists in kernels built with the `fault-injection` feature.
+pub fn init() {
+    RANDOM.fetch_xor(timer::uptime().as_nanos() as u64, Ordering::Relaxed);
+
+    for arg in multiboot::args() {
+        let Some((name, value)) = arg.strip_prefix("fault.").and_then(|x| x.split_once('=')) else {
+            continue;
+        };
+
+        let fault = Fault::from_name(name);
+        match (fault, parse_u64(value)) {
+            (Some(fault), Some(value)) if fault.set(value) => {
+                log!("fault::init(): injecting {name}={value}");
+            }
+            _ => log!("fault::init(): ignoring malformed {arg}"),
+        }
+    }
+
+    assert!(
+        shell::register(Command {
+            name: "fault",
+            usage: "[<fault> <value>]",
+            help: "show or change the injected faults",
+            run: fault_command,
+        }),
+        "fault::init(): failed to register shell command"
+    );
+
+    log!("fault::init(): fault injection enabled [ \x1b[0;32mOK\x1b[0m ]");
+}
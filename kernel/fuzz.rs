@@ -0,0 +1,42 @@
+//! Entry points of the fuzz targets in fuzz/, which run the parsers of data handed to
+//! the kernel by the bootloader and the hypervisor on arbitrary bytes on the host.
+
+use crate::multiboot;
+use crate::pci;
+use crate::virtio_pci::VirtioCapabilities;
+
+/// Parses `data` as a multiboot memory map and walks its entries.
+pub fn multiboot_mmap(data: &[u8]) {
+    let Ok(areas) = multiboot::parse_memory_map(data) else {
+        return;
+    };
+
+    let mut count = 0;
+
+    for area in areas {
+        let (start, end) = area.bounds();
+        assert!(start <= end);
+        let _ = (area.start_address(), area.end_address(), area.area_type());
+
+        count += 1;
+    }
+
+    assert!(count <= multiboot::MAX_MEMORY_AREAS);
+}
+
+/// Parses `data` as the configuration space of a PCI device and walks its capability
+/// list, as a virtio device driver does.
+pub fn pci_caps(data: &[u8]) {
+    let mut count = 0;
+
+    for capability in pci::capabilities(data) {
+        // Every capability handed out lies within the configuration space.
+        assert!(capability.offset as usize + 4 <= data.len());
+
+        count += 1;
+    }
+
+    assert!(count <= data.len() / 4);
+
+    let _ = VirtioCapabilities::parse(data);
+}
@@ -1,62 +1,765 @@
+use crate::critical;
+#[cfg(feature = "fault-injection")]
+use crate::fault;
+use crate::initcall::InitGuard;
+use crate::inspect::parse_u64;
+use crate::layout;
 use crate::log;
-use linked_list_allocator::LockedHeap;
+use crate::memory;
+use crate::memory::PhysRegion;
+use crate::multiboot;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use linked_list_allocator::{Heap, LockedHeap};
+use spin::Mutex;
 use x86_64::structures::paging::PageTableFlags;
-use x86_64::structures::paging::Size4KiB;
-use x86_64::VirtAddr;
+use x86_64::structures::paging::{PageSize, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Allocations at least this large bypass the heap and get physical regions of their
+/// own, so that big buffers like packet pools do not fragment it.
+pub const LARGE_ALLOCATION_SIZE: usize = 64 * 1024;
+
+/// Maximum number of caches that can give memory back when the heap runs out.
+const MAX_RECLAIMERS: usize = 8;
+
+/// Smallest physical region added to the heap when physical memory is fragmented.
+const MIN_HEAP_REGION_SIZE: usize = 1024 * 1024;
+
+/// Number of size classes allocations are counted in, see [`size_class`].
+pub const SIZE_CLASSES: usize = 16;
+
+/// Largest allocation in the smallest size class.
+const MIN_CLASS_SIZE: usize = 16;
 
 // TODO(kosinw): Replace this with a custom buddy allocator (debugging is too hard rn...)
-#[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+#[cfg_attr(not(any(test, fuzzing)), global_allocator)]
+static KERNEL_HEAP: KernelHeap = KernelHeap;
+
+// One heap per physical region, so that every allocation is physically contiguous.
+// Only the first REGION_COUNT are initialized.
+static HEAPS: [LockedHeap; layout::HEAP_REGIONS] =
+    [const { LockedHeap::empty() }; layout::HEAP_REGIONS];
+
+// Number of heap regions in use.
+static REGION_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// Taken while a region is added, so that two callers do not pick the same slot.
+static GROW_LOCK: Mutex<()> = Mutex::new(());
+
+// Initialization of the heap, before which nothing can allocate.
+pub static INIT: InitGuard = InitGuard::new("heap");
+
+// Bytes currently allocated from the heap regions, kept up to date by the allocation
+// paths from the usage of the region they hold locked.
+static SMALL_USED: AtomicU64 = AtomicU64::new(0);
+
+// Most bytes ever allocated from the heap at once.
+static HIGH_WATER: AtomicU64 = AtomicU64::new(0);
+
+// Bytes currently allocated as dedicated physical regions.
+static LARGE_USED: AtomicU64 = AtomicU64::new(0);
+
+// Number of allocations that could not be satisfied.
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+
+// What to do when an allocation cannot be satisfied, see [`OomPolicy`].
+static POLICY: AtomicU8 = AtomicU8::new(OomPolicy::Panic as u8);
+
+// Caches asked to free memory when the heap runs out. Stored as plain function
+// pointers because they are read from inside the allocator.
+static RECLAIMERS: [AtomicUsize; MAX_RECLAIMERS] = [const { AtomicUsize::new(0) }; MAX_RECLAIMERS];
+
+// Hook telling the application that the heap ran out.
+static OOM_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+// Allocations counted by size class, whether served by the heap or by physical regions.
+static CLASSES: [ClassCounters; SIZE_CLASSES] = [const { ClassCounters::new() }; SIZE_CLASSES];
+
+struct ClassCounters {
+    allocations: AtomicU64,
+    deallocations: AtomicU64,
+    allocated: AtomicU64,
+    freed: AtomicU64,
+}
+
+impl ClassCounters {
+    const fn new() -> Self {
+        Self {
+            allocations: AtomicU64::new(0),
+            deallocations: AtomicU64::new(0),
+            allocated: AtomicU64::new(0),
+            freed: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Frees memory held by a cache, like a block cache. Gets the number of bytes needed
+/// and returns the number of bytes it gave back.
+pub type Reclaimer = fn(usize) -> usize;
+
+/// Called with the failed request when the heap runs out, after the caches had their
+/// chance to free memory.
+pub type OomHook = fn(Layout);
+
+/// What the allocator does when a request cannot be satisfied, even after reclaiming.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OomPolicy {
+    /// Panic with the heap statistics.
+    Panic = 0,
+    /// Return null, so that fallible allocations like `Vec::try_reserve` can recover.
+    /// Infallible allocations still end in the allocation error handler.
+    Fail = 1,
+}
+
+/// Global allocator wrapping the heap with statistics and out-of-memory handling.
+struct KernelHeap;
 
-// Offset where heap starts.
-pub const HEAP_ADDR: u64 = 0x000044444444000u64;
-pub const HEAP_SIZE: u64 = 10 * 1024 * 1024; // 10 MiB.
+// Whether an allocation is served by a dedicated physical region. Physical regions
+// are only aligned to pages, bigger alignments go to the heap.
+fn is_large(layout: &Layout) -> bool {
+    layout.size() >= LARGE_ALLOCATION_SIZE && layout.align() <= Size4KiB::SIZE as usize
+}
+
+// Allocates a large block as a physical region, reached through the direct map.
+fn alloc_large(layout: Layout) -> Option<*mut u8> {
+    let region = critical::with(|_| unsafe { memory::allocate_physical_region(layout.size()) })?;
+
+    LARGE_USED.fetch_add(region.size() as u64, Ordering::Relaxed);
+    Some(memory::phys_to_virt(region.start_address()).as_mut_ptr())
+}
+
+unsafe fn dealloc_large(ptr: *mut u8, layout: Layout) {
+    let pa = memory::virt_to_phys(VirtAddr::from_ptr(ptr));
+    let size = layout.size().next_multiple_of(Size4KiB::SIZE as usize);
+
+    critical::with(|_| memory::deallocate_physical_region(PhysRegion::new(pa, size)));
+    LARGE_USED.fetch_sub(size as u64, Ordering::Relaxed);
+}
+
+// Returns the heap regions in use.
+fn heaps() -> &'static [LockedHeap] {
+    &HEAPS[..REGION_COUNT.load(Ordering::Acquire)]
+}
+
+fn alloc_small(layout: Layout) -> Option<*mut u8> {
+    let (ptr, grown) = critical::with(|_| {
+        heaps().iter().find_map(|heap| {
+            let mut heap = heap.lock();
+            let before = heap.used();
+            let ptr = heap.allocate_first_fit(layout).ok()?;
+            Some((ptr, heap.used() - before))
+        })
+    })?;
+
+    let used = SMALL_USED.fetch_add(grown as u64, Ordering::Relaxed) + grown as u64;
+    HIGH_WATER.fetch_max(used, Ordering::Relaxed);
+    Some(ptr.as_ptr())
+}
+
+// Allocates from the heap or a physical region, making room or giving up as the
+// reclaimers and the policy say.
+fn allocate(layout: Layout) -> *mut u8 {
+    #[cfg(feature = "fault-injection")]
+    if fault::fail_alloc() {
+        return out_of_memory(layout);
+    }
+
+    let try_alloc = || {
+        if is_large(&layout) {
+            alloc_large(layout)
+        } else {
+            alloc_small(layout)
+        }
+    };
+
+    if let Some(ptr) = try_alloc() {
+        return ptr;
+    }
+
+    // Give the caches one chance to make room before giving up.
+    if reclaim(layout.size()) > 0 {
+        if let Some(ptr) = try_alloc() {
+            return ptr;
+        }
+    }
+
+    out_of_memory(layout)
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = allocate(layout);
+
+        if !ptr.is_null() {
+            count_allocation(&layout);
+        }
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let class = &CLASSES[size_class(layout.size())];
+        class.deallocations.fetch_add(1, Ordering::Relaxed);
+        class
+            .freed
+            .fetch_add(layout.size() as u64, Ordering::Relaxed);
+
+        if is_large(&layout) {
+            return dealloc_large(ptr, layout);
+        }
+
+        let shrunk = critical::with(|_| {
+            let mut heap = heaps()
+                .iter()
+                .map(|x| x.lock())
+                .find(|x| (x.bottom()..x.top()).contains(&ptr))
+                .expect("heap::dealloc(): pointer is not in any heap region");
+
+            let before = heap.used();
+            heap.deallocate(NonNull::new_unchecked(ptr), layout);
+            before - heap.used()
+        });
+
+        SMALL_USED.fetch_sub(shrunk as u64, Ordering::Relaxed);
+    }
+}
+
+// Counts an allocation in its size class, frees are counted by `KernelHeap::dealloc()`.
+fn count_allocation(layout: &Layout) {
+    let class = &CLASSES[size_class(layout.size())];
+    class.allocations.fetch_add(1, Ordering::Relaxed);
+    class
+        .allocated
+        .fetch_add(layout.size() as u64, Ordering::Relaxed);
+}
+
+// Asks every registered cache to free memory, returns the number of bytes freed.
+fn reclaim(size: usize) -> usize {
+    RECLAIMERS
+        .iter()
+        .map(|x| x.load(Ordering::Acquire))
+        .filter(|&x| x != 0)
+        .map(|x| {
+            let reclaimer: Reclaimer = unsafe { core::mem::transmute(x) };
+            reclaimer(size)
+        })
+        .sum()
+}
+
+fn out_of_memory(layout: Layout) -> *mut u8 {
+    FAILURES.fetch_add(1, Ordering::Relaxed);
+
+    let hook = OOM_HOOK.load(Ordering::Acquire);
+    if hook != 0 {
+        let hook: OomHook = unsafe { core::mem::transmute(hook) };
+        hook(layout);
+    }
+
+    if policy() == OomPolicy::Panic {
+        panic!(
+            "heap::out_of_memory(): failed to allocate {} bytes aligned to {}, {}",
+            layout.size(),
+            layout.align(),
+            stats()
+        );
+    }
+
+    core::ptr::null_mut()
+}
+
+pub const HEAP_SIZE: u64 = 10 * 1024 * 1024; // 10 MiB, unless `heap=` says otherwise.
+
+/// Returns the number of bytes currently allocated from the heap.
+pub fn used() -> u64 {
+    SMALL_USED.load(Ordering::Relaxed)
+}
+
+/// Returns the number of bytes currently free in the heap.
+pub fn free() -> u64 {
+    critical::with(|_| heaps().iter().map(|x| x.lock().free() as u64).sum())
+}
+
+/// Returns the number of bytes the heap is made of.
+pub fn size() -> u64 {
+    critical::with(|_| heaps().iter().map(|x| x.lock().size() as u64).sum())
+}
+
+// Returns the size of the largest block `heap` can hand out, found by trying to.
+fn largest_free_block(heap: &mut Heap) -> usize {
+    let mut fits = 0;
+    let mut too_big = heap.free() + 1;
+
+    while too_big - fits > 1 {
+        let size = fits + (too_big - fits) / 2;
+        let layout = Layout::from_size_align(size, 1).unwrap();
+
+        match heap.allocate_first_fit(layout) {
+            Ok(ptr) => {
+                unsafe { heap.deallocate(ptr, layout) };
+                fits = size;
+            }
+            Err(()) => too_big = size,
+        }
+    }
+
+    fits
+}
+
+/// Returns the size of the largest block the heap can hand out, which is less than
+/// [`free`] when the free memory is fragmented.
+pub fn largest_free() -> u64 {
+    critical::with(|_| {
+        heaps()
+            .iter()
+            .map(|x| largest_free_block(&mut x.lock()) as u64)
+            .max()
+            .unwrap_or(0)
+    })
+}
+
+/// Statistics of the heap at one point in time, see [`stats`].
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub size: u64,
+    pub used: u64,
+    pub free: u64,
+    pub largest_free: u64,
+    pub regions: usize,
+    pub large_used: u64,
+    pub high_water: u64,
+    pub failures: u64,
+    pub classes: [SizeClassStats; SIZE_CLASSES],
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} bytes used in {} regions, {} free with {} in the largest block, \
+             {} in large regions, {} used at most, {} failures",
+            self.used,
+            self.size,
+            self.regions,
+            self.free,
+            self.largest_free,
+            self.large_used,
+            self.high_water,
+            self.failures
+        )
+    }
+}
+
+/// Returns the statistics of the heap.
+///
+/// Finding the largest free block takes a few allocations per heap region, so this is
+/// meant for reports rather than for deciding how much to allocate.
+pub fn stats() -> HeapStats {
+    HeapStats {
+        size: size(),
+        used: used(),
+        free: free(),
+        largest_free: largest_free(),
+        regions: heaps().len(),
+        large_used: large_used(),
+        high_water: high_water(),
+        failures: failures(),
+        classes: size_classes(),
+    }
+}
+
+/// Allocations of one size class, see [`size_classes`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeClassStats {
+    /// Size of the largest allocation in the class, in bytes.
+    pub max_size: usize,
+    pub allocations: u64,
+    pub deallocations: u64,
+    /// Bytes ever allocated in the class.
+    pub allocated_bytes: u64,
+    /// Bytes currently allocated in the class.
+    pub used_bytes: u64,
+}
+
+/// Gets the size class of an allocation of `size` bytes. Class 0 holds sizes up to
+/// 16 bytes and every class after it sizes up to twice as large, except for the last,
+/// which holds everything larger.
+pub fn size_class(size: usize) -> usize {
+    let class = size
+        .div_ceil(MIN_CLASS_SIZE)
+        .max(1)
+        .next_power_of_two()
+        .trailing_zeros();
+    (class as usize).min(SIZE_CLASSES - 1)
+}
+
+/// Returns the allocations made through the global allocator so far, by size class.
+///
+/// Unlike [`stats`], this only reads counters and is cheap enough to call often.
+pub fn size_classes() -> [SizeClassStats; SIZE_CLASSES] {
+    core::array::from_fn(|i| {
+        let class = &CLASSES[i];
+        let allocated = class.allocated.load(Ordering::Relaxed);
+
+        SizeClassStats {
+            max_size: match i {
+                x if x == SIZE_CLASSES - 1 => usize::MAX,
+                x => MIN_CLASS_SIZE << x,
+            },
+            allocations: class.allocations.load(Ordering::Relaxed),
+            deallocations: class.deallocations.load(Ordering::Relaxed),
+            allocated_bytes: allocated,
+            used_bytes: allocated.saturating_sub(class.freed.load(Ordering::Relaxed)),
+        }
+    })
+}
+
+/// Returns the number of bytes currently allocated as dedicated physical regions.
+pub fn large_used() -> u64 {
+    LARGE_USED.load(Ordering::Relaxed)
+}
+
+/// Returns the most bytes ever allocated from the heap at once.
+pub fn high_water() -> u64 {
+    HIGH_WATER.load(Ordering::Relaxed)
+}
+
+/// Returns the number of allocations that could not be satisfied.
+pub fn failures() -> u64 {
+    FAILURES.load(Ordering::Relaxed)
+}
+
+/// Returns what the allocator does when the heap runs out.
+pub fn policy() -> OomPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        0 => OomPolicy::Panic,
+        _ => OomPolicy::Fail,
+    }
+}
+
+/// Changes what the allocator does when the heap runs out.
+pub fn set_policy(policy: OomPolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// Registers a cache that is asked to free memory when the heap runs out. Returns
+/// false if there is no room.
+///
+/// Reclaimers run inside the allocator: they may free memory, but must not allocate.
+pub fn register_reclaimer(reclaimer: Reclaimer) -> bool {
+    RECLAIMERS.iter().any(|slot| {
+        slot.compare_exchange(0, reclaimer as usize, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    })
+}
+
+/// Sets the hook telling the application that the heap ran out, replacing the
+/// previous one. Like reclaimers, the hook must not allocate.
+pub fn set_oom_hook(hook: Option<OomHook>) {
+    OOM_HOOK.store(hook.map_or(0, |x| x as usize), Ordering::Release);
+}
+
+/// Allocates memory for `layout` with its alignment honoured, whatever it is. Returns
+/// None if the memory is not available, regardless of the out-of-memory policy.
+///
+/// The heap and the dedicated physical regions are both physically contiguous, so
+/// the memory can be handed to devices once translated with [`memory::translate`].
+pub fn alloc_aligned(layout: Layout) -> Option<NonNull<u8>> {
+    let ptr = if is_large(&layout) {
+        alloc_large(layout)
+    } else {
+        alloc_small(layout)
+    };
+
+    // Counted like the global allocator, since it is freed through it.
+    let ptr = ptr.and_then(NonNull::new)?;
+    count_allocation(&layout);
+    Some(ptr)
+}
+
+/// Frees memory allocated with [`alloc_aligned`] with the same layout.
+pub unsafe fn dealloc_aligned(ptr: NonNull<u8>, layout: Layout) {
+    KERNEL_HEAP.dealloc(ptr.as_ptr(), layout);
+}
+
+/// Allocates a stack of `size` bytes from the heap with an unmapped guard page below
+/// it, and returns its top. Overflowing the stack faults instead of corrupting
+/// whatever is next to it on the heap.
+///
+/// The stack is never freed, since the guard page is gone from the heap for good. This
+/// is meant for the stacks of exception handlers, allocated once per processor.
+pub fn alloc_guarded_stack(size: usize) -> Option<VirtAddr> {
+    let page = Size4KiB::SIZE as usize;
+    let layout = Layout::from_size_align(size.next_multiple_of(page) + page, page).ok()?;
+
+    // Large allocations come from the direct map, which has no small pages to unmap.
+    let guard = VirtAddr::from_ptr(alloc_small(layout)?);
+
+    if let Err(e) = unsafe { memory::unmap(guard, page as u64) } {
+        log!("heap::alloc_guarded_stack(): failed to unmap the guard page: {e}");
+        return None;
+    }
+
+    Some(guard + layout.size())
+}
+
+/// Owned value stored in whole pages of its own, for structures that devices access
+/// directly like virtqueue rings.
+pub struct PageBox<T> {
+    ptr: NonNull<T>,
+}
+
+impl<T> PageBox<T> {
+    fn layout() -> Layout {
+        let size = core::mem::size_of::<T>().next_multiple_of(Size4KiB::SIZE as usize);
+        let align = core::mem::align_of::<T>().max(Size4KiB::SIZE as usize);
+        Layout::from_size_align(size.max(align), align).unwrap()
+    }
+
+    fn allocate() -> NonNull<T> {
+        alloc_aligned(Self::layout())
+            .expect("heap::PageBox::allocate(): out of memory")
+            .cast()
+    }
+
+    /// Allocates pages filled with zeroes, including those past the end of `T`.
+    ///
+    /// # Safety
+    /// All zeroes must be a valid value of `T`.
+    pub unsafe fn new_zeroed() -> Self {
+        let ptr = Self::allocate();
+        ptr.cast::<u8>()
+            .as_ptr()
+            .write_bytes(0, Self::layout().size());
+        Self { ptr }
+    }
+
+    /// Returns the virtual address of the value.
+    pub fn virt_addr(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.ptr.as_ptr())
+    }
+
+    /// Returns the physical address of the value, to be given to a device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        memory::translate(self.virt_addr())
+            .expect("heap::PageBox::phys_addr(): pages are not mapped")
+            .0
+    }
+}
+
+impl<T> Deref for PageBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for PageBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for PageBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.ptr.as_ptr().drop_in_place();
+            dealloc_aligned(self.ptr.cast(), Self::layout());
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for PageBox<T> {}
+unsafe impl<T: Sync> Sync for PageBox<T> {}
+
+/// Owned value stored in physical memory below 4 GiB, see
+/// [`memory::allocate_dma_region`], for structures devices that take 32 bit addresses
+/// access directly, like the rings of a legacy virtqueue.
+pub struct DmaBox<T> {
+    region: PhysRegion,
+    ptr: NonNull<T>,
+}
+
+impl<T> DmaBox<T> {
+    /// Allocates pages filled with zeroes, including those past the end of `T`.
+    /// Panics if memory below 4 GiB ran out.
+    ///
+    /// # Safety
+    /// All zeroes must be a valid value of `T`.
+    pub unsafe fn new_zeroed() -> Self {
+        let page = Size4KiB::SIZE as usize;
+        let size = core::mem::size_of::<T>().next_multiple_of(page);
+        let align = core::mem::align_of::<T>().max(page);
+
+        let region = critical::with(|_| memory::allocate_dma_region(size.max(page), align))
+            .expect("heap::DmaBox::new_zeroed(): out of memory below 4 GiB");
+        let ptr = memory::phys_to_virt(region.start_address()).as_mut_ptr::<u8>();
+        ptr.write_bytes(0, region.size());
+
+        Self {
+            region,
+            ptr: NonNull::new_unchecked(ptr).cast(),
+        }
+    }
+
+    /// Returns the physical address of the value, to be given to a device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.region.start_address()
+    }
+}
+
+impl<T> Deref for DmaBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for DmaBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for DmaBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.ptr.as_ptr().drop_in_place();
+            critical::with(|_| memory::deallocate_physical_region(self.region));
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for DmaBox<T> {}
+unsafe impl<T: Sync> Sync for DmaBox<T> {}
+
+/// Grows the heap by `size` bytes, rounded up to pages. Returns the number of bytes
+/// actually added, which is less if physical memory or heap regions ran out.
+///
+/// Physical memory is taken in regions as large as possible, halving the request
+/// down to [`MIN_HEAP_REGION_SIZE`] while no region that large is free. Each region
+/// becomes a heap of its own at the next free slot of virtual address space.
+pub fn grow(size: usize) -> usize {
+    let _guard = GROW_LOCK.lock();
+    let page = Size4KiB::SIZE as usize;
+    let mut remaining = size.next_multiple_of(page);
+
+    // Leave a page unmapped between regions, so nothing runs from one into the next.
+    let max_region = layout::HEAP_REGION_SPACING as usize - page;
+    let mut chunk = remaining.min(max_region);
+
+    while remaining > 0 {
+        let slot = REGION_COUNT.load(Ordering::Acquire);
+        if slot == layout::HEAP_REGIONS {
+            log!("heap::grow(): out of heap regions");
+            break;
+        }
+
+        chunk = chunk.min(remaining);
+        let region = critical::with(|_| unsafe { memory::allocate_physical_region(chunk) });
+
+        let Some(region) = region else {
+            if chunk / 2 < MIN_HEAP_REGION_SIZE.min(remaining) {
+                log!("heap::grow(): out of physical memory");
+                break;
+            }
+
+            chunk = (chunk / 2).next_multiple_of(page);
+            continue;
+        };
+
+        let va = VirtAddr::new(layout::HEAP_BASE + slot as u64 * layout::HEAP_REGION_SPACING);
+        let len = region.size();
+
+        log!(
+            "heap::grow(): region {slot} is phys [{:#016x}-{:#016x}] at virt [{:#016x}-{:#016x}]",
+            region.start_address().as_u64(),
+            region.end_address().as_u64(),
+            va.as_u64(),
+            va.as_u64() + len as u64
+        );
+
+        unsafe {
+            let flags =
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            memory::kernel_map_region::<Size4KiB>(va, region.start_address(), len as u64, flags)
+                .expect("heap::grow(): failed to map heap pages");
+
+            HEAPS[slot].lock().init(va.as_mut_ptr(), len);
+        }
+
+        REGION_COUNT.store(slot + 1, Ordering::Release);
+        remaining = remaining.saturating_sub(len);
+    }
+
+    size.next_multiple_of(page) - remaining
+}
+
+/// Parses a size in bytes with an optional K, M or G suffix, as given on the kernel
+/// command line.
+pub fn parse_size(s: &str) -> Option<usize> {
+    let (number, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+
+    let bytes = parse_u64(number)?.checked_mul(1 << shift)?;
+    usize::try_from(bytes).ok()
+}
 
 /// Initializes the heap for the kernel.
 ///
-/// This function is responsible for setting up the heap memory for dynamic memory allocation
-/// within the kernel. It configures the allocator, allocates an initial heap region, and
-/// performs any necessary setup for the memory management subsystem.
+/// The heap starts out with [`HEAP_SIZE`] bytes, or as many as `heap=<size>` on the
+/// kernel command line asks for, with an optional `K`, `M` or `G` suffix. When
+/// physical memory has no free region that large, the heap is made of several
+/// smaller ones, see [`grow`]. More can be added later on with [`grow`] as well.
+///
+/// `heap.oom=fail` makes failed allocations return null instead of panicking, see
+/// [`OomPolicy`].
 pub fn init() {
-    use crate::memory;
+    let _init = INIT.start();
+    memory::INIT.require("heap");
 
-    log!("heap::init(): allocating physical region for heap...");
+    let mut size = HEAP_SIZE as usize;
 
-    let va = VirtAddr::new(HEAP_ADDR);
-    let region = unsafe {
-        memory::allocate_physical_region(HEAP_SIZE as usize)
-            .expect("could not allocate enough physical space for heap")
-    };
-    let pa = region.start_address();
-    let size = region.size();
+    for value in multiboot::arg("heap.oom") {
+        match value {
+            "panic" => set_policy(OomPolicy::Panic),
+            "fail" => set_policy(OomPolicy::Fail),
+            _ => log!("heap::init(): ignoring malformed heap.oom={value}"),
+        }
+    }
 
-    log!("heap::init(): allocating physical region for heap... [ \x1b[0;32mOK\x1b[0m ]");
-    log!(
-        "heap::init(): using phys region [{:#016x}-{:#016x}]",
-        region.start_address().as_u64(),
-        region.end_address().as_u64()
-    );
-    log!(
-        "heap::init(): using virt region [{:#016x}-{:#016x}]",
-        HEAP_ADDR,
-        HEAP_ADDR + size as u64
-    );
+    for value in multiboot::arg("heap") {
+        match parse_size(value) {
+            Some(x) if x > 0 => size = x,
+            _ => log!("heap::init(): ignoring malformed heap={value}"),
+        }
+    }
+
+    log!("heap::init(): allocating {size} bytes for heap...");
 
+    let added = grow(size);
     assert!(
-        size >= HEAP_SIZE as usize,
-        "heap region returned by physical allocator is too small"
+        added > 0,
+        "could not allocate enough physical space for heap"
     );
 
-    unsafe {
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
-        memory::kernel_map_region::<Size4KiB>(va, pa, size as u64, flags)
-            .expect("failed to map heap pages");
-    }
-
-    // Tell allocator about new heap region.
-    unsafe {
-        ALLOCATOR.lock().init(va.as_mut_ptr(), size);
+    if added < size {
+        log!("heap::init(): only {added} of {size} bytes available for heap");
     }
 
-    log!("heap::init(): successfully initialized [ \x1b[0;32mOK\x1b[0m ]");
+    log!(
+        "heap::init(): {} usable bytes in {} regions [ \x1b[0;32mOK\x1b[0m ]",
+        self::size(),
+        heaps().len()
+    );
 }
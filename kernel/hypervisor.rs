@@ -0,0 +1,396 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+
+use raw_cpuid::native_cpuid::CpuIdReaderNative;
+use raw_cpuid::{cpuid, CpuId, Hypervisor};
+use x86_64::registers::model_specific::Msr;
+use x86_64::VirtAddr;
+
+use crate::cpu;
+use crate::cpu::{CpuFrequency, CPU_COUNT};
+use crate::log;
+use crate::memory;
+use crate::mmio;
+use crate::{assert_offset, assert_size};
+
+/// CPUID leaf with KVM paravirtual feature bits.
+const KVM_CPUID_FEATURES: u32 = 0x4000_0001;
+/// kvmclock is available through the new MSR numbers.
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+/// Paravirtual end of interrupt is available.
+const KVM_FEATURE_PV_EOI: u32 = 1 << 6;
+/// Halted vCPUs can be woken with the KICK_CPU hypercall.
+const KVM_FEATURE_PV_UNHALT: u32 = 1 << 7;
+
+/// Registers the physical address of the kvmclock structure.
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+/// Registers the physical address of the PV EOI flag.
+const MSR_KVM_PV_EOI_EN: u32 = 0x4b56_4d04;
+
+/// Hypercall that wakes a vCPU halted with `hlt`.
+const KVM_HC_KICK_CPU: u64 = 5;
+
+/// CPUID leaf with Hyper-V partition privileges.
+const HV_CPUID_FEATURES: u32 = 0x4000_0003;
+/// The partition may read the reference TSC page.
+const HV_ACCESS_REFERENCE_TSC: u32 = 1 << 9;
+/// The partition may read the TSC and APIC frequency MSRs.
+const HV_ACCESS_FREQUENCY_MSRS: u32 = 1 << 11;
+
+const HV_X64_MSR_REFERENCE_TSC: u32 = 0x4000_0021;
+const HV_X64_MSR_TSC_FREQUENCY: u32 = 0x4000_0022;
+
+// Which paravirtual clock was registered with the hypervisor.
+static CLOCK_SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Tsc as u8);
+
+// Whether PV EOI has been enabled on the processors.
+static PV_EOI: AtomicBool = AtomicBool::new(false);
+
+// Whether halted processors can be kicked with a hypercall.
+static PV_UNHALT: AtomicBool = AtomicBool::new(false);
+
+// Per-cpu PV EOI flags. KVM sets bit 0 when an interrupt can be acknowledged
+// without an exit to the hypervisor.
+static PV_EOI_FLAGS: [AtomicU32; CPU_COUNT] = [const { AtomicU32::new(0) }; CPU_COUNT];
+
+// Shared with the hypervisor, which updates it in place. Both live in the
+// identity mapped kernel image so their virtual address is also physical.
+static mut KVM_CLOCK: PvClockVcpuTimeInfo = PvClockVcpuTimeInfo::new();
+static mut HV_TSC_PAGE: HvReferenceTscPage = HvReferenceTscPage::new();
+
+/// Paravirtual clock used to read monotonic time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+    /// Raw timestamp counter, no paravirtual clock.
+    Tsc = 0,
+    /// KVM `pvclock_vcpu_time_info` structure.
+    KvmClock = 1,
+    /// Hyper-V reference TSC page.
+    HyperVTscPage = 2,
+}
+
+/// `pvclock_vcpu_time_info`, see Documentation/virt/kvm/x86/msr.rst.
+#[repr(C, align(32))]
+struct PvClockVcpuTimeInfo {
+    version: u32,
+    pad0: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+    pad: [u8; 2],
+}
+
+assert_size!(PvClockVcpuTimeInfo, 32);
+assert_offset!(PvClockVcpuTimeInfo, tsc_to_system_mul, 24);
+assert_offset!(PvClockVcpuTimeInfo, flags, 29);
+
+impl PvClockVcpuTimeInfo {
+    const fn new() -> Self {
+        Self {
+            version: 0,
+            pad0: 0,
+            tsc_timestamp: 0,
+            system_time: 0,
+            tsc_to_system_mul: 0,
+            tsc_shift: 0,
+            flags: 0,
+            pad: [0; 2],
+        }
+    }
+}
+
+/// `HV_REFERENCE_TSC_PAGE`, see the Hyper-V TLFS section 12.7.
+#[repr(C, align(4096))]
+struct HvReferenceTscPage {
+    sequence: u32,
+    reserved: u32,
+    scale: u64,
+    offset: i64,
+}
+
+assert_offset!(HvReferenceTscPage, scale, 8);
+assert_offset!(HvReferenceTscPage, offset, 16);
+
+impl HvReferenceTscPage {
+    const fn new() -> Self {
+        Self {
+            sequence: 0,
+            reserved: 0,
+            scale: 0,
+            offset: 0,
+        }
+    }
+}
+
+/// Identifies the hypervisor we are running under, if any.
+pub fn detect() -> Option<Hypervisor> {
+    let cpuid: CpuId<CpuIdReaderNative> = CpuId::new();
+    cpuid.get_hypervisor_info().map(|x| x.identify())
+}
+
+/// Returns the paravirtual clock in use.
+pub fn clock_source() -> ClockSource {
+    match CLOCK_SOURCE.load(Ordering::Relaxed) {
+        1 => ClockSource::KvmClock,
+        2 => ClockSource::HyperVTscPage,
+        _ => ClockSource::Tsc,
+    }
+}
+
+fn rdtsc() -> u64 {
+    unsafe { cpu::current().get_timestamp() }
+}
+
+/// Reads the monotonic paravirtual clock in nanoseconds.
+///
+/// Returns None if no paravirtual clock has been registered.
+pub fn clock_ns() -> Option<u64> {
+    match clock_source() {
+        ClockSource::Tsc => None,
+        ClockSource::KvmClock => Some(kvm_clock_ns()),
+        ClockSource::HyperVTscPage => hv_clock_ns(),
+    }
+}
+
+fn kvm_clock_ns() -> u64 {
+    let info = core::ptr::addr_of!(KVM_CLOCK);
+
+    loop {
+        unsafe {
+            // An odd version means the hypervisor is in the middle of an update.
+            let version = core::ptr::addr_of!((*info).version).read_volatile();
+            if version & 1 != 0 {
+                core::hint::spin_loop();
+                continue;
+            }
+            mmio::read_barrier();
+
+            let tsc_timestamp = core::ptr::addr_of!((*info).tsc_timestamp).read_volatile();
+            let system_time = core::ptr::addr_of!((*info).system_time).read_volatile();
+            let mul = core::ptr::addr_of!((*info).tsc_to_system_mul).read_volatile();
+            let shift = core::ptr::addr_of!((*info).tsc_shift).read_volatile();
+
+            let mut delta = rdtsc().wrapping_sub(tsc_timestamp);
+            if shift >= 0 {
+                delta <<= shift;
+            } else {
+                delta >>= -shift;
+            }
+            let ns = system_time + (((delta as u128) * (mul as u128)) >> 32) as u64;
+
+            mmio::read_barrier();
+            if core::ptr::addr_of!((*info).version).read_volatile() == version {
+                return ns;
+            }
+        }
+    }
+}
+
+fn hv_clock_ns() -> Option<u64> {
+    let page = core::ptr::addr_of!(HV_TSC_PAGE);
+
+    loop {
+        unsafe {
+            // A sequence of zero means the page is not valid and the MSR should be used.
+            let sequence = core::ptr::addr_of!((*page).sequence).read_volatile();
+            if sequence == 0 {
+                return None;
+            }
+            mmio::read_barrier();
+
+            let scale = core::ptr::addr_of!((*page).scale).read_volatile();
+            let offset = core::ptr::addr_of!((*page).offset).read_volatile();
+            let ticks = (((rdtsc() as u128) * (scale as u128)) >> 64) as i64 + offset;
+
+            mmio::read_barrier();
+            if core::ptr::addr_of!((*page).sequence).read_volatile() == sequence {
+                // The reference counter runs at 10 MHz.
+                return Some(ticks as u64 * 100);
+            }
+        }
+    }
+}
+
+/// Obtains the TSC frequency from the hypervisor.
+fn tsc_frequency(hypervisor: &Hypervisor) -> Option<u64> {
+    let cpuid: CpuId<CpuIdReaderNative> = CpuId::new();
+
+    // Leaf 0x40000010 is implemented by VMware and by KVM with invtsc.
+    if let Some(khz) = cpuid.get_hypervisor_info().and_then(|x| x.tsc_frequency()) {
+        return Some(khz as u64 * 1000);
+    }
+
+    match hypervisor {
+        Hypervisor::HyperV => {
+            let features = cpuid!(HV_CPUID_FEATURES);
+            if features.eax & HV_ACCESS_FREQUENCY_MSRS != 0 {
+                Some(unsafe { Msr::new(HV_X64_MSR_TSC_FREQUENCY).read() })
+            } else {
+                None
+            }
+        }
+        Hypervisor::KVM if clock_source() == ClockSource::KvmClock => {
+            // kvmclock converts TSC to nanoseconds with (tsc << shift) * mul >> 32.
+            let info = unsafe { &*core::ptr::addr_of!(KVM_CLOCK) };
+            if info.tsc_to_system_mul == 0 {
+                return None;
+            }
+
+            let mut hz = (1_000_000_000u128 << 32) / info.tsc_to_system_mul as u128;
+            if info.tsc_shift >= 0 {
+                hz >>= info.tsc_shift;
+            } else {
+                hz <<= -info.tsc_shift;
+            }
+            Some(hz as u64)
+        }
+        _ => None,
+    }
+}
+
+fn register_kvm_clock() -> bool {
+    if kvm_features() & KVM_FEATURE_CLOCKSOURCE2 == 0 {
+        return false;
+    }
+
+    unsafe {
+        let pa = core::ptr::addr_of!(KVM_CLOCK) as u64;
+        Msr::new(MSR_KVM_SYSTEM_TIME_NEW).write(pa | 1);
+    }
+
+    CLOCK_SOURCE.store(ClockSource::KvmClock as u8, Ordering::Relaxed);
+    true
+}
+
+fn register_hv_tsc_page() -> bool {
+    let features = cpuid!(HV_CPUID_FEATURES);
+    if features.eax & HV_ACCESS_REFERENCE_TSC == 0 {
+        return false;
+    }
+
+    unsafe {
+        let pa = core::ptr::addr_of!(HV_TSC_PAGE) as u64;
+        let mut msr = Msr::new(HV_X64_MSR_REFERENCE_TSC);
+        // Keep the reserved bits of the MSR intact.
+        let value = (msr.read() & 0xFFE) | pa | 1;
+        msr.write(value);
+    }
+
+    CLOCK_SOURCE.store(ClockSource::HyperVTscPage as u8, Ordering::Relaxed);
+    true
+}
+
+fn kvm_features() -> u32 {
+    cpuid!(KVM_CPUID_FEATURES).eax
+}
+
+/// Issues a KVM hypercall with up to two arguments.
+unsafe fn kvm_hypercall(nr: u64, a0: u64, a1: u64) -> u64 {
+    let ret: u64;
+    // rbx is reserved by LLVM, swap it in and out around the call.
+    asm!(
+        "xchg {a0}, rbx",
+        "vmcall",
+        "xchg {a0}, rbx",
+        a0 = inout(reg) a0 => _,
+        inout("rax") nr => ret,
+        in("rcx") a1,
+        options(nostack),
+    );
+    ret
+}
+
+/// Enables paravirtual end of interrupt for the current processor.
+///
+/// Must be called on every processor after [`init`] has detected support.
+pub fn enable_pv_eoi() {
+    if !PV_EOI.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let id = unsafe { cpu::current().id() };
+
+    unsafe {
+        let pa = memory::virt_to_phys(VirtAddr::from_ptr(PV_EOI_FLAGS[id].as_ptr()));
+        Msr::new(MSR_KVM_PV_EOI_EN).write(pa.as_u64() | 1);
+    }
+}
+
+/// Checks whether the hypervisor already acknowledged the interrupt being handled.
+///
+/// When this returns true the local APIC EOI register must not be written, which
+/// saves a costly exit to the hypervisor.
+pub fn pv_eoi_handled() -> bool {
+    if !PV_EOI.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    let id = unsafe { cpu::current().id() };
+    PV_EOI_FLAGS[id].fetch_and(!1, Ordering::AcqRel) & 1 != 0
+}
+
+/// Wakes a processor that is halted with `hlt`.
+///
+/// Returns false if the hypervisor cannot kick processors, in which case an
+/// interrupt has to be sent instead.
+pub fn kick_cpu(id: usize) -> bool {
+    if !PV_UNHALT.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    unsafe { kvm_hypercall(KVM_HC_KICK_CPU, 0, cpu::apic_id(id) as u64) == 0 }
+}
+
+/// Detects the hypervisor and sets up its paravirtual clock.
+///
+/// When the hypervisor knows the TSC frequency it replaces whatever was guessed
+/// during [`crate::cpu::init`], since CPUID leaf 0x15 is rarely exposed to guests.
+pub fn init() {
+    let Some(hypervisor) = detect() else {
+        log!("hypervisor::init(): running on bare metal");
+        return;
+    };
+
+    log!("hypervisor::init(): detected hypervisor {hypervisor:?}");
+
+    let registered = match hypervisor {
+        Hypervisor::KVM => register_kvm_clock(),
+        Hypervisor::HyperV => register_hv_tsc_page(),
+        _ => false,
+    };
+
+    if registered {
+        log!(
+            "hypervisor::init(): using {:?} clock source",
+            clock_source()
+        );
+    }
+
+    if hypervisor == Hypervisor::KVM {
+        let features = kvm_features();
+
+        if features & KVM_FEATURE_PV_EOI != 0 {
+            PV_EOI.store(true, Ordering::Relaxed);
+            enable_pv_eoi();
+            log!("hypervisor::init(): using paravirtual EOI");
+        }
+
+        // Spinlocks already spin with pause, which lets the host deschedule us
+        // through PAUSE-loop exiting; halted processors are kicked instead of
+        // sent an IPI.
+        if features & KVM_FEATURE_PV_UNHALT != 0 {
+            PV_UNHALT.store(true, Ordering::Relaxed);
+            log!("hypervisor::init(): using paravirtual unhalt");
+        }
+    }
+
+    if let Some(hz) = tsc_frequency(&hypervisor) {
+        cpu::current_mut().set_frequency(CpuFrequency::Hypervisor { hz });
+        log!("hypervisor::init(): TSC frequency is {hz} Hz");
+    }
+
+    log!("hypervisor::init(): paravirtual features initialized [ \x1b[0;32mOK\x1b[0m ]");
+}
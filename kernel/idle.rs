@@ -0,0 +1,327 @@
+use core::arch::asm;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use raw_cpuid::native_cpuid::CpuIdReaderNative;
+use raw_cpuid::CpuId;
+
+use x86_64::instructions::interrupts;
+
+use crate::arch::{Current, Interrupts};
+use crate::cpu;
+use crate::cpu::{CpuState, CPU_COUNT};
+use crate::hypervisor;
+use crate::log;
+use crate::multiboot;
+use crate::sched;
+use crate::shell;
+use crate::shell::{Command, CommandError};
+
+// Whether MONITOR/MWAIT can be used to idle processors.
+static MWAIT_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+// Whether MWAIT can be woken by an interrupt while interrupts are masked.
+static MWAIT_BREAK_ON_INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+// MWAIT hint for the deepest C-state reported by CPUID leaf 5.
+static MWAIT_DEEPEST_HINT: AtomicU8 = AtomicU8::new(0);
+
+// Current idle policy.
+static POLICY: AtomicU8 = AtomicU8::new(IdlePolicy::LowLatency as u8);
+
+// Per-cpu idle residency statistics.
+static STATS: [IdleCounters; CPU_COUNT] = [const { IdleCounters::new() }; CPU_COUNT];
+
+// Per-cpu flags set while a processor sleeps in hlt.
+static HALTED: [AtomicBool; CPU_COUNT] = [const { AtomicBool::new(false) }; CPU_COUNT];
+
+// Per-cpu words that MONITOR arms; a write to them wakes the processor.
+static WAKE_WORDS: [AtomicU64; CPU_COUNT] = [const { AtomicU64::new(0) }; CPU_COUNT];
+
+/// Knob for choosing between exit latency and power savings when idle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum IdlePolicy {
+    /// Use the shallowest sleep state (C1) so the processor wakes as fast as possible.
+    LowLatency = 0,
+    /// Use the deepest sleep state reported by the processor.
+    LowPower = 1,
+}
+
+impl IdlePolicy {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "low-latency" => Some(Self::LowLatency),
+            "low-power" => Some(Self::LowPower),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::LowLatency => "low-latency",
+            Self::LowPower => "low-power",
+        }
+    }
+}
+
+/// The way the processor was put to sleep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleState {
+    /// `hlt` instruction.
+    Halt,
+    /// `mwait` instruction with the given C-state hint.
+    Mwait { hint: u8 },
+}
+
+struct IdleCounters {
+    halt_entries: AtomicU64,
+    mwait_entries: AtomicU64,
+    cycles: AtomicU64,
+}
+
+impl IdleCounters {
+    const fn new() -> Self {
+        Self {
+            halt_entries: AtomicU64::new(0),
+            mwait_entries: AtomicU64::new(0),
+            cycles: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Snapshot of idle residency statistics for a single processor.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdleStats {
+    /// Number of times the processor slept with `hlt`.
+    pub halt_entries: u64,
+    /// Number of times the processor slept with `mwait`.
+    pub mwait_entries: u64,
+    /// Timestamp counter cycles spent asleep.
+    pub cycles: u64,
+    /// Time spent asleep in microseconds.
+    pub residency_us: u64,
+}
+
+/// Sets the idle policy used the next time a processor goes idle.
+pub fn set_policy(policy: IdlePolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// Gets the current idle policy.
+pub fn policy() -> IdlePolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        1 => IdlePolicy::LowPower,
+        _ => IdlePolicy::LowLatency,
+    }
+}
+
+/// Returns the idle residency statistics for a processor.
+pub fn stats(id: usize) -> IdleStats {
+    let counters = &STATS[id];
+    let cycles = counters.cycles.load(Ordering::Relaxed);
+    let freq = cpu::frequency(id);
+
+    IdleStats {
+        halt_entries: counters.halt_entries.load(Ordering::Relaxed),
+        mwait_entries: counters.mwait_entries.load(Ordering::Relaxed),
+        cycles,
+        residency_us: ((cycles as u128) * 1_000_000 / (freq as u128)) as u64,
+    }
+}
+
+/// Wakes a processor sleeping in `mwait` by writing to the word it is monitoring.
+///
+/// Processors sleeping in `hlt` are kicked through the hypervisor when possible,
+/// otherwise they must be woken by an interrupt.
+pub fn wake(id: usize) {
+    WAKE_WORDS[id].fetch_add(1, Ordering::Release);
+
+    if HALTED[id].load(Ordering::SeqCst) {
+        hypervisor::kick_cpu(id);
+    }
+}
+
+/// Picks the sleep state for the current policy.
+fn select() -> IdleState {
+    if !MWAIT_SUPPORTED.load(Ordering::Relaxed) {
+        return IdleState::Halt;
+    }
+
+    match policy() {
+        IdlePolicy::LowLatency => IdleState::Mwait { hint: 0x00 },
+        IdlePolicy::LowPower => IdleState::Mwait {
+            hint: MWAIT_DEEPEST_HINT.load(Ordering::Relaxed),
+        },
+    }
+}
+
+/// Puts the current processor to sleep until the next interrupt or wakeup.
+///
+/// Interrupts are enabled when this function returns.
+pub fn enter() {
+    let cpu = unsafe { cpu::current() };
+    let id = cpu.id();
+    let counters = &STATS[id];
+    let state = select();
+
+    let start = cpu.get_timestamp();
+
+    match state {
+        IdleState::Halt => {
+            HALTED[id].store(true, Ordering::SeqCst);
+
+            // A thread made runnable by another processor before the flag was set did
+            // not kick this one.
+            if sched::has_runnable() {
+                interrupts::enable();
+            } else {
+                counters.halt_entries.fetch_add(1, Ordering::Relaxed);
+                // sti; hlt so that an interrupt cannot slip in between the two.
+                Current::enable_and_wait();
+            }
+
+            HALTED[id].store(false, Ordering::Release);
+        }
+        IdleState::Mwait { hint } => {
+            let word = WAKE_WORDS[id].as_ptr();
+            let break_on_interrupt = MWAIT_BREAK_ON_INTERRUPT.load(Ordering::Relaxed);
+
+            // Arm the monitor with interrupts masked, an interrupt in between would
+            // otherwise be handled before mwait and not wake it.
+            interrupts::disable();
+
+            unsafe {
+                monitor(word as *const u8);
+
+                // Same as for hlt, the wake word may have been written before the
+                // monitor was armed.
+                if sched::has_runnable() {
+                    interrupts::enable();
+                } else if break_on_interrupt {
+                    counters.mwait_entries.fetch_add(1, Ordering::Relaxed);
+                    // Pending interrupts wake mwait even while masked, they are
+                    // serviced as soon as interrupts are turned back on.
+                    mwait(hint, 1);
+                    interrupts::enable();
+                } else {
+                    counters.mwait_entries.fetch_add(1, Ordering::Relaxed);
+                    sti_mwait(hint);
+                }
+            }
+        }
+    }
+
+    let end = cpu.get_timestamp();
+    counters
+        .cycles
+        .fetch_add(end.wrapping_sub(start), Ordering::Relaxed);
+}
+
+unsafe fn monitor(addr: *const u8) {
+    asm!("monitor", in("rax") addr, in("ecx") 0, in("edx") 0, options(nostack));
+}
+
+unsafe fn mwait(hint: u8, extensions: u32) {
+    asm!("mwait", in("eax") hint as u32, in("ecx") extensions, options(nostack));
+}
+
+/// Enables interrupts and sleeps in `mwait`.
+///
+/// `sti` holds off interrupts until the next instruction completes, so an interrupt
+/// arriving in between wakes `mwait` rather than being handled before it.
+unsafe fn sti_mwait(hint: u8) {
+    asm!("sti", "mwait", in("eax") hint as u32, in("ecx") 0, options(nostack));
+}
+
+fn idle_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    match args {
+        [] => {}
+        [policy] => {
+            set_policy(IdlePolicy::parse(policy).ok_or(CommandError::Usage)?);
+            return Ok(());
+        }
+        _ => return Err(CommandError::Usage),
+    }
+
+    writeln!(out, "policy {}", policy().name())?;
+
+    for id in (0..CPU_COUNT).filter(|&x| cpu::state(x) != CpuState::Offline) {
+        let stats = stats(id);
+        writeln!(
+            out,
+            "cpu {id}: {} hlt, {} mwait, asleep for {} cycles ({}.{:06} s)",
+            stats.halt_entries,
+            stats.mwait_entries,
+            stats.cycles,
+            stats.residency_us / 1_000_000,
+            stats.residency_us % 1_000_000
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Initializes the idle governor.
+///
+/// Detects whether the processor supports MONITOR/MWAIT and which C-states can be
+/// requested through it. Processors without MWAIT fall back to `hlt`. The policy is
+/// picked with `idle.policy=low-latency|low-power` on the kernel command line or the
+/// `idle` shell command.
+pub fn init() {
+    let cpuid: CpuId<CpuIdReaderNative> = CpuId::new();
+
+    let supported = cpuid
+        .get_feature_info()
+        .is_some_and(|x| x.has_monitor_mwait());
+
+    if let (true, Some(info)) = (supported, cpuid.get_monitor_mwait_info()) {
+        // Sub-state counts for C1..C7, the MWAIT hint encodes (C-state - 1) << 4.
+        let substates = [
+            info.supported_c1_states(),
+            info.supported_c2_states(),
+            info.supported_c3_states(),
+            info.supported_c4_states(),
+            info.supported_c5_states(),
+            info.supported_c6_states(),
+            info.supported_c7_states(),
+        ];
+
+        let hint = substates
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, &n)| n > 0)
+            .map_or(0, |(i, &n)| ((i as u8) << 4) | (n as u8 - 1));
+
+        MWAIT_DEEPEST_HINT.store(hint, Ordering::Relaxed);
+        MWAIT_BREAK_ON_INTERRUPT.store(info.interrupts_as_break_event(), Ordering::Relaxed);
+        MWAIT_SUPPORTED.store(true, Ordering::Relaxed);
+
+        log!("idle::init(): using mwait, deepest hint {hint:#04x}");
+    } else {
+        log!("idle::init(): mwait not supported, using hlt");
+    }
+
+    for value in multiboot::arg("idle.policy") {
+        match IdlePolicy::parse(value) {
+            Some(x) => set_policy(x),
+            None => log!("idle::init(): ignoring malformed idle.policy={value}"),
+        }
+    }
+
+    assert!(
+        shell::register(Command {
+            name: "idle",
+            usage: "[low-latency|low-power]",
+            help: "show idle residency or set the idle policy",
+            run: idle_command,
+        }),
+        "idle::init(): failed to register shell command"
+    );
+
+    log!(
+        "idle::init(): idle governor initialized, policy {} [ \x1b[0;32mOK\x1b[0m ]",
+        policy().name()
+    );
+}
@@ -0,0 +1,230 @@
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+use crate::boot;
+use crate::idle;
+use crate::log;
+use crate::sched;
+use crate::sched::Priority;
+
+/// Maximum number of init calls.
+const MAX_INITCALLS: usize = 16;
+
+/// Initialization of a driver or subsystem that runs in its own kernel thread during
+/// boot, concurrently with the ones it does not depend on.
+#[derive(Clone, Copy)]
+pub struct Initcall {
+    pub name: &'static str,
+    /// Names of the init calls that have to finish before this one starts.
+    pub after: &'static [&'static str],
+    pub run: fn(),
+}
+
+// Registered init calls in registration order.
+static INITCALLS: Mutex<[Option<Initcall>; MAX_INITCALLS]> = Mutex::new([None; MAX_INITCALLS]);
+
+// Whether the init call in the same slot finished.
+static DONE: [AtomicBool; MAX_INITCALLS] = [const { AtomicBool::new(false) }; MAX_INITCALLS];
+
+/// Registers an init call. Returns false if the table is full or the name is taken.
+pub fn register(initcall: Initcall) -> bool {
+    let mut initcalls = INITCALLS.lock();
+
+    if initcalls.iter().flatten().any(|x| x.name == initcall.name) {
+        return false;
+    }
+
+    match initcalls.iter_mut().find(|x| x.is_none()) {
+        Some(slot) => {
+            *slot = Some(initcall);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Subsystem that is initialized the first time it is used instead of during boot, so
+/// applications that never use it do not pay for it at startup.
+///
+/// Entry points of the subsystem call [`Deferred::ensure`] before anything else. The
+/// initialization must not go through those entry points itself, and the first use
+/// must not be from an interrupt handler, since both would wait for the
+/// initialization forever.
+pub struct Deferred {
+    name: &'static str,
+    init: fn(),
+    once: Once,
+}
+
+impl Deferred {
+    pub const fn new(name: &'static str, init: fn()) -> Self {
+        Self {
+            name,
+            init,
+            once: Once::new(),
+        }
+    }
+
+    /// Initializes the subsystem unless that already happened.
+    pub fn ensure(&self) {
+        self.once.call_once(|| {
+            log!(
+                "initcall::ensure(): initializing {} on first use",
+                self.name
+            );
+            (self.init)();
+        });
+    }
+}
+
+// States of an [`InitGuard`].
+const INIT_NOT_STARTED: u8 = 0;
+const INIT_RUNNING: u8 = 1;
+const INIT_DONE: u8 = 2;
+
+/// Subsystem whose `init()` must run exactly once, before the subsystems that use it.
+///
+/// The `init()` of the subsystem calls [`InitGuard::start`] first thing, and the ones
+/// of the subsystems that need it call [`InitGuard::require`]. Both panic with a
+/// message naming the subsystems instead of letting state get corrupted. The state
+/// is atomic, so it can be checked from interrupt handlers too.
+pub struct InitGuard {
+    name: &'static str,
+    state: AtomicU8,
+}
+
+/// Marks the initialization of an [`InitGuard`] as done when dropped.
+pub struct InitToken(&'static InitGuard);
+
+impl Drop for InitToken {
+    fn drop(&mut self) {
+        self.0.state.store(INIT_DONE, Ordering::Release);
+    }
+}
+
+impl InitGuard {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            state: AtomicU8::new(INIT_NOT_STARTED),
+        }
+    }
+
+    /// Marks the initialization as started, and as done once the token is dropped at
+    /// the end of `init()`. Panics if it was started before.
+    pub fn start(&'static self) -> InitToken {
+        if let Err(state) = self.state.compare_exchange(
+            INIT_NOT_STARTED,
+            INIT_RUNNING,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            let what = if state == INIT_RUNNING {
+                "is running"
+            } else {
+                "already ran"
+            };
+            panic!("{}::init(): called again, initialization {what}", self.name);
+        }
+
+        InitToken(self)
+    }
+
+    /// Checks whether the initialization is done.
+    pub fn is_done(&self) -> bool {
+        self.state.load(Ordering::Acquire) == INIT_DONE
+    }
+
+    /// Panics unless the initialization is done, naming `user` as the subsystem that
+    /// needs it.
+    #[track_caller]
+    pub fn require(&self, user: &str) {
+        assert!(
+            self.is_done(),
+            "{user}::init(): needs {} to be initialized first",
+            self.name
+        );
+    }
+}
+
+fn index_of(initcalls: &[Option<Initcall>], name: &str) -> Option<usize> {
+    initcalls
+        .iter()
+        .position(|x| x.is_some_and(|x| x.name == name))
+}
+
+/// Runs all registered init calls and returns once they all finished.
+///
+/// Every init call gets a kernel thread as soon as the ones it runs after are done,
+/// and the boot processor runs the scheduler until then. While one driver waits for
+/// its device, e.g. to finish a reset, the others make progress. Each init call is
+/// timed as a boot phase.
+pub fn run() {
+    let initcalls = *INITCALLS.lock();
+    let mut started = [false; MAX_INITCALLS];
+
+    for initcall in initcalls.iter().flatten() {
+        for dependency in initcall.after {
+            assert!(
+                index_of(&initcalls, dependency).is_some(),
+                "initcall::run(): {} runs after unknown init call {dependency}",
+                initcall.name
+            );
+        }
+    }
+
+    loop {
+        let mut running = false;
+
+        for (i, initcall) in initcalls.iter().enumerate() {
+            let Some(initcall) = *initcall else {
+                continue;
+            };
+
+            if started[i] {
+                running |= !DONE[i].load(Ordering::Acquire);
+                continue;
+            }
+
+            let ready = initcall.after.iter().all(|x| {
+                let j = index_of(&initcalls, x).unwrap();
+                DONE[j].load(Ordering::Acquire)
+            });
+
+            if ready {
+                started[i] = true;
+                running = true;
+                sched::spawn(initcall.name, Priority::Normal, move || {
+                    boot::phase(initcall.name, initcall.run);
+                    DONE[i].store(true, Ordering::Release);
+                });
+            }
+        }
+
+        if !running {
+            break;
+        }
+
+        // Same as the idle loop, keep interrupts off between checking for work and
+        // going to sleep so that a wakeup is never missed.
+        interrupts::disable();
+
+        if sched::has_runnable() {
+            interrupts::enable();
+            sched::schedule();
+        } else {
+            idle::enter();
+        }
+    }
+
+    if let Some(i) = (0..MAX_INITCALLS).find(|&i| initcalls[i].is_some() && !started[i]) {
+        panic!(
+            "initcall::run(): {} is part of a dependency cycle",
+            initcalls[i].unwrap().name
+        );
+    }
+
+    log!("initcall::run(): all init calls finished [ \x1b[0;32mOK\x1b[0m ]");
+}
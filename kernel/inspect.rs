@@ -0,0 +1,453 @@
+use core::fmt;
+use core::fmt::Write;
+
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::heap;
+use crate::layout;
+use crate::layout::Section;
+use crate::log;
+use crate::lowmem;
+use crate::memory;
+use crate::shell;
+use crate::shell::{Command, CommandError};
+use crate::trampoline;
+
+/// Largest range `md` dumps at once.
+const MAX_DUMP_SIZE: u64 = 4096;
+
+/// Bytes shown per hexdump line.
+const HEXDUMP_WIDTH: usize = 16;
+
+/// Width of the allocator bitmap visualization in characters.
+const FRAMES_COLUMNS: usize = 64;
+
+/// Most rows drawn per allocator region, larger regions use coarser characters.
+const FRAMES_MAX_ROWS: usize = 16;
+
+/// Sections of the kernel image, in the order they are laid out.
+fn sections() -> [(&'static str, Section); 4] {
+    [
+        (".text", layout::text()),
+        (".rodata", layout::rodata()),
+        (".data", layout::data()),
+        (".bss", layout::bss()),
+    ]
+}
+
+/// Parses a number in hexadecimal with a `0x` prefix or in decimal.
+pub fn parse_u64(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Writes `data` as a canonical hexdump, labelling lines starting at `address`.
+pub fn hexdump(out: &mut dyn Write, address: u64, data: &[u8]) -> fmt::Result {
+    for (i, line) in data.chunks(HEXDUMP_WIDTH).enumerate() {
+        write!(out, "{:016x}  ", address + (i * HEXDUMP_WIDTH) as u64)?;
+
+        for j in 0..HEXDUMP_WIDTH {
+            match line.get(j) {
+                Some(byte) => write!(out, "{byte:02x} ")?,
+                None => write!(out, "   ")?,
+            }
+
+            if j == HEXDUMP_WIDTH / 2 - 1 {
+                write!(out, " ")?;
+            }
+        }
+
+        write!(out, " |")?;
+        for &byte in line {
+            let ch = if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            };
+            write!(out, "{ch}")?;
+        }
+        writeln!(out, "|")?;
+    }
+
+    Ok(())
+}
+
+/// Checks that every page of `[start, start + len)` is mapped in the kernel page table.
+fn is_mapped(start: u64, len: u64) -> bool {
+    let Some(end) = start.checked_add(len) else {
+        return false;
+    };
+
+    let mut page = start & !0xFFF;
+    while page < end {
+        let Ok(va) = VirtAddr::try_new(page) else {
+            return false;
+        };
+
+        if memory::translate(va).is_none() {
+            return false;
+        }
+
+        page += 0x1000;
+    }
+
+    true
+}
+
+fn md_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    let (address, len) = match args {
+        [address] => (parse_u64(address), Some(64)),
+        [address, len] => (parse_u64(address), parse_u64(len)),
+        _ => return Err(CommandError::Usage),
+    };
+
+    let (Some(address), Some(len)) = (address, len) else {
+        return Err(CommandError::Usage);
+    };
+
+    if len > MAX_DUMP_SIZE {
+        return Err(CommandError::Failed("length too large"));
+    }
+
+    if !is_mapped(address, len) {
+        return Err(CommandError::Failed("address range is not mapped"));
+    }
+
+    let mut line = [0u8; HEXDUMP_WIDTH];
+    let mut offset = 0;
+
+    while offset < len {
+        let n = (len - offset).min(HEXDUMP_WIDTH as u64) as usize;
+
+        for (i, byte) in line[..n].iter_mut().enumerate() {
+            let ptr = (address + offset + i as u64) as *const u8;
+            *byte = unsafe { ptr.read_volatile() };
+        }
+
+        hexdump(out, address + offset, &line[..n])?;
+        offset += n as u64;
+    }
+
+    Ok(())
+}
+
+fn pt_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    let [address] = args else {
+        return Err(CommandError::Usage);
+    };
+
+    let va = parse_u64(address)
+        .and_then(|x| VirtAddr::try_new(x).ok())
+        .ok_or(CommandError::Failed("invalid virtual address"))?;
+
+    let mut result = Ok(());
+    memory::walk(va, |level, index, entry| {
+        let name = match level {
+            4 => "PML4",
+            3 => "PDPT",
+            2 => "PD  ",
+            _ => "PT  ",
+        };
+
+        if result.is_ok() {
+            result = writeln!(
+                out,
+                "{name}[{index:>3}] = {:#018x} {:?}",
+                entry.addr().as_u64(),
+                entry.flags()
+            );
+        }
+    });
+    result?;
+
+    match memory::translate(va) {
+        Some((pa, flags)) => {
+            let access = match (
+                flags.contains(PageTableFlags::WRITABLE),
+                flags.contains(PageTableFlags::NO_EXECUTE),
+            ) {
+                (true, true) => "rw-",
+                (true, false) => "rwx",
+                (false, true) => "r--",
+                (false, false) => "r-x",
+            };
+            write!(
+                out,
+                "{:#018x} -> {:#018x} {access}",
+                va.as_u64(),
+                pa.as_u64()
+            )?;
+            match sections().into_iter().find(|(_, x)| x.contains(pa)) {
+                Some((name, _)) => writeln!(out, " {name}")?,
+                None => writeln!(out)?,
+            }
+        }
+        None => writeln!(out, "{:#018x} is not mapped", va.as_u64())?,
+    }
+
+    Ok(())
+}
+
+fn ptdump_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    let mut result = Ok(());
+    memory::dump_page_table(|x| {
+        if result.is_ok() {
+            result = writeln!(
+                out,
+                "{:#018x}-{:#018x} -> {:#018x} {:>10} KiB {:?}",
+                x.start.as_u64(),
+                x.end().as_u64(),
+                x.phys.as_u64(),
+                x.size >> 10,
+                x.flags
+            );
+        }
+    });
+    result?;
+
+    Ok(())
+}
+
+fn protect_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    let [address, len, access] = args else {
+        return Err(CommandError::Usage);
+    };
+
+    let (Some(address), Some(len)) = (parse_u64(address), parse_u64(len)) else {
+        return Err(CommandError::Usage);
+    };
+    let va =
+        VirtAddr::try_new(address).map_err(|_| CommandError::Failed("invalid virtual address"))?;
+
+    let flags = match *access {
+        "r" => PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+        "rw" => PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        "rx" => PageTableFlags::PRESENT,
+        _ => return Err(CommandError::Usage),
+    };
+
+    // Whoever asks for this wants the writes or jumps into the range to fault.
+    if let Err(e) = unsafe { memory::protect(va, len, flags) } {
+        writeln!(out, "{e}")?;
+        return Err(CommandError::Failed("cannot change the flags"));
+    }
+
+    Ok(())
+}
+
+fn sections_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    for (name, section) in sections().into_iter().chain([("image", layout::image())]) {
+        writeln!(
+            out,
+            "{name:<8} {:#018x}-{:#018x} {:>6} KiB",
+            section.start.as_u64(),
+            section.end.as_u64(),
+            section.size() >> 10
+        )?;
+    }
+
+    Ok(())
+}
+
+fn frames_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    let mut result = Ok(());
+    memory::for_each_frame_region(|region, block_size, bitmap| {
+        if result.is_err() {
+            return;
+        }
+
+        result = draw_region(out, region, block_size, bitmap);
+    });
+    result?;
+
+    writeln!(out, "legend: '#' used, '+' partially used, '.' free")?;
+
+    let mut result = Ok(());
+    memory::for_each_reservation(|x| {
+        if result.is_ok() {
+            result = writeln!(
+                out,
+                "reserved {:#018x}-{:#018x} {}",
+                x.region.start_address().as_u64(),
+                x.region.end_address().as_u64(),
+                x.kind
+            );
+        }
+    });
+    result?;
+
+    writeln!(out, "low memory pool: {} bytes free", lowmem::bytes_free())?;
+    if let Some((vector, arrivals)) = trampoline::parked() {
+        writeln!(
+            out,
+            "parking trampoline: vector {vector:#04x}, {arrivals} parked"
+        )?;
+    }
+
+    Ok(())
+}
+
+fn heap_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    let stats = heap::stats();
+    writeln!(
+        out,
+        "size:          {} bytes in {} regions",
+        stats.size, stats.regions
+    )?;
+    writeln!(out, "used:          {} bytes", stats.used)?;
+    writeln!(out, "free:          {} bytes", stats.free)?;
+    writeln!(out, "largest free:  {} bytes", stats.largest_free)?;
+    writeln!(out, "large regions: {} bytes", stats.large_used)?;
+    writeln!(out, "high water:    {} bytes", stats.high_water)?;
+    writeln!(out, "failures:      {}", stats.failures)?;
+    Ok(())
+}
+
+fn heapstat_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    writeln!(
+        out,
+        "{:>10} {:>12} {:>12} {:>16} {:>12}",
+        "up to", "allocs", "frees", "allocated", "in use"
+    )?;
+
+    let stats = heap::stats();
+    for class in stats.classes.iter().filter(|x| x.allocations != 0) {
+        match class.max_size {
+            usize::MAX => write!(out, "{:>10}", "larger")?,
+            x => write!(out, "{:>10}", x)?,
+        }
+
+        writeln!(
+            out,
+            " {:>12} {:>12} {:>16} {:>12}",
+            class.allocations, class.deallocations, class.allocated_bytes, class.used_bytes
+        )?;
+    }
+
+    Ok(())
+}
+
+fn draw_region(
+    out: &mut dyn Write,
+    region: memory::PhysRegion,
+    block_size: usize,
+    bitmap: &[u8],
+) -> fmt::Result {
+    let blocks = (region.size() / block_size).min(bitmap.len() * 8);
+    let used_at = |i: usize| bitmap[i >> 3] & (1 << (i & 7)) != 0;
+    let used = (0..blocks).filter(|&i| used_at(i)).count();
+
+    writeln!(
+        out,
+        "[{:#016x}-{:#016x}] {used}/{blocks} blocks of {block_size} bytes used",
+        region.start_address().as_u64(),
+        region.end_address().as_u64()
+    )?;
+
+    let per_char = blocks.div_ceil(FRAMES_COLUMNS * FRAMES_MAX_ROWS).max(1);
+
+    for (column, start) in (0..blocks).step_by(per_char).enumerate() {
+        let end = (start + per_char).min(blocks);
+        let count = (start..end).filter(|&i| used_at(i)).count();
+
+        let ch = match count {
+            0 => '.',
+            x if x == end - start => '#',
+            _ => '+',
+        };
+
+        out.write_char(ch)?;
+        if column % FRAMES_COLUMNS == FRAMES_COLUMNS - 1 {
+            writeln!(out)?;
+        }
+    }
+
+    writeln!(out)
+}
+
+/// Initializes the memory inspection shell commands.
+pub fn init() {
+    let commands = [
+        Command {
+            name: "md",
+            usage: "<address> [<length>]",
+            help: "dump memory, refusing ranges that are not mapped",
+            run: md_command,
+        },
+        Command {
+            name: "pt",
+            usage: "<address>",
+            help: "walk the kernel page table for an address",
+            run: pt_command,
+        },
+        Command {
+            name: "ptdump",
+            usage: "",
+            help: "list the mappings of the kernel page table",
+            run: ptdump_command,
+        },
+        Command {
+            name: "protect",
+            usage: "<address> <length> r|rw|rx",
+            help: "change the access to kernel pages, e.g. to catch stray writes",
+            run: protect_command,
+        },
+        Command {
+            name: "sections",
+            usage: "",
+            help: "list the sections of the kernel image",
+            run: sections_command,
+        },
+        Command {
+            name: "frames",
+            usage: "",
+            help: "show the physical frame allocator bitmap and reservations",
+            run: frames_command,
+        },
+        Command {
+            name: "heap",
+            usage: "",
+            help: "show the kernel heap statistics",
+            run: heap_command,
+        },
+        Command {
+            name: "heapstat",
+            usage: "",
+            help: "show the allocations made through the global allocator by size class",
+            run: heapstat_command,
+        },
+    ];
+
+    for command in commands {
+        assert!(
+            shell::register(command),
+            "inspect::init(): failed to register {}",
+            command.name
+        );
+    }
+
+    log!("inspect::init(): memory inspection commands registered [ \x1b[0;32mOK\x1b[0m ]");
+}
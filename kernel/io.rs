@@ -0,0 +1,197 @@
+use alloc::vec::Vec;
+use core::cmp;
+use core::fmt;
+
+/// Kinds of I/O errors, a subset of the ones in `std::io`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A write returned zero bytes before all data was written.
+    WriteZero,
+    /// Anything else.
+    Other,
+}
+
+/// Error returned by I/O operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error {
+    kind: ErrorKind,
+    message: &'static str,
+}
+
+impl Error {
+    pub const fn new(kind: ErrorKind, message: &'static str) -> Self {
+        Self { kind, message }
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Self::new(kind, "")
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "{:?}", self.kind)
+        } else {
+            write!(f, "{:?}: {}", self.kind, self.message)
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Source of bytes.
+pub trait Read {
+    /// Reads some bytes into `buf` and returns how many were read. Zero means the
+    /// end of the source was reached, or `buf` is empty.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+}
+
+/// Sink for bytes.
+pub trait Write {
+    /// Writes some bytes from `buf` and returns how many were written.
+    fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+    /// Makes sure buffered data reached its destination.
+    fn flush(&mut self) -> Result<()>;
+
+    /// Writes all of `buf`.
+    fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf)? {
+                0 => {
+                    return Err(Error::new(
+                        ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ))
+                }
+                n => buf = &buf[n..],
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = cmp::min(buf.len(), self.len());
+        let (head, tail) = self.split_at(n);
+        buf[..n].copy_from_slice(head);
+        *self = tail;
+        Ok(n)
+    }
+}
+
+impl Write for &mut [u8] {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = cmp::min(buf.len(), self.len());
+        let (head, tail) = core::mem::take(self).split_at_mut(n);
+        head.copy_from_slice(&buf[..n]);
+        *self = tail;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for Vec<u8> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<R: Read + ?Sized> Read for &mut R {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        (**self).read(buf)
+    }
+}
+
+impl<W: Write + ?Sized> Write for &mut W {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        (**self).write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+/// Writes bytes to a text sink, replacing invalid UTF-8 with U+FFFD.
+pub fn write_lossy(out: &mut dyn fmt::Write, buf: &[u8]) -> fmt::Result {
+    for chunk in buf.utf8_chunks() {
+        out.write_str(chunk.valid())?;
+        if !chunk.invalid().is_empty() {
+            out.write_char(char::REPLACEMENT_CHARACTER)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies everything from `reader` to `writer` and returns the number of bytes copied.
+pub fn copy<R, W>(reader: &mut R, writer: &mut W) -> Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let mut buf = [0u8; 256];
+    let mut copied = 0;
+
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(copied),
+            n => {
+                writer.write_all(&buf[..n])?;
+                copied += n as u64;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy, write_lossy, Error, ErrorKind, Write};
+
+    #[test]
+    fn copies_a_slice_into_a_vec() {
+        let mut data: &[u8] = &[7; 600];
+        let mut out = Vec::new();
+
+        assert_eq!(copy(&mut data, &mut out), Ok(600));
+        assert!(data.is_empty());
+        assert_eq!(out, [7; 600]);
+    }
+
+    #[test]
+    fn write_all_fails_once_the_slice_is_full() {
+        let mut buf = [0u8; 4];
+        let mut out = &mut buf[..];
+
+        assert_eq!(
+            out.write_all(b"hello"),
+            Err(Error::new(
+                ErrorKind::WriteZero,
+                "failed to write whole buffer"
+            ))
+        );
+        assert_eq!(&buf, b"hell");
+    }
+
+    #[test]
+    fn write_lossy_replaces_invalid_utf8() {
+        let mut out = String::new();
+
+        write_lossy(&mut out, b"a\xffb").unwrap();
+        assert_eq!(out, "a\u{fffd}b");
+    }
+}
@@ -0,0 +1,670 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::fmt::Write;
+
+use spin::Mutex;
+
+use crate::log;
+use crate::multiboot;
+use crate::shell;
+use crate::shell::{Command, CommandError};
+
+/// Size of the in-memory storage used unless `kv=<size>` is given.
+const DEFAULT_STORAGE_SIZE: u64 = 256 * 1024;
+
+/// Marks the header of a segment.
+const SEGMENT_MAGIC: [u8; 4] = *b"LKV1";
+
+/// Magic, generation and checksum of the header.
+const SEGMENT_HEADER_SIZE: u64 = 16;
+
+/// Checksum, key length and value length of a record.
+const RECORD_HEADER_SIZE: u64 = 10;
+
+/// Value length of a record deleting its key.
+const TOMBSTONE: u32 = u32::MAX;
+
+/// Longest key, so a record header keeps its length in 16 bits.
+pub const MAX_KEY_SIZE: usize = u16::MAX as usize;
+
+// The store, once storage is attached.
+static STORE: Mutex<Option<Store>> = Mutex::new(None);
+
+/// Errors returned by the key-value store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvError {
+    /// No storage is attached.
+    NoStorage,
+    /// The key is empty or longer than [`MAX_KEY_SIZE`].
+    InvalidKey,
+    /// The live entries do not fit into a segment, even after compaction.
+    Full,
+    /// The storage failed to read, write or flush.
+    Io,
+}
+
+impl KvError {
+    fn message(self) -> &'static str {
+        match self {
+            KvError::NoStorage => "no storage attached",
+            KvError::InvalidKey => "invalid key",
+            KvError::Full => "store is full",
+            KvError::Io => "storage failed",
+        }
+    }
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+/// Byte-addressed storage the store keeps its log on, e.g. a block device or memory.
+pub trait Storage: Send {
+    /// Returns the size of the storage in bytes.
+    fn size(&self) -> u64;
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), KvError>;
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), KvError>;
+
+    /// Returns once everything written before is durable.
+    fn flush(&mut self) -> Result<(), KvError>;
+}
+
+/// Storage in kernel memory, which lasts until the machine goes down.
+#[derive(Debug)]
+pub struct MemoryStorage(Vec<u8>);
+
+impl MemoryStorage {
+    pub fn new(size: usize) -> Self {
+        Self(vec![0; size])
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn size(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), KvError> {
+        let start = offset as usize;
+        let data = self.0.get(start..start + buf.len()).ok_or(KvError::Io)?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), KvError> {
+        let start = offset as usize;
+        let dest = self
+            .0
+            .get_mut(start..start + data.len())
+            .ok_or(KvError::Io)?;
+        dest.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), KvError> {
+        Ok(())
+    }
+}
+
+/// CRC-32 (IEEE) of `data`, continuing from `crc`.
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+
+    !crc
+}
+
+/// Encodes a record, `None` as the value deleting the key.
+fn encode_record(key: &[u8], value: Option<&[u8]>) -> Vec<u8> {
+    let value_len = value.map_or(TOMBSTONE, |x| x.len() as u32);
+
+    let mut record = vec![0; 4];
+    record.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    record.extend_from_slice(&value_len.to_le_bytes());
+    record.extend_from_slice(key);
+    record.extend_from_slice(value.unwrap_or(&[]));
+
+    let crc = crc32(0, &record[4..]);
+    record[..4].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+/// Log-structured store of the live entries of one of two segments.
+///
+/// The storage is split into two segments, each a header followed by records appended
+/// one after the other. The active segment is the one with a valid header and the
+/// higher generation. A put or delete appends a record and flushes the storage before
+/// it returns, so it is committed once it returns. Recovery replays the records of the
+/// active segment up to the first one that does not check out, which is where a crash
+/// cut the log short.
+///
+/// Compaction writes the live entries into the other segment and only then gives it a
+/// header with the next generation, so a crash during compaction leaves the old segment
+/// active.
+struct Store {
+    storage: Box<dyn Storage>,
+    // Live entries, kept in memory in key order.
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+    // Size of each segment.
+    segment_size: u64,
+    // Index of the active segment, 0 or 1.
+    active: u64,
+    generation: u64,
+    // Offset within the active segment at which the next record goes.
+    tail: u64,
+}
+
+impl Store {
+    fn open(storage: Box<dyn Storage>) -> Result<Self, KvError> {
+        let segment_size = storage.size() / 2;
+
+        if segment_size <= SEGMENT_HEADER_SIZE + RECORD_HEADER_SIZE {
+            return Err(KvError::Full);
+        }
+
+        let mut store = Self {
+            storage,
+            entries: BTreeMap::new(),
+            segment_size,
+            active: 0,
+            generation: 0,
+            tail: SEGMENT_HEADER_SIZE,
+        };
+
+        let generations = [store.read_header(0)?, store.read_header(1)?];
+        match generations {
+            [None, None] => {
+                // Fresh storage.
+                store.write_header(0, 1)?;
+                store.generation = 1;
+                store.terminate()?;
+                store.storage.flush()?;
+            }
+            [a, b] => {
+                store.active = (b > a) as u64;
+                store.generation = a.max(b).unwrap_or(0);
+                store.replay()?;
+            }
+        }
+
+        Ok(store)
+    }
+
+    fn segment_start(&self, segment: u64) -> u64 {
+        segment * self.segment_size
+    }
+
+    // Returns the generation of a segment, or `None` if it has no valid header.
+    fn read_header(&mut self, segment: u64) -> Result<Option<u64>, KvError> {
+        let mut header = [0u8; SEGMENT_HEADER_SIZE as usize];
+        self.storage
+            .read(self.segment_start(segment), &mut header)?;
+
+        let generation = u64::from_le_bytes(header[4..12].try_into().unwrap());
+        let crc = u32::from_le_bytes(header[12..16].try_into().unwrap());
+
+        let valid = header[..4] == SEGMENT_MAGIC && crc == crc32(0, &header[..12]);
+        Ok(valid.then_some(generation))
+    }
+
+    fn write_header(&mut self, segment: u64, generation: u64) -> Result<(), KvError> {
+        let mut header = [0u8; SEGMENT_HEADER_SIZE as usize];
+        header[..4].copy_from_slice(&SEGMENT_MAGIC);
+        header[4..12].copy_from_slice(&generation.to_le_bytes());
+        let crc = crc32(0, &header[..12]);
+        header[12..16].copy_from_slice(&crc.to_le_bytes());
+
+        self.storage.write(self.segment_start(segment), &header)?;
+        self.storage.flush()
+    }
+
+    // Reads the records of the active segment into the entries.
+    fn replay(&mut self) -> Result<(), KvError> {
+        let start = self.segment_start(self.active);
+        let mut offset = SEGMENT_HEADER_SIZE;
+
+        while offset + RECORD_HEADER_SIZE <= self.segment_size {
+            let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+            self.storage.read(start + offset, &mut header)?;
+
+            let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+            let key_len = u16::from_le_bytes(header[4..6].try_into().unwrap()) as u64;
+            let value_len = u32::from_le_bytes(header[6..10].try_into().unwrap());
+            let data_len = key_len
+                + if value_len == TOMBSTONE {
+                    0
+                } else {
+                    value_len as u64
+                };
+
+            let end = offset + RECORD_HEADER_SIZE + data_len;
+            if key_len == 0 || end > self.segment_size {
+                break;
+            }
+
+            let mut data = vec![0; data_len as usize];
+            self.storage
+                .read(start + offset + RECORD_HEADER_SIZE, &mut data)?;
+
+            if crc32(crc32(0, &header[4..]), &data) != crc {
+                break;
+            }
+
+            let value = data.split_off(key_len as usize);
+            if value_len == TOMBSTONE {
+                self.entries.remove(&data);
+            } else {
+                self.entries.insert(data, value);
+            }
+
+            offset = end;
+        }
+
+        self.tail = offset;
+
+        // Leftovers of an earlier, longer log past the tail would be picked up by a
+        // later replay once the records before them happen to line up, so end the log
+        // explicitly.
+        self.terminate()
+    }
+
+    // Writes a record header that never checks out at the tail, if there is room.
+    fn terminate(&mut self) -> Result<(), KvError> {
+        if self.tail + RECORD_HEADER_SIZE <= self.segment_size {
+            let start = self.segment_start(self.active);
+            self.storage
+                .write(start + self.tail, &[0; RECORD_HEADER_SIZE as usize])?;
+        }
+
+        Ok(())
+    }
+
+    // Appends a record and makes it durable, compacting first if it does not fit.
+    fn append(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<(), KvError> {
+        if key.is_empty() || key.len() > MAX_KEY_SIZE {
+            return Err(KvError::InvalidKey);
+        }
+
+        let record = encode_record(key, value);
+
+        if self.tail + record.len() as u64 > self.segment_size {
+            self.compact()?;
+
+            if self.tail + record.len() as u64 > self.segment_size {
+                return Err(KvError::Full);
+            }
+        }
+
+        let start = self.segment_start(self.active);
+        self.storage.write(start + self.tail, &record)?;
+        self.tail += record.len() as u64;
+        self.terminate()?;
+        self.storage.flush()?;
+
+        match value {
+            Some(value) => self.entries.insert(key.to_vec(), value.to_vec()),
+            None => self.entries.remove(key),
+        };
+
+        Ok(())
+    }
+
+    // Rewrites the live entries into the other segment and makes it the active one.
+    fn compact(&mut self) -> Result<(), KvError> {
+        let target = 1 - self.active;
+        let start = self.segment_start(target);
+        let mut tail = SEGMENT_HEADER_SIZE;
+
+        for (key, value) in &self.entries {
+            let record = encode_record(key, Some(value));
+            if tail + record.len() as u64 > self.segment_size {
+                return Err(KvError::Full);
+            }
+
+            self.storage.write(start + tail, &record)?;
+            tail += record.len() as u64;
+        }
+
+        // The records must be durable before the header makes them the live log.
+        self.storage.flush()?;
+        self.write_header(target, self.generation + 1)?;
+
+        self.active = target;
+        self.generation += 1;
+        self.tail = tail;
+        self.terminate()?;
+        self.storage.flush()
+    }
+}
+
+fn with_store<R>(f: impl FnOnce(&mut Store) -> Result<R, KvError>) -> Result<R, KvError> {
+    f(STORE.lock().as_mut().ok_or(KvError::NoStorage)?)
+}
+
+/// Opens the store on `storage`, recovering the entries committed to it before, and
+/// replaces the store in use.
+pub fn attach(storage: Box<dyn Storage>) -> Result<(), KvError> {
+    let store = Store::open(storage)?;
+    *STORE.lock() = Some(store);
+    Ok(())
+}
+
+/// Returns the value of `key`.
+pub fn get(key: &[u8]) -> Option<Vec<u8>> {
+    with_store(|x| Ok(x.entries.get(key).cloned()))
+        .ok()
+        .flatten()
+}
+
+/// Sets the value of `key`, committing it before returning.
+pub fn put(key: &[u8], value: &[u8]) -> Result<(), KvError> {
+    with_store(|x| x.append(key, Some(value)))
+}
+
+/// Removes `key`, committing it before returning. Returns false if there was no such
+/// key.
+pub fn delete(key: &[u8]) -> Result<bool, KvError> {
+    with_store(|x| {
+        if !x.entries.contains_key(key) {
+            return Ok(false);
+        }
+
+        x.append(key, None).map(|_| true)
+    })
+}
+
+/// Calls `f` with every entry in key order. The store is locked meanwhile, so `f` must
+/// not use it.
+pub fn iterate(mut f: impl FnMut(&[u8], &[u8])) -> Result<(), KvError> {
+    with_store(|x| {
+        x.entries.iter().for_each(|(key, value)| f(key, value));
+        Ok(())
+    })
+}
+
+/// Rewrites the log with only the live entries, which otherwise happens once it fills
+/// its segment.
+pub fn compact() -> Result<(), KvError> {
+    with_store(|x| x.compact())
+}
+
+fn kv_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    let fail = |e: KvError| CommandError::Failed(e.message());
+
+    match args {
+        [] => {
+            let mut result = Ok(());
+            iterate(|key, value| {
+                if result.is_ok() {
+                    result = writeln!(
+                        out,
+                        "{} = {}",
+                        core::str::from_utf8(key).unwrap_or("<binary>"),
+                        core::str::from_utf8(value).unwrap_or("<binary>")
+                    );
+                }
+            })
+            .map_err(fail)?;
+            result?;
+        }
+        ["get", key] => match get(key.as_bytes()) {
+            Some(value) => writeln!(
+                out,
+                "{}",
+                core::str::from_utf8(&value).unwrap_or("<binary>")
+            )?,
+            None => return Err(CommandError::Failed("no such key")),
+        },
+        ["put", key, value] => put(key.as_bytes(), value.as_bytes()).map_err(fail)?,
+        ["delete", key] => {
+            if !delete(key.as_bytes()).map_err(fail)? {
+                return Err(CommandError::Failed("no such key"));
+            }
+        }
+        ["compact"] => compact().map_err(fail)?,
+        _ => return Err(CommandError::Usage),
+    }
+
+    Ok(())
+}
+
+/// Initializes the key-value store, giving applications durable state without a
+/// filesystem.
+///
+/// Until a driver attaches a block device with [`attach`], the store lives in kernel
+/// memory of the size given as `kv=<size>` on the kernel command line, which survives
+/// application restarts but not the machine going down.
+pub fn init() {
+    let mut size = DEFAULT_STORAGE_SIZE;
+
+    for value in multiboot::arg("kv") {
+        match crate::heap::parse_size(value) {
+            Some(x) => size = x as u64,
+            None => log!("kv::init(): ignoring malformed kv={value}"),
+        }
+    }
+
+    if let Err(e) = attach(Box::new(MemoryStorage::new(size as usize))) {
+        log!("kv::init(): failed to open store of {size} bytes: {e}");
+        return;
+    }
+
+    assert!(
+        shell::register(Command {
+            name: "kv",
+            usage: "[get <key>|put <key> <value>|delete <key>|compact]",
+            help: "list or change the entries of the key-value store",
+            run: kv_command,
+        }),
+        "kv::init(): failed to register kv"
+    );
+
+    log!("kv::init(): {size} bytes of memory storage [ \x1b[0;32mOK\x1b[0m ]");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    struct Disk {
+        storage: MemoryStorage,
+        // Bytes written before the machine goes down, cutting the write at it short.
+        budget: Option<usize>,
+    }
+
+    /// Memory storage that outlives the store, so it can be opened again as if after a
+    /// reboot.
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Disk>>);
+
+    impl Shared {
+        fn new(size: usize) -> Self {
+            Self(Arc::new(Mutex::new(Disk {
+                storage: MemoryStorage::new(size),
+                budget: None,
+            })))
+        }
+
+        fn open(&self) -> Store {
+            Store::open(Box::new(self.clone())).unwrap()
+        }
+
+        fn crash_after(&self, budget: Option<usize>) {
+            self.0.lock().unwrap().budget = budget;
+        }
+    }
+
+    impl Storage for Shared {
+        fn size(&self) -> u64 {
+            self.0.lock().unwrap().storage.size()
+        }
+
+        fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), KvError> {
+            self.0.lock().unwrap().storage.read(offset, buf)
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), KvError> {
+            let mut disk = self.0.lock().unwrap();
+            let Some(budget) = disk.budget else {
+                return disk.storage.write(offset, data);
+            };
+
+            let len = budget.min(data.len());
+            disk.budget = Some(budget - len);
+            disk.storage.write(offset, &data[..len])?;
+
+            if len < data.len() {
+                return Err(KvError::Io);
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), KvError> {
+            self.0.lock().unwrap().storage.flush()
+        }
+    }
+
+    fn entries(store: &Store) -> Vec<(&[u8], &[u8])> {
+        store
+            .entries
+            .iter()
+            .map(|(key, value)| (key.as_slice(), value.as_slice()))
+            .collect()
+    }
+
+    #[test]
+    fn crc32_matches_check_value() {
+        assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn recovers_committed_entries() {
+        let disk = Shared::new(4096);
+
+        let mut store = disk.open();
+        store.append(b"a", Some(b"1")).unwrap();
+        store.append(b"b", Some(b"2")).unwrap();
+        store.append(b"a", Some(b"3")).unwrap();
+        store.append(b"c", Some(b"")).unwrap();
+        store.append(b"b", None).unwrap();
+        drop(store);
+
+        let store = disk.open();
+        assert_eq!(entries(&store), [(&b"a"[..], &b"3"[..]), (b"c", b"")]);
+        assert_eq!(store.generation, 1);
+    }
+
+    #[test]
+    fn drops_torn_records() {
+        let disk = Shared::new(4096);
+
+        let mut store = disk.open();
+        store.append(b"a", Some(b"1")).unwrap();
+
+        // The machine goes down halfway through the next record.
+        disk.crash_after(Some(8));
+        assert_eq!(store.append(b"b", Some(b"2")), Err(KvError::Io));
+        disk.crash_after(None);
+
+        let mut store = disk.open();
+        assert_eq!(entries(&store), [(&b"a"[..], &b"1"[..])]);
+
+        // A shorter record written over the torn one does not bring its end back.
+        store.append(b"c", Some(b"")).unwrap();
+        let store = disk.open();
+        assert_eq!(entries(&store), [(&b"a"[..], &b"1"[..]), (b"c", b"")]);
+    }
+
+    #[test]
+    fn compacts_when_segment_is_full() {
+        // Segments of 128 bytes, room for a few records of 22 bytes.
+        let disk = Shared::new(256);
+
+        let mut store = disk.open();
+        store.append(b"kept", Some(b"value")).unwrap();
+        for i in 0..20u8 {
+            store.append(b"key", Some(&[b'0' + i % 10; 9])).unwrap();
+        }
+        store.append(b"gone", Some(b"x")).unwrap();
+        store.append(b"gone", None).unwrap();
+
+        assert!(store.generation > 1);
+        let expected = [(&b"kept"[..], &b"value"[..]), (b"key", b"999999999")];
+        assert_eq!(entries(&store), expected);
+
+        let generation = store.generation;
+        drop(store);
+
+        let mut store = disk.open();
+        assert_eq!(entries(&store), expected);
+        assert_eq!(store.generation, generation);
+
+        store.compact().unwrap();
+        let store = disk.open();
+        assert_eq!(entries(&store), expected);
+        assert_eq!(store.generation, generation + 1);
+    }
+
+    #[test]
+    fn refuses_entries_that_do_not_fit() {
+        let disk = Shared::new(256);
+
+        let mut store = disk.open();
+        assert_eq!(store.append(b"", Some(b"x")), Err(KvError::InvalidKey));
+        assert_eq!(store.append(b"big", Some(&[0; 128])), Err(KvError::Full));
+
+        store.append(b"small", Some(b"x")).unwrap();
+        let store = disk.open();
+        assert_eq!(entries(&store), [(&b"small"[..], &b"x"[..])]);
+    }
+
+    #[test]
+    fn survives_crash_at_any_point_of_compaction() {
+        // Recovery finds either the entries before the put or those after it, wherever
+        // the machine went down.
+        for budget in 0..256 {
+            let disk = Shared::new(256);
+
+            let mut store = disk.open();
+            store.append(b"a", Some(&[1; 20])).unwrap();
+            store.append(b"b", Some(&[2; 20])).unwrap();
+            store.append(b"a", Some(&[3; 20])).unwrap();
+            let before = store.entries.clone();
+
+            // Does not fit into the active segment, so it compacts first.
+            disk.crash_after(Some(budget));
+            let result = store.append(b"b", Some(&[4; 20]));
+            disk.crash_after(None);
+
+            let mut after = before.clone();
+            after.insert(b"b".to_vec(), vec![4; 20]);
+
+            let store = disk.open();
+            if result.is_ok() {
+                assert_eq!(store.entries, after, "budget {budget}");
+                assert_eq!(store.generation, 2, "budget {budget}");
+            } else {
+                assert!(
+                    store.entries == before || store.entries == after,
+                    "budget {budget}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use core::ptr::addr_of;
+use x86_64::PhysAddr;
+
+// Boundaries of the kernel image, see kernel/kernel.ld. Only their addresses mean
+// anything.
+extern "C" {
+    static __kernel_start: u8;
+    static __text_end: u8;
+    static __data_start: u8;
+    static __bss_start: u8;
+    static __kernel_end: u8;
+}
+
+// Boundaries of the boot GDT and page tables, see kernel/entry.S.
+extern "C" {
+    static bootdata: u8;
+    static bootdata_end: u8;
+}
+
+/// Part of the kernel image, as placed by kernel/kernel.ld.
+///
+/// The kernel is linked where it is loaded, so the addresses are both physical and,
+/// through the identity mapping, virtual.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Section {
+    pub start: PhysAddr,
+    pub end: PhysAddr,
+}
+
+impl Section {
+    fn between(start: *const u8, end: *const u8) -> Self {
+        Self {
+            start: PhysAddr::new(start as u64),
+            end: PhysAddr::new(end as u64),
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, addr: PhysAddr) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+}
+
+/// The whole kernel image, from the multiboot header to the end of `.bss`.
+pub fn image() -> Section {
+    Section::between(addr_of!(__kernel_start), addr_of!(__kernel_end))
+}
+
+/// Code, including the boot code and the trap entry points.
+pub fn text() -> Section {
+    Section::between(addr_of!(__kernel_start), addr_of!(__text_end))
+}
+
+/// Read-only data, which includes the boot page table and GDT.
+pub fn rodata() -> Section {
+    Section::between(addr_of!(__text_end), addr_of!(__data_start))
+}
+
+/// The GDT and page tables the boot code runs on, part of `.rodata`. Whole pages that
+/// nothing else shares, which are no longer needed once the kernel page table is on.
+pub fn boot_data() -> Section {
+    Section::between(addr_of!(bootdata), addr_of!(bootdata_end))
+}
+
+/// Initialized writable data.
+pub fn data() -> Section {
+    Section::between(addr_of!(__data_start), addr_of!(__bss_start))
+}
+
+/// Zeroed writable data, which includes the boot stack.
+pub fn bss() -> Section {
+    Section::between(addr_of!(__bss_start), addr_of!(__kernel_end))
+}
+
+// The kernel's address space. Everything the kernel maps lies in one of the regions
+// below, which are checked at compile time to be sane and apart from each other.
+
+/// Physical memory the boot page table identity maps with one gigapage, see entry.S.
+/// Everything the kernel reads before its own page table is on must lie below.
+pub const BOOT_MAP_SIZE: u64 = 1 << 30;
+
+/// End of the physical memory the kernel page table identity maps, starting with the
+/// kernel image.
+pub const IDENTITY_MAP_END: u64 = 0x8000_0000;
+
+/// Start of the device memory below 4 GiB, which must never be identity mapped as
+/// ordinary memory.
+pub const DEVICE_MEMORY_START: u64 = 0xFE00_0000;
+
+/// Where the heap regions are mapped, see heap.rs.
+pub const HEAP_BASE: u64 = 0x0000_0444_4444_4000;
+/// Number of heap regions.
+pub const HEAP_REGIONS: usize = 8;
+/// Virtual address space set aside for every heap region, the largest a region can be.
+pub const HEAP_REGION_SPACING: u64 = 1 << 30;
+/// End of the virtual address space of the last heap region.
+pub const HEAP_END: u64 = HEAP_BASE + HEAP_REGIONS as u64 * HEAP_REGION_SPACING;
+
+/// Where physical memory is mapped in the higher half, so that any of it, including
+/// device memory, can be reached with the kernel page table.
+pub const DIRECT_MAP_BASE: u64 = 0xFFFF_8000_0000_0000;
+/// Amount of physical memory mapped at [`DIRECT_MAP_BASE`].
+pub const DIRECT_MAP_SIZE: u64 = 4 << 30;
+
+/// Where device registers are mapped uncached with 4 KiB pages, see
+/// [`memory::map_device`](crate::memory::map_device).
+pub const DEVICE_MAP_BASE: u64 = 0xFFFF_9000_0000_0000;
+/// Size of the device mapping window.
+pub const DEVICE_MAP_SIZE: u64 = 1 << 30;
+
+// Virtual address ranges of the kernel page table, as start and end.
+const REGIONS: [(u64, u64); 4] = [
+    (0, IDENTITY_MAP_END),
+    (HEAP_BASE, HEAP_END),
+    (DIRECT_MAP_BASE, DIRECT_MAP_BASE + DIRECT_MAP_SIZE),
+    (DEVICE_MAP_BASE, DEVICE_MAP_BASE + DEVICE_MAP_SIZE),
+];
+
+// Whether `va` is canonical with 48 address bits, bits 63:47 all equal.
+const fn is_canonical(va: u64) -> bool {
+    let high = va >> 47;
+    high == 0 || high == 0x1_FFFF
+}
+
+const _: () = {
+    let mut i = 0;
+    while i < REGIONS.len() {
+        let (start, end) = REGIONS[i];
+        assert!(start < end, "layout: empty region");
+        assert!(
+            is_canonical(start) && is_canonical(end - 1),
+            "layout: region is not canonical"
+        );
+
+        let mut j = i + 1;
+        while j < REGIONS.len() {
+            let (other_start, other_end) = REGIONS[j];
+            assert!(
+                end <= other_start || other_end <= start,
+                "layout: regions overlap"
+            );
+            j += 1;
+        }
+
+        i += 1;
+    }
+};
+
+const _: () = assert!(
+    IDENTITY_MAP_END <= DEVICE_MEMORY_START,
+    "layout: identity map covers device memory"
+);
+const _: () = assert!(
+    IDENTITY_MAP_END <= DIRECT_MAP_SIZE && DEVICE_MEMORY_START < DIRECT_MAP_SIZE,
+    "layout: direct map misses identity mapped or device memory"
+);
+const _: () = assert!(
+    HEAP_BASE.is_multiple_of(4096),
+    "layout: heap is not page aligned"
+);
+
+/// Fails the build unless `$ty` is `$size` bytes large.
+///
+/// Meant for structures shared with hardware, firmware, the bootloader or assembly,
+/// where a wrong field type or a forgotten padding field compiles just fine and then
+/// corrupts whatever reads the structure. The check sits next to the definition it
+/// guards. Register blocks check their offsets themselves, see
+/// [`register_block!`](crate::register_block).
+///
+/// ```ignore
+/// assert_size!(Descriptor, 16);
+/// ```
+#[macro_export]
+macro_rules! assert_size {
+    ($ty:ty, $size:expr) => {
+        const _: () = assert!(
+            core::mem::size_of::<$ty>() == $size,
+            concat!(
+                "assert_size!: ",
+                stringify!($ty),
+                " is not ",
+                stringify!($size),
+                " bytes"
+            )
+        );
+    };
+}
+
+/// Fails the build unless `$field` of `$ty` is at offset `$offset`.
+///
+/// ```ignore
+/// assert_offset!(TrapFrame, rip, 0xA8);
+/// ```
+#[macro_export]
+macro_rules! assert_offset {
+    ($ty:ty, $field:ident, $offset:expr) => {
+        const _: () = assert!(
+            core::mem::offset_of!($ty, $field) == $offset,
+            concat!(
+                "assert_offset!: ",
+                stringify!($ty),
+                "::",
+                stringify!($field),
+                " is not at offset ",
+                stringify!($offset)
+            )
+        );
+    };
+}
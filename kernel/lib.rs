@@ -1,35 +1,209 @@
-#![no_std]
-#![feature(panic_info_message)]
-#![feature(abi_x86_interrupt)]
+// Unit tests are built for and run on the host, see `make test`, as are the fuzz
+// targets in fuzz/.
+#![cfg_attr(not(test), no_std)]
 
 extern crate alloc;
 
+mod acpi;
+mod arch;
+mod bitmap;
+mod boot;
 mod console;
 mod cpu;
+mod critical;
+mod debug;
+mod endian;
+#[cfg(feature = "fault-injection")]
+mod fault;
+#[cfg(fuzzing)]
+pub mod fuzz;
 mod heap;
+mod hypervisor;
+mod idle;
+mod initcall;
+mod inspect;
+mod io;
+mod kv;
+mod layout;
+mod logger;
+mod lowmem;
+mod mce;
+mod mdns;
 mod memory;
+mod metrics;
+mod mmio;
+mod mpsc;
 mod multiboot;
-mod net;
+mod mux;
+pub mod net;
+#[cfg(feature = "net-smoltcp")]
+mod net_smoltcp;
+#[cfg(not(feature = "net-smoltcp"))]
+mod net_stack;
 mod panic;
 mod pci;
+mod power;
+mod ps2;
+mod replay;
+mod ring;
+mod sched;
+mod shell;
+mod socket;
+mod stdio;
+mod syslog;
+mod tftp;
+mod thermal;
+mod timer;
+mod trampoline;
 mod trap;
+mod tty;
+mod virtio;
+mod virtio_legacy;
+mod virtio_mmio;
+mod virtio_pci;
+mod virtqueue;
+mod workqueue;
 
 /// The library operating system calls initialization routines in this function
 /// related to memory management and drivers before transferring control to the
 /// statically-linked unikernel application.
 #[no_mangle]
 pub extern "C" fn kernel_main(mbi: *const multiboot::MultibootInformation) -> ! {
-    cpu::init(0);
-    console::init();
-    memory::init(mbi);
-    heap::init();
-    trap::init();
-    pci::init();
-    net::init();
+    boot::start();
+    multiboot::init(mbi);
+    boot::phase("cpu", || cpu::init(0));
+    boot::phase("console", console::init);
+    boot::phase("panic", panic::init);
+    boot::phase("hypervisor", hypervisor::init);
+    boot::phase("memory", || memory::init(mbi));
+    boot::phase("lowmem", lowmem::init);
+    boot::phase("trampoline", trampoline::init);
+    boot::phase("heap", heap::init);
+    boot::phase("trap", trap::init);
+    boot::phase("mce", mce::init);
+    boot::phase("debug", debug::init);
+    boot::phase("idle", idle::init);
+    boot::phase("sched", sched::init);
+    boot::phase("workqueue", workqueue::init);
+    boot::phase("timer", timer::init);
+    boot::phase("mux", mux::init);
+    boot::phase("stdio", stdio::init);
+    boot::phase("shell", shell::init);
+    boot::phase("inspect", inspect::init);
+    boot::phase("power", power::init);
+    boot::phase("acpi", acpi::init);
+    boot::phase("thermal", thermal::init);
+    boot::phase("replay", replay::init);
+    boot::phase("mdns", mdns::init);
+    boot::phase("tftp", tftp::init);
+    boot::phase("kv", kv::init);
+    boot::phase("metrics", metrics::init);
+    #[cfg(feature = "fault-injection")]
+    boot::phase("fault", fault::init);
 
+    // Drivers are brought up concurrently, each after the ones it needs.
+    let initcalls = [
+        initcall::Initcall {
+            name: "pci",
+            after: &[],
+            run: || {
+                // Log the mappings drivers add to the kernel page table.
+                let before = memory::snapshot();
+                pci::init();
+                memory::log_changes(&before, "pci::init()");
+            },
+        },
+        initcall::Initcall {
+            name: "virtio",
+            after: &[],
+            run: virtio::init,
+        },
+        initcall::Initcall {
+            name: "ps2",
+            after: &[],
+            run: ps2::init,
+        },
+        initcall::Initcall {
+            name: "net",
+            after: &["pci", "virtio"],
+            run: net::init,
+        },
+    ];
+
+    for initcall in initcalls {
+        assert!(
+            initcall::register(initcall),
+            "kernel_main(): failed to register init call {}",
+            initcall.name
+        );
+    }
+
+    #[cfg(feature = "net-smoltcp")]
+    assert!(
+        initcall::register(initcall::Initcall {
+            name: "net_smoltcp",
+            after: &["net"],
+            run: net_smoltcp::init,
+        }),
+        "kernel_main(): failed to register init call net_smoltcp"
+    );
+
+    #[cfg(feature = "net-smoltcp")]
+    assert!(
+        initcall::register(initcall::Initcall {
+            name: "metrics_http",
+            after: &["net_smoltcp"],
+            run: metrics::serve,
+        }),
+        "kernel_main(): failed to register init call metrics_http"
+    );
+
+    #[cfg(not(feature = "net-smoltcp"))]
+    assert!(
+        initcall::register(initcall::Initcall {
+            name: "net_stack",
+            after: &["net"],
+            run: net_stack::init,
+        }),
+        "kernel_main(): failed to register init call net_stack"
+    );
+
+    #[cfg(feature = "net-smoltcp")]
+    assert!(
+        initcall::register(initcall::Initcall {
+            name: "syslog",
+            after: &["net_smoltcp"],
+            run: syslog::init,
+        }),
+        "kernel_main(): failed to register init call syslog"
+    );
+
+    #[cfg(not(feature = "net-smoltcp"))]
+    assert!(
+        initcall::register(initcall::Initcall {
+            name: "syslog",
+            after: &["net_stack"],
+            run: syslog::init,
+        }),
+        "kernel_main(): failed to register init call syslog"
+    );
+
+    initcall::run();
+    boot::report();
     console::enable_echo(true);
 
     loop {
-        x86_64::instructions::hlt();
+        timer::update_tick(sched::runnable_count());
+
+        // Keep interrupts off between checking for work and going to sleep so that a
+        // thread woken by an interrupt handler is never missed.
+        x86_64::instructions::interrupts::disable();
+
+        if sched::has_runnable() {
+            x86_64::instructions::interrupts::enable();
+            sched::schedule();
+        } else {
+            idle::enter();
+        }
     }
 }
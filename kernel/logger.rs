@@ -0,0 +1,843 @@
+use core::cell::Cell;
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use spin::Mutex;
+
+use crate::critical::IrqMutex;
+use crate::task_local;
+use crate::timer;
+use crate::workqueue;
+use crate::workqueue::Work;
+
+/// Number of records kept in the log ring buffer.
+const LOG_RING_SIZE: usize = 128;
+
+/// Maximum length of a single log message, longer messages are truncated.
+const LOG_MESSAGE_SIZE: usize = 120;
+
+/// Maximum number of remote log sinks.
+const MAX_SINKS: usize = 4;
+
+/// Maximum number of per-module log level overrides.
+const MAX_MODULE_LEVELS: usize = 16;
+
+/// Maximum number of tracepoints that can be enabled at once.
+const MAX_TRACEPOINTS: usize = 16;
+
+/// Maximum length of a module or tracepoint name.
+const NAME_SIZE: usize = 32;
+
+/// Private enterprise number used for the structured data element.
+const SYSLOG_ENTERPRISE_ID: u32 = 32473;
+
+// Most recent log records, oldest records are overwritten first.
+static RING: IrqMutex<LogRing> = IrqMutex::new(LogRing::new());
+
+// Remote destinations the log ring is shipped to.
+static SINKS: Mutex<[Option<Sink>; MAX_SINKS]> = Mutex::new([None; MAX_SINKS]);
+
+// Lets the logging path check for sinks without taking the lock.
+static HAS_SINKS: AtomicBool = AtomicBool::new(false);
+
+// Ships new records to the sinks outside of the context that logged them.
+static SHIP_WORK: Work = Work::new(ship);
+
+// Most verbose level that is logged for modules without an override.
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+// Per-module log level overrides.
+static MODULE_LEVELS: IrqMutex<[Option<(Name, Level)>; MAX_MODULE_LEVELS]> =
+    IrqMutex::new([None; MAX_MODULE_LEVELS]);
+
+// Names of the enabled tracepoints.
+static TRACEPOINTS: IrqMutex<[Option<Name>; MAX_TRACEPOINTS]> =
+    IrqMutex::new([None; MAX_TRACEPOINTS]);
+
+// How the console prints records.
+static FORMAT: IrqMutex<Format> = IrqMutex::new(Format::DEFAULT);
+
+// Number of threads running with a context, see [`with_context`]. Lets the logging path
+// skip looking it up while there is none, including during boot before any thread.
+static CONTEXTS: AtomicUsize = AtomicUsize::new(0);
+
+task_local! {
+    // Field the records of a thread carry, see [`with_context`].
+    static CONTEXT: Cell<Option<(&'static str, &'static str)>> = Cell::new(None);
+}
+
+// Lets the logging path skip the override and tracepoint tables while they are empty.
+static HAS_MODULE_LEVELS: AtomicBool = AtomicBool::new(false);
+static HAS_TRACEPOINTS: AtomicBool = AtomicBool::new(false);
+
+/// Severity of a log record, ordered from least to most verbose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    Trace = 4,
+}
+
+impl Level {
+    const ALL: [Level; 5] = [
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+
+    /// Parses a level from its lowercase name.
+    pub fn parse(s: &str) -> Option<Level> {
+        Self::ALL.into_iter().find(|x| x.as_str() == s)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    fn from_u8(v: u8) -> Level {
+        Self::ALL[(v as usize).min(Self::ALL.len() - 1)]
+    }
+
+    /// Syslog severity for facility `kern`, which is also the syslog priority.
+    fn syslog_priority(&self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        }
+    }
+}
+
+/// Fixed size copy of a module or tracepoint name given at runtime.
+#[derive(Clone, Copy)]
+struct Name {
+    len: usize,
+    data: [u8; NAME_SIZE],
+}
+
+impl Name {
+    fn new(s: &str) -> Option<Name> {
+        if s.is_empty() || s.len() > NAME_SIZE {
+            return None;
+        }
+
+        let mut data = [0; NAME_SIZE];
+        data[..s.len()].copy_from_slice(s.as_bytes());
+        Some(Name { len: s.len(), data })
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.data[..self.len]).unwrap_or("")
+    }
+}
+
+/// Returns the module name of a source file, e.g. `timer` for `kernel/timer.rs`.
+pub fn module_of(file: &str) -> &str {
+    let name = file.rsplit('/').next().unwrap_or(file);
+    name.strip_suffix(".rs").unwrap_or(name)
+}
+
+/// Returns the level used for modules without an override.
+pub fn level() -> Level {
+    Level::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+/// Changes the level used for modules without an override.
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Overrides the level of a single module, or removes the override if `level` is
+/// `None`. Returns false if the override table is full or the name is too long.
+pub fn set_module_level(module: &str, level: Option<Level>) -> bool {
+    let Some(name) = Name::new(module) else {
+        return false;
+    };
+
+    MODULE_LEVELS.with(|levels| {
+        let existing = levels
+            .iter()
+            .position(|x| x.is_some_and(|(x, _)| x.as_str() == module));
+
+        let updated = match (existing, level) {
+            (Some(i), Some(level)) => {
+                levels[i] = Some((name, level));
+                true
+            }
+            (Some(i), None) => {
+                levels[i] = None;
+                true
+            }
+            (None, Some(level)) => match levels.iter_mut().find(|x| x.is_none()) {
+                Some(slot) => {
+                    *slot = Some((name, level));
+                    true
+                }
+                None => false,
+            },
+            (None, None) => true,
+        };
+
+        HAS_MODULE_LEVELS.store(levels.iter().any(|x| x.is_some()), Ordering::Relaxed);
+        updated
+    })
+}
+
+/// Calls `f` with every module level override.
+pub fn for_each_module_level(mut f: impl FnMut(&str, Level)) {
+    let levels = MODULE_LEVELS.with(|x| *x);
+    for (name, level) in levels.iter().flatten() {
+        f(name.as_str(), *level);
+    }
+}
+
+/// Checks whether a record of the given level emitted from `file` is logged.
+pub fn enabled(file: &str, level: Level) -> bool {
+    if HAS_MODULE_LEVELS.load(Ordering::Relaxed) {
+        let module = module_of(file);
+        let found = MODULE_LEVELS.with(|levels| {
+            levels
+                .iter()
+                .flatten()
+                .find(|(x, _)| x.as_str() == module)
+                .map(|(_, x)| *x)
+        });
+
+        if let Some(max) = found {
+            return level <= max;
+        }
+    }
+
+    level <= self::level()
+}
+
+/// Enables or disables a tracepoint by name. Returns false if too many tracepoints
+/// are enabled or the name is too long.
+pub fn set_tracepoint(tracepoint: &str, enable: bool) -> bool {
+    let Some(name) = Name::new(tracepoint) else {
+        return false;
+    };
+
+    TRACEPOINTS.with(|tracepoints| {
+        let existing = tracepoints
+            .iter()
+            .position(|x| x.is_some_and(|x| x.as_str() == tracepoint));
+
+        let updated = match (existing, enable) {
+            (Some(_), true) | (None, false) => true,
+            (Some(i), false) => {
+                tracepoints[i] = None;
+                true
+            }
+            (None, true) => match tracepoints.iter_mut().find(|x| x.is_none()) {
+                Some(slot) => {
+                    *slot = Some(name);
+                    true
+                }
+                None => false,
+            },
+        };
+
+        HAS_TRACEPOINTS.store(tracepoints.iter().any(|x| x.is_some()), Ordering::Relaxed);
+        updated
+    })
+}
+
+/// Checks whether a tracepoint is enabled.
+pub fn tracepoint_enabled(tracepoint: &str) -> bool {
+    HAS_TRACEPOINTS.load(Ordering::Relaxed)
+        && TRACEPOINTS.with(|tracepoints| {
+            tracepoints
+                .iter()
+                .flatten()
+                .any(|x| x.as_str() == tracepoint)
+        })
+}
+
+/// Calls `f` with the name of every enabled tracepoint.
+pub fn for_each_tracepoint(mut f: impl FnMut(&str)) {
+    let tracepoints = TRACEPOINTS.with(|x| *x);
+    for name in tracepoints.iter().flatten() {
+        f(name.as_str());
+    }
+}
+
+/// Layout of the log lines printed on the console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    /// Columns for people reading the console.
+    Text,
+    /// One JSON object per line, for tools reading the serial port.
+    Json,
+}
+
+impl Style {
+    const ALL: [Style; 2] = [Style::Text, Style::Json];
+
+    pub fn parse(s: &str) -> Option<Style> {
+        Self::ALL.into_iter().find(|x| x.as_str() == s)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Style::Text => "text",
+            Style::Json => "json",
+        }
+    }
+}
+
+/// How the time since boot is printed in log lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timestamp {
+    None,
+    /// Seconds with six decimals.
+    Seconds,
+    /// Whole milliseconds.
+    Millis,
+}
+
+impl Timestamp {
+    const ALL: [Timestamp; 3] = [Timestamp::None, Timestamp::Seconds, Timestamp::Millis];
+
+    pub fn parse(s: &str) -> Option<Timestamp> {
+        Self::ALL.into_iter().find(|x| x.as_str() == s)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Timestamp::None => "none",
+            Timestamp::Seconds => "seconds",
+            Timestamp::Millis => "millis",
+        }
+    }
+}
+
+/// How the console prints log records, see [`set_format`].
+///
+/// Only the console is affected, the log ring and the sinks keep their own formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    pub style: Style,
+    /// Colors text lines with ANSI escapes. Without color, escapes in the messages
+    /// themselves are dropped too, for consumers that cannot handle them.
+    pub color: bool,
+    pub timestamp: Timestamp,
+    /// Prints the source file and line of text lines. JSON lines always have them.
+    pub location: bool,
+}
+
+impl Format {
+    pub const DEFAULT: Format = Format {
+        style: Style::Text,
+        color: true,
+        timestamp: Timestamp::Seconds,
+        location: true,
+    };
+
+    /// Applies a kernel command line argument. Returns false if the argument is meant
+    /// for the log format but malformed, leaving the format unchanged.
+    ///
+    /// `log.style=<text|json>`, `log.color=<on|off>`, `log.timestamp=<none|seconds|millis>`
+    /// and `log.location=<on|off>` set the field of the same name, e.g. `log.style=json`
+    /// for tools that read the serial port from the host.
+    pub fn parse_arg(&mut self, arg: &str) -> bool {
+        let Some((key, value)) = arg.strip_prefix("log.").and_then(|x| x.split_once('=')) else {
+            return true;
+        };
+
+        let on_off = |x| match x {
+            "on" => Some(true),
+            "off" => Some(false),
+            _ => None,
+        };
+
+        match key {
+            "style" => Style::parse(value).map(|x| self.style = x),
+            "color" => on_off(value).map(|x| self.color = x),
+            "timestamp" => Timestamp::parse(value).map(|x| self.timestamp = x),
+            "location" => on_off(value).map(|x| self.location = x),
+            // Where the log is shipped to rather than how it looks, see syslog::init().
+            "syslog" => Some(()),
+            _ => None,
+        }
+        .is_some()
+    }
+}
+
+/// A named value attached to a log record, given as `log!(key = value; "message")`.
+///
+/// JSON lines have them as strings in the `fields` object, text lines and the log ring
+/// as ` key=value` after the message.
+#[derive(Clone, Copy)]
+pub struct Field<'a> {
+    pub key: &'static str,
+    pub value: &'a dyn fmt::Display,
+}
+
+/// Formats fields the way text lines end, see [`Field`].
+pub struct Fields<'a>(pub &'a [Field<'a>]);
+
+impl fmt::Display for Fields<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0
+            .iter()
+            .try_for_each(|x| write!(f, " {}={}", x.key, x.value))
+    }
+}
+
+/// Runs `f` with every record the current thread logs carrying the field `key=value`,
+/// e.g. the shell command being run, so that records logged deep down on its behalf
+/// can be told apart. The innermost context wins. Outside of a kernel thread `f` runs
+/// without one.
+pub fn with_context<R>(key: &'static str, value: &'static str, f: impl FnOnce() -> R) -> R {
+    let Ok(previous) = CONTEXT.try_with(|x| x.replace(Some((key, value)))) else {
+        return f();
+    };
+
+    CONTEXTS.fetch_add(1, Ordering::Relaxed);
+    let result = f();
+    CONTEXTS.fetch_sub(1, Ordering::Relaxed);
+
+    CONTEXT.with(|x| x.set(previous));
+    result
+}
+
+/// Returns the key and value of the field the records of the current thread carry,
+/// see [`with_context`].
+pub fn context() -> Option<(&'static str, &'static str)> {
+    if CONTEXTS.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+
+    CONTEXT.try_with_existing(|x| x.get()).flatten()
+}
+
+/// Returns how the console prints log records.
+pub fn format() -> Format {
+    FORMAT.with(|x| *x)
+}
+
+/// Changes how the console prints log records.
+pub fn set_format(format: Format) {
+    FORMAT.with(|x| *x = format);
+}
+
+// Drops ANSI escape sequences, like the color of `[ OK ]`, from what is written through.
+struct StripAnsi<'a> {
+    out: &'a mut dyn Write,
+    // 0 outside of an escape sequence, 1 right after ESC, 2 inside a control sequence.
+    escape: u8,
+}
+
+impl Write for StripAnsi<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut start = 0;
+
+        for (i, ch) in s.char_indices() {
+            match (self.escape, ch) {
+                (0, '\x1b') => {
+                    self.out.write_str(&s[start..i])?;
+                    self.escape = 1;
+                }
+                (0, _) => continue,
+                // Control sequences end with a byte from @ to ~, others after one byte.
+                (1, '[') => self.escape = 2,
+                (1, _) | (_, '@'..='~') => self.escape = 0,
+                _ => {}
+            }
+
+            start = i + ch.len_utf8();
+        }
+
+        match self.escape {
+            0 => self.out.write_str(&s[start..]),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Escapes what is written through for use inside a JSON string.
+struct JsonEscape<'a> {
+    out: &'a mut dyn Write,
+}
+
+impl Write for JsonEscape<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut start = 0;
+
+        for (i, ch) in s.char_indices() {
+            if ch != '"' && ch != '\\' && !ch.is_control() {
+                continue;
+            }
+
+            self.out.write_str(&s[start..i])?;
+            match ch {
+                '"' | '\\' => write!(self.out, "\\{ch}")?,
+                '\n' => self.out.write_str("\\n")?,
+                '\r' => self.out.write_str("\\r")?,
+                '\t' => self.out.write_str("\\t")?,
+                _ => write!(self.out, "\\u{:04x}", ch as u32)?,
+            }
+            start = i + ch.len_utf8();
+        }
+
+        self.out.write_str(&s[start..])
+    }
+}
+
+// Writes the inside of a JSON string, without the escape sequences meant for terminals.
+fn write_json_string(out: &mut dyn Write, args: fmt::Arguments) -> fmt::Result {
+    let mut escaped = JsonEscape { out };
+    StripAnsi {
+        out: &mut escaped,
+        escape: 0,
+    }
+    .write_fmt(args)
+}
+
+/// A log record on its way to the console.
+#[derive(Clone, Copy)]
+pub struct Line<'a> {
+    pub file: &'a str,
+    pub line: u32,
+    pub level: Level,
+    /// Time since boot when the record was logged.
+    pub uptime: Duration,
+    pub args: fmt::Arguments<'a>,
+    pub fields: &'a [Field<'a>],
+}
+
+/// Writes a log record as one line in `format`, including the newline.
+///
+/// This is what the console prints for every record, see [`crate::console::log`].
+pub fn write_line(out: &mut dyn Write, format: &Format, line: &Line) -> fmt::Result {
+    const ANSI_FOREGROUND_RED: &str = "\x1b[31m";
+    const ANSI_FOREGROUND_YELLOW: &str = "\x1b[33m";
+    const ANSI_CLEAR: &str = "\x1b[0m";
+    const ANSI_FOREGROUND_CYAN: &str = "\x1b[36m";
+
+    let Line {
+        file,
+        line,
+        level,
+        uptime,
+        args,
+        fields,
+    } = *line;
+
+    if format.style == Style::Json {
+        out.write_str("{")?;
+        match format.timestamp {
+            Timestamp::None => {}
+            Timestamp::Seconds => write!(out, "\"ts\":{:.6},", uptime.as_secs_f64())?,
+            Timestamp::Millis => write!(out, "\"ts\":{},", uptime.as_millis())?,
+        }
+        write!(
+            out,
+            "\"level\":\"{}\",\"module\":\"{}\",\"file\":\"{file}\",\"line\":{line},\"msg\":\"",
+            level.as_str(),
+            module_of(file)
+        )?;
+
+        write_json_string(out, args)?;
+        out.write_str("\",\"fields\":{")?;
+
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                out.write_str(",")?;
+            }
+
+            out.write_str("\"")?;
+            write_json_string(out, format_args!("{}", field.key))?;
+            out.write_str("\":\"")?;
+            write_json_string(out, format_args!("{}", field.value))?;
+            out.write_str("\"")?;
+        }
+
+        return writeln!(out, "}}}}");
+    }
+
+    let (yellow, cyan, red, clear) = if format.color {
+        (
+            ANSI_FOREGROUND_YELLOW,
+            ANSI_FOREGROUND_CYAN,
+            ANSI_FOREGROUND_RED,
+            ANSI_CLEAR,
+        )
+    } else {
+        ("", "", "", "")
+    };
+
+    match format.timestamp {
+        Timestamp::None => {}
+        Timestamp::Seconds => write!(out, "{yellow}[{: >13.6}]{clear} ", uptime.as_secs_f64())?,
+        Timestamp::Millis => write!(out, "{yellow}[{: >10}]{clear} ", uptime.as_millis())?,
+    }
+
+    if format.location {
+        write!(out, "{cyan}{file: <20} | line {line: <5} | {clear}")?;
+    }
+
+    let fields = Fields(fields);
+    match (format.color, level) {
+        (true, Level::Error | Level::Warn) => writeln!(out, " {red}{args}{fields}{clear}"),
+        (true, _) => writeln!(out, " {args}{fields}"),
+        (false, _) => {
+            out.write_str(" ")?;
+            write!(StripAnsi { out, escape: 0 }, "{args}{fields}")?;
+            writeln!(out)
+        }
+    }
+}
+
+/// A single log record.
+#[derive(Clone, Copy)]
+pub struct Record {
+    /// Sequence number, starting at zero for the first record since boot.
+    pub seq: u64,
+    /// Severity of the record.
+    pub level: Level,
+    /// Seconds since boot.
+    pub timestamp: f64,
+    /// Source file that emitted the record.
+    pub file: &'static str,
+    /// Source line that emitted the record.
+    pub line: u32,
+    len: usize,
+    message: [u8; LOG_MESSAGE_SIZE],
+}
+
+impl Record {
+    const fn empty() -> Self {
+        Self {
+            seq: 0,
+            level: Level::Info,
+            timestamp: 0.0,
+            file: "",
+            line: 0,
+            len: 0,
+            message: [0; LOG_MESSAGE_SIZE],
+        }
+    }
+
+    /// The (possibly truncated) message of the record.
+    pub fn message(&self) -> &str {
+        core::str::from_utf8(&self.message[..self.len]).unwrap_or("<invalid utf-8>")
+    }
+}
+
+struct LogRing {
+    records: [Record; LOG_RING_SIZE],
+    next_seq: u64,
+}
+
+impl LogRing {
+    const fn new() -> Self {
+        Self {
+            records: [Record::empty(); LOG_RING_SIZE],
+            next_seq: 0,
+        }
+    }
+
+    fn push(&mut self, mut record: Record) {
+        record.seq = self.next_seq;
+        self.records[(self.next_seq as usize) % LOG_RING_SIZE] = record;
+        self.next_seq += 1;
+    }
+
+    /// Gets the oldest record with a sequence number of at least `seq`.
+    fn get(&self, seq: u64) -> Option<Record> {
+        let oldest = self.next_seq.saturating_sub(LOG_RING_SIZE as u64);
+        let seq = seq.max(oldest);
+
+        if seq < self.next_seq {
+            Some(self.records[(seq as usize) % LOG_RING_SIZE])
+        } else {
+            None
+        }
+    }
+}
+
+/// Formatter that writes into a fixed buffer and silently truncates.
+pub struct FixedBuf<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> FixedBuf<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Write for FixedBuf<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut n = s.len().min(self.buf.len() - self.len);
+
+        // Never cut a multi-byte character in half.
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Destination for log records shipped off the machine, e.g. a UDP or TCP socket.
+pub trait LogTransport: Sync {
+    /// Sends one framed record. Returns false if the transport is not ready yet, in
+    /// which case the record is retried on the next flush.
+    fn send(&self, data: &[u8]) -> bool;
+}
+
+/// How records are framed on a transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// One RFC 5424 message per datagram.
+    SyslogUdp,
+    /// RFC 5424 messages with RFC 6587 octet counting, for stream transports.
+    SyslogTcp,
+}
+
+#[derive(Clone, Copy)]
+struct Sink {
+    transport: &'static dyn LogTransport,
+    framing: Framing,
+    next_seq: u64,
+}
+
+/// Records a log message in the ring buffer and schedules shipping to the sinks.
+///
+/// This is called by the [`crate::log`] macro.
+pub fn record(file: &'static str, line: u32, level: Level, args: fmt::Arguments) {
+    let mut record = Record::empty();
+    record.level = level;
+    record.timestamp = timer::uptime().as_secs_f64();
+    record.file = file;
+    record.line = line;
+
+    let mut buf = FixedBuf::new(&mut record.message);
+    let _ = buf.write_fmt(args);
+    record.len = buf.len();
+
+    RING.with(|ring| ring.push(record));
+
+    if HAS_SINKS.load(Ordering::Acquire) {
+        workqueue::schedule(&SHIP_WORK);
+    }
+}
+
+/// Calls `f` with every record still held in the ring buffer, oldest first.
+pub fn for_each(mut f: impl FnMut(&Record)) {
+    let mut seq = 0;
+
+    while let Some(record) = RING.with(|ring| ring.get(seq)) {
+        f(&record);
+        seq = record.seq + 1;
+    }
+}
+
+/// Registers a remote sink. Records logged before the sink was registered, as far
+/// as they are still in the ring buffer, are shipped as well.
+pub fn add_sink(transport: &'static dyn LogTransport, framing: Framing) -> bool {
+    let added = {
+        let mut sinks = SINKS.lock();
+        match sinks.iter_mut().find(|x| x.is_none()) {
+            Some(slot) => {
+                *slot = Some(Sink {
+                    transport,
+                    framing,
+                    next_seq: 0,
+                });
+                true
+            }
+            None => false,
+        }
+    };
+
+    if added {
+        HAS_SINKS.store(true, Ordering::Release);
+        workqueue::schedule(&SHIP_WORK);
+    }
+
+    added
+}
+
+/// Formats a record as an RFC 5424 syslog message.
+fn format_syslog(out: &mut FixedBuf, record: &Record) -> fmt::Result {
+    // There is no wall clock, so the timestamp is nil and uptime goes into the
+    // structured data instead.
+    write!(
+        out,
+        "<{}>1 - lithium kernel - - [meta@{SYSLOG_ENTERPRISE_ID} seq=\"{}\" uptime=\"{:.6}\" file=\"{}\" line=\"{}\"] {}",
+        record.level.syslog_priority(),
+        record.seq,
+        record.timestamp,
+        record.file,
+        record.line,
+        record.message()
+    )
+}
+
+/// Sends all new records to every sink.
+fn ship() {
+    let mut message = [0u8; 384];
+    let mut frame = [0u8; 400];
+
+    for i in 0..MAX_SINKS {
+        let Some(mut sink) = SINKS.lock()[i] else {
+            continue;
+        };
+
+        while let Some(record) = RING.with(|ring| ring.get(sink.next_seq)) {
+            let mut out = FixedBuf::new(&mut message);
+            let _ = format_syslog(&mut out, &record);
+
+            let sent = match sink.framing {
+                Framing::SyslogUdp => sink.transport.send(out.as_bytes()),
+                Framing::SyslogTcp => {
+                    let mut framed = FixedBuf::new(&mut frame);
+                    let _ = write!(framed, "{} ", out.len());
+                    let n = framed.len();
+                    framed.buf[n..n + out.len()].copy_from_slice(out.as_bytes());
+                    framed.len += out.len();
+                    sink.transport.send(framed.as_bytes())
+                }
+            };
+
+            if !sent {
+                break;
+            }
+
+            sink.next_seq = record.seq + 1;
+        }
+
+        if let Some(slot) = SINKS.lock()[i].as_mut() {
+            slot.next_seq = sink.next_seq;
+        }
+    }
+}
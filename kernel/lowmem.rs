@@ -0,0 +1,132 @@
+use crate::bitmap::BlockBitmap;
+use crate::initcall::InitGuard;
+use crate::log;
+use crate::memory;
+use core::ptr::addr_of_mut;
+use spin::Mutex;
+use x86_64::structures::paging::{PageSize, Size4KiB};
+use x86_64::PhysAddr;
+
+/// End of the memory real mode code can reach, and below which a startup IPI can start
+/// a processor.
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// Number of pages taken from conventional memory for the pool.
+const POOL_PAGES: usize = 16;
+
+const PAGE_SIZE: usize = Size4KiB::SIZE as usize;
+
+// Allocation bits of the pool, one per page.
+static mut BITS: [u8; BlockBitmap::bytes_for(POOL_PAGES)] = [0; BlockBitmap::bytes_for(POOL_PAGES)];
+
+// The pool, or None if there was no conventional memory to take it from.
+static POOL: Mutex<Option<Pool>> = Mutex::new(None);
+
+// Initialization of the pool, which needs the memory below the kernel reclaimed.
+pub static INIT: InitGuard = InitGuard::new("lowmem");
+
+// Pages below LOW_MEMORY_END, set aside so the frame allocator cannot hand all of them
+// out to the heap before real mode code needs one.
+struct Pool {
+    start: PhysAddr,
+    bitmap: BlockBitmap<'static>,
+}
+
+/// Pages below 1 MiB for code and data used in real mode, e.g. the startup code of an
+/// application processor or buffers for BIOS calls. Given back when dropped.
+#[derive(Debug)]
+pub struct LowRegion {
+    start: PhysAddr,
+    pages: usize,
+}
+
+impl LowRegion {
+    pub fn start_address(&self) -> PhysAddr {
+        self.start
+    }
+
+    pub fn size(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+
+    /// Gets the real mode segment whose offset zero is the start of the region.
+    pub fn segment(&self) -> u16 {
+        (self.start.as_u64() >> 4) as u16
+    }
+
+    /// Gets the region through the direct map.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        let va = memory::phys_to_virt(self.start);
+        unsafe { core::slice::from_raw_parts_mut(va.as_mut_ptr(), self.size()) }
+    }
+}
+
+impl Drop for LowRegion {
+    fn drop(&mut self) {
+        let mut pool = POOL.lock();
+        let pool = pool
+            .as_mut()
+            .expect("lowmem::drop(): region without a pool");
+        let index = ((self.start - pool.start) as usize) / PAGE_SIZE;
+
+        assert!(
+            pool.bitmap.deallocate(index, self.pages),
+            "lowmem::drop(): region at {:#x} is not part of the pool",
+            self.start.as_u64()
+        );
+    }
+}
+
+/// Allocates at least `size` bytes of zeroed, page aligned memory below 1 MiB. Returns
+/// `None` if the pool has no run of pages that large left.
+pub fn allocate(size: usize) -> Option<LowRegion> {
+    let pages = size.div_ceil(PAGE_SIZE).max(1);
+
+    let start = {
+        let mut pool = POOL.lock();
+        let pool = pool.as_mut()?;
+        let index = pool.bitmap.allocate(pages)?;
+        pool.start + (index * PAGE_SIZE) as u64
+    };
+
+    let mut region = LowRegion { start, pages };
+    region.as_mut_slice().fill(0);
+    Some(region)
+}
+
+/// Gets the number of bytes left in the pool.
+pub fn bytes_free() -> usize {
+    POOL.lock()
+        .as_ref()
+        .map_or(0, |x| x.bitmap.free() * PAGE_SIZE)
+}
+
+/// Takes the pool out of the conventional memory that memory::init() handed to the frame
+/// allocator.
+pub fn init() {
+    let _init = INIT.start();
+    memory::INIT.require("lowmem");
+
+    let size = POOL_PAGES * PAGE_SIZE;
+    let region =
+        memory::frame_allocator().allocate_aligned(size, PAGE_SIZE, PhysAddr::new(LOW_MEMORY_END));
+
+    let Some(region) = region else {
+        log!("lowmem::init(): no {size} bytes of conventional memory, real mode code cannot run");
+        return;
+    };
+
+    let bits = unsafe { &mut *addr_of_mut!(BITS) };
+    let bitmap = BlockBitmap::new(bits, POOL_PAGES, 0).expect("lowmem::init(): bitmap too small");
+
+    *POOL.lock() = Some(Pool {
+        start: region.start_address(),
+        bitmap,
+    });
+
+    log!(
+        "lowmem::init(): pool at [{:#016x}-{:#016x}] [ \x1b[0;32mOK\x1b[0m ]",
+        region.start_address().as_u64(),
+        region.end_address().as_u64()
+    );
+}
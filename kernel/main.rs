@@ -0,0 +1,7 @@
+//! The kernel executable. The boot code in entry.S calls `kernel_main` of the library,
+//! build.rs links both together with kernel/kernel.ld.
+
+#![no_std]
+#![no_main]
+
+use lithium as _;
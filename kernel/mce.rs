@@ -0,0 +1,228 @@
+use core::fmt;
+
+use raw_cpuid::native_cpuid::CpuIdReaderNative;
+use raw_cpuid::CpuId;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+
+use crate::emergency_print;
+use crate::log;
+use crate::trap::TrapFrame;
+
+/// Number of banks in bits 7:0 and whether `IA32_MCG_CTL` exists in bit 8.
+const IA32_MCG_CAP: u32 = 0x179;
+/// Global machine check status.
+const IA32_MCG_STATUS: u32 = 0x17A;
+/// Enables machine check reporting of all banks at once.
+const IA32_MCG_CTL: u32 = 0x17B;
+/// `IA32_MC0_CTL`, every bank has four registers: control, status, address and misc.
+const IA32_MC0_CTL: u32 = 0x400;
+
+const MCG_CAP_COUNT: u64 = 0xFF;
+const MCG_CAP_CTL_P: u64 = 1 << 8;
+
+// Bits of `IA32_MCG_STATUS`.
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+const MCG_STATUS_MCIP: u64 = 1 << 2;
+
+// Bits of `IA32_MCi_STATUS`.
+const MCI_STATUS_VAL: u64 = 1 << 63;
+const MCI_STATUS_OVER: u64 = 1 << 62;
+const MCI_STATUS_UC: u64 = 1 << 61;
+const MCI_STATUS_EN: u64 = 1 << 60;
+const MCI_STATUS_MISCV: u64 = 1 << 59;
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+const MCI_STATUS_PCC: u64 = 1 << 57;
+
+fn bank_register(bank: u8, register: u32) -> Msr {
+    Msr::new(IA32_MC0_CTL + 4 * bank as u32 + register)
+}
+
+/// Error logged in one of the machine check banks.
+#[derive(Debug, Clone, Copy)]
+struct BankError {
+    bank: u8,
+    status: u64,
+    address: Option<u64>,
+    misc: Option<u64>,
+}
+
+impl BankError {
+    /// Reads the error logged in `bank`, if any.
+    fn read(bank: u8) -> Option<Self> {
+        let status = unsafe { bank_register(bank, 1).read() };
+
+        if status & MCI_STATUS_VAL == 0 {
+            return None;
+        }
+
+        let address =
+            (status & MCI_STATUS_ADDRV != 0).then(|| unsafe { bank_register(bank, 2).read() });
+        let misc =
+            (status & MCI_STATUS_MISCV != 0).then(|| unsafe { bank_register(bank, 3).read() });
+
+        Some(Self {
+            bank,
+            status,
+            address,
+            misc,
+        })
+    }
+
+    /// Returns a rough class of the architectural MCA error code in bits 15:0, see the
+    /// Intel SDM 16.9 "Interpreting the MCA Error Codes".
+    fn class(&self) -> &'static str {
+        match self.status as u16 {
+            0x0000 => "no error",
+            0x0001 => "unclassified",
+            0x0002 => "microcode rom parity error",
+            0x0003 => "external error",
+            0x0004 => "frc error",
+            0x0005 => "internal parity error",
+            0x0006 => "smm handler code access violation",
+            0x0400 => "internal timer error",
+            0x0E0B => "i/o error",
+            0x0401..=0x07FF => "internal unclassified error",
+            // Compound codes, bit 12 only tells whether the error was filtered.
+            x if x & 0xEFF0 == 0x0010 => "tlb error",
+            x if x & 0xEF80 == 0x0080 => "memory controller error",
+            x if x & 0xEF00 == 0x0100 => "cache error",
+            x if x & 0xE800 == 0x0800 => "bus or interconnect error",
+            _ => "model specific error",
+        }
+    }
+
+    fn clear(&self) {
+        unsafe { bank_register(self.bank, 1).write(0) };
+    }
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bank {}: {} (status {:#018x}, code {:#06x}, model code {:#06x})",
+            self.bank,
+            self.class(),
+            self.status,
+            self.status as u16,
+            (self.status >> 16) as u16
+        )?;
+
+        let flags = [
+            (MCI_STATUS_UC, "uncorrected"),
+            (MCI_STATUS_PCC, "context corrupt"),
+            (MCI_STATUS_OVER, "overflow"),
+            (MCI_STATUS_EN, "enabled"),
+        ];
+
+        for (bit, name) in flags {
+            if self.status & bit != 0 {
+                write!(f, ", {name}")?;
+            }
+        }
+
+        if let Some(address) = self.address {
+            write!(f, ", address {address:#018x}")?;
+        }
+
+        if let Some(misc) = self.misc {
+            write!(f, ", misc {misc:#018x}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the number of machine check banks.
+fn bank_count() -> u8 {
+    (unsafe { Msr::new(IA32_MCG_CAP).read() } & MCG_CAP_COUNT) as u8
+}
+
+/// Reports a machine check exception and panics, which carries out the panic action.
+///
+/// The processor raises one when hardware detected an error it could not correct,
+/// e.g. an uncorrectable ECC error in memory or a cache. Every bank with a valid error
+/// is decoded and printed. Nothing here takes a lock, the exception may have
+/// interrupted any lock holder, and a machine check that arrives while `MCIP` is set
+/// shuts the processor down, so `MCIP` is cleared before panicking.
+pub fn handle(frame: &TrapFrame) -> ! {
+    let mut global = Msr::new(IA32_MCG_STATUS);
+    let status = unsafe { global.read() };
+
+    let restartable = match status & MCG_STATUS_RIPV {
+        0 => ", cannot restart",
+        _ => "",
+    };
+    let precise = match status & MCG_STATUS_EIPV {
+        0 => "",
+        _ => ", at the faulting instruction",
+    };
+
+    emergency_print!(
+        "mce::handle(): machine check at {:#016x}, mcg status {status:#x}{restartable}{precise}\n",
+        frame.rip
+    );
+
+    for bank in 0..bank_count() {
+        if let Some(error) = BankError::read(bank) {
+            emergency_print!("mce::handle(): {error}\n");
+            error.clear();
+        }
+    }
+
+    emergency_print!("{frame}");
+
+    unsafe { global.write(status & !MCG_STATUS_MCIP) };
+
+    panic!("mce::handle(): machine check at {:#016x}", frame.rip);
+}
+
+/// Initializes machine check reporting.
+///
+/// Without `CR4.MCE` a machine check shuts the processor down, which looks like a
+/// triple fault and leaves nothing to debug. This enables reporting in every bank so
+/// errors raise an exception handled by [`handle`]. The banks keep their contents
+/// across a warm reset, so errors that brought the machine down before are logged and
+/// cleared first.
+pub fn init() {
+    let cpuid: CpuId<CpuIdReaderNative> = CpuId::new();
+    let features = cpuid.get_feature_info();
+
+    if !features.as_ref().is_some_and(|x| x.has_mce()) {
+        log!("mce::init(): machine checks not supported");
+        return;
+    }
+
+    if !features.is_some_and(|x| x.has_mca()) {
+        unsafe { Cr4::update(|x| x.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION)) };
+        log!("mce::init(): machine checks enabled without banks [ \x1b[0;32mOK\x1b[0m ]");
+        return;
+    }
+
+    let banks = bank_count();
+
+    for bank in 0..banks {
+        if let Some(error) = BankError::read(bank) {
+            log!("mce::init(): logged before boot: {error}");
+            error.clear();
+        }
+    }
+
+    unsafe {
+        if Msr::new(IA32_MCG_CAP).read() & MCG_CAP_CTL_P != 0 {
+            Msr::new(IA32_MCG_CTL).write(u64::MAX);
+        }
+
+        // Bank 0 is left as firmware configured it, Intel recommends against touching
+        // its control register on older processors.
+        for bank in 1..banks {
+            bank_register(bank, 0).write(u64::MAX);
+        }
+
+        Cr4::update(|x| x.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION));
+    }
+
+    log!("mce::init(): {banks} banks enabled [ \x1b[0;32mOK\x1b[0m ]");
+}
@@ -0,0 +1,430 @@
+use alloc::format;
+use alloc::string::String;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use spin::Mutex;
+
+use crate::log;
+use crate::multiboot;
+
+/// UDP port mDNS queries and responses are sent to.
+pub const MDNS_PORT: u16 = 5353;
+
+/// IPv4 multicast group of mDNS.
+pub const MDNS_GROUP: [u8; 4] = [224, 0, 0, 251];
+
+/// Name used unless `hostname=` is given on the kernel command line.
+const DEFAULT_HOSTNAME: &str = "lithium";
+
+/// How long other hosts may cache our records, in seconds.
+const TTL: u32 = 120;
+
+/// How long plain DNS resolvers may cache our records, see RFC 6762 6.7.
+const LEGACY_TTL: u32 = 10;
+
+/// Size of the fixed header of a DNS message.
+const HEADER_SIZE: usize = 12;
+
+/// Most labels followed while reading a name, so compression loops end.
+const MAX_LABELS: usize = 128;
+
+/// Flags of a response: authoritative answer.
+const FLAGS_RESPONSE: u16 = 0x8400;
+
+/// Bit of the flags set in responses.
+const FLAG_QR: u16 = 0x8000;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+
+/// Bit of the class of a record telling caches to replace what they have, set on
+/// records only we answer for.
+const CLASS_CACHE_FLUSH: u16 = 0x8000;
+
+/// Bit of the class of a question asking for a unicast response.
+const CLASS_UNICAST_RESPONSE: u16 = 0x8000;
+
+/// Service type of the HTTP service, see RFC 6763.
+const HTTP_SERVICE: &str = "_http._tcp.local";
+
+/// Name DNS-SD browsers query to list the service types on the link.
+const SERVICES: &str = "_services._dns-sd._udp.local";
+
+// Host name advertised as `<hostname>.local`.
+static HOSTNAME: Mutex<&'static str> = Mutex::new(DEFAULT_HOSTNAME);
+
+// IPv4 address of the interface, none until the network is configured.
+static ADDRESS: Mutex<Option<[u8; 4]>> = Mutex::new(None);
+
+// Port of the advertised HTTP service, 0 if there is none.
+static HTTP_PORT: AtomicU16 = AtomicU16::new(0);
+
+/// Sets the address `<hostname>.local` resolves to, `None` to stop answering.
+pub fn set_address(address: Option<[u8; 4]>) {
+    *ADDRESS.lock() = address;
+}
+
+/// Advertises an HTTP service on `port`, or stops advertising it with `None`.
+#[cfg(feature = "net-smoltcp")]
+pub fn advertise_http(port: Option<u16>) {
+    HTTP_PORT.store(port.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Returns the host name advertised as `<hostname>.local`.
+pub fn hostname() -> &'static str {
+    *HOSTNAME.lock()
+}
+
+/// Writes a DNS message into a buffer, failing once it is full.
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    // Whether the message goes to a plain DNS resolver, which gets records with a short
+    // TTL and without the cache flush bit.
+    legacy: bool,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, data: &[u8]) -> Option<()> {
+        let end = self.len.checked_add(data.len())?;
+        self.buf.get_mut(self.len..end)?.copy_from_slice(data);
+        self.len = end;
+        Some(())
+    }
+
+    fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    /// Writes a dotted name as labels, without compression.
+    fn name(&mut self, name: &str) -> Option<()> {
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return None;
+            }
+
+            self.bytes(&[label.len() as u8])?;
+            self.bytes(label.as_bytes())?;
+        }
+
+        self.bytes(&[0])
+    }
+
+    /// Writes a record, with `data` writing its data.
+    fn record(
+        &mut self,
+        name: &str,
+        ty: u16,
+        flush: bool,
+        data: impl FnOnce(&mut Self) -> Option<()>,
+    ) -> Option<()> {
+        self.name(name)?;
+        self.u16(ty)?;
+        self.u16(if flush && !self.legacy {
+            CLASS_IN | CLASS_CACHE_FLUSH
+        } else {
+            CLASS_IN
+        })?;
+        self.u32(if self.legacy { LEGACY_TTL } else { TTL })?;
+
+        // The data length is only known once the data is written.
+        let length_at = self.len;
+        self.u16(0)?;
+        data(self)?;
+
+        let length = (self.len - length_at - 2) as u16;
+        self.buf[length_at..length_at + 2].copy_from_slice(&length.to_be_bytes());
+        Some(())
+    }
+}
+
+/// Reads the name at `pos` of `msg` as a lowercase dotted name, following compression
+/// pointers. Returns it and the position after it.
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+
+    for _ in 0..MAX_LABELS {
+        let len = *msg.get(pos)? as usize;
+
+        match len {
+            0 => return Some((name, end.unwrap_or(pos + 1))),
+            x if x & 0xC0 == 0xC0 => {
+                let pointer = (x & 0x3F) << 8 | *msg.get(pos + 1)? as usize;
+                end.get_or_insert(pos + 2);
+                pos = pointer;
+            }
+            x if x < 64 => {
+                let label = core::str::from_utf8(msg.get(pos + 1..pos + 1 + x)?).ok()?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&label.to_ascii_lowercase());
+                pos += 1 + x;
+            }
+            _ => return None,
+        }
+    }
+
+    None
+}
+
+/// The records we answer for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Record {
+    /// `<hostname>.local` to our address.
+    Address,
+    /// Service type list to the HTTP service type.
+    ServiceType,
+    /// HTTP service type to our instance of it.
+    ServicePointer,
+    /// Host and port of our HTTP service.
+    Service,
+    /// Empty properties of our HTTP service.
+    ServiceText,
+}
+
+impl Record {
+    const ALL: [Record; 5] = [
+        Record::Address,
+        Record::ServiceType,
+        Record::ServicePointer,
+        Record::Service,
+        Record::ServiceText,
+    ];
+
+    fn ty(self) -> u16 {
+        match self {
+            Record::Address => TYPE_A,
+            Record::ServiceType | Record::ServicePointer => TYPE_PTR,
+            Record::Service => TYPE_SRV,
+            Record::ServiceText => TYPE_TXT,
+        }
+    }
+}
+
+/// Snapshot of what we advertise, taken once per message.
+struct Names {
+    host: String,
+    instance: String,
+    address: Option<[u8; 4]>,
+    http_port: u16,
+}
+
+impl Names {
+    fn current() -> Self {
+        let hostname = hostname().to_ascii_lowercase();
+
+        Self {
+            host: format!("{hostname}.local"),
+            instance: format!("{hostname}.{HTTP_SERVICE}"),
+            address: *ADDRESS.lock(),
+            http_port: HTTP_PORT.load(Ordering::Relaxed),
+        }
+    }
+
+    fn name(&self, record: Record) -> &str {
+        match record {
+            Record::Address => &self.host,
+            Record::ServiceType => SERVICES,
+            Record::ServicePointer => HTTP_SERVICE,
+            Record::Service | Record::ServiceText => &self.instance,
+        }
+    }
+
+    fn is_available(&self, record: Record) -> bool {
+        match record {
+            Record::Address => self.address.is_some(),
+            _ => self.http_port != 0,
+        }
+    }
+
+    /// Writes a record, shared ones without the cache flush bit.
+    fn write(&self, out: &mut Writer, record: Record) -> Option<()> {
+        let name = self.name(record);
+
+        match record {
+            Record::Address => {
+                let address = self.address?;
+                out.record(name, TYPE_A, true, |x| x.bytes(&address))
+            }
+            Record::ServiceType => out.record(name, TYPE_PTR, false, |x| x.name(HTTP_SERVICE)),
+            Record::ServicePointer => out.record(name, TYPE_PTR, false, |x| x.name(&self.instance)),
+            Record::Service => out.record(name, TYPE_SRV, true, |x| {
+                // Priority, weight, port and target.
+                x.u16(0)?;
+                x.u16(0)?;
+                x.u16(self.http_port)?;
+                x.name(&self.host)
+            }),
+            // A TXT record must hold at least one string, an empty one means no
+            // properties.
+            Record::ServiceText => out.record(name, TYPE_TXT, true, |x| x.bytes(&[0])),
+        }
+    }
+}
+
+/// Query from a plain DNS resolver, which sent it from a port other than [`MDNS_PORT`]
+/// and expects a response like a DNS server's, see RFC 6762 6.7.
+struct LegacyQuery<'a> {
+    id: u16,
+    count: u16,
+    // The question section as received, which starts right after the header like it
+    // does in the response, so compressed names in it still point to the same labels.
+    questions: &'a [u8],
+}
+
+/// Writes a response with the given records into `out`, returning its length.
+///
+/// mDNS responses have an ID of zero and no questions, those to a plain DNS resolver
+/// repeat the ID and the questions of `legacy`.
+fn write_response(
+    names: &Names,
+    records: &[Record],
+    legacy: Option<&LegacyQuery>,
+    out: &mut [u8],
+) -> Option<usize> {
+    let mut writer = Writer {
+        buf: out,
+        len: 0,
+        legacy: legacy.is_some(),
+    };
+
+    writer.u16(legacy.map_or(0, |x| x.id))?;
+    writer.u16(FLAGS_RESPONSE)?;
+    writer.u16(legacy.map_or(0, |x| x.count))?;
+    writer.u16(records.len() as u16)?;
+    writer.u16(0)?;
+    writer.u16(0)?;
+
+    if let Some(query) = legacy {
+        writer.bytes(query.questions)?;
+    }
+
+    for &record in records {
+        names.write(&mut writer, record)?;
+    }
+
+    Some(writer.len)
+}
+
+/// Answers an mDNS query received on [`MDNS_PORT`] from `source_port`, writing the
+/// response into `out`.
+///
+/// Returns the length of the response and whether it goes to the sender instead of
+/// [`MDNS_GROUP`], or `None` if there is nothing to answer. It goes to the sender if any
+/// question asked for a unicast response, or if the query came from a port other than
+/// [`MDNS_PORT`], i.e. from a plain DNS resolver. Responses to other hosts and malformed
+/// messages are ignored.
+pub fn respond(query: &[u8], source_port: u16, out: &mut [u8]) -> Option<(usize, bool)> {
+    let header = query.get(..HEADER_SIZE)?;
+    let id = u16::from_be_bytes([header[0], header[1]]);
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+
+    if flags & FLAG_QR != 0 {
+        return None;
+    }
+
+    let names = Names::current();
+    let mut answers = [None; Record::ALL.len()];
+    let mut unicast = false;
+    let mut pos = HEADER_SIZE;
+
+    for _ in 0..questions {
+        let (name, next) = read_name(query, pos)?;
+        let ty = u16::from_be_bytes(query.get(next..next + 2)?.try_into().ok()?);
+        let class = u16::from_be_bytes(query.get(next + 2..next + 4)?.try_into().ok()?);
+        pos = next + 4;
+
+        if class & !CLASS_UNICAST_RESPONSE != CLASS_IN {
+            continue;
+        }
+
+        for (i, record) in Record::ALL.into_iter().enumerate() {
+            if names.is_available(record)
+                && names.name(record) == name
+                && (ty == TYPE_ANY || ty == record.ty())
+            {
+                answers[i] = Some(record);
+                unicast |= class & CLASS_UNICAST_RESPONSE != 0;
+            }
+        }
+    }
+
+    let mut records = [Record::Address; Record::ALL.len()];
+    let mut count = 0;
+    for record in answers.into_iter().flatten() {
+        records[count] = record;
+        count += 1;
+    }
+
+    if count == 0 {
+        return None;
+    }
+
+    let legacy = (source_port != MDNS_PORT).then(|| LegacyQuery {
+        id,
+        count: questions,
+        questions: &query[HEADER_SIZE..pos],
+    });
+
+    write_response(&names, &records[..count], legacy.as_ref(), out)
+        .map(|len| (len, unicast || legacy.is_some()))
+}
+
+/// Writes an unsolicited response with every record we have into `out`, sent to
+/// [`MDNS_GROUP`] when the address or the service changes so other hosts learn of it
+/// without asking. Returns its length, or `None` if there is nothing to announce.
+pub fn announcement(out: &mut [u8]) -> Option<usize> {
+    let names = Names::current();
+    let mut records = [Record::Address; Record::ALL.len()];
+    let mut count = 0;
+
+    for record in Record::ALL.into_iter().filter(|&x| names.is_available(x)) {
+        records[count] = record;
+        count += 1;
+    }
+
+    if count == 0 {
+        return None;
+    }
+
+    write_response(&names, &records[..count], None, out)
+}
+
+/// Initializes the mDNS responder, which lets developers reach the unikernel at
+/// `<hostname>.local` and find its HTTP service with DNS-SD instead of reading the
+/// address off the console.
+///
+/// The host name is taken from `hostname=` on the kernel command line. The network
+/// stack feeds the responder the queries it receives on [`MDNS_PORT`] through
+/// [`respond`] once it knows the address of the interface, see [`set_address`], and
+/// applications serving HTTP advertise it with [`advertise_http`].
+pub fn init() {
+    for name in multiboot::arg("hostname") {
+        let valid = !name.is_empty()
+            && name.len() <= 63
+            && name.bytes().all(|x| x.is_ascii_alphanumeric() || x == b'-');
+
+        if valid {
+            *HOSTNAME.lock() = name;
+        } else {
+            log!("mdns::init(): ignoring malformed hostname={name}");
+        }
+    }
+
+    log!(
+        "mdns::init(): answering for {}.local [ \x1b[0;32mOK\x1b[0m ]",
+        hostname()
+    );
+}
@@ -1,15 +1,27 @@
+use crate::bitmap::BlockBitmap;
+use crate::cpu;
+use crate::critical;
+use crate::initcall::InitGuard;
+use crate::layout;
 use crate::log;
 use crate::multiboot::InfoFlags;
 use crate::multiboot::MemoryAreaType;
+use crate::multiboot::Module;
 use crate::multiboot::MultibootInformation;
+use alloc::vec::Vec;
+use core::fmt;
 use core::ops::Deref;
 use core::ops::DerefMut;
-use spin::Mutex;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::tlb;
 use x86_64::registers::control::Cr3;
 use x86_64::structures::paging::mapper::CleanUp;
 use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::mapper::{MappedFrame, Translate, TranslateResult};
 use x86_64::structures::paging::page::AddressNotAligned;
 use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::page_table::PageTableEntry;
 use x86_64::structures::paging::FrameAllocator;
 use x86_64::structures::paging::FrameDeallocator;
 use x86_64::structures::paging::Mapper;
@@ -26,15 +38,130 @@ use x86_64::{PhysAddr, VirtAddr};
 /// Maximum number of physical memory regions that can be used by physical allocator.
 const MAX_PHYS_REGIONS: usize = 16;
 
-/// Offset where 4GiB of physical memory is identity mapped to.
-pub const HIGH_HALF_BASE: u64 = 0xFFFF800000000000u64;
-// pub const DEVICE_BASE: u64 = 0xFFFFFFFF40000000u64;
+/// Maximum number of ranges of physical memory kept from the allocator, see
+/// [`Reservation`].
+const MAX_RESERVATIONS: usize = 64;
+
+/// Maximum number of available memory areas below the kernel that are reclaimed.
+const MAX_LOW_AREAS: usize = 4;
+
+/// Where the BIOS data area keeps the segment of the extended BIOS data area.
+const EBDA_SEGMENT_POINTER: u64 = 0x40E;
+
+/// Lowest address an EBDA is believed at, anything below is a bogus pointer.
+const EBDA_LOWEST: u64 = 0x80000;
+
+/// End of conventional memory, where video memory starts.
+const CONVENTIONAL_MEMORY_END: u64 = 0xA0000;
+
+/// Where the allocator reaches its bitmaps: physical memory below `limit` is mapped at
+/// `offset` plus its address.
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    offset: u64,
+    limit: u64,
+}
+
+impl Window {
+    /// The boot page table, which identity maps the first gigabyte.
+    const BOOT: Self = Self {
+        offset: 0,
+        limit: layout::BOOT_MAP_SIZE,
+    };
+
+    /// The direct map of the kernel page table.
+    const DIRECT_MAP: Self = Self {
+        offset: layout::DIRECT_MAP_BASE,
+        limit: layout::DIRECT_MAP_SIZE,
+    };
+
+    /// Returns where the `len` bytes at `pa` are mapped, if all of them are.
+    fn reach(&self, pa: PhysAddr, len: usize) -> Option<*mut u8> {
+        let end = pa.as_u64().checked_add(len as u64)?;
+        (end <= self.limit).then(|| (self.offset + pa.as_u64()) as *mut u8)
+    }
+}
 
 /// Physical frame allocator. Responsible for allocating physical frames for virtual memory manager.
-static mut FRAME_ALLOCATOR: Mutex<PhysicalAllocator> = Mutex::new(PhysicalAllocator::new());
+static FRAME_ALLOCATOR: Mutex<PhysicalAllocator> = Mutex::new(PhysicalAllocator::new());
+
+// CPU holding FRAME_ALLOCATOR, to catch recursive locking.
+static FRAME_ALLOCATOR_OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
 
 // Kernel page table.
-static mut KERNEL_PAGETABLE: Mutex<PageTable> = Mutex::new(PageTable::new());
+static KERNEL_PAGETABLE: Mutex<PageTable> = Mutex::new(PageTable::new());
+
+// CPU holding KERNEL_PAGETABLE, to catch recursive locking.
+static KERNEL_PAGETABLE_OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+
+// Set once the kernel page table is loaded, before which only the identity mapping of
+// the boot page table exists.
+static KERNEL_PAGETABLE_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Offset of the first unused page of the device mapping window, see map_device().
+static DEVICE_MAP_NEXT: AtomicU64 = AtomicU64::new(0);
+
+const NO_OWNER: usize = usize::MAX;
+
+// Initialization of the kernel page table and the frame allocator.
+pub static INIT: InitGuard = InitGuard::new("memory");
+
+/// Lock guard of the frame allocator or the kernel page table.
+///
+/// Locking either twice on the same CPU would spin forever, so debug builds remember
+/// which CPU holds the lock and panic instead. The kernel page table is always locked
+/// before the frame allocator.
+pub struct Guard<T: 'static> {
+    guard: MutexGuard<'static, T>,
+    owner: &'static AtomicUsize,
+}
+
+impl<T> Deref for Guard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for Guard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for Guard<T> {
+    fn drop(&mut self) {
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+    }
+}
+
+fn lock<T>(mutex: &'static Mutex<T>, owner: &'static AtomicUsize, name: &str) -> Guard<T> {
+    let id = unsafe { cpu::current().id() };
+
+    debug_assert_ne!(
+        owner.load(Ordering::Relaxed),
+        id,
+        "memory::lock(): {name} locked recursively on cpu {id}"
+    );
+
+    let guard = mutex.lock();
+    owner.store(id, Ordering::Relaxed);
+    Guard { guard, owner }
+}
+
+/// Locks the physical frame allocator.
+pub fn frame_allocator() -> Guard<PhysicalAllocator> {
+    lock(&FRAME_ALLOCATOR, &FRAME_ALLOCATOR_OWNER, "frame allocator")
+}
+
+fn kernel_page_table() -> Guard<PageTable> {
+    lock(
+        &KERNEL_PAGETABLE,
+        &KERNEL_PAGETABLE_OWNER,
+        "kernel page table",
+    )
+}
 
 /// Represents a physical memory region.
 #[derive(Debug, Copy, Clone)]
@@ -44,6 +171,14 @@ pub struct PhysRegion {
 }
 
 impl PhysRegion {
+    /// Creates a region of `size` bytes starting at `start_address`.
+    pub const fn new(start_address: PhysAddr, size: usize) -> Self {
+        Self {
+            start_address,
+            size,
+        }
+    }
+
     /// Gets the starting address of the physical frame.
     pub fn start_address(&self) -> PhysAddr {
         self.start_address
@@ -69,6 +204,49 @@ impl PhysRegion {
     }
 }
 
+/// What reserved physical memory is in use for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservationKind {
+    /// The kernel image.
+    Kernel,
+    /// A boot module, like an initrd.
+    Module,
+    /// The multiboot structure and what it points to.
+    BootInfo,
+    /// Memory the firmware memory map does not report as available.
+    Firmware,
+    /// ACPI tables.
+    Acpi,
+    /// The framebuffer the bootloader set up.
+    Framebuffer,
+    /// Device memory below 4 GiB.
+    Device,
+}
+
+impl fmt::Display for ReservationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Kernel => "kernel",
+            Self::Module => "module",
+            Self::BootInfo => "boot info",
+            Self::Firmware => "firmware",
+            Self::Acpi => "acpi",
+            Self::Framebuffer => "framebuffer",
+            Self::Device => "device",
+        };
+
+        f.write_str(s)
+    }
+}
+
+/// Physical memory in use by something other than the allocator's callers, which the
+/// allocator never hands out.
+#[derive(Debug, Clone, Copy)]
+pub struct Reservation {
+    pub region: PhysRegion,
+    pub kind: ReservationKind,
+}
+
 impl<S: PageSize> From<PhysFrame<S>> for PhysRegion {
     fn from(value: PhysFrame<S>) -> Self {
         Self {
@@ -115,6 +293,8 @@ impl<S: PageSize> TryFrom<PhysRegion> for PhysFrame<S> {
 #[derive(Debug)]
 pub struct PhysicalAllocator {
     regions: [Option<PhysicalMemoryBitmap>; MAX_PHYS_REGIONS],
+    reservations: [Option<Reservation>; MAX_RESERVATIONS],
+    window: Window,
 }
 
 impl PhysicalAllocator {
@@ -125,27 +305,245 @@ impl PhysicalAllocator {
 
         Self {
             regions: [ARRAY_REPEAT_VALUE; MAX_PHYS_REGIONS],
+            reservations: [None; MAX_RESERVATIONS],
+            window: Window::BOOT,
         }
     }
 
+    /// Records that `region` is in use as `kind`, so that it is never handed out.
+    ///
+    /// Memory given to [`reserve`](Self::reserve) later is trimmed around it, blocks
+    /// already managed are taken out of the free ones. Blocks that were handed out
+    /// before are logged, since something else may be using them.
+    ///
+    /// Panics if more than [`MAX_RESERVATIONS`] ranges are recorded.
+    pub fn add_reservation(&mut self, region: PhysRegion, kind: ReservationKind) {
+        if region.size() == 0 {
+            return;
+        }
+
+        // Tables and strings are often recorded more than once.
+        let known = self.reservations.iter().flatten().any(|x| {
+            x.kind == kind
+                && x.region.start_address() <= region.start_address()
+                && region.end_address() <= x.region.end_address()
+        });
+
+        if known {
+            return;
+        }
+
+        let Some(slot) = self.reservations.iter_mut().find(|x| x.is_none()) else {
+            panic!("memory::add_reservation(): more than {MAX_RESERVATIONS} reservations");
+        };
+
+        *slot = Some(Reservation { region, kind });
+
+        for managed in self.regions.iter_mut().flatten() {
+            let used = managed.claim(region);
+
+            if used > 0 {
+                log!(
+                    "memory::add_reservation(): {kind} [{:#016x}-{:#016x}] overlaps {used} blocks in use",
+                    region.start_address().as_u64(),
+                    region.end_address().as_u64()
+                );
+            }
+        }
+    }
+
+    /// Stops reserving `region`, splitting the reservations it lies in. The memory is
+    /// not handed out before it is given to [`reserve`](Self::reserve).
+    ///
+    /// Panics if more than [`MAX_RESERVATIONS`] ranges are left.
+    pub fn remove_reservation(&mut self, region: PhysRegion) {
+        for i in 0..MAX_RESERVATIONS {
+            let Some(x) = self.reservations[i].filter(|x| x.region.intersects(&region)) else {
+                continue;
+            };
+
+            self.reservations[i] = None;
+
+            let start = x.region.start_address();
+            let end = x.region.end_address();
+
+            if start < region.start_address() {
+                let before = PhysRegion::new(start, (region.start_address() - start) as usize);
+                self.add_reservation(before, x.kind);
+            }
+
+            if region.end_address() < end {
+                let after =
+                    PhysRegion::new(region.end_address(), (end - region.end_address()) as usize);
+                self.add_reservation(after, x.kind);
+            }
+        }
+    }
+
+    /// Returns a reservation that intersects `region`, if there is one.
+    pub fn reservation(&self, region: &PhysRegion) -> Option<Reservation> {
+        self.reservations
+            .iter()
+            .flatten()
+            .find(|x| x.region.intersects(region))
+            .copied()
+    }
+
+    /// Calls `f` with every reservation, in the order they were recorded.
+    pub fn for_each_reservation(&self, f: impl FnMut(&Reservation)) {
+        self.reservations.iter().flatten().for_each(f);
+    }
+
+    /// Returns the first page of `region` the allocator manages and could still hand
+    /// out, if there is one.
+    pub fn first_free(&self, region: &PhysRegion) -> Option<PhysAddr> {
+        self.regions
+            .iter()
+            .flatten()
+            .find_map(|x| x.first_free(region))
+    }
+
     /// Informs memory allocator about a new memory region from `start` to `start + size`.
+    ///
+    /// Parts of the region that overlap reservations or regions reserved before are
+    /// trimmed off, as are partial blocks at both ends and anything past the direct map,
+    /// since handed out memory must be reachable through it. Every adjustment is logged.
     pub fn reserve(&mut self, start: PhysAddr, size: usize, block_size: usize) {
+        let window_end = PhysAddr::new(layout::DIRECT_MAP_SIZE);
+
+        if start >= window_end {
+            log!(
+                "memory::reserve(): [{:#016x}-{:#016x}] is past the direct map, skipping",
+                start.as_u64(),
+                (start + size).as_u64()
+            );
+            return;
+        }
+
+        if start + size > window_end {
+            log!(
+                "memory::reserve(): [{:#016x}-{:#016x}] clipped to the direct map",
+                start.as_u64(),
+                (start + size).as_u64()
+            );
+            return self.reserve(start, (window_end - start) as usize, block_size);
+        }
+
+        let start_aligned = start.align_up(block_size as u64);
+        let end_aligned = (start + size).align_down(block_size as u64);
+
+        if start_aligned >= end_aligned {
+            log!(
+                "memory::reserve(): [{:#016x}-{:#016x}] is smaller than a block, skipping",
+                start.as_u64(),
+                (start + size).as_u64()
+            );
+            return;
+        }
+
+        if start_aligned != start || end_aligned != start + size {
+            log!(
+                "memory::reserve(): [{:#016x}-{:#016x}] trimmed to {block_size} byte blocks",
+                start.as_u64(),
+                (start + size).as_u64()
+            );
+        }
+
+        let region = PhysRegion {
+            start_address: start_aligned,
+            size: (end_aligned - start_aligned) as usize,
+        };
+
+        if let Some(conflict) = self.find_conflict(&region) {
+            log!(
+                "memory::reserve(): [{:#016x}-{:#016x}] overlaps [{:#016x}-{:#016x}], trimming",
+                region.start_address().as_u64(),
+                region.end_address().as_u64(),
+                conflict.start_address().as_u64(),
+                conflict.end_address().as_u64()
+            );
+
+            // Reserve what is left on both sides of the conflict, which may conflict
+            // with something else again.
+            if region.start_address() < conflict.start_address() {
+                let size = conflict.start_address() - region.start_address();
+                self.reserve(region.start_address(), size as usize, block_size);
+            }
+
+            if conflict.end_address() < region.end_address() {
+                let size = region.end_address() - conflict.end_address();
+                self.reserve(conflict.end_address(), size as usize, block_size);
+            }
+
+            return;
+        }
+
         // Find first unused region and mark that out.
-        if let Some(region) = self.regions.iter_mut().find(|i| i.is_none()) {
-            *region = Some(PhysicalMemoryBitmap::new(start, size, block_size));
+        if let Some(slot) = self.regions.iter_mut().find(|i| i.is_none()) {
+            let bitmap = PhysicalMemoryBitmap::new(
+                region.start_address(),
+                region.size(),
+                block_size,
+                self.window,
+            );
+
+            match bitmap {
+                Ok(bitmap) => *slot = Some(bitmap),
+                Err(BitmapError::TooSmall) => log!(
+                    "memory::reserve(): [{:#016x}-{:#016x}] is too small for its bitmap, skipping",
+                    region.start_address().as_u64(),
+                    region.end_address().as_u64()
+                ),
+                Err(BitmapError::Unmapped) => log!(
+                    "memory::reserve(): [{:#016x}-{:#016x}] is not mapped yet, skipping",
+                    region.start_address().as_u64(),
+                    region.end_address().as_u64()
+                ),
+            }
         } else {
             panic!("Too many memory regions have been reserved. Can only reserve up to {MAX_PHYS_REGIONS}.");
         }
     }
 
+    // Returns a reservation or a managed region that intersects `region`.
+    fn find_conflict(&self, region: &PhysRegion) -> Option<PhysRegion> {
+        let managed = self.regions.iter().flatten().map(|x| PhysRegion {
+            start_address: x.start_addr,
+            size: x.size,
+        });
+
+        self.reservations
+            .iter()
+            .flatten()
+            .map(|x| x.region)
+            .chain(managed)
+            .find(|x| x.intersects(region))
+    }
+
     /// Allocates a contiguous block of physical memory with the specified size.
     pub fn allocate(&mut self, size: usize) -> Option<PhysRegion> {
+        self.allocate_aligned(size, 1, PhysAddr::new(u64::MAX))
+    }
+
+    /// Allocates a contiguous block of physical memory with the specified size, starting
+    /// at a multiple of `align` and taken from a managed region that ends at `limit` or
+    /// below. `align` must be a power of two.
+    pub fn allocate_aligned(
+        &mut self,
+        size: usize,
+        align: usize,
+        limit: PhysAddr,
+    ) -> Option<PhysRegion> {
         // Find first memory region that has memory available of that sized.
         for region in self.regions.iter_mut().flatten() {
+            if region.start_addr + region.size as u64 > limit {
+                continue;
+            }
+
             if region.bytes_remaining() >= size {
                 let blocks = region.bytes_to_blocks(size);
 
-                match region.allocate(blocks) {
+                match region.allocate(blocks, align) {
                     Some(addr) => return Some(addr),
                     None => continue,
                 }
@@ -155,6 +553,23 @@ impl PhysicalAllocator {
         None
     }
 
+    /// Gets the total number of bytes managed by the memory allocator, including the
+    /// blocks its bitmaps occupy.
+    pub fn bytes_total(&self) -> usize {
+        self.regions.iter().flatten().map(|x| x.size).sum()
+    }
+
+    /// Reaches the bitmaps through the direct map from now on, which must be in use. The
+    /// boot page table only maps the first gigabyte, the kernel page table identity maps
+    /// only part of physical memory.
+    fn use_direct_map(&mut self) {
+        for region in self.regions.iter_mut().flatten() {
+            region.rebind(Window::DIRECT_MAP);
+        }
+
+        self.window = Window::DIRECT_MAP;
+    }
+
     /// Gets the total number of bytes remaining in memory allocator.
     pub fn bytes_remaining(&self) -> usize {
         self.regions
@@ -164,16 +579,39 @@ impl PhysicalAllocator {
             .sum()
     }
 
+    /// Calls `f` with every managed region, its block size and its allocation bitmap,
+    /// in which bit `i` is set if block `i` is in use.
+    pub fn for_each_region(&self, mut f: impl FnMut(PhysRegion, usize, &[u8])) {
+        for region in self.regions.iter().flatten() {
+            let phys = PhysRegion {
+                start_address: region.start_addr,
+                size: region.size,
+            };
+
+            debug_assert!(
+                region.bitmap.is_consistent(),
+                "memory::for_each_region(): free block count does not match the bitmap"
+            );
+
+            f(phys, region.block_size, region.bitmap.as_bytes());
+        }
+    }
+
     /// Deallocates a previously allocated physical memory region.
+    ///
+    /// Memory that no managed region holds, such as the kernel image, is left alone.
     pub fn deallocate(&mut self, frame: PhysRegion) {
-        // Placeholder implementation
         for region in self.regions.iter_mut().flatten() {
             if region.try_deallocate(frame) {
                 return;
             }
         }
 
-        // Otherwise just drop frame lmao
+        log!(
+            "memory::deallocate(): [{:#016x}-{:#016x}] is not managed, ignoring",
+            frame.start_address().as_u64(),
+            frame.end_address().as_u64()
+        );
     }
 }
 
@@ -190,196 +628,154 @@ impl<S: PageSize> FrameDeallocator<S> for PhysicalAllocator {
     }
 }
 
+// Why a region cannot be managed.
+#[derive(Debug)]
+enum BitmapError {
+    // The region cannot hold more than its own bitmap.
+    TooSmall,
+    // The bitmap would lie in memory that is not mapped yet.
+    Unmapped,
+}
+
 #[derive(Debug)]
-#[repr(C, align(8))]
 struct PhysicalMemoryBitmap {
     start_addr: PhysAddr,
     size: usize,
     block_size: usize,
-    blocks_remaining: usize,
-    reserved: usize,
-    bitmap: &'static mut [u8],
+    bitmap: BlockBitmap<'static>,
 }
 
 impl PhysicalMemoryBitmap {
-    fn new(start_addr: PhysAddr, size: usize, block_size: usize) -> Self {
+    /// Manages the blocks from `start_addr` to `start_addr + size`, keeping the bitmap
+    /// in the first blocks of the region, which is written through `window`. Fails if
+    /// the region is too small to hold more than its own bitmap, or `window` does not
+    /// reach all of the bitmap.
+    fn new(
+        start_addr: PhysAddr,
+        size: usize,
+        block_size: usize,
+        window: Window,
+    ) -> Result<Self, BitmapError> {
         debug_assert!(block_size.is_power_of_two());
 
         let start_aligned = start_addr.align_up(block_size as u64);
         let end_aligned = (start_addr + size).align_down(block_size as u64);
 
         let aligned_size = (end_aligned - start_aligned) as usize;
-        let bitmap_size = aligned_size / block_size / 8;
-
-        let bitmap = unsafe {
-            let virt = VirtAddr::new(start_aligned.as_u64());
-            core::slice::from_raw_parts_mut(virt.as_mut_ptr(), bitmap_size)
-        };
-
-        // Mark all regions as unused except for the regions currently occupied by the bitmap
-        bitmap.fill(0);
-
-        let mut bitmap_reserved_blocks = bitmap_size.next_multiple_of(block_size) / block_size;
-        let mut index = 0;
+        let blocks = aligned_size / block_size;
+        let bitmap_size = BlockBitmap::bytes_for(blocks);
 
-        let reserved = bitmap_reserved_blocks;
-
-        while bitmap_reserved_blocks > 0 {
-            for bit in 0..=7 {
-                if bitmap_reserved_blocks > 0 {
-                    bitmap[index] |= 1 << bit;
-                    bitmap_reserved_blocks -= 1;
-                } else {
-                    break;
-                }
-            }
-
-            index += 1;
+        // The blocks the bitmap occupies are reserved.
+        let reserved = bitmap_size.div_ceil(block_size);
+        if reserved >= blocks {
+            return Err(BitmapError::TooSmall);
         }
 
-        Self {
+        let ptr = window
+            .reach(start_aligned, bitmap_size)
+            .ok_or(BitmapError::Unmapped)?;
+        let bits = unsafe { core::slice::from_raw_parts_mut(ptr, bitmap_size) };
+
+        Ok(Self {
             start_addr: start_aligned,
             size: aligned_size,
             block_size,
-            blocks_remaining: (aligned_size / block_size) - reserved,
-            reserved,
-            bitmap,
-        }
+            bitmap: BlockBitmap::new(bits, blocks, reserved).ok_or(BitmapError::TooSmall)?,
+        })
     }
 
-    const fn bytes_remaining(&self) -> usize {
-        self.blocks_remaining * self.block_size
-    }
-
-    const fn bytes_to_blocks(&self, size: usize) -> usize {
-        size.next_multiple_of(self.block_size) / self.block_size
-    }
+    // Reaches the bitmap through `window` from now on.
+    fn rebind(&mut self, window: Window) {
+        let len = self.bitmap.as_bytes().len();
+        let ptr = window
+            .reach(self.start_addr, len)
+            .expect("memory::rebind(): bitmap is not reachable");
 
-    const fn bitmap_end(&self) -> usize {
-        self.bitmap.len() * 8
+        self.bitmap
+            .rebind(unsafe { core::slice::from_raw_parts_mut(ptr, len) });
     }
 
-    const fn bitmap_start(&self) -> usize {
-        self.reserved
+    const fn bytes_remaining(&self) -> usize {
+        self.bitmap.free() * self.block_size
     }
 
-    const fn total_blocks(&self) -> usize {
-        self.size / self.block_size
+    const fn bytes_to_blocks(&self, size: usize) -> usize {
+        size.div_ceil(self.block_size)
     }
 
-    fn contains_frame(&self, frame: PhysRegion) -> bool {
-        let start_block = ((frame.start_address - self.start_addr) as usize) / self.block_size;
-        let blocks = frame.size.next_multiple_of(self.block_size) / self.block_size;
-        let end_block = start_block + blocks;
-
-        (start_block < self.total_blocks()) && (end_block <= self.total_blocks())
+    fn allocate(&mut self, blocks: usize, align: usize) -> Option<PhysRegion> {
+        // The region starts at a block boundary, so aligning to less than a block
+        // comes for free.
+        let align = (align / self.block_size).max(1);
+        let offset = self.start_addr.as_u64() as usize / self.block_size;
+        let start_block = self.bitmap.allocate_aligned(blocks, align, offset)?;
+
+        Some(PhysRegion {
+            start_address: self.start_addr + (start_block * self.block_size),
+            size: blocks * self.block_size,
+        })
     }
 
-    fn allocate(&mut self, blocks: usize) -> Option<PhysRegion> {
-        let mut consecutive_blocks = 0;
-        let mut start_block = 0;
-
-        for i in self.bitmap_start()..self.bitmap_end() {
-            let bit = i & 7;
-            let entry = i >> 3;
-
-            if (self.bitmap[entry] & (1 << bit)) == 0 {
-                if consecutive_blocks == 0 {
-                    start_block = i;
-                }
-
-                consecutive_blocks += 1;
-
-                if consecutive_blocks == blocks {
-                    // Mark all consecutive blocks as allocated.
-                    for j in start_block..start_block + blocks {
-                        let bit = j & 7;
-                        let entry = j >> 3;
-
-                        self.bitmap[entry] |= 1 << bit;
-                    }
-
-                    self.blocks_remaining -= blocks;
+    // Returns the blocks that `region` overlaps, at least partially.
+    fn blocks_of(&self, region: &PhysRegion) -> core::ops::Range<usize> {
+        let start = region.start_address().max(self.start_addr);
+        let end = region.end_address().min(self.start_addr + self.size as u64);
 
-                    return Some(PhysRegion {
-                        start_address: self.start_addr + (start_block * self.block_size),
-                        size: blocks * self.block_size,
-                    });
-                }
-            } else {
-                consecutive_blocks = 0;
-            }
+        if start >= end {
+            return 0..0;
         }
 
-        None
+        let first = (start - self.start_addr) as usize / self.block_size;
+        let last = ((end - self.start_addr) as usize).div_ceil(self.block_size);
+        first..last
     }
 
-    fn try_deallocate(&mut self, frame: PhysRegion) -> bool {
-        let addr = frame.start_address;
-        let blocks: usize = frame.size.next_multiple_of(self.block_size) / self.block_size;
-        let relative_addr = (addr - self.start_addr) as usize;
+    // Takes the blocks `region` overlaps out of the free ones, returning how many of
+    // them were in use already.
+    fn claim(&mut self, region: PhysRegion) -> usize {
+        let blocks = self.blocks_of(&region);
+        self.bitmap.claim(blocks.start, blocks.len())
+    }
 
-        let start_block = relative_addr / self.block_size;
-        let end_block = start_block + blocks;
+    fn first_free(&self, region: &PhysRegion) -> Option<PhysAddr> {
+        self.blocks_of(region)
+            .find(|&i| !self.bitmap.is_used(i))
+            .map(|i| self.start_addr + (i * self.block_size) as u64)
+    }
 
-        if start_block < self.reserved {
+    fn try_deallocate(&mut self, frame: PhysRegion) -> bool {
+        // Frames of other regions may lie below this one, or not be aligned to its
+        // blocks at all.
+        if frame.start_address < self.start_addr {
             return false;
         }
 
-        if !self.contains_frame(frame) {
+        if !frame.start_address.is_aligned(self.block_size as u64) {
             return false;
         }
 
-        for block in start_block..end_block {
-            let entry = block >> 3;
-            let bit = block & 7;
-
-            assert!(
-                (self.bitmap[entry] & (1 << bit)) != 0,
-                "Deallocating block that was not held before."
-            );
-
-            self.bitmap[entry] &= !(1 << bit);
-        }
-
-        self.blocks_remaining += blocks;
-
-        true
+        let start_block = (frame.start_address - self.start_addr) as usize / self.block_size;
+        self.bitmap
+            .deallocate(start_block, self.bytes_to_blocks(frame.size))
     }
 }
 
-extern "C" {
-    static __kernel_start: [usize; 0];
-    static __data_start: [usize; 0];
-    static __kernel_end: [usize; 0];
-}
-
 /// Represents important locations in physical address space.
 pub struct PhysicalMemoryLayout {
     kernel_start: PhysAddr,
     data_start: PhysAddr,
     kernel_end: PhysAddr,
     phys_stop: PhysAddr,
-    // device_start: PhysAddr,
 }
 
 impl PhysicalMemoryLayout {
     #[inline]
     pub fn new() -> Self {
-        unsafe {
-            let kernel_start = __kernel_start.as_ptr() as u64;
-            let data_start = __data_start.as_ptr() as u64;
-            let kernel_end = __kernel_end.as_ptr() as u64;
-            let phys_stop = 0x80000000u64;
-            // let device_start = 0xFE000000u64;
-
-            Self {
-                kernel_start: PhysAddr::new(kernel_start),
-                data_start: PhysAddr::new(data_start),
-                kernel_end: PhysAddr::new(kernel_end),
-                phys_stop: PhysAddr::new(phys_stop),
-                // device_start: PhysAddr::new(device_start),
-            }
+        Self {
+            kernel_start: layout::image().start,
+            data_start: layout::data().start,
+            kernel_end: layout::image().end,
+            phys_stop: PhysAddr::new(layout::IDENTITY_MAP_END),
         }
     }
 }
@@ -390,16 +786,17 @@ impl PhysicalMemoryLayout {
 /// and establishes a mapping between the specified virtual and physical addresses.
 /// The size parameter determines the length of the memory region to be mapped.
 ///
+/// Page tables needed on the way are taken from `alloc`.
+///
 /// This function does not flush the TLB.
 pub unsafe fn map_region<S: PageSize>(
     pgtbl: &mut impl Mapper<S>,
+    alloc: &mut impl FrameAllocator<Size4KiB>,
     va: VirtAddr,
     pa: PhysAddr,
     size: u64,
     flags: PageTableFlags,
 ) -> Result<(), MapToError<S>> {
-    let mut alloc = FRAME_ALLOCATOR.lock();
-
     let page_range: PageRange<S> = {
         let start_page = Page::containing_address(va);
         let end_page = Page::containing_address(va + size);
@@ -416,23 +813,23 @@ pub unsafe fn map_region<S: PageSize>(
         // );
         let frame = PhysFrame::containing_address(frame_addr);
 
-        let _ = pgtbl.map_to(page, frame, flags, alloc.deref_mut())?;
+        let _ = pgtbl.map_to(page, frame, flags, alloc)?;
     }
 
     Ok(())
 }
 
-/// Unmaps a region of memory in a page table.
+/// Unmaps a region of memory in a page table, giving page tables left empty back to
+/// `alloc` if `should_free` is set.
 pub unsafe fn unmap_region(
     pgtbl: &mut (impl CleanUp + Mapper<Size4KiB>),
+    alloc: &mut impl FrameDeallocator<Size4KiB>,
     va: VirtAddr,
     size: u64,
     should_free: bool,
 ) {
     assert!(va.is_aligned(Size4KiB::SIZE));
 
-    let mut alloc = FRAME_ALLOCATOR.lock();
-
     let page_range = {
         let start_page = Page::containing_address(va);
         let end_page = Page::containing_address(va + size - 1u64);
@@ -440,14 +837,17 @@ pub unsafe fn unmap_region(
     };
 
     for page in page_range {
-        log!("memory::unmap_region(): unmapping {:016p}", page.start_address().as_ptr::<u8>());
+        log!(
+            "memory::unmap_region(): unmapping {:016p}",
+            page.start_address().as_ptr::<u8>()
+        );
         let _ = pgtbl
             .unmap(page)
             .expect("memory::unmap_region(): trying to unmmap invalid memory region");
     }
 
     if should_free {
-        pgtbl.clean_up_addr_range(page_range, &mut *alloc);
+        pgtbl.clean_up_addr_range(page_range, alloc);
     }
 }
 
@@ -457,6 +857,10 @@ pub unsafe fn unmap_region(
 /// and establishes a mapping between the specified virtual and physical addresses.
 /// The size parameter determines the length of the memory region to be mapped.
 ///
+/// The memory is meant to come from the frame allocator, so mapping memory that is
+/// reserved for something else, see [`Reservation`], panics. Device registers and the
+/// like are mapped with [`map`] instead.
+///
 /// This function does not flush the TLB.
 pub unsafe fn kernel_map_region<S: PageSize>(
     va: VirtAddr,
@@ -467,22 +871,888 @@ pub unsafe fn kernel_map_region<S: PageSize>(
 where
     for<'a> OffsetPageTable<'a>: Mapper<S>,
 {
-    let mut kpgtbl = KERNEL_PAGETABLE.lock();
-    let mut mapper = OffsetPageTable::new(&mut kpgtbl, VirtAddr::new(HIGH_HALF_BASE));
-    map_region(&mut mapper, va, pa, size, flags)
+    let mut kpgtbl = kernel_page_table();
+    let mut alloc = frame_allocator();
+
+    if let Some(x) = alloc.reservation(&PhysRegion::new(pa, size as usize)) {
+        panic!(
+            "memory::kernel_map_region(): [{:#016x}-{:#016x}] overlaps reserved {} memory",
+            pa.as_u64(),
+            pa.as_u64() + size,
+            x.kind
+        );
+    }
+
+    let mut mapper = OffsetPageTable::new(&mut kpgtbl, VirtAddr::new(layout::DIRECT_MAP_BASE));
+    map_region(&mut mapper, alloc.deref_mut(), va, pa, size, flags)
 }
 
 /// Unmaps a region of memory in kernel page table.
 pub unsafe fn kernel_unmap_region(va: VirtAddr, size: u64, should_free: bool) {
-    let mut kpgtbl = KERNEL_PAGETABLE.lock();
-    let mut mapper = OffsetPageTable::new(&mut kpgtbl, VirtAddr::new(HIGH_HALF_BASE));
-    unmap_region(&mut mapper, va, size, should_free)
+    let mut kpgtbl = kernel_page_table();
+    let mut alloc = frame_allocator();
+    let mut mapper = OffsetPageTable::new(&mut kpgtbl, VirtAddr::new(layout::DIRECT_MAP_BASE));
+    unmap_region(&mut mapper, alloc.deref_mut(), va, size, should_free)
+}
+
+/// Reason [`map`], [`unmap`] or [`protect`] refused to change the kernel page table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// An address or the size is not a multiple of 4 KiB, or the size is zero.
+    Misaligned,
+    /// The flags are missing PRESENT, ask for a huge page or make the pages both
+    /// writable and executable.
+    InvalidFlags,
+    /// A page in the range is already mapped.
+    AlreadyMapped(VirtAddr),
+    /// A page in the range is not mapped.
+    NotMapped(VirtAddr),
+    /// A page in the range lies within a huge page.
+    HugePage(VirtAddr),
+    /// There was no physical memory left for a page table.
+    OutOfMemory,
+    /// The physical range includes memory the allocator may still hand out.
+    Allocatable(PhysAddr),
+    /// The device mapping window has no room left for the range.
+    WindowFull,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Misaligned => write!(f, "range is not aligned to pages"),
+            Self::InvalidFlags => write!(f, "flags are not allowed for a mapping"),
+            Self::AlreadyMapped(va) => write!(f, "page {:#016x} is already mapped", va.as_u64()),
+            Self::NotMapped(va) => write!(f, "page {:#016x} is not mapped", va.as_u64()),
+            Self::HugePage(va) => write!(f, "page {:#016x} is within a huge page", va.as_u64()),
+            Self::OutOfMemory => write!(f, "out of memory for page tables"),
+            Self::Allocatable(pa) => {
+                write!(
+                    f,
+                    "frame {:#016x} is free memory of the allocator",
+                    pa.as_u64()
+                )
+            }
+            Self::WindowFull => write!(f, "device mapping window is full"),
+        }
+    }
+}
+
+// Checks that `va`, `pa` and `size` describe whole pages and returns those pages.
+fn page_range(va: VirtAddr, pa: PhysAddr, size: u64) -> Result<PageRange, MapError> {
+    if size == 0
+        || !va.is_aligned(Size4KiB::SIZE)
+        || !pa.is_aligned(Size4KiB::SIZE)
+        || size & (Size4KiB::SIZE - 1) != 0
+    {
+        return Err(MapError::Misaligned);
+    }
+
+    let start = Page::from_start_address(va).map_err(|_| MapError::Misaligned)?;
+    Ok(Page::range(start, start + size / Size4KiB::SIZE))
+}
+
+// Checks that `flags` are fit for a kernel mapping.
+fn check_flags(flags: PageTableFlags) -> Result<(), MapError> {
+    let writable_code =
+        flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE);
+
+    if !flags.contains(PageTableFlags::PRESENT)
+        || flags.contains(PageTableFlags::HUGE_PAGE)
+        || writable_code
+    {
+        return Err(MapError::InvalidFlags);
+    }
+
+    Ok(())
+}
+
+fn flush_pages(pages: PageRange) {
+    for page in pages {
+        tlb::flush(page.start_address());
+    }
+}
+
+/// Maps the `size` bytes at physical address `pa` at `va` in the kernel page table,
+/// with 4 KiB pages, for memory such as device registers or a framebuffer.
+///
+/// Every address and the size must be page aligned, and no page may be mapped yet.
+/// The flags must include PRESENT and may not make pages both writable and
+/// executable. Memory the allocator could still hand out is refused, see
+/// [`Reservation`]. If a page cannot be mapped, the ones before it are unmapped again.
+/// The TLB is flushed for the range.
+///
+/// # Safety
+/// The caller must make sure nothing else relies on the physical memory, for example
+/// through the heap.
+pub unsafe fn map(
+    va: VirtAddr,
+    pa: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapError> {
+    check_flags(flags)?;
+    let pages = page_range(va, pa, size)?;
+
+    let mut kpgtbl = kernel_page_table();
+    let mut alloc = frame_allocator();
+
+    if let Some(pa) = alloc.first_free(&PhysRegion::new(pa, size as usize)) {
+        return Err(MapError::Allocatable(pa));
+    }
+
+    let mut mapper = OffsetPageTable::new(&mut kpgtbl, VirtAddr::new(layout::DIRECT_MAP_BASE));
+
+    for (i, page) in pages.enumerate() {
+        let frame = PhysFrame::containing_address(pa + i as u64 * Size4KiB::SIZE);
+
+        let err = match mapper.map_to(page, frame, flags, alloc.deref_mut()) {
+            Ok(flush) => {
+                flush.ignore();
+                continue;
+            }
+            Err(MapToError::FrameAllocationFailed) => MapError::OutOfMemory,
+            Err(MapToError::ParentEntryHugePage) => MapError::HugePage(page.start_address()),
+            Err(MapToError::PageAlreadyMapped(_)) => MapError::AlreadyMapped(page.start_address()),
+        };
+
+        for page in pages.take(i) {
+            let _ = mapper.unmap(page).map(|(_, flush)| flush.ignore());
+        }
+
+        flush_pages(pages);
+        return Err(err);
+    }
+
+    flush_pages(pages);
+    Ok(())
+}
+
+/// Unmaps the `size` bytes at `va` from the kernel page table, without freeing the
+/// memory they were mapped to or page tables left empty.
+///
+/// Every page must be mapped with a 4 KiB page, otherwise nothing is unmapped. The TLB
+/// is flushed for the range.
+///
+/// # Safety
+/// Nothing may use the range anymore.
+pub unsafe fn unmap(va: VirtAddr, size: u64) -> Result<(), MapError> {
+    let pages = page_range(va, PhysAddr::zero(), size)?;
+
+    let mut kpgtbl = kernel_page_table();
+    let mut mapper = OffsetPageTable::new(&mut kpgtbl, VirtAddr::new(layout::DIRECT_MAP_BASE));
+
+    for page in pages {
+        check_mapped(&mapper, page)?;
+    }
+
+    for page in pages {
+        let _ = mapper.unmap(page).map(|(_, flush)| flush.ignore());
+    }
+
+    flush_pages(pages);
+    Ok(())
+}
+
+/// Changes the flags of the `size` bytes mapped at `va` in the kernel page table, with
+/// the same rules for the flags as [`map`].
+///
+/// Every page must be mapped with a 4 KiB page, otherwise nothing is changed. The TLB
+/// is flushed for the range.
+///
+/// # Safety
+/// Nothing may rely on the old flags, such as writing to pages made read only.
+pub unsafe fn protect(va: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MapError> {
+    check_flags(flags)?;
+    let pages = page_range(va, PhysAddr::zero(), size)?;
+
+    let mut kpgtbl = kernel_page_table();
+    let mut mapper = OffsetPageTable::new(&mut kpgtbl, VirtAddr::new(layout::DIRECT_MAP_BASE));
+
+    for page in pages {
+        check_mapped(&mapper, page)?;
+    }
+
+    for page in pages {
+        let _ = mapper.update_flags(page, flags).map(|flush| flush.ignore());
+    }
+
+    flush_pages(pages);
+    Ok(())
+}
+
+/// Maps the `size` bytes of device registers at `pa` uncached into the device mapping
+/// window and returns where `pa` ends up. The range need not be page aligned.
+///
+/// Mappings are never taken down again, drivers are expected to map their registers
+/// once.
+///
+/// # Safety
+/// `pa` must be device memory, not RAM.
+pub unsafe fn map_device(pa: PhysAddr, size: u64) -> Result<VirtAddr, MapError> {
+    let start = pa.align_down(Size4KiB::SIZE);
+    let end = pa
+        .as_u64()
+        .checked_add(size.max(1))
+        .ok_or(MapError::Misaligned)?;
+    let len = (end - start.as_u64()).next_multiple_of(Size4KiB::SIZE);
+
+    let offset = DEVICE_MAP_NEXT
+        .try_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+            x.checked_add(len).filter(|&y| y <= layout::DEVICE_MAP_SIZE)
+        })
+        .map_err(|_| MapError::WindowFull)?;
+    let va = VirtAddr::new(layout::DEVICE_MAP_BASE + offset);
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    map(va, start, len, flags)?;
+
+    Ok(va + (pa - start))
+}
+
+// Checks that `page` is mapped by a 4 KiB page.
+fn check_mapped(mapper: &OffsetPageTable, page: Page) -> Result<(), MapError> {
+    match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(_),
+            ..
+        } => Ok(()),
+        TranslateResult::Mapped { .. } => Err(MapError::HugePage(page.start_address())),
+        _ => Err(MapError::NotMapped(page.start_address())),
+    }
+}
+
+/// Calls `f` with every region of the physical frame allocator, its block size and
+/// its allocation bitmap.
+pub fn for_each_frame_region(f: impl FnMut(PhysRegion, usize, &[u8])) {
+    frame_allocator().for_each_region(f)
+}
+
+/// Calls `f` with every range of physical memory kept from the frame allocator.
+pub fn for_each_reservation(f: impl FnMut(&Reservation)) {
+    frame_allocator().for_each_reservation(f)
+}
+
+/// Returns the flags of the page `va` is mapped by, walking the page table the processor
+/// uses without taking the kernel page table lock.
+///
+/// Meant for exception and panic handlers, which may run with the lock held. The answer
+/// is only as current as the entries read, a mapping changed meanwhile may be missed.
+pub fn probe(va: VirtAddr) -> Option<PageTableFlags> {
+    if !KERNEL_PAGETABLE_ACTIVE.load(Ordering::Acquire) {
+        return (va.as_u64() < layout::BOOT_MAP_SIZE).then_some(PageTableFlags::PRESENT);
+    }
+
+    let (frame, _) = Cr3::read();
+    let mut table = frame.start_address();
+    let indices = [va.p4_index(), va.p3_index(), va.p2_index(), va.p1_index()];
+
+    for (level, index) in indices.into_iter().enumerate() {
+        let entries = phys_to_virt(table).as_ptr::<PageTableEntry>();
+        let entry = unsafe { entries.add(usize::from(index)).read_volatile() };
+        let flags = entry.flags();
+
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+
+        // Huge pages end the walk in the PDPT or the page directory.
+        if level == 3 || (level > 0 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+            return Some(flags);
+        }
+
+        table = entry.addr();
+    }
+
+    None
+}
+
+/// Translates a virtual address using the kernel page table.
+///
+/// Returns the physical address and the flags of the page it is mapped by.
+pub fn translate(va: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    let mut kpgtbl = kernel_page_table();
+    let mapper =
+        unsafe { OffsetPageTable::new(&mut kpgtbl, VirtAddr::new(layout::DIRECT_MAP_BASE)) };
+
+    match mapper.translate(va) {
+        TranslateResult::Mapped {
+            frame,
+            offset,
+            flags,
+        } => Some((frame.start_address() + offset, flags)),
+        _ => None,
+    }
+}
+
+/// Translates the `len` bytes at `va` using the kernel page table, calling `f` with
+/// every physically contiguous segment they are made of, in order.
+///
+/// This takes the page table lock once and walks the tables once per page, or once
+/// per huge page, instead of once per address as [`translate`] would. Drivers use it
+/// to turn a buffer into a scatter list. Returns the first address that is not mapped,
+/// in which case `f` has only been called for the segments before it.
+pub fn translate_range(
+    va: VirtAddr,
+    len: usize,
+    mut f: impl FnMut(PhysAddr, usize),
+) -> Result<(), VirtAddr> {
+    let mut kpgtbl = kernel_page_table();
+    let mapper =
+        unsafe { OffsetPageTable::new(&mut kpgtbl, VirtAddr::new(layout::DIRECT_MAP_BASE)) };
+
+    let end = va + len as u64;
+    let mut addr = va;
+    // Segment being built, flushed when the next page does not follow it.
+    let mut segment: Option<(PhysAddr, usize)> = None;
+
+    while addr < end {
+        let TranslateResult::Mapped { frame, offset, .. } = mapper.translate(addr) else {
+            return Err(addr);
+        };
+
+        let pa = frame.start_address() + offset;
+        let size = ((frame.size() - offset) as usize).min((end - addr) as usize);
+
+        segment = match segment {
+            Some((start, len)) if start + len as u64 == pa => Some((start, len + size)),
+            Some((start, len)) => {
+                f(start, len);
+                Some((pa, size))
+            }
+            None => Some((pa, size)),
+        };
+
+        addr += size as u64;
+    }
+
+    if let Some((start, len)) = segment {
+        f(start, len);
+    }
+
+    Ok(())
+}
+
+/// Walks the kernel page table for a virtual address, calling `f` with the level
+/// (4 for the PML4 down to 1 for the page table), index and entry used at every step.
+///
+/// The walk stops at the first entry that is not present or maps a huge page.
+pub fn walk(va: VirtAddr, mut f: impl FnMut(u8, u16, &PageTableEntry)) {
+    let kpgtbl = kernel_page_table();
+    let indices = [va.p4_index(), va.p3_index(), va.p2_index(), va.p1_index()];
+    let mut table: &PageTable = &kpgtbl;
+
+    for (level, index) in (1..=4).rev().zip(indices) {
+        let entry = &table[index];
+        f(level, u16::from(index), entry);
+
+        let flags = entry.flags();
+        if level == 1
+            || !flags.contains(PageTableFlags::PRESENT)
+            || flags.contains(PageTableFlags::HUGE_PAGE)
+        {
+            break;
+        }
+
+        // Page tables are reached through the direct map of physical memory.
+        table = unsafe { &*phys_to_virt(entry.addr()).as_ptr::<PageTable>() };
+    }
+}
+
+/// Contiguous range of virtual memory mapped to contiguous physical memory with the
+/// same flags.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: VirtAddr,
+    pub phys: PhysAddr,
+    pub size: u64,
+    /// Effective flags: writable only if every level is writable, and not executable
+    /// if any level forbids it.
+    pub flags: PageTableFlags,
+}
+
+impl Mapping {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    // Extends this mapping with the next one if they continue each other.
+    fn merge(&mut self, next: &Mapping) -> bool {
+        if self.end() == next.start
+            && self.phys + self.size == next.phys
+            && self.flags == next.flags
+        {
+            self.size += next.size;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Summary of the kernel page table taken by [`snapshot`].
+pub struct Snapshot(Vec<Mapping>);
+
+/// Change between two snapshots of the kernel page table.
+#[derive(Debug, Copy, Clone)]
+pub enum MappingChange {
+    Added(Mapping),
+    Removed(Mapping),
+}
+
+// Flags that the hardware changes or that only describe the page size.
+const IGNORED_FLAGS: PageTableFlags = PageTableFlags::ACCESSED
+    .union(PageTableFlags::DIRTY)
+    .union(PageTableFlags::HUGE_PAGE);
+
+fn walk_table(
+    table: &PageTable,
+    level: u8,
+    base: u64,
+    parent: PageTableFlags,
+    f: &mut dyn FnMut(Mapping),
+) {
+    let entry_size = 1u64 << (12 + 9 * (level as u64 - 1));
+
+    for (i, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+
+        let start = VirtAddr::new_truncate(base + i as u64 * entry_size);
+        let mut effective = flags - IGNORED_FLAGS;
+        effective.set(
+            PageTableFlags::WRITABLE,
+            flags.contains(PageTableFlags::WRITABLE) && parent.contains(PageTableFlags::WRITABLE),
+        );
+        effective |= parent & PageTableFlags::NO_EXECUTE;
+
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            f(Mapping {
+                start,
+                phys: entry.addr(),
+                size: entry_size,
+                flags: effective,
+            });
+        } else {
+            let next = unsafe { &*phys_to_virt(entry.addr()).as_ptr::<PageTable>() };
+            walk_table(next, level - 1, start.as_u64(), effective, f);
+        }
+    }
+}
+
+/// Calls `f` with every mapping of the kernel page table in address order, merging
+/// neighbouring pages that continue each other into one mapping.
+pub fn dump_page_table(mut f: impl FnMut(Mapping)) {
+    let kpgtbl = kernel_page_table();
+    let mut current: Option<Mapping> = None;
+
+    let mut visit = |mapping: Mapping| {
+        if current.as_mut().is_some_and(|x| x.merge(&mapping)) {
+            return;
+        }
+
+        if let Some(x) = current.replace(mapping) {
+            f(x);
+        }
+    };
+
+    walk_table(&kpgtbl, 4, 0, PageTableFlags::WRITABLE, &mut visit);
+
+    if let Some(x) = current {
+        f(x);
+    }
+}
+
+/// Takes a summary of the kernel page table, to compare with [`diff`] later.
+pub fn snapshot() -> Snapshot {
+    let mut mappings = Vec::new();
+    dump_page_table(|x| mappings.push(x));
+    Snapshot(mappings)
+}
+
+/// Calls `f` with every mapping that is in `after` but not in `before` and the other
+/// way round. A mapping whose flags or target changed shows as removed and added.
+pub fn diff(before: &Snapshot, after: &Snapshot, mut f: impl FnMut(MappingChange)) {
+    let (mut old, mut new) = (before.0.iter().peekable(), after.0.iter().peekable());
+
+    loop {
+        match (old.peek(), new.peek()) {
+            (Some(a), Some(b)) if a == b => {
+                old.next();
+                new.next();
+            }
+            (Some(a), Some(b)) if a.start <= b.start => {
+                f(MappingChange::Removed(**a));
+                old.next();
+            }
+            (_, Some(b)) => {
+                f(MappingChange::Added(**b));
+                new.next();
+            }
+            (Some(a), None) => {
+                f(MappingChange::Removed(**a));
+                old.next();
+            }
+            (None, None) => break,
+        }
+    }
+}
+
+/// Logs how the kernel page table changed since `before` was taken, e.g. by a driver.
+pub fn log_changes(before: &Snapshot, what: &str) {
+    let after = snapshot();
+    let mut changes = 0;
+
+    diff(before, &after, |change| {
+        let (sign, x) = match change {
+            MappingChange::Added(x) => ('+', x),
+            MappingChange::Removed(x) => ('-', x),
+        };
+
+        changes += 1;
+        log!(
+            "memory::log_changes(): {what}: {sign} {:#016x}-{:#016x} -> {:#016x} {:?}",
+            x.start.as_u64(),
+            x.end().as_u64(),
+            x.phys.as_u64(),
+            x.flags
+        );
+    });
+
+    if changes == 0 {
+        log!("memory::log_changes(): {what}: kernel page table unchanged");
+    }
+}
+
+/// Access a range of virtual memory is expected to have.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Access {
+    ReadOnly,
+    ReadExecute,
+    ReadWrite,
+    ReadWriteExecute,
+    Unmapped,
+}
+
+impl Access {
+    fn of(flags: PageTableFlags) -> Self {
+        match (
+            flags.contains(PageTableFlags::WRITABLE),
+            flags.contains(PageTableFlags::NO_EXECUTE),
+        ) {
+            (false, true) => Access::ReadOnly,
+            (false, false) => Access::ReadExecute,
+            (true, true) => Access::ReadWrite,
+            (true, false) => Access::ReadWriteExecute,
+        }
+    }
+}
+
+// Checks every page of `[start, end)` against the expected access, and that mapped
+// pages point `offset` bytes below their virtual address.
+fn verify_range(what: &str, start: u64, end: u64, offset: u64, expected: Access) {
+    let mut page = start & !(Size4KiB::SIZE - 1);
+
+    while page < end {
+        let found = translate(VirtAddr::new(page));
+        let access = found.map_or(Access::Unmapped, |(_, flags)| Access::of(flags));
+
+        if access != expected {
+            panic!(
+                "memory::verify(): {what}: {page:#016x} expected {expected:?}, found {access:?}"
+            );
+        }
+
+        if let Some((pa, _)) = found {
+            if pa.as_u64() != page - offset {
+                panic!(
+                    "memory::verify(): {what}: {page:#016x} maps to {:#016x}, expected {:#016x}",
+                    pa.as_u64(),
+                    page - offset
+                );
+            }
+        }
+
+        page += Size4KiB::SIZE;
+    }
+}
+
+/// Checks the kernel page table after switching to it, panicking with the first
+/// mapping that is not what the rest of the kernel relies on.
+fn verify(layout: &PhysicalMemoryLayout, boot: layout::Section) {
+    let kernel_start = layout.kernel_start.as_u64();
+    let data_start = layout.data_start.as_u64();
+    let kernel_end = layout.kernel_end.as_u64();
+
+    // The boot GDT and page tables live inside the kernel image and are unmapped, which
+    // leaves a guard hole.
+    let (guard_start, guard_end) = (boot.start.as_u64(), boot.end.as_u64());
+    verify_range("guard page", guard_start, guard_end, 0, Access::Unmapped);
+
+    for (what, start, end, access) in [
+        ("kernel text", kernel_start, data_start, Access::ReadExecute),
+        ("kernel data", data_start, kernel_end, Access::ReadWrite),
+    ] {
+        let before_guard = guard_start.clamp(start, end);
+        let after_guard = guard_end.clamp(start, end);
+
+        verify_range(what, start, before_guard, 0, access);
+        verify_range(what, after_guard, end, 0, access);
+    }
+
+    let heap_start = layout::HEAP_BASE;
+    let heap_end = heap_start + crate::heap::HEAP_SIZE;
+    verify_range("heap", heap_start, heap_end, 0, Access::Unmapped);
+
+    // The direct map is made of 1 GiB pages, checking one page per gigabyte is enough.
+    for pa in (0..layout::DIRECT_MAP_SIZE).step_by(Size1GiB::SIZE as usize) {
+        let va = layout::DIRECT_MAP_BASE + pa;
+        verify_range(
+            "direct map",
+            va,
+            va + 1,
+            layout::DIRECT_MAP_BASE,
+            Access::ReadWrite,
+        );
+    }
+
+    // Probe that the direct map and the identity map really reach the same memory.
+    let mut probe = 0u64;
+    let identity = &mut probe as *mut u64;
+    let alias = phys_to_virt(PhysAddr::new(identity as u64)).as_mut_ptr::<u64>();
+
+    unsafe {
+        alias.write_volatile(0x6c69746869756d);
+        if identity.read_volatile() != 0x6c69746869756d {
+            panic!("memory::verify(): direct map alias {alias:p} does not reach {identity:p}");
+        }
+    }
+
+    // The allocator bitmaps are written through the direct map from now on.
+    let mut bitmaps = [(0, 0); MAX_PHYS_REGIONS];
+    let mut count = 0;
+
+    frame_allocator().for_each_region(|_, _, bits| {
+        let start = bits.as_ptr() as u64;
+        bitmaps[count] = (start, start + bits.len() as u64);
+        count += 1;
+    });
+
+    for &(start, end) in &bitmaps[..count] {
+        verify_range(
+            "allocator bitmap",
+            start,
+            end,
+            layout::DIRECT_MAP_BASE,
+            Access::ReadWrite,
+        );
+    }
+
+    log!("memory::verify(): kernel mappings verified [ \x1b[0;32mOK\x1b[0m ]");
+}
+
+/// Keeps `region` from ever being handed out, see
+/// [`PhysicalAllocator::add_reservation`].
+pub fn add_reservation(region: PhysRegion, kind: ReservationKind) {
+    frame_allocator().add_reservation(region, kind);
 }
 
 /// Allocates a contiguous physical region with the specified size.
 pub unsafe fn allocate_physical_region(size: usize) -> Option<PhysRegion> {
-    let mut frame_allocator = FRAME_ALLOCATOR.lock();
-    frame_allocator.allocate(size)
+    frame_allocator().allocate(size)
+}
+
+/// Gives a region allocated with [`allocate_physical_region`] or [`allocate_dma_region`]
+/// back to the allocator.
+pub unsafe fn deallocate_physical_region(region: PhysRegion) {
+    frame_allocator().deallocate(region)
+}
+
+/// Allocates a contiguous physical region for devices to access, starting at a multiple
+/// of `align`, which must be a power of two.
+///
+/// The region is taken from memory below 4 GiB only, so that it can be reached through
+/// the direct map with [`direct_map`] and handed to devices that take 32 bit addresses.
+pub unsafe fn allocate_dma_region(size: usize, align: usize) -> Option<PhysRegion> {
+    frame_allocator().allocate_aligned(size, align, PhysAddr::new(layout::DIRECT_MAP_SIZE))
+}
+
+/// Returns where physical address `pa` is mapped in the direct map, if it is.
+pub fn direct_map(pa: PhysAddr) -> Option<VirtAddr> {
+    (pa.as_u64() < layout::DIRECT_MAP_SIZE)
+        .then(|| VirtAddr::new(layout::DIRECT_MAP_BASE + pa.as_u64()))
+}
+
+/// Returns where physical address `pa` is mapped in the direct map, for addresses known
+/// to be in it, like page tables, DMA regions and device memory below 4 GiB.
+///
+/// Debug builds panic if `pa` is past the direct map, release builds return an address
+/// that faults when used.
+#[inline]
+pub fn phys_to_virt(pa: PhysAddr) -> VirtAddr {
+    debug_assert!(
+        pa.as_u64() < layout::DIRECT_MAP_SIZE,
+        "memory::phys_to_virt(): {:#016x} is not in the direct map",
+        pa.as_u64()
+    );
+
+    VirtAddr::new_truncate(layout::DIRECT_MAP_BASE + pa.as_u64())
+}
+
+/// Returns the physical address of `va`, which must be linearly mapped: either in the
+/// direct map, or identity mapped like the kernel image and its statics. Anything
+/// else, like the heap, must go through [`translate`].
+///
+/// Debug builds panic if `va` is in neither mapping.
+#[inline]
+pub fn virt_to_phys(va: VirtAddr) -> PhysAddr {
+    let va = va.as_u64();
+
+    if va >= layout::DIRECT_MAP_BASE {
+        debug_assert!(
+            va - layout::DIRECT_MAP_BASE < layout::DIRECT_MAP_SIZE,
+            "memory::virt_to_phys(): {va:#016x} is past the direct map"
+        );
+
+        return PhysAddr::new_truncate(va - layout::DIRECT_MAP_BASE);
+    }
+
+    debug_assert!(
+        va < layout::IDENTITY_MAP_END,
+        "memory::virt_to_phys(): {va:#016x} is not linearly mapped"
+    );
+
+    PhysAddr::new_truncate(va)
+}
+
+/// Returns the number of bytes of physical memory managed by the frame allocator.
+pub fn physical_total() -> u64 {
+    critical::with(|_| frame_allocator().bytes_total() as u64)
+}
+
+/// Returns the number of bytes of physical memory the frame allocator can hand out.
+pub fn physical_free() -> u64 {
+    critical::with(|_| frame_allocator().bytes_remaining() as u64)
+}
+
+// Records the memory that is still in use before any is handed to the allocator: the
+// kernel image, boot modules, what the bootloader passed, firmware areas, the
+// framebuffer and device memory. The direct map does not exist yet, so everything is
+// read through the boot page table.
+fn add_boot_reservations(mbi: &MultibootInformation, layout: &PhysicalMemoryLayout) {
+    let mut alloc = frame_allocator();
+    let range =
+        |start: u64, end: u64| PhysRegion::new(PhysAddr::new(start), (end - start) as usize);
+
+    alloc.add_reservation(
+        range(layout.kernel_start.as_u64(), layout.kernel_end.as_u64()),
+        ReservationKind::Kernel,
+    );
+
+    let modules: &[Module] = if mbi.flags.contains(InfoFlags::MODS) {
+        unsafe {
+            core::slice::from_raw_parts(mbi.mods_addr as *const Module, mbi.mods_count as usize)
+        }
+    } else {
+        &[]
+    };
+
+    for module in modules {
+        let end = module.mod_end.max(module.mod_start);
+        alloc.add_reservation(
+            range(module.mod_start as u64, end as u64),
+            ReservationKind::Module,
+        );
+    }
+
+    mbi.for_each_boot_data(|pa, len| {
+        alloc.add_reservation(PhysRegion::new(pa, len as usize), ReservationKind::BootInfo);
+    });
+
+    // Only what the allocator could be given matters.
+    if let Ok(areas) = mbi.memory_areas() {
+        for area in areas.filter(|x| !matches!(x.area_type(), MemoryAreaType::Available)) {
+            let (start, end) = area.bounds();
+            if start < layout::DIRECT_MAP_SIZE {
+                let end = end.min(layout::DIRECT_MAP_SIZE);
+                alloc.add_reservation(range(start, end), ReservationKind::Firmware);
+            }
+        }
+    }
+
+    if let Some(fb) = mbi.framebuffer() {
+        let region = PhysRegion::new(fb.addr, fb.size() as usize);
+        alloc.add_reservation(region, ReservationKind::Framebuffer);
+    }
+
+    alloc.add_reservation(
+        range(layout::DEVICE_MEMORY_START, layout::DIRECT_MAP_SIZE),
+        ReservationKind::Device,
+    );
+
+    alloc.for_each_reservation(|x| {
+        log!(
+            "memory::init(): reserved [{:#016x}-{:#016x}] for {}",
+            x.region.start_address().as_u64(),
+            x.region.end_address().as_u64(),
+            x.kind
+        );
+    });
+}
+
+// Hands the available memory below the kernel to the allocator, except for the real
+// mode interrupt vector table and BIOS data area in the first page and the extended
+// BIOS data area. They stay reserved for firmware and real mode code.
+fn reclaim_low_memory(areas: impl Iterator<Item = (PhysAddr, PhysAddr)>) {
+    let before = frame_allocator().bytes_total();
+
+    add_reservation(
+        PhysRegion::new(PhysAddr::zero(), Size4KiB::SIZE as usize),
+        ReservationKind::Firmware,
+    );
+
+    // The BIOS data area holds the segment of the EBDA, which reaches up to the video
+    // memory at 640 KiB. Some firmware does not mark it in the memory map.
+    let pointer = phys_to_virt(PhysAddr::new(EBDA_SEGMENT_POINTER)).as_ptr::<u16>();
+    let ebda = PhysAddr::new(unsafe { pointer.read() } as u64 * 16).align_down(Size4KiB::SIZE);
+
+    if (EBDA_LOWEST..CONVENTIONAL_MEMORY_END).contains(&ebda.as_u64()) {
+        let size = (CONVENTIONAL_MEMORY_END - ebda.as_u64()) as usize;
+        add_reservation(PhysRegion::new(ebda, size), ReservationKind::Firmware);
+    }
+
+    for (start, end) in areas {
+        frame_allocator().reserve(start, (end - start) as usize, Size4KiB::SIZE as usize);
+    }
+
+    let reclaimed = frame_allocator().bytes_total() - before;
+    log!("memory::init(): reclaimed {reclaimed} bytes below the kernel");
+}
+
+// Hands the boot GDT and page tables in entry.S to the allocator. cpu::init() loaded
+// this CPU's own GDT and the kernel page table is on, so nothing uses them anymore. Their
+// identity mapping is gone, the allocator reaches them through the direct map. The GDT
+// page holds the allocation bitmap, so both page tables can be handed out.
+fn reclaim_boot_data() {
+    let boot = layout::boot_data();
+    let mut alloc = frame_allocator();
+    let before = alloc.bytes_total();
+
+    alloc.remove_reservation(PhysRegion::new(boot.start, boot.size() as usize));
+    alloc.reserve(boot.start, boot.size() as usize, Size4KiB::SIZE as usize);
+
+    let reclaimed = alloc.bytes_total() - before;
+    log!(
+        "memory::init(): reclaimed {reclaimed} bytes of boot page tables at [{:#016x}-{:#016x}]",
+        boot.start.as_u64(),
+        boot.end.as_u64()
+    );
 }
 
 /// Initializes the memory subsystem of the kernel.
@@ -491,14 +1761,16 @@ pub unsafe fn allocate_physical_region(size: usize) -> Option<PhysRegion> {
 /// memory components of the kernel. It sets up essential data structures, allocates
 /// necessary resources, and prepares the system for memory management operations.
 pub fn init(mbi_ptr: *const MultibootInformation) {
+    let _init = INIT.start();
+    crate::console::INIT.require("memory");
+
     let mbi = unsafe { mbi_ptr.as_ref().unwrap() };
     let layout = PhysicalMemoryLayout::new();
 
     log!("memory::init(): found multiboot structure at {:016p}", mbi);
 
     // Print out bootloader name.
-    if mbi.flags.contains(InfoFlags::BOOT_LOADER_NAME) {
-        let name = unsafe { core::ffi::CStr::from_ptr(mbi.boot_loader_name as *const i8) };
+    if let Some(name) = mbi.boot_loader_name() {
         log!("memory::init(): bootloader name {name:?}");
     }
 
@@ -514,14 +1786,14 @@ pub fn init(mbi_ptr: *const MultibootInformation) {
         layout.kernel_end.as_u64()
     );
 
-    // Panic if memory map is not available.
-    if !mbi.flags.contains(InfoFlags::MEM_MAP) {
-        panic!("multiboot structure did not have memory map");
-    }
+    // Panic if memory map is not usable.
+    let memory_areas = mbi
+        .memory_areas()
+        .unwrap_or_else(|err| panic!("memory::init(): {err}"));
 
     log!("memory::init(): physical memory layout:");
 
-    for (i, area) in mbi.memory_areas().enumerate() {
+    for (i, area) in memory_areas.enumerate() {
         let size = (area.size() as f64) / (1 << 20) as f64;
         log!(
             "{:016} | Base: {:#016x} | End: {:#016x} | {:>10.2} MiB {}",
@@ -533,38 +1805,33 @@ pub fn init(mbi_ptr: *const MultibootInformation) {
         );
     }
 
-    // Keep track of kernel frame so we don't give it to the allocator.
-    let kernel_frame = PhysRegion {
-        start_address: layout.kernel_start,
-        size: (layout.kernel_end - layout.kernel_start) as usize,
-    };
+    add_boot_reservations(mbi, &layout);
 
-    for area in mbi
-        .memory_areas()
-        .filter(|x| matches!(x.area_type(), MemoryAreaType::Available))
-    {
-        let mut start = area.start_address();
-        let mut size = area.size();
-        let frame = PhysRegion {
-            start_address: start,
-            size,
-        };
+    // Memory below the kernel is only handed over once the kernel page table is in use,
+    // see reclaim_low_memory().
+    let mut low_memory = [None; MAX_LOW_AREAS];
 
-        if frame.intersects(&kernel_frame) {
-            start = kernel_frame.end_address();
-            size = (frame.end_address() - start) as usize;
-        }
+    for area in memory_areas.filter(|x| matches!(x.area_type(), MemoryAreaType::Available)) {
+        if area.start_address() < layout.kernel_start {
+            let end = area.end_address().min(layout.kernel_start);
+
+            match low_memory.iter_mut().find(|x| x.is_none()) {
+                Some(slot) => *slot = Some((area.start_address(), end)),
+                None => log!(
+                    "memory::init(): [{:#016x}-{:#016x}] is one low area too many, skipping",
+                    area.start_address().as_u64(),
+                    end.as_u64()
+                ),
+            }
 
-        // NOTE(kosinw): Skip memory below the kernel
-        if frame.start_address() < layout.kernel_start {
             continue;
         }
 
         // TODO(kosinw): Maybe change this number dynamically to something else?
-        unsafe { FRAME_ALLOCATOR.lock().reserve(start, size, 4096) };
+        frame_allocator().reserve(area.start_address(), area.size(), 4096);
     }
 
-    let sz = unsafe { FRAME_ALLOCATOR.lock().bytes_remaining() };
+    let sz = frame_allocator().bytes_remaining();
 
     log!("memory::init(): physical bitmap allocator initialized [ \x1b[0;32mOK\x1b[0m ]");
     log!("memory::init(): {sz} total bytes available");
@@ -576,20 +1843,22 @@ pub fn init(mbi_ptr: *const MultibootInformation) {
     );
 
     unsafe {
-        let mut kpgtbl = KERNEL_PAGETABLE.lock();
+        let mut kpgtbl = kernel_page_table();
+        let mut alloc = frame_allocator();
 
         kpgtbl.zero();
 
         // Our physical offset here is zero because the first 1GiB is direct mapped from the bootloader.
-        // Later when we modify the page table kernel it will be based on HIGH_HALF_DIRECT_MAP.
+        // Later when we modify the page table kernel it will be based on the direct map.
         let mut mapper = OffsetPageTable::new(kpgtbl.deref_mut(), VirtAddr::zero());
 
         // map 4 GiB physical memory into higher half address
         map_region::<Size1GiB>(
             &mut mapper,
-            VirtAddr::new(HIGH_HALF_BASE),
+            alloc.deref_mut(),
+            VirtAddr::new(layout::DIRECT_MAP_BASE),
             PhysAddr::zero(),
-            Size1GiB::SIZE * 4,
+            layout::DIRECT_MAP_SIZE,
             PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE | PageTableFlags::WRITABLE,
         )
         .expect("failed to map higher half direct map");
@@ -607,6 +1876,7 @@ pub fn init(mbi_ptr: *const MultibootInformation) {
         // identity map text section of kernel with execute and no write
         map_region::<Size4KiB>(
             &mut mapper,
+            alloc.deref_mut(),
             VirtAddr::new(layout.kernel_start.as_u64()),
             layout.kernel_start,
             layout.data_start - layout.kernel_start,
@@ -617,6 +1887,7 @@ pub fn init(mbi_ptr: *const MultibootInformation) {
         // identity map rest of kernel with read and write
         map_region::<Size4KiB>(
             &mut mapper,
+            alloc.deref_mut(),
             VirtAddr::new(layout.data_start.as_u64()),
             layout.data_start,
             layout.kernel_end.align_up(Size2MiB::SIZE) - layout.data_start,
@@ -627,6 +1898,7 @@ pub fn init(mbi_ptr: *const MultibootInformation) {
         // identity map rest of kernel with read and write
         map_region::<Size2MiB>(
             &mut mapper,
+            alloc.deref_mut(),
             VirtAddr::new(layout.kernel_end.align_up(Size2MiB::SIZE).as_u64()),
             layout.kernel_end.align_up(Size2MiB::SIZE),
             layout.phys_stop - layout.kernel_end.align_up(Size2MiB::SIZE),
@@ -635,19 +1907,24 @@ pub fn init(mbi_ptr: *const MultibootInformation) {
         .expect("failed to identity map unallocated memory");
 
         let new_page_table = kpgtbl.deref() as *const PageTable as u64;
-        let page_table_frame: PhysFrame<Size4KiB> = PhysFrame::containing_address(PhysAddr::new(new_page_table));
+        let page_table_frame: PhysFrame<Size4KiB> =
+            PhysFrame::containing_address(PhysAddr::new(new_page_table));
 
         let (_, flags) = Cr3::read();
         Cr3::write(page_table_frame, flags);
+        KERNEL_PAGETABLE_ACTIVE.store(true, Ordering::Release);
+
+        alloc.use_direct_map();
     }
 
-    // Unmap old page table to use as guard page for stack.
+    // The boot GDT and page tables go to the allocator below, which reaches them
+    // through the direct map. Their identity mapping is left unmapped as a guard.
+    let boot = layout::boot_data();
+    debug_assert!(boot.contains(bootpgtbl.start_address()));
+    debug_assert!(boot.start.is_aligned(Size4KiB::SIZE) && boot.end.is_aligned(Size4KiB::SIZE));
+
     unsafe {
-        kernel_unmap_region(
-            VirtAddr::new(bootpgtbl.start_address().as_u64()),
-            Size4KiB::SIZE,
-            false,
-        );
+        kernel_unmap_region(VirtAddr::new(boot.start.as_u64()), boot.size(), false);
     }
 
     let (frame, _) = Cr3::read();
@@ -656,8 +1933,13 @@ pub fn init(mbi_ptr: *const MultibootInformation) {
         frame.start_address().as_u64()
     );
 
+    verify(&layout, boot);
+
     log!("memory::init(): paging initialized [ \x1b[0;32mOK\x1b[0m ]");
 
-    let sz = unsafe { FRAME_ALLOCATOR.lock().bytes_remaining() };
+    reclaim_low_memory(low_memory.iter().flatten().copied());
+    reclaim_boot_data();
+
+    let sz = frame_allocator().bytes_remaining();
     log!("memory::init(): {sz} total bytes available");
 }
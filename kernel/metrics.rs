@@ -0,0 +1,412 @@
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU16, Ordering};
+#[cfg(feature = "net-smoltcp")]
+use core::time::Duration;
+
+use spin::Mutex;
+
+use crate::heap;
+use crate::initcall::Deferred;
+use crate::log;
+#[cfg(feature = "net-smoltcp")]
+use crate::mdns;
+use crate::memory;
+use crate::multiboot;
+#[cfg(feature = "net-smoltcp")]
+use crate::net_smoltcp;
+use crate::sched;
+#[cfg(feature = "net-smoltcp")]
+use crate::sched::Priority;
+use crate::shell;
+use crate::shell::{Command, CommandError};
+#[cfg(feature = "net-smoltcp")]
+use crate::socket::{TcpListener, TcpStream};
+use crate::timer;
+use crate::trap;
+
+/// Maximum number of metrics that can be registered.
+const MAX_METRICS: usize = 32;
+
+/// Port the metrics endpoint listens on unless configured otherwise.
+pub const DEFAULT_PORT: u16 = 9100;
+
+/// Content type of the Prometheus text exposition format.
+#[cfg(feature = "net-smoltcp")]
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Longest request header the endpoint reads, the request line is all it looks at.
+#[cfg(feature = "net-smoltcp")]
+const MAX_REQUEST_SIZE: usize = 1024;
+
+/// How long the endpoint waits for a client to send its request or take the response.
+#[cfg(feature = "net-smoltcp")]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Registered metrics in registration order.
+static REGISTRY: Mutex<[Option<Metric>; MAX_METRICS]> = Mutex::new([None; MAX_METRICS]);
+
+// Metrics of the core kernel subsystems, registered when they are first rendered.
+static CORE_METRICS: Deferred = Deferred::new("metrics", register_core_metrics);
+
+// Port the metrics endpoint is served on, 0 to not serve them.
+static PORT: AtomicU16 = AtomicU16::new(DEFAULT_PORT);
+
+/// Prometheus metric type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    /// Monotonically increasing value.
+    Counter,
+    /// Value that can go up and down.
+    Gauge,
+}
+
+impl MetricKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+        }
+    }
+}
+
+/// How the current value of a metric is sampled.
+///
+/// Metrics do not own their values, every subsystem keeps its own counters and the
+/// registry only reads them while rendering.
+#[derive(Clone, Copy)]
+pub enum Sample {
+    /// A single value.
+    Value(fn() -> u64),
+    /// One value per label value, e.g. one interrupt count per vector. The function
+    /// calls the given closure with every label value and sample.
+    Labeled {
+        label: &'static str,
+        samples: fn(&mut dyn FnMut(u64, u64)),
+    },
+}
+
+/// A metric exposed on the metrics endpoint.
+#[derive(Clone, Copy)]
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: MetricKind,
+    pub sample: Sample,
+}
+
+impl Metric {
+    fn render(&self, out: &mut dyn Write) -> fmt::Result {
+        writeln!(out, "# HELP {} {}", self.name, self.help)?;
+        writeln!(out, "# TYPE {} {}", self.name, self.kind.as_str())?;
+
+        match self.sample {
+            Sample::Value(read) => writeln!(out, "{} {}", self.name, read()),
+            Sample::Labeled { label, samples } => {
+                let mut result = Ok(());
+                samples(&mut |key, value| {
+                    if result.is_ok() {
+                        result = writeln!(out, "{}{{{label}=\"{key}\"}} {value}", self.name);
+                    }
+                });
+                result
+            }
+        }
+    }
+}
+
+/// Registers a metric. Returns false if the registry is full or a metric with the
+/// same name already exists.
+pub fn register(metric: Metric) -> bool {
+    let mut registry = REGISTRY.lock();
+
+    if registry.iter().flatten().any(|x| x.name == metric.name) {
+        return false;
+    }
+
+    match registry.iter_mut().find(|x| x.is_none()) {
+        Some(slot) => {
+            *slot = Some(metric);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Writes all registered metrics in the Prometheus text exposition format.
+pub fn render(out: &mut dyn Write) -> fmt::Result {
+    CORE_METRICS.ensure();
+
+    // Copy the registry so that sampling never runs with the lock held.
+    let registry = *REGISTRY.lock();
+
+    for metric in registry.iter().flatten() {
+        metric.render(out)?;
+    }
+
+    Ok(())
+}
+
+/// Writes a complete HTTP/1.0 response carrying all registered metrics.
+#[cfg(feature = "net-smoltcp")]
+pub fn http_response(out: &mut dyn Write) -> fmt::Result {
+    write!(
+        out,
+        "HTTP/1.0 200 OK\r\nContent-Type: {CONTENT_TYPE}\r\n\r\n"
+    )?;
+    render(out)
+}
+
+/// Returns the port the metrics endpoint is served on.
+pub fn port() -> u16 {
+    PORT.load(Ordering::Relaxed)
+}
+
+/// Changes the port the metrics endpoint is served on, 0 to not serve them. Only has
+/// an effect before the endpoint is started by [`serve`].
+pub fn set_port(port: u16) {
+    PORT.store(port, Ordering::Relaxed);
+}
+
+/// Starts the metrics endpoint on [`port`]. Runs once the network stack is up.
+#[cfg(feature = "net-smoltcp")]
+pub fn serve() {
+    let port = port();
+    if port == 0 {
+        log!("metrics::serve(): endpoint disabled");
+        return;
+    }
+
+    let listener = match TcpListener::bind(([0, 0, 0, 0], port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log!("metrics::serve(): failed to listen on port {port}: {e}");
+            return;
+        }
+    };
+
+    sched::spawn("metrics", Priority::Low, move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => respond(stream),
+                Err(e) => log!("metrics::serve(): failed to accept connection: {e}"),
+            }
+        }
+    });
+
+    mdns::advertise_http(Some(port));
+    net_smoltcp::announce_mdns();
+    log!("metrics::serve(): serving metrics on port {port} [ \x1b[0;32mOK\x1b[0m ]");
+}
+
+// Answers one request on the metrics endpoint and closes the connection.
+#[cfg(feature = "net-smoltcp")]
+fn respond(mut stream: TcpStream) {
+    let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(REQUEST_TIMEOUT));
+
+    let mut request = [0; MAX_REQUEST_SIZE];
+    let mut len = 0;
+
+    while len < request.len() && !request[..len].ends_with(b"\r\n\r\n") {
+        match stream.read(&mut request[len..]) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => len += n,
+        }
+    }
+
+    let line = request[..len].split(|&x| x == b'\r').next().unwrap_or(&[]);
+    let mut words = line.split(|&x| x == b' ');
+
+    let _ = match (words.next(), words.next()) {
+        (Some(b"GET"), Some(b"/" | b"/metrics")) => http_response(&mut stream),
+        _ => stream.write_str("HTTP/1.0 404 Not Found\r\n\r\n"),
+    };
+}
+
+// Calls `f` with the largest size of every heap size class that was allocated from, and
+// `value` of the class.
+fn heap_class_samples(f: &mut dyn FnMut(u64, u64), value: fn(&heap::SizeClassStats) -> u64) {
+    for class in heap::size_classes().iter().filter(|x| x.allocations != 0) {
+        f(class.max_size as u64, value(class));
+    }
+}
+
+fn metrics_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    render(out)?;
+    Ok(())
+}
+
+/// Initializes the metrics endpoint.
+///
+/// With the smoltcp network stack, the metrics are served over HTTP on [`DEFAULT_PORT`]
+/// unless `metrics.port=<port>` on the kernel command line says otherwise, with 0 to
+/// not serve them. The `metrics` shell command shows them either way.
+pub fn init() {
+    for value in multiboot::arg("metrics.port") {
+        match value.parse() {
+            Ok(port) => set_port(port),
+            Err(_) => log!("metrics::init(): ignoring malformed metrics.port={value}"),
+        }
+    }
+
+    assert!(
+        shell::register(Command {
+            name: "metrics",
+            usage: "",
+            help: "show the metrics in the Prometheus text format",
+            run: metrics_command,
+        }),
+        "metrics::init(): failed to register shell command"
+    );
+
+    #[cfg(feature = "net-smoltcp")]
+    log!(
+        "metrics::init(): endpoint on port {} [ \x1b[0;32mOK\x1b[0m ]",
+        port()
+    );
+
+    // The endpoint needs TCP, which only the smoltcp network stack has.
+    #[cfg(not(feature = "net-smoltcp"))]
+    log!(
+        "metrics::init(): no TCP to serve port {} on, see the metrics command",
+        port()
+    );
+}
+
+// Registers the metrics of the core kernel subsystems: allocator, interrupts, scheduler
+// and timer. Nothing reads them until the metrics are rendered for the first time, so
+// this runs then instead of during boot. Drivers register their own metrics with
+// `register` when they are brought up.
+fn register_core_metrics() {
+    let metrics = [
+        Metric {
+            name: "lithium_heap_used_bytes",
+            help: "Bytes currently allocated from the kernel heap.",
+            kind: MetricKind::Gauge,
+            sample: Sample::Value(heap::used),
+        },
+        Metric {
+            name: "lithium_heap_free_bytes",
+            help: "Bytes currently free in the kernel heap.",
+            kind: MetricKind::Gauge,
+            sample: Sample::Value(heap::free),
+        },
+        Metric {
+            name: "lithium_heap_large_bytes",
+            help: "Bytes allocated as dedicated physical regions instead of from the heap.",
+            kind: MetricKind::Gauge,
+            sample: Sample::Value(heap::large_used),
+        },
+        Metric {
+            name: "lithium_heap_high_water_bytes",
+            help: "Most bytes ever allocated from the kernel heap at once.",
+            kind: MetricKind::Gauge,
+            sample: Sample::Value(heap::high_water),
+        },
+        Metric {
+            name: "lithium_heap_failures_total",
+            help: "Allocations the kernel heap could not satisfy.",
+            kind: MetricKind::Counter,
+            sample: Sample::Value(heap::failures),
+        },
+        Metric {
+            name: "lithium_heap_allocations_total",
+            help: "Allocations made per size class, by the largest size in the class.",
+            kind: MetricKind::Counter,
+            sample: Sample::Labeled {
+                label: "size_class",
+                samples: |f| heap_class_samples(f, |x| x.allocations),
+            },
+        },
+        Metric {
+            name: "lithium_heap_deallocations_total",
+            help: "Allocations freed per size class, by the largest size in the class.",
+            kind: MetricKind::Counter,
+            sample: Sample::Labeled {
+                label: "size_class",
+                samples: |f| heap_class_samples(f, |x| x.deallocations),
+            },
+        },
+        Metric {
+            name: "lithium_heap_allocated_bytes_total",
+            help: "Bytes allocated per size class, by the largest size in the class.",
+            kind: MetricKind::Counter,
+            sample: Sample::Labeled {
+                label: "size_class",
+                samples: |f| heap_class_samples(f, |x| x.allocated_bytes),
+            },
+        },
+        Metric {
+            name: "lithium_heap_class_used_bytes",
+            help: "Bytes currently allocated per size class, by the largest size in the class.",
+            kind: MetricKind::Gauge,
+            sample: Sample::Labeled {
+                label: "size_class",
+                samples: |f| heap_class_samples(f, |x| x.used_bytes),
+            },
+        },
+        Metric {
+            name: "lithium_physical_memory_bytes",
+            help: "Bytes of physical memory managed by the frame allocator.",
+            kind: MetricKind::Gauge,
+            sample: Sample::Value(memory::physical_total),
+        },
+        Metric {
+            name: "lithium_physical_free_bytes",
+            help: "Bytes of physical memory the frame allocator can hand out.",
+            kind: MetricKind::Gauge,
+            sample: Sample::Value(memory::physical_free),
+        },
+        Metric {
+            name: "lithium_interrupts_total",
+            help: "Traps handled per vector.",
+            kind: MetricKind::Counter,
+            sample: Sample::Labeled {
+                label: "vector",
+                samples: trap::for_each_count,
+            },
+        },
+        Metric {
+            name: "lithium_context_switches_total",
+            help: "Kernel threads switched to by the scheduler.",
+            kind: MetricKind::Counter,
+            sample: Sample::Value(sched::context_switches),
+        },
+        Metric {
+            name: "lithium_threads_runnable",
+            help: "Kernel threads waiting to run.",
+            kind: MetricKind::Gauge,
+            sample: Sample::Value(|| sched::thread_counts().0 as u64),
+        },
+        Metric {
+            name: "lithium_threads_blocked",
+            help: "Kernel threads blocked waiting for a wakeup.",
+            kind: MetricKind::Gauge,
+            sample: Sample::Value(|| sched::thread_counts().1 as u64),
+        },
+        Metric {
+            name: "lithium_timer_ticks_total",
+            help: "Periodic timer ticks delivered.",
+            kind: MetricKind::Counter,
+            sample: Sample::Value(timer::ticks),
+        },
+    ];
+
+    for metric in metrics {
+        assert!(
+            register(metric),
+            "metrics::register_core_metrics(): failed to register {}",
+            metric.name
+        );
+    }
+
+    log!(
+        "metrics::register_core_metrics(): {} metrics registered",
+        metrics.len()
+    );
+}
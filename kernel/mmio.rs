@@ -0,0 +1,193 @@
+use core::cell::UnsafeCell;
+use core::mem::{align_of, size_of};
+use core::sync::atomic::{fence, Ordering};
+
+/// Keeps reads of memory shared with a device from moving before earlier reads, e.g.
+/// reading a ring entry only after the index that covers it.
+#[inline]
+pub fn read_barrier() {
+    fence(Ordering::Acquire);
+}
+
+/// Keeps writes to memory shared with a device from moving before earlier writes, e.g.
+/// publishing an index only after the entry it covers.
+#[inline]
+pub fn write_barrier() {
+    fence(Ordering::Release);
+}
+
+/// Orders all earlier accesses before all later ones, including reads after writes,
+/// e.g. reading whether the device wants a notification after publishing an index.
+#[inline]
+pub fn full_barrier() {
+    fence(Ordering::SeqCst);
+}
+
+/// A register or a field shared with a device, only ever accessed with volatile reads
+/// and writes so the compiler neither merges nor drops them.
+///
+/// Meant as a field of a register block placed over device memory, see
+/// [`register_block!`](crate::register_block).
+#[repr(transparent)]
+pub struct Volatile<T>(UnsafeCell<T>);
+
+// Registers are shared with the device anyway.
+unsafe impl<T: Send> Sync for Volatile<T> {}
+
+impl<T: Copy> Volatile<T> {
+    #[inline]
+    pub fn read(&self) -> T {
+        unsafe { self.0.get().read_volatile() }
+    }
+
+    #[inline]
+    pub fn write(&self, value: T) {
+        unsafe { self.0.get().write_volatile(value) }
+    }
+}
+
+/// A register the driver must not write.
+#[repr(transparent)]
+pub struct ReadOnly<T>(Volatile<T>);
+
+impl<T: Copy> ReadOnly<T> {
+    #[inline]
+    pub fn read(&self) -> T {
+        self.0.read()
+    }
+}
+
+/// Types that are made only of registers and can be placed over device memory.
+///
+/// # Safety
+/// Every access through the type must be volatile. Implemented by [`Volatile`],
+/// [`ReadOnly`], arrays of registers and blocks defined with
+/// [`register_block!`](crate::register_block).
+pub unsafe trait Register {}
+
+unsafe impl<T> Register for Volatile<T> {}
+unsafe impl<T> Register for ReadOnly<T> {}
+unsafe impl<R: Register, const N: usize> Register for [R; N] {}
+
+/// Defines a `#[repr(C)]` block of registers with the offset of every field, which is
+/// checked at compile time, so a layout copied from a specification cannot silently
+/// disagree with the struct. Reserved ranges are spelled out as arrays of registers.
+///
+/// ```ignore
+/// register_block! {
+///     /// `virtio_pci_notify_cap`.
+///     struct VirtioPciNotifyCap {
+///         0x00 => cap: VirtioPciCap,
+///         0x10 => notify_off_multiplier: ReadOnly<u32>,
+///     }
+/// }
+/// ```
+///
+/// Fields must be registers, see [`Register`]. The block is reached through
+/// [`Region::block`], and `offset_of!` gives the offsets where the same layout is read
+/// out of a byte buffer, as for PCI capabilities.
+#[macro_export]
+macro_rules! register_block {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_attr:meta])*
+                $offset:literal => $field_vis:vis $field:ident: $ty:ty,
+            )*
+        }
+    ) => {
+        $(#[$attr])*
+        #[repr(C)]
+        $vis struct $name {
+            $(
+                $(#[$field_attr])*
+                $field_vis $field: $ty,
+            )*
+        }
+
+        const _: () = {
+            const fn is_register<R: $crate::mmio::Register>() {}
+
+            $(
+                is_register::<$ty>();
+                assert!(
+                    core::mem::offset_of!($name, $field) == $offset,
+                    concat!(
+                        "register_block!: ",
+                        stringify!($name),
+                        "::",
+                        stringify!($field),
+                        " is not at offset ",
+                        stringify!($offset)
+                    )
+                );
+            )*
+        };
+
+        unsafe impl $crate::mmio::Register for $name {}
+
+        impl core::fmt::Debug for $name {
+            // Reading registers can have side effects, so they are not printed.
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                f.debug_struct(stringify!($name)).finish_non_exhaustive()
+            }
+        }
+    };
+}
+
+/// A window of device memory, e.g. a BAR or the local APIC page, reached through the
+/// direct map.
+///
+/// Every access is volatile and checked to be aligned and inside the window.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    base: u64,
+    size: u64,
+}
+
+impl Region {
+    /// Creates a window of `size` bytes of device memory at virtual address `base`.
+    ///
+    /// # Safety
+    /// The memory must be mapped, uncached, and stay so for as long as the window is
+    /// used.
+    pub const unsafe fn new(base: u64, size: u64) -> Self {
+        Self { base, size }
+    }
+
+    pub const fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn check<T>(&self, offset: u64) -> *mut T {
+        assert!(
+            offset.is_multiple_of(align_of::<T>() as u64)
+                && offset
+                    .checked_add(size_of::<T>() as u64)
+                    .is_some_and(|x| x <= self.size),
+            "mmio::Region::check(): access at {offset:#x} outside region of {:#x} bytes",
+            self.size
+        );
+
+        (self.base + offset) as *mut T
+    }
+
+    /// Reads the register at `offset`.
+    #[inline]
+    pub fn read<T: Copy>(&self, offset: u64) -> T {
+        unsafe { self.check::<T>(offset).read_volatile() }
+    }
+
+    /// Writes the register at `offset`.
+    #[inline]
+    pub fn write<T: Copy>(&self, offset: u64, value: T) {
+        unsafe { self.check::<T>(offset).write_volatile(value) }
+    }
+
+    /// Returns the registers at `offset` laid out as `B`, usually a block defined with
+    /// [`register_block!`](crate::register_block).
+    pub fn block<B: Register>(&self, offset: u64) -> &B {
+        unsafe { &*self.check::<B>(offset) }
+    }
+}
@@ -0,0 +1,260 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Fixed-size lock-free FIFO for handing values from interrupt handlers to threads.
+///
+/// Any number of producers may push at once, including an interrupt handler that
+/// interrupted another push on the same processor, which would deadlock on a lock.
+/// Popping is lock-free as well, but the queue is meant to be drained by one thread.
+///
+/// Every slot has a stamp telling which lap of the ring it belongs to and whether it
+/// is filled, so a push claims a position with a single compare-and-swap and never
+/// waits for another push to finish writing its slot.
+pub struct Queue<T, const N: usize> {
+    slots: [Slot<T>; N],
+    // Position of the next push.
+    tail: AtomicUsize,
+    // Position of the next pop.
+    head: AtomicUsize,
+}
+
+struct Slot<T> {
+    // Twice the lap the slot was last emptied for, plus one once it is filled again.
+    stamp: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Slot<T> {
+    const fn new() -> Self {
+        Self {
+            stamp: AtomicUsize::new(0),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+}
+
+unsafe impl<T: Send, const N: usize> Send for Queue<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for Queue<T, N> {}
+
+impl<T, const N: usize> Queue<T, N> {
+    pub const fn new() -> Self {
+        assert!(N > 0, "mpsc::Queue::new(): capacity must not be zero");
+
+        Self {
+            slots: [const { Slot::new() }; N],
+            tail: AtomicUsize::new(0),
+            head: AtomicUsize::new(0),
+        }
+    }
+
+    /// Gets the number of values the queue can hold.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Gets the number of values waiting to be popped. Only a hint while producers or
+    /// consumers are running.
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        tail.saturating_sub(head).min(N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Appends a value. Gives it back if the queue is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[pos % N];
+            let lap = pos / N;
+            let stamp = slot.stamp.load(Ordering::Acquire);
+
+            if stamp == 2 * lap {
+                match self.tail.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.stamp.store(2 * lap + 1, Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(x) => pos = x,
+                }
+            } else if stamp < 2 * lap {
+                // The slot still holds the value of the previous lap.
+                return Err(value);
+            } else {
+                // Another producer claimed the position first.
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Removes and returns the oldest value, or `None` if the queue is empty or the
+    /// oldest value is still being pushed.
+    pub fn pop(&self) -> Option<T> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[pos % N];
+            let lap = pos / N;
+            let stamp = slot.stamp.load(Ordering::Acquire);
+
+            if stamp == 2 * lap + 1 {
+                match self.head.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        slot.stamp.store(2 * (lap + 1), Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(x) => pos = x,
+                }
+            } else if stamp < 2 * lap + 1 {
+                return None;
+            } else {
+                // Another consumer took the value first.
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<T, const N: usize> Default for Queue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for Queue<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::{Ordering, Queue};
+
+    #[test]
+    fn wraps_around() {
+        let queue = Queue::<u32, 4>::new();
+
+        // Every lap reuses the slots, with the stamps telling the laps apart.
+        for value in 0..10 {
+            assert!(queue.push(value).is_ok());
+            assert!(queue.push(value + 100).is_ok());
+            assert_eq!(queue.pop(), Some(value));
+            assert_eq!(queue.pop(), Some(value + 100));
+            assert_eq!(queue.pop(), None);
+        }
+
+        assert_eq!(queue.tail.load(Ordering::Relaxed), 20);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn full_queue_gives_the_value_back() {
+        let queue = Queue::<u32, 3>::new();
+
+        for value in 0..3 {
+            assert!(queue.push(value).is_ok());
+        }
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.push(3), Err(3));
+
+        // Popping one value makes room for exactly one more, in the next lap.
+        assert_eq!(queue.pop(), Some(0));
+        assert!(queue.push(3).is_ok());
+        assert_eq!(queue.push(4), Err(4));
+
+        let values: Vec<u32> = core::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(values, [1, 2, 3]);
+    }
+
+    #[test]
+    fn pop_waits_for_push_in_flight() {
+        let queue = Queue::<u32, 4>::new();
+
+        // A producer claimed position 0 but was interrupted before writing its value.
+        queue.tail.store(1, Ordering::Relaxed);
+
+        // Later pushes go behind it, and nothing can be popped until it is done.
+        assert!(queue.push(1).is_ok());
+        assert_eq!(queue.pop(), None);
+
+        unsafe { (*queue.slots[0].value.get()).write(0) };
+        queue.slots[0].stamp.store(1, Ordering::Release);
+
+        assert_eq!(queue.pop(), Some(0));
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn drop_frees_values_left() {
+        let value = Arc::new(());
+        let queue = Queue::<Arc<()>, 4>::new();
+
+        assert!(queue.push(value.clone()).is_ok());
+        assert!(queue.push(value.clone()).is_ok());
+        assert_eq!(Arc::strong_count(&value), 3);
+
+        drop(queue);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn concurrent_producers() {
+        const PRODUCERS: usize = 4;
+        const VALUES: usize = 10_000;
+
+        let queue = Arc::new(Queue::<(usize, usize), 16>::new());
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|producer| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    for i in 0..VALUES {
+                        let mut value = (producer, i);
+                        while let Err(x) = queue.push(value) {
+                            value = x;
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        // Values of one producer arrive in the order it pushed them.
+        let mut next = [0; PRODUCERS];
+        while next.iter().any(|&x| x < VALUES) {
+            match queue.pop() {
+                Some((producer, i)) => {
+                    assert_eq!(i, next[producer]);
+                    next[producer] += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert!(queue.is_empty());
+    }
+}
@@ -1,10 +1,14 @@
 use core::fmt;
-use core::marker::PhantomData;
 use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use bitflags::bitflags;
 
-use x86_64::PhysAddr;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::layout::{BOOT_MAP_SIZE, DIRECT_MAP_BASE, DIRECT_MAP_SIZE};
+use crate::memory;
+use crate::{assert_offset, assert_size};
 
 bitflags! {
     /// Flags for multiboot info structure.
@@ -22,6 +26,47 @@ bitflags! {
         const BOOT_LOADER_NAME  = 1 << 9;
         const APM_TABLE         = 1 << 10;
         const VIDEO_INFO        = 1 << 11;
+        const FRAMEBUFFER_INFO  = 1 << 12;
+    }
+}
+
+/// Maximum number of entries a memory map may have. Real maps have a few dozen.
+pub const MAX_MEMORY_AREAS: usize = 128;
+
+/// Start of the highest page a physical address can be in. Areas are cut off there
+/// rather than handing out addresses the processor cannot have.
+const MAX_PAGE_ADDRESS: u64 = (1 << 52) - 4096;
+
+/// Longest string that is read from the bootloader, without the terminating NUL.
+pub const MAX_STRING_LEN: usize = 4096;
+
+/// Reason the memory map the bootloader passed cannot be used.
+#[derive(Debug, Clone, Copy)]
+pub enum MemoryMapError {
+    /// The bootloader did not pass a memory map.
+    Missing,
+    /// The map does not lie within the memory mapped during boot.
+    Unmapped { addr: u32, len: u32 },
+    /// The entry at this offset into the map is too short or does not fit into it.
+    Truncated { offset: usize },
+    /// The map has more than [`MAX_MEMORY_AREAS`] entries.
+    TooManyEntries,
+}
+
+impl fmt::Display for MemoryMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing => write!(f, "multiboot structure did not have memory map"),
+            Self::Unmapped { addr, len } => {
+                write!(f, "memory map at {addr:#x} ({len} bytes) is not mapped")
+            }
+            Self::Truncated { offset } => {
+                write!(f, "memory map entry at offset {offset} is malformed")
+            }
+            Self::TooManyEntries => {
+                write!(f, "memory map has more than {MAX_MEMORY_AREAS} entries")
+            }
+        }
     }
 }
 
@@ -51,24 +96,32 @@ impl fmt::Display for MemoryAreaType {
     }
 }
 
-#[repr(C, packed)]
+/// Entry of the memory map, decoded from the buffer the bootloader passed.
 #[derive(Debug, Clone)]
 pub struct MemoryArea {
-    size: u32,
     addr: u64,
     len: u64,
-    area_type: MemoryAreaType,
+    area_type: u32,
 }
 
 impl MemoryArea {
+    /// Size in bytes of an entry without its `size` field.
+    const SIZE: usize = 20;
+
     /// The start address of the memory region.
     pub fn start_address(&self) -> PhysAddr {
-        PhysAddr::new(self.addr).align_up(4096u64)
+        PhysAddr::new(self.addr.min(MAX_PAGE_ADDRESS)).align_up(4096u64)
     }
 
     /// The end address of the memory region.
     pub fn end_address(&self) -> PhysAddr {
-        PhysAddr::new(self.addr + self.len).align_down(4096u64)
+        PhysAddr::new(self.addr.saturating_add(self.len).min(MAX_PAGE_ADDRESS)).align_down(4096u64)
+    }
+
+    /// The exact start and end address of the memory region, where the ones above are
+    /// rounded inwards to pages.
+    pub fn bounds(&self) -> (u64, u64) {
+        (self.addr, self.addr.saturating_add(self.len))
     }
 
     /// The size, in bytes, of the memory region.
@@ -77,8 +130,43 @@ impl MemoryArea {
     }
 
     /// The type of the memory region.
+    ///
+    /// Types the multiboot specification does not know are reserved.
     pub fn area_type(&self) -> MemoryAreaType {
-        self.area_type
+        match self.area_type {
+            0 => MemoryAreaType::Invalid,
+            1 => MemoryAreaType::Available,
+            3 => MemoryAreaType::AcpiReclaimable,
+            4 => MemoryAreaType::ReservedHibernate,
+            5 => MemoryAreaType::Defective,
+            _ => MemoryAreaType::Reserved,
+        }
+    }
+}
+
+/// File the bootloader loaded next to the kernel, like an initrd.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub mod_start: u32,
+    pub mod_end: u32,
+    cmdline: u32,
+    _reserved: u32,
+}
+
+assert_size!(Module, 16);
+
+impl Module {
+    /// The contents of the module, read through the direct map.
+    pub fn data(&self) -> &'static [u8] {
+        let ptr = memory::phys_to_virt(PhysAddr::new(self.mod_start as u64)).as_ptr::<u8>();
+        let len = self.mod_end.saturating_sub(self.mod_start) as usize;
+        unsafe { core::slice::from_raw_parts(ptr, len) }
+    }
+
+    /// The command line the module was given, usually starting with its file name.
+    pub fn cmdline(&self) -> Option<&'static str> {
+        read_string(self, self.cmdline)
     }
 }
 
@@ -98,43 +186,346 @@ pub struct MultibootInformation {
     pub drives_length: u32,
     pub drives_addr: u32,
     _unused1: u32,
-    pub boot_loader_name: u32,
+    boot_loader_name: u32,
     _unused2: [u16; 10],
 }
 
+// Fields up to the VBE information of the Multiboot 0.6.96 specification.
+assert_size!(MultibootInformation, 88);
+assert_offset!(MultibootInformation, mods_addr, 24);
+assert_offset!(MultibootInformation, mmap_length, 44);
+assert_offset!(MultibootInformation, boot_loader_name, 64);
+
+/// Framebuffer fields, which follow [`MultibootInformation`] if
+/// [`InfoFlags::FRAMEBUFFER_INFO`] is set. Bootloaders that do not set it may pass a
+/// shorter structure, so they are not part of it.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct FramebufferInfo {
+    addr: u64,
+    pitch: u32,
+    width: u32,
+    height: u32,
+    bpp: u8,
+    kind: u8,
+}
+
+assert_size!(FramebufferInfo, 22);
+
+/// Framebuffer the bootloader set up.
+#[derive(Debug, Clone, Copy)]
+pub struct Framebuffer {
+    pub addr: PhysAddr,
+    /// Bytes per line.
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+}
+
+impl Framebuffer {
+    /// Size in bytes of the framebuffer memory.
+    pub fn size(&self) -> u64 {
+        self.pitch as u64 * self.height as u64
+    }
+}
+
 impl MultibootInformation {
-    /// Return iterator over all memory areas.
-    /// Must check flags to see if MEM_MAP is present otherwise function will panic.
-    pub fn memory_areas(&self) -> MemoryAreaIter {
-        MemoryAreaIter {
-            current_area: self.mmap_addr,
-            last_area: self.mmap_length + self.mmap_addr,
-            phantom: PhantomData,
+    /// Returns an iterator over all memory areas, after checking that the memory map is
+    /// present, lies in memory the boot page table maps, and has at most
+    /// [`MAX_MEMORY_AREAS`] well-formed entries.
+    ///
+    /// Reads through the boot page table, so this only works during early boot.
+    pub fn memory_areas(&self) -> Result<MemoryAreaIter<'static>, MemoryMapError> {
+        if !self.flags.contains(InfoFlags::MEM_MAP) {
+            return Err(MemoryMapError::Missing);
+        }
+
+        let end = self.mmap_addr as u64 + self.mmap_length as u64;
+        if self.mmap_addr == 0 || end > BOOT_MAP_SIZE {
+            return Err(MemoryMapError::Unmapped {
+                addr: self.mmap_addr,
+                len: self.mmap_length,
+            });
+        }
+
+        let ptr = self.mmap_addr as usize as *const u8;
+        let buffer = unsafe { core::slice::from_raw_parts(ptr, self.mmap_length as usize) };
+
+        parse_memory_map(buffer)
+    }
+
+    /// Returns the kernel command line, if the bootloader passed one.
+    /// Reads through the same mapping as this structure, see [`read_string`].
+    pub fn cmdline(&self) -> Option<&'static str> {
+        if !self.flags.contains(InfoFlags::CMDLINE) {
+            return None;
+        }
+
+        read_string(self, self.cmdline)
+    }
+
+    /// Returns the name of the bootloader, if it passed one.
+    /// Reads through the same mapping as this structure, see [`read_string`].
+    pub fn boot_loader_name(&self) -> Option<&'static str> {
+        if !self.flags.contains(InfoFlags::BOOT_LOADER_NAME) {
+            return None;
+        }
+
+        read_string(self, self.boot_loader_name)
+    }
+
+    /// Returns the framebuffer the bootloader set up, if any.
+    pub fn framebuffer(&self) -> Option<Framebuffer> {
+        if !self.flags.contains(InfoFlags::FRAMEBUFFER_INFO) {
+            return None;
+        }
+
+        let info = unsafe {
+            (self as *const Self)
+                .add(1)
+                .cast::<FramebufferInfo>()
+                .read_unaligned()
+        };
+
+        Some(Framebuffer {
+            addr: PhysAddr::new(info.addr),
+            pitch: info.pitch,
+            width: info.width,
+            height: info.height,
+            bpp: info.bpp,
+        })
+    }
+
+    /// Calls `f` with the physical address and length of everything the bootloader
+    /// passed that the kernel reads after boot: this structure, the memory map, the
+    /// module list, and the command lines and bootloader name. The modules themselves
+    /// are left out.
+    ///
+    /// Works through either mapping, like [`read_string`].
+    pub fn for_each_boot_data(&self, mut f: impl FnMut(PhysAddr, u64)) {
+        let info = memory::virt_to_phys(VirtAddr::from_ptr(self));
+        f(info, size_of::<Self>() as u64);
+
+        let string = |s: &str| {
+            let pa = memory::virt_to_phys(VirtAddr::from_ptr(s.as_ptr()));
+            (pa, s.len() as u64 + 1)
+        };
+
+        if self.flags.contains(InfoFlags::MEM_MAP) && self.mmap_addr != 0 {
+            f(
+                PhysAddr::new(self.mmap_addr as u64),
+                self.mmap_length as u64,
+            );
+        }
+
+        let mut modules: &[Module] = &[];
+        if self.flags.contains(InfoFlags::MODS) && self.mods_addr != 0 {
+            let len = self.mods_count as u64 * size_of::<Module>() as u64;
+            f(PhysAddr::new(self.mods_addr as u64), len);
+
+            // Through the same mapping as this structure, the direct map may not exist.
+            let base = self as *const Self as u64 - info.as_u64();
+            let ptr = (base + self.mods_addr as u64) as *const Module;
+            modules = unsafe { core::slice::from_raw_parts(ptr, self.mods_count as usize) };
+        }
+
+        let commands = modules.iter().filter_map(|x| read_string(self, x.cmdline));
+        for s in self
+            .cmdline()
+            .into_iter()
+            .chain(self.boot_loader_name())
+            .chain(commands)
+        {
+            let (pa, len) = string(s);
+            f(pa, len);
+        }
+    }
+
+    /// Returns the modules the bootloader loaded, read through the direct map.
+    pub fn modules(&self) -> &'static [Module] {
+        if !self.flags.contains(InfoFlags::MODS) || self.mods_count == 0 {
+            return &[];
+        }
+
+        let ptr = memory::phys_to_virt(PhysAddr::new(self.mods_addr as u64)).as_ptr::<Module>();
+        unsafe { core::slice::from_raw_parts(ptr, self.mods_count as usize) }
+    }
+}
+
+/// Reads the NUL terminated string the bootloader left at physical address `addr`.
+///
+/// The string is read through the mapping `via`, the structure the address was found
+/// in, was read through: the direct map if it lies in the higher half, otherwise the
+/// boot page table. Returns `None` if `addr` is zero, or the string is not terminated
+/// within [`MAX_STRING_LEN`] bytes or the end of that mapping. Bytes that are not UTF-8
+/// cut the string off, so that whatever came before them can still be used.
+fn read_string<T>(via: &T, addr: u32) -> Option<&'static str> {
+    if addr == 0 {
+        return None;
+    }
+
+    let (base, mapped) = if via as *const T as u64 >= DIRECT_MAP_BASE {
+        (DIRECT_MAP_BASE, DIRECT_MAP_SIZE)
+    } else {
+        (0, BOOT_MAP_SIZE)
+    };
+
+    let ptr = (base + addr as u64) as *const u8;
+    let limit = mapped
+        .checked_sub(addr as u64)?
+        .min(MAX_STRING_LEN as u64 + 1) as usize;
+
+    let len = (0..limit).find(|&i| unsafe { ptr.add(i).read() } == 0)?;
+    let bytes = unsafe { core::slice::from_raw_parts(ptr, len) };
+
+    match core::str::from_utf8(bytes) {
+        Ok(string) => Some(string),
+        Err(err) => core::str::from_utf8(&bytes[..err.valid_up_to()]).ok(),
+    }
+}
+
+// Physical address of the multiboot structure the bootloader passed.
+static INFO: AtomicUsize = AtomicUsize::new(0);
+
+/// Remembers the multiboot structure the bootloader passed to the kernel, for [`info`].
+pub fn init(mbi: *const MultibootInformation) {
+    INFO.store(mbi as usize, Ordering::Relaxed);
+}
+
+/// Returns the multiboot structure through the direct map, so it can be read after the
+/// boot page table is gone.
+pub fn info() -> Option<&'static MultibootInformation> {
+    match INFO.load(Ordering::Relaxed) {
+        0 => None,
+        mbi => {
+            let va = memory::phys_to_virt(PhysAddr::new(mbi as u64));
+            Some(unsafe { &*va.as_ptr::<MultibootInformation>() })
         }
     }
 }
 
+/// Returns the multiboot structure through the boot page table, for code that runs
+/// before the direct map exists.
+pub fn early_info() -> Option<&'static MultibootInformation> {
+    match INFO.load(Ordering::Relaxed) {
+        0 => None,
+        mbi => Some(unsafe { &*(mbi as *const MultibootInformation) }),
+    }
+}
+
+/// Returns the arguments on the kernel command line, separated by whitespace.
+///
+/// The command line is read through the boot page table until memory management is
+/// up, so this works at any point of the boot.
+pub fn args() -> impl Iterator<Item = &'static str> {
+    let info = if memory::INIT.is_done() {
+        info()
+    } else {
+        early_info()
+    };
+
+    info.and_then(|x| x.cmdline())
+        .unwrap_or("")
+        .split_ascii_whitespace()
+}
+
+/// Returns the value of every `<key>=<value>` argument on the kernel command line, in
+/// the order they were given.
+pub fn arg(key: &str) -> impl Iterator<Item = &'static str> + '_ {
+    args().filter_map(move |x| x.strip_prefix(key)?.strip_prefix('='))
+}
+
+/// Checks that the memory map in `buffer` has at most [`MAX_MEMORY_AREAS`] well-formed
+/// entries and returns an iterator over them.
+pub fn parse_memory_map(buffer: &[u8]) -> Result<MemoryAreaIter<'_>, MemoryMapError> {
+    // Walk the map once, so that the iterator handed out never stops early.
+    let mut rest = buffer;
+    let mut count = 0;
+
+    while !rest.is_empty() {
+        let Some((area, next)) = decode_area(rest) else {
+            return Err(MemoryMapError::Truncated {
+                offset: buffer.len() - rest.len(),
+            });
+        };
+
+        count += 1;
+        if count > MAX_MEMORY_AREAS {
+            return Err(MemoryMapError::TooManyEntries);
+        }
+
+        if matches!(area.area_type(), MemoryAreaType::Invalid) {
+            break;
+        }
+
+        rest = next;
+    }
+
+    Ok(MemoryAreaIter::new(buffer))
+}
+
+/// Iterator over the entries of a memory map buffer.
+///
+/// Entries are decoded from the bytes of the buffer and never read past its end. An
+/// entry that does not fit ends the iteration, [`parse_memory_map`] checks for that up
+/// front.
 #[derive(Debug, Clone, Copy)]
-pub struct MemoryAreaIter {
-    current_area: u32,
-    last_area: u32,
-    phantom: PhantomData<&'static MemoryArea>,
+pub struct MemoryAreaIter<'a> {
+    buffer: &'a [u8],
 }
 
-impl Iterator for MemoryAreaIter {
-    type Item = &'static MemoryArea;
+impl<'a> MemoryAreaIter<'a> {
+    /// Creates an iterator over the memory map in `buffer`.
+    pub fn new(buffer: &'a [u8]) -> Self {
+        Self { buffer }
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+/// Decodes the entry at the start of `buffer` and returns it with the rest of the
+/// buffer, or `None` if it does not fit.
+fn decode_area(buffer: &[u8]) -> Option<(MemoryArea, &[u8])> {
+    // Every entry starts with its size, which does not count the size field itself.
+    let size = read_u32(buffer.get(..size_of::<u32>())?) as usize;
+    let entry = &buffer[size_of::<u32>()..];
+
+    if size < MemoryArea::SIZE || size > entry.len() {
+        return None;
+    }
+
+    let area = MemoryArea {
+        addr: read_u64(&entry[0..]),
+        len: read_u64(&entry[8..]),
+        area_type: read_u32(&entry[16..]),
+    };
+
+    Some((area, &entry[size..]))
+}
+
+impl Iterator for MemoryAreaIter<'_> {
+    type Item = MemoryArea;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.current_area >= self.last_area {
+        let Some((area, rest)) = decode_area(self.buffer) else {
+            self.buffer = &[];
+            return None;
+        };
+
+        self.buffer = rest;
+
+        if matches!(area.area_type(), MemoryAreaType::Invalid) {
+            self.buffer = &[];
             None
         } else {
-            let area = unsafe { &*(self.current_area as *const MemoryArea) };
-            self.current_area += (area.size as usize + size_of::<u32>()) as u32;
-            if matches!(area.area_type, MemoryAreaType::Invalid) {
-                None
-            } else {
-                Some(area)
-            }
+            Some(area)
         }
     }
 }
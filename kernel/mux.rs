@@ -0,0 +1,279 @@
+use core::fmt;
+use core::fmt::Write;
+
+use spin::Mutex;
+
+use crate::arch::{Console, Current};
+use crate::console;
+use crate::console::uart;
+use crate::log;
+use crate::ring::{Overflow, RingBuffer};
+use crate::shell;
+use crate::shell::{Command, CommandError};
+use crate::tty::{Mode, Request, Tty, TtyError};
+
+/// Number of channels multiplexed over the serial line.
+pub const CHANNEL_COUNT: usize = 4;
+
+/// Channel carrying the kernel shell.
+pub const SHELL: usize = 0;
+
+/// Channel carrying application output.
+pub const APP: usize = 1;
+
+/// Bytes of output kept for a channel while it is not shown.
+const BACKLOG_SIZE: usize = 2048;
+
+/// Prefix key of the multiplexer commands, the same as tmux. Ctrl-A is avoided
+/// because QEMU uses it for its own serial multiplexer.
+const PREFIX: u8 = uart::ctrl(b'B');
+
+const NAMES: [&str; CHANNEL_COUNT] = ["shell", "app", "aux1", "aux2"];
+
+// Terminals of the channels, each writes through the multiplexer.
+static CHANNELS: [Tty; CHANNEL_COUNT] = [
+    Tty::new(output::<0>),
+    Tty::new(output::<1>),
+    Tty::new(output::<2>),
+    Tty::new(output::<3>),
+];
+
+// Active channel and output of the channels in the background.
+static MUX: Mutex<Mux> = Mutex::new(Mux::new());
+
+/// Output of a background channel, the oldest output is dropped first.
+struct Backlog(RingBuffer<BACKLOG_SIZE>);
+
+impl Write for Backlog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(|x| {
+            self.0.push(x);
+        });
+        Ok(())
+    }
+}
+
+struct Mux {
+    active: usize,
+    // Whether the previous input byte was the prefix key.
+    prefix: bool,
+    backlogs: [Backlog; CHANNEL_COUNT],
+}
+
+impl Mux {
+    const fn new() -> Self {
+        Self {
+            active: SHELL,
+            prefix: false,
+            backlogs: [const { Backlog(RingBuffer::new(Overflow::DropOldest)) }; CHANNEL_COUNT],
+        }
+    }
+
+    /// Shows another channel and replays the output it produced in the background.
+    fn switch(&mut self, channel: usize) {
+        if channel >= CHANNEL_COUNT || channel == self.active {
+            return;
+        }
+
+        self.active = channel;
+        crate::print!(
+            "\n\x1b[7m[mux: channel {channel} ({})]\x1b[0m\n",
+            NAMES[channel]
+        );
+
+        let backlog = &mut self.backlogs[channel].0;
+        while let Some(ch) = backlog.pop() {
+            Current::write_bytes(&[ch]);
+        }
+    }
+}
+
+fn output<const N: usize>(args: fmt::Arguments) {
+    write(N, args);
+}
+
+/// Writes output to a channel. Output of background channels is buffered until they
+/// are switched to.
+pub fn write(channel: usize, args: fmt::Arguments) {
+    let mut mux = MUX.lock();
+
+    if mux.active == channel {
+        console::print(args);
+    } else {
+        let _ = mux.backlogs[channel].write_fmt(args);
+    }
+}
+
+/// Returns the terminal of a channel.
+pub fn channel(channel: usize) -> &'static Tty {
+    &CHANNELS[channel]
+}
+
+/// Returns the channel currently shown on the serial line.
+pub fn active() -> usize {
+    MUX.lock().active
+}
+
+/// Shows a channel on the serial line.
+pub fn switch(channel: usize) {
+    MUX.lock().switch(channel);
+}
+
+/// Routes bytes received on the serial line to the active channel, handling
+/// multiplexer commands on the way.
+///
+/// The prefix key followed by a digit switches to that channel, followed by `n`
+/// switches to the next channel, and pressing it twice sends it to the channel.
+pub fn input(data: impl IntoIterator<Item = u8>) {
+    let mut pending = [0u8; 64];
+    let mut len = 0;
+    let mut active = active();
+
+    for ch in data {
+        let mut mux = MUX.lock();
+
+        if mux.prefix {
+            mux.prefix = false;
+
+            let target = match ch {
+                b'0'..=b'9' => Some((ch - b'0') as usize),
+                b'n' => Some((mux.active + 1) % CHANNEL_COUNT),
+                PREFIX => None,
+                _ => continue,
+            };
+
+            if let Some(target) = target {
+                // Input typed so far still belongs to the previous channel.
+                drop(mux);
+                CHANNELS[active].input(pending[..len].iter().copied());
+                len = 0;
+
+                let mut mux = MUX.lock();
+                mux.switch(target);
+                active = mux.active;
+                continue;
+            }
+        } else if ch == PREFIX {
+            mux.prefix = true;
+            continue;
+        }
+
+        drop(mux);
+
+        if len == pending.len() {
+            CHANNELS[active].input(pending.iter().copied());
+            len = 0;
+        }
+
+        pending[len] = ch;
+        len += 1;
+    }
+
+    CHANNELS[active].input(pending[..len].iter().copied());
+}
+
+fn mux_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            // Output goes through the multiplexer, so the lock cannot be held for it.
+            let (active, backlogs) = {
+                let mux = MUX.lock();
+                let backlogs = mux.backlogs.each_ref().map(|x| (x.0.len(), x.0.dropped()));
+                (mux.active, backlogs)
+            };
+
+            for (i, name) in NAMES.iter().enumerate() {
+                let marker = if i == active { '*' } else { ' ' };
+                let (held, dropped) = backlogs[i];
+                writeln!(
+                    out,
+                    "{marker} {i} {name}, {held}/{BACKLOG_SIZE} bytes held, {dropped} dropped"
+                )?;
+            }
+            Ok(())
+        }
+        [channel] => match channel.parse::<usize>() {
+            Ok(channel) if channel < CHANNEL_COUNT => {
+                switch(channel);
+                Ok(())
+            }
+            _ => Err(CommandError::Failed("no such channel")),
+        },
+        _ => Err(CommandError::Usage),
+    }
+}
+
+fn tty_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    let (channel, request) = match args {
+        [channel] => (channel, None),
+        [channel, "raw"] => (channel, Some(Request::SetMode(Mode::RAW))),
+        [channel, "cooked"] => (channel, Some(Request::SetMode(Mode::COOKED))),
+        [channel, "flush"] => (channel, Some(Request::FlushInput)),
+        _ => return Err(CommandError::Usage),
+    };
+
+    let channel = match channel.parse::<usize>() {
+        Ok(channel) if channel < CHANNEL_COUNT => channel,
+        _ => return Err(CommandError::Failed("no such channel")),
+    };
+
+    // The shell reads whole lines, it would never see one again in raw mode.
+    if channel == SHELL && matches!(request, Some(Request::SetMode(_))) {
+        return Err(CommandError::Failed("the shell channel stays cooked"));
+    }
+
+    let tty = &CHANNELS[channel];
+    let ioctl = |request| {
+        tty.ioctl(request).map_err(|e| match e {
+            TtyError::InvalidMode => CommandError::Failed("invalid mode"),
+        })
+    };
+
+    if let Some(request) = request {
+        ioctl(request)?;
+    }
+
+    let mode = Mode::from_bits_truncate(ioctl(Request::GetMode)? as u8);
+    let available = ioctl(Request::InputAvailable)?;
+    let space = ioctl(Request::InputSpace)?;
+    let dropped = ioctl(Request::InputDropped)?;
+
+    writeln!(
+        out,
+        "{channel} {}: {mode:?}, {available} bytes available, {space} free, {dropped} dropped",
+        NAMES[channel]
+    )?;
+    Ok(())
+}
+
+/// Initializes the serial multiplexer.
+///
+/// Every channel has its own terminal with its own line discipline, but only the
+/// active channel is shown on the serial line. Output of the others is buffered and
+/// replayed when they are switched to.
+pub fn init() {
+    let commands = [
+        Command {
+            name: "mux",
+            usage: "[<channel>]",
+            help: "list serial channels or switch to one",
+            run: mux_command,
+        },
+        Command {
+            name: "tty",
+            usage: "<channel> [raw|cooked|flush]",
+            help: "show or change the terminal of a serial channel",
+            run: tty_command,
+        },
+    ];
+
+    for command in commands {
+        assert!(
+            shell::register(command),
+            "mux::init(): failed to register {}",
+            command.name
+        );
+    }
+
+    log!("mux::init(): {CHANNEL_COUNT} channels, press ^B <n> to switch [ \x1b[0;32mOK\x1b[0m ]");
+}
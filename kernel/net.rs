@@ -1,169 +1,1044 @@
-use core::ptr::NonNull;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::fmt::Write;
+use core::mem::offset_of;
+use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
 
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+use crate::critical::IrqMutex;
+use crate::endian::Le32;
+#[cfg(feature = "fault-injection")]
+use crate::fault;
+use crate::heap::PageBox;
+use crate::initcall::InitGuard;
 use crate::log;
+use crate::memory;
+use crate::mpsc;
+use crate::multiboot;
 use crate::pci;
+use crate::pci::DeviceEvent;
+use crate::power;
+use crate::power::ShutdownKind;
+use crate::replay;
+use crate::replay::{FrameHandler, Source};
+use crate::shell;
+use crate::shell::{Command, CommandError};
+use crate::timer;
+use crate::trap;
+use crate::virtio;
+use crate::virtio::{DeviceType, InterruptStatus, Transport};
+
+pub use crate::socket::{Error, ErrorKind, ToSocketAddrs, UdpSocket};
+#[cfg(feature = "net-smoltcp")]
+pub use crate::socket::{Incoming, Shutdown, TcpListener, TcpStream};
+use crate::virtio_legacy::LegacyTransport;
+use crate::virtio_pci::PciTransport;
+use crate::virtqueue::{Buffer, QueueError, VirtQueue, VIRTIO_F_INDIRECT_DESC};
+use crate::workqueue;
+use crate::workqueue::Work;
+
+/// Feature bit: the device has a MAC address in its configuration.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+/// Feature bit: the device reports the link status in its configuration.
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+/// Feature bit: the device has a control queue.
+const VIRTIO_NET_F_CTRL_VQ: u64 = 1 << 17;
+/// Feature bit: the receive mode can be changed through the control queue.
+const VIRTIO_NET_F_CTRL_RX: u64 = 1 << 18;
+
+/// Index of the receive queue of the only queue pair we use.
+const RX_QUEUE: u16 = 0;
+
+/// Number of entries of the receive queue, the size QEMU gives it.
+const RX_QUEUE_SIZE: usize = 256;
+
+/// Number of buffers the device receives frames into.
+const RX_BUFFERS: usize = 64;
+
+/// Size of every receive buffer, room for the header and the largest frame. Without
+/// VIRTIO_NET_F_MRG_RXBUF a frame has to fit into one buffer.
+const RX_BUFFER_SIZE: usize = 2048;
+
+/// Index of the transmit queue of the only queue pair we use.
+const TX_QUEUE: u16 = 1;
+
+/// Number of entries of the transmit queue, the size QEMU gives it.
+const TX_QUEUE_SIZE: usize = 256;
+
+/// Number of frames that can wait for room in the transmit queue.
+const TX_BACKLOG_SIZE: usize = 64;
+
+/// Largest frame that can be sent, without the frame check sequence.
+pub const MAX_FRAME_SIZE: usize = 1514;
+
+/// Most physically contiguous pieces a frame is sent from, more scattered frames are
+/// copied into one buffer first.
+const MAX_TX_SEGMENTS: usize = 16;
+
+/// Size of `virtio_net_hdr` in front of every frame, see 5.1.6 "Device Operation". With
+/// VIRTIO_F_VERSION_1 it always has `num_buffers`, the legacy layout only has it with
+/// VIRTIO_NET_F_MRG_RXBUF, which we do not negotiate, and is two bytes shorter. All
+/// zeroes asks for no checksum or segmentation offload.
+const NET_HEADER_SIZE: usize = 12;
+const LEGACY_NET_HEADER_SIZE: usize = 10;
+
+/// Index of the control queue, after the receive and transmit queue of the only queue
+/// pair we use.
+const CTRL_QUEUE: u16 = 2;
+
+/// Number of entries of the control queue, the size QEMU gives it.
+const CTRL_QUEUE_SIZE: usize = 64;
+
+/// How long the device may take to answer a control command.
+const CTRL_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Control command classes and commands, see 5.1.6.5 "Control Virtqueue".
+const VIRTIO_NET_CTRL_RX: u8 = 0;
+const VIRTIO_NET_CTRL_RX_PROMISC: u8 = 0;
+const VIRTIO_NET_CTRL_RX_ALLMULTI: u8 = 1;
+const VIRTIO_NET_CTRL_MAC: u8 = 1;
+const VIRTIO_NET_CTRL_MAC_TABLE_SET: u8 = 0;
+
+/// Acknowledgement of a control command the device carried out.
+const VIRTIO_NET_OK: u8 = 0;
+
+/// Maximum number of multicast addresses in the filter.
+pub const MAX_MULTICAST_ADDRESSES: usize = 32;
+
+/// Room for the data of a control command, enough for an empty unicast table and a
+/// full multicast table.
+const CTRL_DATA_SIZE: usize = 8 + 6 * MAX_MULTICAST_ADDRESSES;
+
+// Offsets of the fields of `virtio_net_config`, see 5.1.4 "Device configuration layout".
+const CONFIG_MAC: usize = 0;
+const CONFIG_STATUS: usize = 6;
+
+/// Bit of the status field set while the link is up.
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+/// Locally administered address used if the device does not have one.
+const FALLBACK_MAC: MacAddress = MacAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+
+/// Address of the interface unless `ip=` is given on the kernel command line, the one
+/// QEMU user networking hands out.
+const DEFAULT_ADDRESS: [u8; 4] = [10, 0, 2, 15];
+const DEFAULT_PREFIX_LEN: u8 = 24;
+
+/// Router unless `gateway=` is given on the kernel command line, QEMU's.
+const DEFAULT_GATEWAY: [u8; 4] = [10, 0, 2, 2];
+
+/// Number of received frames that can wait for the network stack.
+const RX_BACKLOG_SIZE: usize = 32;
+
+// Initialization of the virtio-net device.
+pub static INIT: InitGuard = InitGuard::new("net");
+
+// The network device, once it was found. Its interrupt handler takes frames off the
+// receive queue.
+static NIC: IrqMutex<Option<VirtioNet<Box<dyn Transport + Send>>>> = IrqMutex::new(None);
+
+// PCI device of the network interface, until it is unplugged.
+static DEVICE: Mutex<Option<pci::DeviceConfig>> = Mutex::new(None);
+
+// Frames handed over by the receive interrupt, oldest first.
+static RX_FRAMES: mpsc::Queue<Vec<u8>, RX_BACKLOG_SIZE> = mpsc::Queue::new();
+
+// Number of frames taken off the receive queue.
+static RX_RECEIVED: AtomicU64 = AtomicU64::new(0);
+
+// Number of frames dropped because the network stack fell behind.
+static RX_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+// Deferred delivery of received frames.
+static RX_WORK: Work = Work::new(process_frames);
+
+// Network stack received frames are delivered to.
+static RECEIVER: AtomicUsize = AtomicUsize::new(0);
+
+// Frames waiting for room in the transmit queue, oldest first.
+static TX_BACKLOG: mpsc::Queue<TxFrame, TX_BACKLOG_SIZE> = mpsc::Queue::new();
+
+// Moves frames from TX_BACKLOG to the transmit queue.
+static TX_WORK: Work = Work::new(process_backlog);
 
-pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
-pub const VIRTIO_NET_DEVICE_ID: u16 = 0x1000;
-
-/// The offset of the bar field within `virtio_pci_cap`.
-const VIRTIO_PCI_CAP_BAR_OFFSET: u8 = 4;
-/// The offset of the offset field with `virtio_pci_cap`.
-const VIRTIO_PCI_CAP_OFFSET_OFFSET: u8 = 8;
-/// The offset of the `length` field within `virtio_pci_cap`.
-const VIRTIO_PCI_CAP_LENGTH_OFFSET: u8 = 12;
-/// The offset of the`notify_off_multiplier` field within `virtio_pci_notify_cap`.
-const VIRTIO_PCI_CAP_NOTIFY_OFF_MULTIPLIER_OFFSET: u8 = 16;
-
-/// Common configuration.
-const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
-/// Notifications.
-const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
-/// ISR Status.
-const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
-/// Device specific configuration.
-const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
-
-/// `virtio_pci_cap`, see section 4.1.4 Virtio Structure PCI Capabilities
-#[derive(Clone, Debug, Eq, PartialEq)]
-struct VirtioPciCapability {
-    bar: u8,
-    offset: u32,
-    length: u32,
-}
-
-/// `virtio_pci_common_cfg`, see 4.1.4.3 "Common configuration structure layout".
+// Number of frames handed to the device.
+static TX_SENT: AtomicU64 = AtomicU64::new(0);
+
+// Number of frames refused because the transmit queue and the backlog were full.
+static TX_BLOCKED: AtomicU64 = AtomicU64::new(0);
+
+/// Ethernet address of a network interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    /// Returns the address frames to the multicast group `ip` go to, see RFC 1112.
+    pub fn multicast(ip: Ipv4Addr) -> Self {
+        let [_, b, c, d] = ip.octets();
+        Self([0x01, 0x00, 0x5e, b & 0x7f, c, d])
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+/// Reasons a network interface refused to send a frame or to change its receive mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NicError {
+    /// The device cannot change its receive mode.
+    Unsupported,
+    /// More multicast addresses were given than fit into the filter.
+    TooManyAddresses,
+    /// The command could not be queued.
+    Queue(QueueError),
+    /// The device did not answer in time.
+    TimedOut,
+    /// The device refused the command.
+    Rejected,
+}
+
+/// Static IPv4 configuration of the interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Config {
+    pub address: [u8; 4],
+    /// Length of the network prefix, e.g. 24 for a netmask of 255.255.255.0.
+    pub prefix_len: u8,
+    /// Router for addresses outside of the network, if there is one.
+    pub gateway: Option<[u8; 4]>,
+}
+
+/// Reasons [`transmit`] did not take a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxError {
+    /// There is no network interface, or it cannot send.
+    NoDevice,
+    /// The frame is larger than [`MAX_FRAME_SIZE`].
+    TooLarge,
+    /// The transmit queue and the frames waiting for it are full, try again once the
+    /// device caught up.
+    WouldBlock,
+}
+
+/// Network interface card, whatever the device behind it.
+pub trait Nic {
+    /// Returns the Ethernet address of the interface.
+    fn mac_address(&self) -> MacAddress;
+
+    /// Checks whether the link is up. Devices that cannot tell are always up.
+    fn link_up(&self) -> bool;
+
+    /// Receives every frame on the link instead of only those for the interface, e.g.
+    /// for packet capture.
+    fn set_promiscuous(&mut self, enabled: bool) -> Result<(), NicError>;
+
+    /// Receives every multicast frame instead of only those in the multicast filter.
+    fn set_all_multicast(&mut self, enabled: bool) -> Result<(), NicError>;
+
+    /// Replaces the multicast addresses frames are received for, e.g. those mDNS and
+    /// IPv6 neighbor discovery listen on.
+    fn set_multicast_filter(&mut self, addresses: &[MacAddress]) -> Result<(), NicError>;
+
+    /// Checks whether the transmit queue has room for another frame, however scattered.
+    fn can_transmit(&self) -> bool;
+
+    /// Hands a frame to the device without waiting for it to be sent. The device keeps
+    /// it until [`Nic::reclaim_transmitted`].
+    fn transmit(&mut self, frame: TxFrame) -> Result<(), NicError>;
+
+    /// Frees the frames the device is done sending and returns how many there were.
+    fn reclaim_transmitted(&mut self) -> usize;
+
+    /// Calls `f` with every frame the device received since the last call, without its
+    /// frame check sequence, and returns how many there were.
+    fn poll_received(&mut self, f: &mut dyn FnMut(&[u8])) -> usize;
+}
+
+/// A frame on its way to the device, made of buffers the device reads in place, e.g. the
+/// protocol headers and the payload, so that they need not be copied together first.
+pub struct TxFrame {
+    // Only kept so that the buffers live until the device is done reading them.
+    _parts: Vec<Vec<u8>>,
+    // Where the device finds the parts, in order.
+    segments: Vec<Buffer>,
+}
+
+impl TxFrame {
+    /// Makes a frame of `parts`, without its frame check sequence.
+    pub fn new(parts: Vec<Vec<u8>>) -> Result<Self, TxError> {
+        if parts.iter().map(Vec::len).sum::<usize>() > MAX_FRAME_SIZE {
+            return Err(TxError::TooLarge);
+        }
+
+        let mut segments = Vec::new();
+        for part in parts.iter().filter(|x| !x.is_empty()) {
+            memory::translate_range(
+                VirtAddr::from_ptr(part.as_ptr()),
+                part.len(),
+                |addr, len| {
+                    segments.push(Buffer {
+                        addr,
+                        len: len as u32,
+                    })
+                },
+            )
+            .expect("net::TxFrame::new(): buffer is not mapped");
+        }
+
+        // A frame is small, so its copy comes from the heap in one piece.
+        if segments.len() > MAX_TX_SEGMENTS {
+            return Self::new(vec![parts.concat()]);
+        }
+
+        Ok(Self {
+            _parts: parts,
+            segments,
+        })
+    }
+}
+
+/// `virtio_net_ctrl`, the buffer of a control command: the header and data the device
+/// reads, then the acknowledgement it writes.
 #[repr(C)]
-struct VirtioPciCommonCfg {
-    device_feature_select: u32,
-    device_feature: u32,
-    driver_feature_select: u32,
-    driver_feature: u32,
-    msix_config: u16,
-    num_queues: u16,
-    device_status: u8,
-    config_generation: u8,
-    queue_select: u16,
-    queue_size: u16,
-    queue_msix_vector: u16,
-    queue_enable: u16,
-    queue_notify_off: u16,
-    queue_desc: u64,
-    queue_driver: u64,
-    queue_device: u64,
-}
-
-#[derive(Debug)]
-struct VirtioTransportConfig {
-    // PCI information.
-    pci_cfg: pci::DeviceConfig,
-    // Common configuration structure.
-    common_cfg: NonNull<VirtioPciCommonCfg>,
-    // Start of queue notification region.
-    notify_region: NonNull<[u16]>,
-    notify_off_mulitplier: u32,
-    // The interrupt status register.
-    isr_status: NonNull<u8>,
-    // Device-specific configuration.
-    config_space: Option<NonNull<[u32]>>,
-}
-
-impl VirtioTransportConfig {
-    fn from_device_config(pci_device_cfg: &mut pci::DeviceConfig) -> VirtioTransportConfig {
-        use bit_field::BitField;
-
-        // Enable PCI bus mastering to allow virtio-net to do DMA.
-        pci_device_cfg.enable_bus_mastering();
-
-        // Find the PCI capabilities we need.
-        let mut common_cfg = None;
-        let mut notify_cfg = None;
-        let mut notify_off_multiplier = 0;
-        let mut isr_cfg = None;
-        let mut device_cfg = None;
-
-        // Find all of the virtio vendor specific capabilities.
-        for capability in pci_device_cfg
-            .capabilities()
-            .expect("could not find capabilities list for virtio-net driver")
-        {
-            if capability.id != pci::PCI_CAP_ID_VNDR {
-                continue;
-            }
+struct ControlRequest {
+    class: u8,
+    command: u8,
+    data: [u8; CTRL_DATA_SIZE],
+    ack: u8,
+}
 
-            let cap_len = capability.private_header.get_bits(0..8) as u8;
-            let cfg_type = capability.private_header.get_bits(8..16) as u8;
+/// virtio-net device, see 5.1 "Network Device".
+pub struct VirtioNet<T: Transport> {
+    transport: T,
+    // Features agreed on with the device.
+    features: u64,
+    // Size of `virtio_net_hdr` for these features.
+    header_len: usize,
+    // Queue frames are received on, if we could set it up.
+    rx_queue: Option<VirtQueue<RX_QUEUE_SIZE>>,
+    rx_buffers: PageBox<[[u8; RX_BUFFER_SIZE]; RX_BUFFERS]>,
+    // Buffer the device fills for each request, by its identifier.
+    rx_slots: Vec<Option<usize>>,
+    // Queue for changing the receive mode, if the device has one that we could set up.
+    ctrl_queue: Option<VirtQueue<CTRL_QUEUE_SIZE>>,
+    ctrl_request: PageBox<ControlRequest>,
+    // Queue frames are sent on, if we could set it up.
+    tx_queue: Option<VirtQueue<TX_QUEUE_SIZE>>,
+    // Frames the device is sending, by the identifier of their request.
+    tx_frames: Vec<Option<TxFrame>>,
+    // `virtio_net_hdr` sent in front of every frame, which the device only reads.
+    tx_header: PageBox<[u8; NET_HEADER_SIZE]>,
+}
 
-            if cap_len < 16 {
-                continue;
+impl<T: Transport> VirtioNet<T> {
+    /// Takes over a virtio-net device, agreeing on the features the driver supports.
+    pub fn new(mut transport: T) -> Result<Self, virtio::Error> {
+        let features = transport.negotiate(
+            VIRTIO_NET_F_MAC
+                | VIRTIO_NET_F_STATUS
+                | VIRTIO_NET_F_CTRL_VQ
+                | VIRTIO_NET_F_CTRL_RX
+                | VIRTIO_F_INDIRECT_DESC,
+        )?;
+
+        let mut ctrl_queue = None;
+        if features & VIRTIO_NET_F_CTRL_VQ != 0 {
+            let queue = VirtQueue::new(features);
+            let (desc, avail, used) = queue.addresses();
+
+            // Legacy devices insist on their own queue size, without the control queue
+            // we can still do everything but change the receive mode.
+            match transport.setup_queue(CTRL_QUEUE, queue.size() as u16, desc, avail, used) {
+                Ok(()) => ctrl_queue = Some(queue),
+                Err(e) => log!("net::VirtioNet::new(): no control queue: {e:?}"),
             }
+        }
 
-            let cap_info = VirtioPciCapability {
-                bar: pci_device_cfg.config_read_word(capability.offset + VIRTIO_PCI_CAP_BAR_OFFSET)
-                    as u8,
-                offset: pci_device_cfg
-                    .config_read_word(capability.offset + VIRTIO_PCI_CAP_OFFSET_OFFSET),
-                length: pci_device_cfg
-                    .config_read_word(capability.offset + VIRTIO_PCI_CAP_LENGTH_OFFSET),
-            };
+        let rx_queue = VirtQueue::new(features);
+        let (desc, avail, used) = rx_queue.addresses();
 
-            match cfg_type {
-                VIRTIO_PCI_CAP_COMMON_CFG => {
-                    common_cfg = if common_cfg.is_some() {
-                        common_cfg
-                    } else {
-                        Some(cap_info)
-                    };
+        let rx_queue =
+            match transport.setup_queue(RX_QUEUE, rx_queue.size() as u16, desc, avail, used) {
+                Ok(()) => Some(rx_queue),
+                Err(e) => {
+                    log!("net::VirtioNet::new(): no receive queue: {e:?}");
+                    None
                 }
-                VIRTIO_PCI_CAP_NOTIFY_CFG => {
-                    // 4.1.4.4 Notification structure layout
-                    // The notification location is found using the VIRTIO_PCI_CAP_NOTIFY_CFG capability.
-                    // This capability is immediately followed by an additional field, like so:
-                    //
-                    // struct virtio_pci_notify_cap {
-                    //         struct virtio_pci_cap cap;
-                    //         le32 notify_off_multiplier; /* Multiplier for queue_notify_off. */
-                    // };
-                    //
-
-                    notify_cfg = if notify_cfg.is_some() {
-                        notify_cfg
-                    } else {
-                        Some(cap_info)
-                    };
-                    notify_off_multiplier = pci_device_cfg.config_read_word(
-                        capability.offset + VIRTIO_PCI_CAP_NOTIFY_OFF_MULTIPLIER_OFFSET,
-                    );
-                }
-                VIRTIO_PCI_CAP_ISR_CFG => {
-                    isr_cfg = if isr_cfg.is_some() {
-                        isr_cfg
-                    } else {
-                        Some(cap_info)
-                    };
+            };
+
+        let mut tx_queue = VirtQueue::new(features);
+        let (desc, avail, used) = tx_queue.addresses();
+
+        // Sent frames are reclaimed on the next send, so their interrupts are only of use
+        // while frames wait for room, see `process_backlog`.
+        tx_queue.disable_interrupts();
+
+        let tx_queue =
+            match transport.setup_queue(TX_QUEUE, tx_queue.size() as u16, desc, avail, used) {
+                Ok(()) => Some(tx_queue),
+                Err(e) => {
+                    log!("net::VirtioNet::new(): no transmit queue: {e:?}");
+                    None
                 }
-                VIRTIO_PCI_CAP_DEVICE_CFG => {
-                    device_cfg = if device_cfg.is_some() {
-                        device_cfg
-                    } else {
-                        Some(cap_info)
-                    };
+            };
+
+        let header_len = if features & virtio::VIRTIO_F_VERSION_1 != 0 {
+            NET_HEADER_SIZE
+        } else {
+            LEGACY_NET_HEADER_SIZE
+        };
+
+        let mut nic = Self {
+            transport,
+            features,
+            header_len,
+            rx_queue,
+            // Large enough to be a physical region of its own.
+            rx_buffers: unsafe { PageBox::new_zeroed() },
+            rx_slots: (0..RX_QUEUE_SIZE).map(|_| None).collect(),
+            ctrl_queue,
+            ctrl_request: unsafe { PageBox::new_zeroed() },
+            tx_queue,
+            tx_frames: (0..TX_QUEUE_SIZE).map(|_| None).collect(),
+            tx_header: unsafe { PageBox::new_zeroed() },
+        };
+
+        // The device may only be notified once it is running.
+        for index in 0..RX_BUFFERS {
+            nic.add_rx_buffer(index);
+        }
+
+        nic.transport.finish_init();
+
+        if let Some(queue) = nic.rx_queue.as_mut() {
+            if queue.should_notify() {
+                nic.transport.notify(RX_QUEUE);
+            }
+        }
+
+        Ok(nic)
+    }
+
+    // Gives receive buffer `index` to the device, without notifying it.
+    fn add_rx_buffer(&mut self, index: usize) {
+        let Some(queue) = self.rx_queue.as_mut() else {
+            return;
+        };
+
+        let buffer = &self.rx_buffers[index];
+        let (addr, _) = memory::translate(VirtAddr::from_ptr(buffer.as_ptr()))
+            .expect("net::VirtioNet::add_rx_buffer(): buffer is not mapped");
+
+        // Buffers are aligned to their size, so none crosses a page.
+        let inputs = [Buffer {
+            addr,
+            len: RX_BUFFER_SIZE as u32,
+        }];
+
+        let id = queue
+            .add(&[], &inputs)
+            .expect("net::VirtioNet::add_rx_buffer(): receive queue is full");
+        self.rx_slots[id as usize] = Some(index);
+    }
+
+    // Acknowledges an interrupt and hands the frames received to the network stack.
+    //
+    // The receive interrupt is suppressed while the queue is drained, and frames that
+    // arrive before it is enabled again are drained too, so none wait for the next one.
+    // Transmit interrupts are only enabled while frames wait in the backlog.
+    fn interrupt(&mut self) {
+        let status = self.transport.ack_interrupt();
+        if !status.contains(InterruptStatus::QUEUE) {
+            return;
+        }
+
+        if let Some(queue) = self.tx_queue.as_mut() {
+            queue.disable_interrupts();
+        }
+
+        if !TX_BACKLOG.is_empty() {
+            workqueue::schedule(&TX_WORK);
+        }
+
+        let Some(queue) = self.rx_queue.as_mut() else {
+            return;
+        };
+        queue.disable_interrupts();
+
+        loop {
+            let count = self.poll_received(&mut |frame| {
+                #[cfg(feature = "fault-injection")]
+                if fault::drop_packet() {
+                    return;
                 }
-                _ => {}
+
+                receive(frame.to_vec());
+            });
+
+            RX_RECEIVED.fetch_add(count as u64, Ordering::Relaxed);
+
+            if self.rx_queue.as_mut().is_none_or(|x| x.enable_interrupts()) {
+                break;
+            }
+        }
+    }
+
+    // Resets the device and frees the buffers it had not returned, e.g. before a reboot,
+    // so it does not write to memory the next kernel uses.
+    fn shutdown(&mut self) {
+        if let Err(e) = self.transport.shutdown(CTRL_QUEUE + 1) {
+            log!("net::VirtioNet::shutdown(): could not reset the device: {e:?}");
+            return;
+        }
+
+        unsafe {
+            if let Some(queue) = self.rx_queue.as_mut() {
+                queue.reclaim(|id| self.rx_slots[id as usize] = None);
+                debug_assert_eq!(queue.num_free(), queue.size());
+            }
+
+            if let Some(queue) = self.tx_queue.as_mut() {
+                queue.reclaim(|id| self.tx_frames[id as usize] = None);
+                debug_assert_eq!(queue.num_free(), queue.size());
+            }
+
+            if let Some(queue) = self.ctrl_queue.as_mut() {
+                queue.reclaim(|_| {});
+            }
+        }
+    }
+
+    // Sends a control command and waits for the device to carry it out.
+    fn control(&mut self, class: u8, command: u8, data: &[u8]) -> Result<(), NicError> {
+        let queue = self.ctrl_queue.as_mut().ok_or(NicError::Unsupported)?;
+
+        if data.len() > CTRL_DATA_SIZE {
+            return Err(NicError::TooManyAddresses);
+        }
+
+        let request = &mut *self.ctrl_request;
+        request.class = class;
+        request.command = command;
+        request.data[..data.len()].copy_from_slice(data);
+        request.ack = !VIRTIO_NET_OK;
+
+        let base = self.ctrl_request.phys_addr();
+        let outputs = [
+            Buffer { addr: base, len: 2 },
+            Buffer {
+                addr: base + offset_of!(ControlRequest, data),
+                len: data.len() as u32,
+            },
+        ];
+        let inputs = [Buffer {
+            addr: base + offset_of!(ControlRequest, ack),
+            len: 1,
+        }];
+
+        // Commands without data have no data buffer.
+        let outputs = if data.is_empty() {
+            &outputs[..1]
+        } else {
+            &outputs[..]
+        };
+        queue.add(outputs, &inputs).map_err(NicError::Queue)?;
+
+        if queue.should_notify() {
+            self.transport.notify(CTRL_QUEUE);
+        }
+
+        timer::wait_until(|| queue.has_used(), CTRL_TIMEOUT).map_err(|_| NicError::TimedOut)?;
+        queue.pop_used();
+
+        let ack = unsafe { core::ptr::read_volatile(&self.ctrl_request.ack) };
+        if ack == VIRTIO_NET_OK {
+            Ok(())
+        } else {
+            Err(NicError::Rejected)
+        }
+    }
+
+    // Sends a command of the receive mode class, which needs VIRTIO_NET_F_CTRL_RX.
+    fn control_rx(&mut self, command: u8, enabled: bool) -> Result<(), NicError> {
+        if self.features & VIRTIO_NET_F_CTRL_RX == 0 {
+            return Err(NicError::Unsupported);
+        }
+
+        self.control(VIRTIO_NET_CTRL_RX, command, &[enabled as u8])
+    }
+}
+
+impl<T: Transport> Nic for VirtioNet<T> {
+    fn mac_address(&self) -> MacAddress {
+        if self.features & VIRTIO_NET_F_MAC == 0 {
+            return FALLBACK_MAC;
+        }
+
+        // Read all six bytes within one configuration generation.
+        loop {
+            let generation = self.transport.config_generation();
+            let mut mac = [0u8; 6];
+
+            for (i, byte) in mac.iter_mut().enumerate() {
+                *byte = self.transport.read_config_u8(CONFIG_MAC + i);
             }
+
+            if self.transport.config_generation() == generation {
+                return MacAddress(mac);
+            }
+        }
+    }
+
+    fn link_up(&self) -> bool {
+        self.features & VIRTIO_NET_F_STATUS == 0
+            || self.transport.read_config_u16(CONFIG_STATUS) & VIRTIO_NET_S_LINK_UP != 0
+    }
+
+    fn set_promiscuous(&mut self, enabled: bool) -> Result<(), NicError> {
+        self.control_rx(VIRTIO_NET_CTRL_RX_PROMISC, enabled)
+    }
+
+    fn set_all_multicast(&mut self, enabled: bool) -> Result<(), NicError> {
+        self.control_rx(VIRTIO_NET_CTRL_RX_ALLMULTI, enabled)
+    }
+
+    fn set_multicast_filter(&mut self, addresses: &[MacAddress]) -> Result<(), NicError> {
+        if self.features & VIRTIO_NET_F_CTRL_RX == 0 {
+            return Err(NicError::Unsupported);
+        }
+
+        if addresses.len() > MAX_MULTICAST_ADDRESSES {
+            return Err(NicError::TooManyAddresses);
         }
 
-        todo!()
+        // An empty unicast table, as the device always takes frames for its own address,
+        // then the multicast table, each a count followed by the addresses.
+        let mut data = [0u8; CTRL_DATA_SIZE];
+        data[4..8].copy_from_slice(&Le32::new(addresses.len() as u32).to_bytes());
+        for (chunk, address) in data[8..].as_chunks_mut::<6>().0.iter_mut().zip(addresses) {
+            *chunk = address.0;
+        }
+
+        let len = 8 + 6 * addresses.len();
+        self.control(
+            VIRTIO_NET_CTRL_MAC,
+            VIRTIO_NET_CTRL_MAC_TABLE_SET,
+            &data[..len],
+        )
+    }
+
+    fn can_transmit(&self) -> bool {
+        let segments = 1 + MAX_TX_SEGMENTS;
+        self.tx_queue.as_ref().is_some_and(|x| x.can_add(segments))
+    }
+
+    fn transmit(&mut self, frame: TxFrame) -> Result<(), NicError> {
+        let queue = self.tx_queue.as_mut().ok_or(NicError::Unsupported)?;
+
+        let mut buffers = Vec::with_capacity(1 + frame.segments.len());
+        buffers.push(Buffer {
+            addr: self.tx_header.phys_addr(),
+            len: self.header_len as u32,
+        });
+        buffers.extend_from_slice(&frame.segments);
+
+        let id = queue.add(&buffers, &[]).map_err(NicError::Queue)?;
+        self.tx_frames[id as usize] = Some(frame);
+
+        if queue.should_notify() {
+            self.transport.notify(TX_QUEUE);
+        }
+
+        Ok(())
+    }
+
+    fn reclaim_transmitted(&mut self) -> usize {
+        let Some(queue) = self.tx_queue.as_mut() else {
+            return 0;
+        };
+
+        let mut count = 0;
+        while let Some((id, _)) = queue.pop_used() {
+            self.tx_frames[id as usize] = None;
+            count += 1;
+        }
+
+        count
+    }
+
+    fn poll_received(&mut self, f: &mut dyn FnMut(&[u8])) -> usize {
+        let mut count = 0;
+        let mut refilled = false;
+
+        while let Some((id, len)) = self.rx_queue.as_mut().and_then(|x| x.pop_used()) {
+            let index = self.rx_slots[id as usize]
+                .take()
+                .expect("net::VirtioNet::poll_received(): device used an unknown buffer");
+
+            let len = (len as usize).min(RX_BUFFER_SIZE);
+            if len > self.header_len {
+                f(&self.rx_buffers[index][self.header_len..len]);
+                count += 1;
+            }
+
+            self.add_rx_buffer(index);
+            refilled = true;
+        }
+
+        if let Some(queue) = self.rx_queue.as_mut() {
+            if refilled && queue.should_notify() {
+                self.transport.notify(RX_QUEUE);
+            }
+        }
+
+        count
+    }
+}
+
+/// Calls `f` with the network interface, or returns `None` if there is none.
+pub fn with_nic<R>(f: impl FnOnce(&mut dyn Nic) -> R) -> Option<R> {
+    NIC.with(|nic| nic.as_mut().map(|nic| f(nic)))
+}
+
+/// Sets the network stack received frames are delivered to, `None` to drop them.
+pub fn set_receiver(handler: Option<FrameHandler>) {
+    RECEIVER.store(handler.map_or(0, |x| x as usize), Ordering::Release);
+}
+
+/// Hands a frame taken off the receive queue to the network stack, which gets it on
+/// the work queue. Returns false if the frame was dropped because too many are
+/// waiting.
+///
+/// Safe to call from interrupt handlers.
+pub fn receive(frame: Vec<u8>) -> bool {
+    if RX_FRAMES.push(frame).is_err() {
+        RX_DROPPED.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+
+    workqueue::schedule(&RX_WORK);
+    true
+}
+
+/// Delivers the frames queued by [`receive`], through the input recorder.
+fn process_frames() {
+    while let Some(frame) = RX_FRAMES.pop() {
+        if replay::input(Source::Network, &frame) {
+            deliver(&frame);
+        }
+    }
+}
+
+fn deliver(frame: &[u8]) {
+    match RECEIVER.load(Ordering::Acquire) {
+        0 => {}
+        handler => {
+            let handler: FrameHandler = unsafe { core::mem::transmute(handler) };
+            handler(frame);
+        }
+    }
+}
+
+/// Sends an Ethernet frame, without its frame check sequence, and returns once it is
+/// queued. The frame is copied, see [`transmit_parts`] to avoid that.
+pub fn transmit(frame: &[u8]) -> Result<(), TxError> {
+    transmit_frame(TxFrame::new(vec![frame.to_vec()])?)
+}
+
+/// Sends an Ethernet frame made of `parts`, e.g. protocol headers and a payload, and
+/// returns once it is queued. The device reads every part where it is, as one or more
+/// descriptors, and the parts are freed once it is done.
+pub fn transmit_parts(parts: Vec<Vec<u8>>) -> Result<(), TxError> {
+    transmit_frame(TxFrame::new(parts)?)
+}
+
+/// Queues `frame` for the device.
+///
+/// The frame goes straight to the transmit queue if it has room. Otherwise it waits in a
+/// bounded backlog that a work item moves to the queue as the device catches up, and
+/// once that is full too, the frame is refused with [`TxError::WouldBlock`] instead of
+/// being dropped. Frames are sent in the order they were queued.
+pub fn transmit_frame(frame: TxFrame) -> Result<(), TxError> {
+    NIC.with(|nic| {
+        let nic = nic.as_mut().ok_or(TxError::NoDevice)?;
+
+        // A dropped frame is lost on the wire, the sender cannot tell.
+        #[cfg(feature = "fault-injection")]
+        if fault::drop_packet() {
+            return Ok(());
+        }
+
+        // Checking the backlog with the interface locked keeps the worker from sending
+        // an older frame after this one.
+        nic.reclaim_transmitted();
+        if TX_BACKLOG.is_empty() && nic.can_transmit() {
+            nic.transmit(frame)
+                .expect("net::transmit(): transmit queue has room but refused a frame");
+            TX_SENT.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        if nic.tx_queue.is_none() {
+            return Err(TxError::NoDevice);
+        }
+
+        if TX_BACKLOG.push(frame).is_err() {
+            TX_BLOCKED.fetch_add(1, Ordering::Relaxed);
+            return Err(TxError::WouldBlock);
+        }
+
+        workqueue::schedule(&TX_WORK);
+        Ok(())
+    })
+}
+
+/// Checks whether [`transmit`] would take another frame now instead of failing with
+/// [`TxError::WouldBlock`]. Only a hint while other threads send.
+pub fn can_transmit() -> bool {
+    TX_BACKLOG.len() < TX_BACKLOG.capacity()
+}
+
+/// Moves frames waiting in the backlog to the transmit queue as long as it has room,
+/// and comes back later for the rest.
+fn process_backlog() {
+    NIC.with(|nic| {
+        let Some(nic) = nic.as_mut() else {
+            return;
+        };
+
+        nic.reclaim_transmitted();
+
+        while nic.can_transmit() {
+            let Some(frame) = TX_BACKLOG.pop() else {
+                return;
+            };
+
+            nic.transmit(frame)
+                .expect("net::process_backlog(): transmit queue has room but refused a frame");
+            TX_SENT.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if TX_BACKLOG.is_empty() {
+            return;
+        }
+
+        // Come back once the device interrupts for sent frames, or right away if it
+        // already sent some.
+        if nic
+            .tx_queue
+            .as_mut()
+            .is_some_and(|x| !x.enable_interrupts())
+        {
+            workqueue::schedule(&TX_WORK);
+        }
+    })
+}
+
+// Interrupt handler of the network device, which may share its line with others.
+fn interrupt(_irq: u8) {
+    NIC.with(|nic| {
+        if let Some(nic) = nic.as_mut() {
+            nic.interrupt();
+        }
+    })
+}
+
+// Stops the network device before the machine is reset or turned off. Frames are not
+// sent afterwards.
+fn stop_device(_kind: ShutdownKind) {
+    if let Some(mut nic) = NIC.with(|x| x.take()) {
+        nic.shutdown();
+    }
+}
+
+/// Lets go of the network interface when its device is unplugged, e.g. with QEMU's
+/// `device_del` and a rescan. Frames are not sent to a device that is gone.
+fn hotplug(device: &pci::DeviceConfig, event: DeviceEvent) {
+    let mut bound = DEVICE.lock();
+
+    if event != DeviceEvent::Removed || !bound.is_some_and(|x| x.is_same_device(device)) {
+        return;
+    }
+
+    if let Some(irq) = pci::irq(device) {
+        pci::unregister_irq_handler(irq, interrupt);
+    }
+
+    // The device is gone, so there is nothing left to stop at shutdown.
+    power::unregister_shutdown_hook(stop_device);
+
+    NIC.with(|x| *x = None);
+    *bound = None;
+
+    log!("net::hotplug(): virtio-net device removed, the network is down");
+}
+
+/// Returns the IPv4 configuration given on the kernel command line as
+/// `ip=<address>/<prefix length>` and `gateway=<address>`, or `gateway=none`. Without
+/// them the interface is set up for QEMU user networking.
+pub fn ipv4_config() -> Ipv4Config {
+    let mut config = Ipv4Config {
+        address: DEFAULT_ADDRESS,
+        prefix_len: DEFAULT_PREFIX_LEN,
+        gateway: Some(DEFAULT_GATEWAY),
+    };
+
+    for value in multiboot::arg("ip") {
+        let parsed = value.split_once('/').and_then(|(address, prefix_len)| {
+            let address = address.parse::<Ipv4Addr>().ok()?;
+            let prefix_len = prefix_len.parse::<u8>().ok().filter(|&x| x <= 32)?;
+            Some((address.octets(), prefix_len))
+        });
+
+        match parsed {
+            Some((address, prefix_len)) => {
+                config.address = address;
+                config.prefix_len = prefix_len;
+            }
+            None => log!("net::ipv4_config(): ignoring malformed ip={value}"),
+        }
+    }
+
+    for value in multiboot::arg("gateway") {
+        match value.parse::<Ipv4Addr>() {
+            Ok(gateway) => config.gateway = Some(gateway.octets()),
+            Err(_) if value == "none" => config.gateway = None,
+            Err(_) => log!("net::ipv4_config(): ignoring malformed gateway={value}"),
+        }
+    }
+
+    config
+}
+
+fn nic_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    let result = with_nic(|nic| {
+        match args {
+            [] => Ok(()),
+            ["promisc", x] => nic.set_promiscuous(parse_switch(x)?),
+            ["allmulti", x] => nic.set_all_multicast(parse_switch(x)?),
+            _ => return Err(CommandError::Usage),
+        }
+        .map_err(|_| CommandError::Failed("device refused the receive mode"))?;
+
+        writeln!(
+            out,
+            "mac {} link {}, {} frames received, {} dropped",
+            nic.mac_address(),
+            if nic.link_up() { "up" } else { "down" },
+            RX_RECEIVED.load(Ordering::Relaxed),
+            RX_DROPPED.load(Ordering::Relaxed)
+        )?;
+        writeln!(
+            out,
+            "{} frames sent, {} waiting, {} refused",
+            TX_SENT.load(Ordering::Relaxed),
+            TX_BACKLOG.len(),
+            TX_BLOCKED.load(Ordering::Relaxed)
+        )?;
+
+        Ok(())
+    });
+
+    result.ok_or(CommandError::Failed("no network interface"))?
+}
+
+fn parse_switch(s: &str) -> Result<bool, CommandError> {
+    match s {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(CommandError::Usage),
+    }
+}
+
+// Builds the transport of a virtio-net device on the PCI bus, older hypervisors only
+// offer the legacy interface.
+fn pci_transport(device_cfg: pci::DeviceConfig) -> Box<dyn Transport + Send> {
+    log!(
+        "net::pci_transport(): found virtio-net device [{:04X}:{:04X}]",
+        device_cfg.vendor_id,
+        device_cfg.device_id
+    );
+
+    if let Some(rom) = device_cfg.expansion_rom() {
+        log!("net::pci_transport(): expansion ROM at {rom:#x}");
+    }
+
+    match PciTransport::new(device_cfg) {
+        Ok(transport) => Box::new(transport),
+        Err(_) => {
+            let transport = LegacyTransport::new(device_cfg)
+                .expect("virtio-net device has neither a modern nor a legacy interface");
+            log!("net::pci_transport(): using legacy virtio transport");
+            Box::new(transport)
+        }
     }
 }
 
 pub fn init() {
-    // First find configuration for virtio net device.
-    let mut device_cfg = pci::find_device(VIRTIO_VENDOR_ID, VIRTIO_NET_DEVICE_ID)
-        .expect("could not find virtio-net device on PCI bus");
+    let _init = INIT.start();
+    pci::INIT.require("net");
+
+    // Look for the device on the PCI bus first, lightweight VMMs only have virtio-mmio.
+    let device_cfg = virtio::find_pci(DeviceType::Network);
+    let transport: Box<dyn Transport + Send> = match device_cfg {
+        Some(device_cfg) => pci_transport(device_cfg),
+        None => {
+            let transport = virtio::take_mmio(DeviceType::Network)
+                .expect("could not find virtio-net device on PCI bus or virtio-mmio");
+            log!("net::init(): found virtio-mmio net device");
+            Box::new(transport)
+        }
+    };
+    let irq = transport.irq();
+
+    let nic = VirtioNet::new(transport).expect("virtio-net device rejected our features");
+
+    log!(
+        "net::init(): mac {} link {} [ \x1b[0;32mOK\x1b[0m ]",
+        nic.mac_address(),
+        if nic.link_up() { "up" } else { "down" }
+    );
+
+    NIC.with(|x| *x = Some(nic));
+    *DEVICE.lock() = device_cfg;
+
+    let irq = match device_cfg {
+        Some(device_cfg) => pci::register_irq_handler(&device_cfg, interrupt),
+        None => trap::register_irq_handler(irq, interrupt).then_some(irq),
+    };
+
+    match irq {
+        Some(irq) => log!(irq = irq; "net::init(): receiving frames"),
+        None => log!("net::init(): no free interrupt line, frames cannot be received"),
+    }
+    replay::set_frame_handler(Some(deliver));
+
+    assert!(
+        shell::register(Command {
+            name: "nic",
+            usage: "[promisc|allmulti on|off]",
+            help: "show the network interface or change its receive mode",
+            run: nic_command,
+        }),
+        "net::init(): failed to register nic"
+    );
 
-    log!("net::init(): found virtio-net device");
+    assert!(
+        power::register_shutdown_hook("net", stop_device),
+        "net::init(): failed to register shutdown hook"
+    );
 
-    // Build the transport layer using PCI bus info.
-    let transport_layer = VirtioTransportConfig::from_device_config(&mut device_cfg);
+    assert!(
+        pci::register_listener(hotplug),
+        "net::init(): failed to register PCI listener"
+    );
 }
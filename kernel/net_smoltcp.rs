@@ -0,0 +1,719 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::x86_64::_rdtsc;
+use core::net::{Ipv4Addr, SocketAddrV4};
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use core::time::Duration;
+
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy;
+use smoltcp::phy::{DeviceCapabilities, Medium};
+use smoltcp::socket::{tcp, udp, Socket};
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    EthernetAddress, HardwareAddress, IpAddress, IpCidr, IpEndpoint, IpListenEndpoint,
+};
+use spin::Mutex;
+
+use crate::heap;
+use crate::initcall::InitGuard;
+use crate::log;
+use crate::mdns;
+use crate::mpsc;
+use crate::net;
+use crate::net::{MacAddress, NicError};
+use crate::socket;
+use crate::socket::ErrorKind;
+use crate::tftp;
+use crate::timer;
+use crate::timer::{TimerAction, TimerId};
+use crate::workqueue;
+use crate::workqueue::Work;
+
+/// Number of received frames that can wait for smoltcp.
+const RX_QUEUE_SIZE: usize = 32;
+
+/// Number of datagrams a UDP socket buffers each way.
+const UDP_PACKETS: usize = 8;
+
+/// Number of bytes of datagrams a UDP socket buffers each way.
+const UDP_BUFFER_SIZE: usize = 8192;
+
+/// Number of bytes a TCP socket buffers each way.
+const TCP_BUFFER_SIZE: usize = 16384;
+
+/// How long a released TCP connection may stay idle while it is closing, before it is
+/// aborted.
+const LINGER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest mDNS message sent, what fits into a frame after the Ethernet, IPv4 and UDP
+/// headers.
+const MAX_MDNS_MESSAGE: usize = net::MAX_FRAME_SIZE - 14 - 20 - 8;
+
+/// Time to live of mDNS packets, which RFC 6762 requires to be 255.
+const MDNS_TTL: u8 = 255;
+
+/// First local port handed out to sockets that do not ask for one, see RFC 6335.
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+// Initialization of smoltcp on top of the network interface.
+pub static INIT: InitGuard = InitGuard::new("net_smoltcp");
+
+// Frames received for smoltcp, oldest first.
+static RX_FRAMES: mpsc::Queue<Vec<u8>, RX_QUEUE_SIZE> = mpsc::Queue::new();
+
+// Number of frames dropped because smoltcp fell behind or the interface was busy.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+// Runs the stack, when frames arrive, sockets change or smoltcp asks for it.
+static POLL_WORK: Work = Work::new(poll);
+
+// Number of ephemeral ports handed out, which go round in circles.
+static EPHEMERAL_PORTS: AtomicU16 = AtomicU16::new(0);
+
+// The interface and its sockets, once the stack is up.
+static STACK: Mutex<Option<Stack>> = Mutex::new(None);
+
+struct Stack {
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    // Timer running the stack when smoltcp next has something to do, if armed.
+    next_poll: Option<TimerId>,
+    // TCP sockets whose owner is gone, removed once their connection is closed.
+    released: Vec<SocketHandle>,
+    // UDP socket on the mDNS port, if it could be bound.
+    mdns: Option<SocketHandle>,
+}
+
+// Protocols with ports of their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Udp,
+    Tcp,
+}
+
+impl Stack {
+    // Whether a socket of `protocol` uses local `port`.
+    fn port_in_use(&self, protocol: Protocol, port: u16) -> bool {
+        self.sockets
+            .iter()
+            .any(|(_, socket)| match (protocol, socket) {
+                (Protocol::Udp, Socket::Udp(x)) => x.endpoint().port == port,
+                (Protocol::Tcp, Socket::Tcp(x)) => {
+                    x.state() != tcp::State::Closed
+                        && (x.listen_endpoint().port == port
+                            || x.local_endpoint().is_some_and(|x| x.port == port))
+                }
+                _ => false,
+            })
+    }
+
+    // Checks that `addr` is free to bind to for `protocol`, and returns its local endpoint
+    // with port 0 replaced by a free ephemeral port.
+    fn local_endpoint(
+        &self,
+        protocol: Protocol,
+        addr: SocketAddrV4,
+    ) -> socket::Result<IpListenEndpoint> {
+        let ip = *addr.ip();
+        if !ip.is_unspecified() && !self.iface.has_ip_addr(ip) {
+            return Err(ErrorKind::AddrNotAvailable.into());
+        }
+
+        let port = match addr.port() {
+            0 => (0..u16::MAX - EPHEMERAL_PORT_START)
+                .map(|_| {
+                    let count = EPHEMERAL_PORTS.fetch_add(1, Ordering::Relaxed);
+                    EPHEMERAL_PORT_START + count % (u16::MAX - EPHEMERAL_PORT_START)
+                })
+                .find(|&x| !self.port_in_use(protocol, x))
+                .ok_or(ErrorKind::AddrInUse)?,
+            port if self.port_in_use(protocol, port) => {
+                return Err(ErrorKind::AddrInUse.into());
+            }
+            port => port,
+        };
+
+        Ok(IpListenEndpoint {
+            addr: (!ip.is_unspecified()).then_some(IpAddress::Ipv4(ip)),
+            port,
+        })
+    }
+
+    // Calls `f` with the TCP socket `handle`.
+    fn tcp<R>(&mut self, handle: SocketHandle, f: impl FnOnce(&mut tcp::Socket) -> R) -> R {
+        f(self.sockets.get_mut::<tcp::Socket>(handle))
+    }
+}
+
+/// smoltcp's view of the network interface: frames come from the receive path of
+/// net.rs and leave through [`net::transmit_parts`].
+struct NicDevice;
+
+struct RxToken(Vec<u8>);
+
+struct TxToken;
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+impl phy::TxToken for TxToken {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+
+        // Tokens are only handed out while the interface takes frames, so this is rare,
+        // and smoltcp sends again whatever it needs to, like TCP segments.
+        if net::transmit_parts(vec![frame]).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+
+        result
+    }
+}
+
+impl phy::Device for NicDevice {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(RxToken, TxToken)> {
+        RX_FRAMES.pop().map(|frame| (RxToken(frame), TxToken))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<TxToken> {
+        net::can_transmit().then_some(TxToken)
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ethernet;
+        caps.max_transmission_unit = net::MAX_FRAME_SIZE;
+        caps
+    }
+}
+
+fn now() -> Instant {
+    Instant::from_micros(timer::uptime().as_micros() as i64)
+}
+
+/// Takes a frame from the network interface, see [`net::set_receiver`].
+fn receive(frame: &[u8]) {
+    if RX_FRAMES.push(frame.to_vec()).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    workqueue::schedule(&POLL_WORK);
+}
+
+/// Lets smoltcp process received frames and send what its sockets have queued, then
+/// arms a timer for when it next has to, e.g. to retransmit.
+fn poll() {
+    let mut stack = STACK.lock();
+    let Some(stack) = stack.as_mut() else {
+        return;
+    };
+
+    let now = now();
+    stack.iface.poll(now, &mut NicDevice, &mut stack.sockets);
+
+    if let Some(handle) = stack.mdns {
+        answer_mdns(stack.sockets.get_mut::<udp::Socket>(handle));
+    }
+
+    let sockets = &mut stack.sockets;
+    stack.released.retain(|&handle| {
+        let closed = sockets.get::<tcp::Socket>(handle).state() == tcp::State::Closed;
+        if closed {
+            sockets.remove(handle);
+        }
+        !closed
+    });
+
+    if let Some(id) = stack.next_poll.take() {
+        timer::cancel(id);
+    }
+
+    if let Some(delay) = stack.iface.poll_delay(now, &stack.sockets) {
+        let delay = Duration::from_micros(delay.total_micros());
+        stack.next_poll = Some(timer::add(delay, TimerAction::Work(&POLL_WORK)));
+    }
+}
+
+// Answers the mDNS queries received on `socket`, see [`mdns::respond`]. The responses
+// are sent the next time the stack runs.
+fn answer_mdns(socket: &mut udp::Socket) {
+    let mut query = [0u8; MAX_MDNS_MESSAGE];
+    let mut response = [0u8; MAX_MDNS_MESSAGE];
+
+    while let Ok((len, meta)) = socket.recv_slice(&mut query) {
+        let port = meta.endpoint.port;
+        let Some((len, unicast)) = mdns::respond(&query[..len], port, &mut response) else {
+            continue;
+        };
+
+        let destination = if unicast {
+            meta.endpoint
+        } else {
+            IpEndpoint::new(IpAddress::Ipv4(mdns::MDNS_GROUP.into()), mdns::MDNS_PORT)
+        };
+
+        let _ = socket.send_slice(&response[..len], destination);
+    }
+}
+
+/// Sends every mDNS record we have to the mDNS group, so other hosts learn of a new
+/// address or service without asking, see [`mdns::announcement`].
+pub fn announce_mdns() {
+    let mut announcement = [0u8; MAX_MDNS_MESSAGE];
+    let Some(len) = mdns::announcement(&mut announcement) else {
+        return;
+    };
+
+    let group = IpEndpoint::new(IpAddress::Ipv4(mdns::MDNS_GROUP.into()), mdns::MDNS_PORT);
+    let _ = with_stack(|stack| {
+        let handle = stack.mdns.ok_or(ErrorKind::NotConnected)?;
+        let socket = stack.sockets.get_mut::<udp::Socket>(handle);
+        socket
+            .send_slice(&announcement[..len], group)
+            .map_err(|_| ErrorKind::WouldBlock.into())
+    });
+}
+
+/// Calls `f` with the smoltcp socket set, or returns `None` if the stack is not up. The
+/// stack runs afterwards, so that whatever `f` queued is sent.
+pub fn with_sockets<R>(f: impl FnOnce(&mut SocketSet<'static>) -> R) -> Option<R> {
+    let result = STACK.lock().as_mut().map(|x| f(&mut x.sockets));
+    workqueue::schedule(&POLL_WORK);
+    result
+}
+
+/// Adds a UDP socket bound to local `port`, or to an ephemeral port if it is 0, and
+/// returns its handle for [`with_sockets`].
+pub fn bind_udp(port: u16) -> Option<SocketHandle> {
+    udp_bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).ok()
+}
+
+/// Closes and removes a socket added to the socket set.
+pub fn remove_socket(handle: SocketHandle) {
+    with_sockets(|sockets| sockets.remove(handle));
+}
+
+/// Gives up the connections that linger after their owner released them when the heap
+/// runs out, see [`heap::register_reclaimer`]. Returns the number of bytes given back.
+fn reclaim(_needed: usize) -> usize {
+    // The thread that ran out may be holding the stack itself.
+    let Some(mut stack) = STACK.try_lock() else {
+        return 0;
+    };
+    let Some(stack) = stack.as_mut() else {
+        return 0;
+    };
+
+    let released = stack.released.len();
+    for handle in stack.released.drain(..) {
+        stack.sockets.remove(handle);
+    }
+
+    // Every TCP socket has a receive and a transmit buffer.
+    released * 2 * TCP_BUFFER_SIZE
+}
+
+// Calls `f` with the stack, and runs the stack afterwards like with_sockets().
+fn with_stack<R>(f: impl FnOnce(&mut Stack) -> socket::Result<R>) -> socket::Result<R> {
+    let result = match STACK.lock().as_mut() {
+        Some(stack) => f(stack),
+        None => Err(ErrorKind::NetworkDown.into()),
+    };
+
+    workqueue::schedule(&POLL_WORK);
+    result
+}
+
+fn socket_addr(endpoint: IpEndpoint) -> SocketAddrV4 {
+    let IpAddress::Ipv4(ip) = endpoint.addr;
+    SocketAddrV4::new(ip, endpoint.port)
+}
+
+fn listen_addr(endpoint: IpListenEndpoint) -> SocketAddrV4 {
+    let ip = match endpoint.addr {
+        Some(IpAddress::Ipv4(ip)) => ip,
+        None => Ipv4Addr::UNSPECIFIED,
+    };
+
+    SocketAddrV4::new(ip, endpoint.port)
+}
+
+// The socket calls below back the std-like sockets of socket.rs.
+
+/// Adds a UDP socket bound to `addr`, or to an ephemeral port if its port is 0.
+pub fn udp_bind(addr: SocketAddrV4) -> socket::Result<SocketHandle> {
+    with_stack(|stack| {
+        let endpoint = stack.local_endpoint(Protocol::Udp, addr)?;
+        let buffer = || {
+            udp::PacketBuffer::new(
+                vec![udp::PacketMetadata::EMPTY; UDP_PACKETS],
+                vec![0; UDP_BUFFER_SIZE],
+            )
+        };
+
+        let mut socket = udp::Socket::new(buffer(), buffer());
+        socket.bind(endpoint).map_err(|_| ErrorKind::InvalidInput)?;
+
+        Ok(stack.sockets.add(socket))
+    })
+}
+
+/// Queues a datagram to `addr` on a UDP socket, see [`udp_bind`].
+pub fn udp_send_to(handle: SocketHandle, data: &[u8], addr: SocketAddrV4) -> socket::Result<usize> {
+    with_stack(|stack| {
+        let socket = stack.sockets.get_mut::<udp::Socket>(handle);
+        match socket.send_slice(data, IpEndpoint::from(addr)) {
+            Ok(()) => Ok(data.len()),
+            Err(udp::SendError::BufferFull) if data.len() > socket.payload_send_capacity() => {
+                Err(ErrorKind::OutOfMemory.into())
+            }
+            Err(udp::SendError::BufferFull) => Err(ErrorKind::WouldBlock.into()),
+            Err(udp::SendError::Unaddressable) => Err(ErrorKind::InvalidInput.into()),
+        }
+    })
+}
+
+/// Takes the oldest datagram received on a UDP socket, cut short if it does not fit
+/// into `buf`.
+pub fn udp_recv_from(
+    handle: SocketHandle,
+    buf: &mut [u8],
+) -> socket::Result<(usize, SocketAddrV4)> {
+    with_stack(|stack| {
+        let socket = stack.sockets.get_mut::<udp::Socket>(handle);
+        let (data, meta) = socket.recv().map_err(|_| ErrorKind::WouldBlock)?;
+        let len = data.len().min(buf.len());
+
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len, socket_addr(meta.endpoint)))
+    })
+}
+
+pub fn udp_local_addr(handle: SocketHandle) -> socket::Result<SocketAddrV4> {
+    with_stack(|stack| {
+        let socket = stack.sockets.get_mut::<udp::Socket>(handle);
+        Ok(listen_addr(socket.endpoint()))
+    })
+}
+
+fn tcp_socket() -> tcp::Socket<'static> {
+    tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+    )
+}
+
+// Adds a TCP socket listening on `endpoint`.
+fn listen(stack: &mut Stack, endpoint: IpListenEndpoint) -> socket::Result<SocketHandle> {
+    let mut socket = tcp_socket();
+    socket
+        .listen(endpoint)
+        .map_err(|_| ErrorKind::InvalidInput)?;
+
+    Ok(stack.sockets.add(socket))
+}
+
+/// Adds a TCP socket listening on `addr`, or on an ephemeral port if its port is 0.
+pub fn tcp_listen(addr: SocketAddrV4) -> socket::Result<SocketHandle> {
+    with_stack(|stack| {
+        let endpoint = stack.local_endpoint(Protocol::Tcp, addr)?;
+        listen(stack, endpoint)
+    })
+}
+
+/// Adds another TCP socket listening on `addr`, which a socket from [`tcp_listen`]
+/// already listens on, so that connections can wait to be accepted.
+pub fn tcp_relisten(addr: SocketAddrV4) -> socket::Result<SocketHandle> {
+    with_stack(|stack| listen(stack, IpListenEndpoint::from(addr)))
+}
+
+/// Adds a TCP socket connecting to `addr` from an ephemeral port, see
+/// [`tcp_poll_established`].
+pub fn tcp_connect(addr: SocketAddrV4) -> socket::Result<SocketHandle> {
+    with_stack(|stack| {
+        let local = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+        let local = stack.local_endpoint(Protocol::Tcp, local)?;
+        let mut socket = tcp_socket();
+        let cx = stack.iface.context();
+
+        socket
+            .connect(cx, IpEndpoint::from(addr), local)
+            .map_err(|_| ErrorKind::InvalidInput)?;
+
+        Ok(stack.sockets.add(socket))
+    })
+}
+
+/// Checks whether a TCP socket got connected, fails with [`ErrorKind::WouldBlock`] while
+/// it is still waiting for or setting up a connection.
+pub fn tcp_poll_established(handle: SocketHandle) -> socket::Result<()> {
+    with_stack(|stack| {
+        stack.tcp(handle, |socket| match socket.state() {
+            tcp::State::Listen | tcp::State::SynSent | tcp::State::SynReceived => {
+                Err(ErrorKind::WouldBlock.into())
+            }
+            tcp::State::Closed => Err(ErrorKind::ConnectionRefused.into()),
+            _ => Ok(()),
+        })
+    })
+}
+
+/// Reads received data from a TCP connection, returns 0 once the remote side closed it.
+pub fn tcp_recv(handle: SocketHandle, buf: &mut [u8]) -> socket::Result<usize> {
+    with_stack(|stack| {
+        stack.tcp(handle, |socket| match socket.recv_slice(buf) {
+            Ok(0) => Err(ErrorKind::WouldBlock.into()),
+            Ok(len) => Ok(len),
+            Err(tcp::RecvError::Finished) => Ok(0),
+            Err(tcp::RecvError::InvalidState) if socket.state() == tcp::State::Closed => {
+                Err(ErrorKind::ConnectionReset.into())
+            }
+            Err(tcp::RecvError::InvalidState) => Err(ErrorKind::NotConnected.into()),
+        })
+    })
+}
+
+/// Queues as much of `data` as fits on a TCP connection and returns how much.
+pub fn tcp_send(handle: SocketHandle, data: &[u8]) -> socket::Result<usize> {
+    with_stack(|stack| {
+        stack.tcp(handle, |socket| match socket.send_slice(data) {
+            Ok(0) => Err(ErrorKind::WouldBlock.into()),
+            Ok(len) => Ok(len),
+            Err(tcp::SendError::InvalidState) => Err(match socket.state() {
+                tcp::State::Closed => ErrorKind::ConnectionReset,
+                tcp::State::Listen | tcp::State::SynSent | tcp::State::SynReceived => {
+                    ErrorKind::NotConnected
+                }
+                _ => ErrorKind::BrokenPipe,
+            }
+            .into()),
+        })
+    })
+}
+
+/// Closes the sending half of a TCP connection once everything queued is sent.
+pub fn tcp_close(handle: SocketHandle) -> socket::Result<()> {
+    with_stack(|stack| {
+        stack.tcp(handle, |socket| socket.close());
+        Ok(())
+    })
+}
+
+pub fn tcp_set_nodelay(handle: SocketHandle, nodelay: bool) -> socket::Result<()> {
+    with_stack(|stack| {
+        stack.tcp(handle, |socket| socket.set_nagle_enabled(!nodelay));
+        Ok(())
+    })
+}
+
+pub fn tcp_nodelay(handle: SocketHandle) -> socket::Result<bool> {
+    with_stack(|stack| Ok(stack.tcp(handle, |socket| !socket.nagle_enabled())))
+}
+
+/// Gets the local address of a TCP connection, or the address it listens on.
+pub fn tcp_local_addr(handle: SocketHandle) -> socket::Result<SocketAddrV4> {
+    with_stack(|stack| {
+        Ok(stack.tcp(handle, |socket| match socket.local_endpoint() {
+            Some(endpoint) => socket_addr(endpoint),
+            None => listen_addr(socket.listen_endpoint()),
+        }))
+    })
+}
+
+pub fn tcp_peer_addr(handle: SocketHandle) -> socket::Result<SocketAddrV4> {
+    with_stack(|stack| {
+        stack
+            .tcp(handle, |socket| socket.remote_endpoint())
+            .map(socket_addr)
+            .ok_or(ErrorKind::NotConnected.into())
+    })
+}
+
+/// Gives up a socket from the calls above. UDP sockets are removed at once, TCP
+/// connections are closed first, which may take until the remote side closed them too.
+pub fn release(handle: SocketHandle) {
+    let _ = with_stack(|stack| {
+        let tcp = stack
+            .sockets
+            .iter_mut()
+            .find_map(|(x, socket)| match socket {
+                Socket::Tcp(socket) if x == handle => Some(socket),
+                _ => None,
+            });
+
+        match tcp {
+            Some(socket) => {
+                socket.close();
+                socket.set_timeout(Some(LINGER_TIMEOUT.into()));
+                stack.released.push(handle);
+            }
+            None => {
+                stack.sockets.remove(handle);
+            }
+        }
+
+        Ok(())
+    });
+}
+
+/// UDP socket talking to one server through smoltcp, e.g. a TFTP server.
+pub struct UdpPeer {
+    handle: SocketHandle,
+    server: IpAddress,
+}
+
+impl UdpPeer {
+    /// Binds a socket to an ephemeral port for talking to `server`.
+    pub fn new(server: [u8; 4]) -> Option<Self> {
+        Some(Self {
+            handle: bind_udp(0)?,
+            server: IpAddress::Ipv4(Ipv4Addr::from(server)),
+        })
+    }
+}
+
+impl Drop for UdpPeer {
+    fn drop(&mut self) {
+        remove_socket(self.handle);
+    }
+}
+
+impl tftp::Datagram for UdpPeer {
+    fn send_to(&mut self, port: u16, data: &[u8]) -> bool {
+        let endpoint = IpEndpoint::new(self.server, port);
+        let sent = with_sockets(|sockets| {
+            let socket = sockets.get_mut::<udp::Socket>(self.handle);
+            socket.send_slice(data, endpoint).is_ok()
+        });
+
+        sent.unwrap_or(false)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8], timeout: Duration) -> Option<(usize, u16)> {
+        let mut received = None;
+
+        // Datagrams from anyone but the server are dropped.
+        let _ = timer::wait_until(
+            || {
+                received = with_sockets(|sockets| {
+                    let socket = sockets.get_mut::<udp::Socket>(self.handle);
+                    while let Ok((len, meta)) = socket.recv_slice(buf) {
+                        if meta.endpoint.addr == self.server {
+                            return Some((len, meta.endpoint.port));
+                        }
+                    }
+                    None
+                })
+                .flatten();
+
+                received.is_some()
+            },
+            timeout,
+        );
+
+        received
+    }
+}
+
+/// Brings up smoltcp on the network interface with the address from
+/// [`net::ipv4_config`], and fetches the files named on the kernel command line from
+/// the TFTP server, if one is given.
+///
+/// The interface joins the mDNS group, and queries to the mDNS port are answered by
+/// [`mdns::respond`] whenever the stack runs.
+pub fn init() {
+    let _init = INIT.start();
+    net::INIT.require("net_smoltcp");
+
+    let Some(mac) = net::with_nic(|nic| nic.mac_address()) else {
+        log!("net_smoltcp::init(): no network interface");
+        return;
+    };
+
+    let mut config = Config::new(HardwareAddress::Ethernet(EthernetAddress(mac.0)));
+    config.random_seed = unsafe { _rdtsc() };
+
+    let mut iface = Interface::new(config, &mut NicDevice, now());
+    let ipv4 = net::ipv4_config();
+    let address = Ipv4Addr::from(ipv4.address);
+
+    iface.update_ip_addrs(|addrs| {
+        addrs
+            .push(IpCidr::new(IpAddress::Ipv4(address), ipv4.prefix_len))
+            .expect("net_smoltcp::init(): no room for the address");
+    });
+
+    if let Some(gateway) = ipv4.gateway {
+        iface
+            .routes_mut()
+            .add_default_ipv4_route(Ipv4Addr::from(gateway))
+            .expect("net_smoltcp::init(): no room for the default route");
+    }
+
+    let group = Ipv4Addr::from(mdns::MDNS_GROUP);
+    if let Err(e) = iface.join_multicast_group(group) {
+        log!("net_smoltcp::init(): cannot join the mDNS group: {e}");
+    }
+
+    *STACK.lock() = Some(Stack {
+        iface,
+        sockets: SocketSet::new(Vec::new()),
+        next_poll: None,
+        released: Vec::new(),
+        mdns: None,
+    });
+
+    net::set_receiver(Some(receive));
+
+    assert!(
+        heap::register_reclaimer(reclaim),
+        "net_smoltcp::init(): failed to register the reclaimer"
+    );
+
+    match net::with_nic(|nic| nic.set_multicast_filter(&[MacAddress::multicast(group)])) {
+        Some(Ok(())) | Some(Err(NicError::Unsupported)) | None => {}
+        Some(Err(e)) => log!("net_smoltcp::init(): cannot receive mDNS queries: {e:?}"),
+    }
+
+    let bound =
+        udp_bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, mdns::MDNS_PORT)).and_then(|handle| {
+            with_stack(|stack| {
+                let socket = stack.sockets.get_mut::<udp::Socket>(handle);
+                socket.set_hop_limit(Some(MDNS_TTL));
+                stack.mdns = Some(handle);
+                Ok(())
+            })
+        });
+    if let Err(e) = bound {
+        log!("net_smoltcp::init(): cannot bind the mDNS port: {e}");
+    }
+
+    mdns::set_address(Some(ipv4.address));
+    announce_mdns();
+
+    log!(
+        "net_smoltcp::init(): {address}/{} [ \x1b[0;32mOK\x1b[0m ]",
+        ipv4.prefix_len
+    );
+
+    if let Some(server) = tftp::server() {
+        let failed = UdpPeer::new(server).map_or(1, |mut peer| tftp::fetch_all(&mut peer));
+        if failed > 0 {
+            log!("net_smoltcp::init(): {failed} files could not be fetched");
+        }
+    }
+}
@@ -0,0 +1,1090 @@
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::net::{Ipv4Addr, SocketAddrV4};
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use core::time::Duration;
+
+use spin::Mutex;
+
+use crate::heap;
+use crate::initcall::InitGuard;
+use crate::log;
+use crate::mdns;
+use crate::net;
+use crate::net::{Ipv4Config, MacAddress, NicError, TxError};
+use crate::shell;
+use crate::shell::{Command, CommandError};
+use crate::socket;
+use crate::socket::{ErrorKind, UdpSocket};
+use crate::tftp;
+use crate::timer;
+use crate::timer::TimerAction;
+use crate::workqueue::Work;
+
+// EtherTypes of the protocols we speak.
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// Size of an Ethernet header, without a VLAN tag.
+const ETHERNET_HEADER_SIZE: usize = 14;
+
+/// Size of an ARP packet for IPv4 over Ethernet, see RFC 826.
+const ARP_PACKET_SIZE: usize = 28;
+
+// ARP operations.
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+/// Size of an IPv4 header without options, the only kind we send.
+const IPV4_HEADER_SIZE: usize = 20;
+
+/// Protocol number of UDP in the IPv4 header.
+const IP_PROTOCOL_UDP: u8 = 17;
+
+/// Flags and fragment offset of the packets we send: don't fragment.
+const IP_FLAG_DF: u16 = 0x4000;
+
+/// Time to live of the packets we send, except for mDNS, which requires 255.
+const DEFAULT_TTL: u8 = 64;
+const MDNS_TTL: u8 = 255;
+
+const UDP_HEADER_SIZE: usize = 8;
+
+/// Largest UDP payload that fits into a frame. Packets are never fragmented.
+pub const MAX_UDP_PAYLOAD: usize =
+    net::MAX_FRAME_SIZE - ETHERNET_HEADER_SIZE - IPV4_HEADER_SIZE - UDP_HEADER_SIZE;
+
+/// Number of datagrams a UDP socket holds until they are received, newer ones are
+/// dropped.
+const UDP_QUEUE_SIZE: usize = 16;
+
+/// Number of addresses in the ARP cache.
+const ARP_CACHE_SIZE: usize = 16;
+
+/// How long an ARP cache entry is used before the address is asked for again.
+const ARP_LIFETIME: Duration = Duration::from_secs(300);
+
+/// How long to wait for an ARP reply before asking again.
+const ARP_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How often an address is asked for before the datagrams waiting for it are dropped.
+const ARP_MAX_REQUESTS: u32 = 3;
+
+/// Number of datagrams that can wait for ARP replies.
+const MAX_PENDING: usize = 16;
+
+/// First local port handed out to sockets that do not ask for one, see RFC 6335.
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+const BROADCAST_MAC: MacAddress = MacAddress([0xff; 6]);
+
+// Initialization of the stack on top of the network interface.
+pub static INIT: InitGuard = InitGuard::new("net_stack");
+
+// The stack, once it is up.
+static STACK: Mutex<Option<Stack>> = Mutex::new(None);
+
+// Asks again for the addresses datagrams wait for.
+static ARP_WORK: Work = Work::new(retry_arp);
+
+// Identification of the next IPv4 packet sent.
+static NEXT_IP_ID: AtomicU16 = AtomicU16::new(0);
+
+// Number of ephemeral ports handed out, which go round in circles.
+static EPHEMERAL_PORTS: AtomicU16 = AtomicU16::new(0);
+
+// Number of frames dropped because they were malformed or for no one.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+// Number of datagrams dropped because no ARP reply came.
+static UNRESOLVED: AtomicU64 = AtomicU64::new(0);
+
+/// UDP socket of the stack, see [`udp_bind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handle(usize);
+
+struct Stack {
+    mac: MacAddress,
+    config: Ipv4Config,
+    arp: [Option<Neighbor>; ARP_CACHE_SIZE],
+    // Datagrams waiting for the Ethernet address of their next hop, oldest first.
+    pending: VecDeque<Pending>,
+    // Whether ARP_WORK is due to run.
+    retry_armed: bool,
+    sockets: Vec<Option<Binding>>,
+}
+
+// Entry of the ARP cache.
+#[derive(Debug, Clone, Copy)]
+struct Neighbor {
+    ip: Ipv4Addr,
+    mac: MacAddress,
+    // Uptime after which the entry is no longer used.
+    expires: Duration,
+}
+
+// Datagram waiting for an ARP reply, see Stack::transmit().
+struct Pending {
+    next_hop: Ipv4Addr,
+    // Number of requests sent for the next hop when the datagram was queued.
+    requests: u32,
+    // The frame with the destination address still to fill in, then the payload.
+    parts: Vec<Vec<u8>>,
+}
+
+// UDP socket bound to a local address.
+struct Binding {
+    local: SocketAddrV4,
+    received: VecDeque<(SocketAddrV4, Vec<u8>)>,
+}
+
+impl Stack {
+    fn address(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.config.address)
+    }
+
+    // Whether `ip` is on the same network as the interface.
+    fn is_local(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::MAX
+            .checked_shl(32 - self.config.prefix_len as u32)
+            .unwrap_or(0);
+        u32::from(ip) & mask == u32::from(self.address()) & mask
+    }
+
+    // Whether `ip` is the broadcast address of the network or the limited broadcast.
+    fn is_broadcast(&self, ip: Ipv4Addr) -> bool {
+        let host_mask = u32::MAX
+            .checked_shr(self.config.prefix_len as u32)
+            .unwrap_or(0);
+        ip.is_broadcast()
+            || (self.is_local(ip) && host_mask != 0 && u32::from(ip) & host_mask == host_mask)
+    }
+
+    fn lookup(&self, ip: Ipv4Addr) -> Option<MacAddress> {
+        let now = timer::uptime();
+        self.arp
+            .iter()
+            .flatten()
+            .find(|x| x.ip == ip && x.expires > now)
+            .map(|x| x.mac)
+    }
+
+    // Remembers the Ethernet address of `ip` and sends what waited for it.
+    fn learn(&mut self, ip: Ipv4Addr, mac: MacAddress) {
+        let now = timer::uptime();
+        let neighbor = Neighbor {
+            ip,
+            mac,
+            expires: now + ARP_LIFETIME,
+        };
+
+        // Take the entry of the address, else a free one, else the oldest.
+        let slot = match self.arp.iter().position(|x| x.is_some_and(|x| x.ip == ip)) {
+            Some(i) => i,
+            None => self
+                .arp
+                .iter()
+                .position(|x| !x.is_some_and(|x| x.expires > now))
+                .unwrap_or_else(|| {
+                    (0..ARP_CACHE_SIZE)
+                        .min_by_key(|&i| self.arp[i].map_or(Duration::ZERO, |x| x.expires))
+                        .unwrap_or(0)
+                }),
+        };
+        self.arp[slot] = Some(neighbor);
+
+        let mut i = 0;
+        while i < self.pending.len() {
+            if self.pending[i].next_hop != ip {
+                i += 1;
+                continue;
+            }
+
+            let mut pending = self.pending.remove(i).unwrap();
+            pending.parts[0][..6].copy_from_slice(&mac.0);
+            let _ = net::transmit_parts(pending.parts);
+        }
+    }
+
+    fn send_arp(&self, operation: u16, target_mac: MacAddress, target_ip: Ipv4Addr) {
+        let destination = if operation == ARP_REQUEST {
+            BROADCAST_MAC
+        } else {
+            target_mac
+        };
+
+        let mut frame = ethernet_header(destination, self.mac, ETHERTYPE_ARP);
+        frame.extend_from_slice(&1u16.to_be_bytes()); // Ethernet
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&[6, 4]);
+        frame.extend_from_slice(&operation.to_be_bytes());
+        frame.extend_from_slice(&self.mac.0);
+        frame.extend_from_slice(&self.config.address);
+        frame.extend_from_slice(&target_mac.0);
+        frame.extend_from_slice(&target_ip.octets());
+
+        let _ = net::transmit(&frame);
+    }
+
+    // Sends an IPv4 packet, once the Ethernet address of the next hop is known.
+    fn transmit(
+        &mut self,
+        destination: Ipv4Addr,
+        protocol: u8,
+        ttl: u8,
+        mut parts: Vec<Vec<u8>>,
+    ) -> socket::Result<()> {
+        let payload_len: usize = parts.iter().map(Vec::len).sum();
+
+        let next_hop = if destination.is_multicast() || self.is_broadcast(destination) {
+            None
+        } else if self.is_local(destination) {
+            Some(destination)
+        } else {
+            let gateway = self.config.gateway.ok_or(ErrorKind::NetworkUnreachable)?;
+            Some(Ipv4Addr::from(gateway))
+        };
+
+        let mac = match next_hop {
+            None if destination.is_multicast() => Some(MacAddress::multicast(destination)),
+            None => Some(BROADCAST_MAC),
+            Some(ip) => self.lookup(ip),
+        };
+
+        // Frames waiting for the address of the next hop get it filled in by learn().
+        let mut header = ethernet_header(mac.unwrap_or(BROADCAST_MAC), self.mac, ETHERTYPE_IPV4);
+        let total_len = (IPV4_HEADER_SIZE + payload_len) as u16;
+        let id = NEXT_IP_ID.fetch_add(1, Ordering::Relaxed);
+
+        let start = header.len();
+        header.extend_from_slice(&[0x45, 0]);
+        header.extend_from_slice(&total_len.to_be_bytes());
+        header.extend_from_slice(&id.to_be_bytes());
+        header.extend_from_slice(&IP_FLAG_DF.to_be_bytes());
+        header.extend_from_slice(&[ttl, protocol, 0, 0]);
+        header.extend_from_slice(&self.config.address);
+        header.extend_from_slice(&destination.octets());
+
+        let checksum = checksum(&header[start..]);
+        header[start + 10..start + 12].copy_from_slice(&checksum.to_be_bytes());
+
+        // The transport header goes along with the others.
+        header.append(&mut parts[0]);
+        parts[0] = header;
+
+        match (next_hop, mac) {
+            (Some(ip), None) => self.resolve(ip, parts),
+            _ => net::transmit_parts(parts).map_err(|e| match e {
+                TxError::NoDevice => ErrorKind::NetworkDown.into(),
+                TxError::TooLarge => ErrorKind::OutOfMemory.into(),
+                TxError::WouldBlock => ErrorKind::WouldBlock.into(),
+            }),
+        }
+    }
+
+    // Queues a frame until the Ethernet address of `next_hop` is known, and asks for it
+    // unless that already happened.
+    fn resolve(&mut self, next_hop: Ipv4Addr, parts: Vec<Vec<u8>>) -> socket::Result<()> {
+        if self.pending.len() == MAX_PENDING {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        let asked = self.pending.iter().any(|x| x.next_hop == next_hop);
+        if !asked {
+            self.send_arp(ARP_REQUEST, MacAddress([0; 6]), next_hop);
+        }
+
+        self.pending.push_back(Pending {
+            next_hop,
+            requests: 1,
+            parts,
+        });
+
+        if !self.retry_armed {
+            self.retry_armed = true;
+            timer::add(ARP_RETRY_DELAY, TimerAction::Work(&ARP_WORK));
+        }
+
+        Ok(())
+    }
+
+    fn receive_arp(&mut self, packet: &[u8]) {
+        let Some(packet) = packet.get(..ARP_PACKET_SIZE) else {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+
+        // Only IPv4 over Ethernet.
+        if packet[..6] != [0, 1, 0x08, 0x00, 6, 4] {
+            return;
+        }
+
+        let operation = u16::from_be_bytes([packet[6], packet[7]]);
+        let sender_mac = MacAddress(packet[8..14].try_into().unwrap());
+        let sender_ip = Ipv4Addr::new(packet[14], packet[15], packet[16], packet[17]);
+        let target_ip = Ipv4Addr::new(packet[24], packet[25], packet[26], packet[27]);
+
+        if sender_ip.is_unspecified() || sender_mac.0[0] & 1 != 0 {
+            return;
+        }
+
+        // Keep what we know up to date, and learn whoever asks for us, who we are likely
+        // to answer to next, see RFC 826.
+        let known = self.arp.iter().flatten().any(|x| x.ip == sender_ip);
+        let for_us = target_ip == self.address();
+        if known || for_us {
+            self.learn(sender_ip, sender_mac);
+        }
+
+        if for_us && operation == ARP_REQUEST {
+            self.send_arp(ARP_REPLY, sender_mac, sender_ip);
+        }
+    }
+
+    fn receive_ipv4(&mut self, packet: &[u8]) {
+        let Some(header) = packet.get(..IPV4_HEADER_SIZE) else {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+
+        let header_len = (header[0] & 0xf) as usize * 4;
+        let total_len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let fragment = u16::from_be_bytes([header[6], header[7]]);
+
+        let valid = header[0] >> 4 == 4
+            && header_len >= IPV4_HEADER_SIZE
+            && header_len <= total_len
+            && total_len <= packet.len()
+            && checksum(&packet[..header_len]) == 0;
+
+        if !valid {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        // Fragments are never reassembled, the MTU is enough for what we receive.
+        if fragment & 0x3fff != 0 {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let source = Ipv4Addr::new(header[12], header[13], header[14], header[15]);
+        let destination = Ipv4Addr::new(header[16], header[17], header[18], header[19]);
+
+        let for_us = destination == self.address()
+            || self.is_broadcast(destination)
+            || destination == Ipv4Addr::from(mdns::MDNS_GROUP);
+
+        if !for_us {
+            return;
+        }
+
+        if header[9] == IP_PROTOCOL_UDP {
+            self.receive_udp(source, destination, &packet[header_len..total_len]);
+        }
+    }
+
+    fn receive_udp(&mut self, source: Ipv4Addr, destination: Ipv4Addr, datagram: &[u8]) {
+        let Some(header) = datagram.get(..UDP_HEADER_SIZE) else {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+
+        let source_port = u16::from_be_bytes([header[0], header[1]]);
+        let destination_port = u16::from_be_bytes([header[2], header[3]]);
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let has_checksum = header[6..8] != [0, 0];
+
+        if len < UDP_HEADER_SIZE
+            || len > datagram.len()
+            || (has_checksum && udp_checksum(source, destination, &[&datagram[..len]]) != 0)
+        {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let from = SocketAddrV4::new(source, source_port);
+        let data = &datagram[UDP_HEADER_SIZE..len];
+
+        if destination_port == mdns::MDNS_PORT {
+            self.answer_mdns(from, data);
+            return;
+        }
+
+        let binding = self.sockets.iter_mut().flatten().find(|x| {
+            x.local.port() == destination_port
+                && (x.local.ip().is_unspecified() || *x.local.ip() == destination)
+        });
+
+        match binding {
+            Some(x) if x.received.len() < UDP_QUEUE_SIZE => {
+                x.received.push_back((from, data.to_vec()))
+            }
+            _ => {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn send_udp(
+        &mut self,
+        source_port: u16,
+        destination: SocketAddrV4,
+        ttl: u8,
+        data: &[u8],
+    ) -> socket::Result<()> {
+        if data.len() > MAX_UDP_PAYLOAD {
+            return Err(ErrorKind::OutOfMemory.into());
+        }
+
+        let len = ((UDP_HEADER_SIZE + data.len()) as u16).to_be_bytes();
+        let mut header = Vec::with_capacity(UDP_HEADER_SIZE);
+        header.extend_from_slice(&source_port.to_be_bytes());
+        header.extend_from_slice(&destination.port().to_be_bytes());
+        header.extend_from_slice(&len);
+        header.extend_from_slice(&[0, 0]);
+
+        // Zero means no checksum, so it is sent as all ones instead, see RFC 768.
+        let checksum = match udp_checksum(self.address(), *destination.ip(), &[&header, data]) {
+            0 => 0xffff,
+            x => x,
+        };
+        header[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+        let ip = *destination.ip();
+        self.transmit(ip, IP_PROTOCOL_UDP, ttl, vec![header, data.to_vec()])
+    }
+
+    // Answers an mDNS query, to the group unless the querier asked for a unicast answer
+    // or is a plain DNS resolver, which does not listen on the mDNS port.
+    fn answer_mdns(&mut self, from: SocketAddrV4, query: &[u8]) {
+        let mut response = [0u8; MAX_UDP_PAYLOAD];
+        let Some((len, unicast)) = mdns::respond(query, from.port(), &mut response) else {
+            return;
+        };
+
+        let destination = if unicast {
+            from
+        } else {
+            SocketAddrV4::new(mdns::MDNS_GROUP.into(), mdns::MDNS_PORT)
+        };
+
+        let _ = self.send_udp(mdns::MDNS_PORT, destination, MDNS_TTL, &response[..len]);
+    }
+
+    // Whether a socket uses local `port`.
+    fn port_in_use(&self, port: u16) -> bool {
+        port == mdns::MDNS_PORT
+            || self
+                .sockets
+                .iter()
+                .flatten()
+                .any(|x| x.local.port() == port)
+    }
+
+    fn binding(&mut self, handle: Handle) -> &mut Binding {
+        self.sockets[handle.0]
+            .as_mut()
+            .expect("net_stack::binding(): socket was released")
+    }
+}
+
+fn ethernet_header(destination: MacAddress, source: MacAddress, ethertype: u16) -> Vec<u8> {
+    let mut header = Vec::with_capacity(ETHERNET_HEADER_SIZE + IPV4_HEADER_SIZE + UDP_HEADER_SIZE);
+    header.extend_from_slice(&destination.0);
+    header.extend_from_slice(&source.0);
+    header.extend_from_slice(&ethertype.to_be_bytes());
+    header
+}
+
+// Adds up `data` as big endian 16-bit words for the Internet checksum, see RFC 1071.
+fn sum_words(mut sum: u32, data: &[u8]) -> u32 {
+    let (words, rest) = data.as_chunks::<2>();
+    for &word in words {
+        sum += u16::from_be_bytes(word) as u32;
+    }
+
+    if let [last] = rest {
+        sum += (*last as u32) << 8;
+    }
+
+    sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// Computes the Internet checksum of `data`, which is zero for data that includes a
+/// valid checksum.
+fn checksum(data: &[u8]) -> u16 {
+    fold(sum_words(0, data))
+}
+
+/// Computes the checksum of a UDP datagram made of `parts`, which must all have an even
+/// length but the last.
+fn udp_checksum(source: Ipv4Addr, destination: Ipv4Addr, parts: &[&[u8]]) -> u16 {
+    let len: usize = parts.iter().map(|x| x.len()).sum();
+
+    let mut sum = sum_words(0, &source.octets());
+    sum = sum_words(sum, &destination.octets());
+    sum += IP_PROTOCOL_UDP as u32 + len as u32;
+
+    fold(parts.iter().fold(sum, |sum, x| sum_words(sum, x)))
+}
+
+/// Takes a frame from the network interface, see [`net::set_receiver`].
+fn receive(frame: &[u8]) {
+    let mut stack = STACK.lock();
+    let Some(stack) = stack.as_mut() else {
+        return;
+    };
+
+    let Some(header) = frame.get(..ETHERNET_HEADER_SIZE) else {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+
+    let destination = MacAddress(header[..6].try_into().unwrap());
+    let ethertype = u16::from_be_bytes([header[12], header[13]]);
+
+    // Multicast frames are filtered by address further up.
+    if destination != stack.mac && destination.0[0] & 1 == 0 {
+        return;
+    }
+
+    let payload = &frame[ETHERNET_HEADER_SIZE..];
+    match ethertype {
+        ETHERTYPE_ARP => stack.receive_arp(payload),
+        ETHERTYPE_IPV4 => stack.receive_ipv4(payload),
+        _ => {}
+    }
+}
+
+/// Asks again for the addresses datagrams wait for, and drops the datagrams of those
+/// that were asked for too often.
+fn retry_arp() {
+    let mut stack = STACK.lock();
+    let Some(stack) = stack.as_mut() else {
+        return;
+    };
+
+    let before = stack.pending.len();
+    stack.pending.retain(|x| x.requests < ARP_MAX_REQUESTS);
+    UNRESOLVED.fetch_add((before - stack.pending.len()) as u64, Ordering::Relaxed);
+
+    let mut asked: Vec<Ipv4Addr> = Vec::new();
+    for i in 0..stack.pending.len() {
+        let next_hop = stack.pending[i].next_hop;
+        stack.pending[i].requests += 1;
+
+        if !asked.contains(&next_hop) {
+            stack.send_arp(ARP_REQUEST, MacAddress([0; 6]), next_hop);
+            asked.push(next_hop);
+        }
+    }
+
+    stack.retry_armed = !stack.pending.is_empty();
+    if stack.retry_armed {
+        timer::add(ARP_RETRY_DELAY, TimerAction::Work(&ARP_WORK));
+    }
+}
+
+/// Drops the datagrams waiting for an ARP reply when the heap runs out, see
+/// [`heap::register_reclaimer`]. Returns the number of bytes given back.
+fn reclaim(_needed: usize) -> usize {
+    // The thread that ran out may be holding the stack itself.
+    let Some(mut stack) = STACK.try_lock() else {
+        return 0;
+    };
+    let Some(stack) = stack.as_mut() else {
+        return 0;
+    };
+
+    let freed = stack
+        .pending
+        .iter()
+        .flat_map(|x| &x.parts)
+        .map(|x| x.capacity())
+        .sum();
+
+    UNRESOLVED.fetch_add(stack.pending.len() as u64, Ordering::Relaxed);
+    stack.pending.clear();
+    freed
+}
+
+// Calls `f` with the stack, failing if it is not up.
+fn with_stack<R>(f: impl FnOnce(&mut Stack) -> socket::Result<R>) -> socket::Result<R> {
+    match STACK.lock().as_mut() {
+        Some(stack) => f(stack),
+        None => Err(ErrorKind::NetworkDown.into()),
+    }
+}
+
+// The socket calls below back the std-like sockets of socket.rs.
+
+/// Adds a UDP socket bound to `addr`, or to an ephemeral port if its port is 0.
+pub fn udp_bind(addr: SocketAddrV4) -> socket::Result<Handle> {
+    with_stack(|stack| {
+        let ip = *addr.ip();
+        if !ip.is_unspecified() && ip != stack.address() {
+            return Err(ErrorKind::AddrNotAvailable.into());
+        }
+
+        let port = match addr.port() {
+            0 => (0..u16::MAX - EPHEMERAL_PORT_START)
+                .map(|_| {
+                    let count = EPHEMERAL_PORTS.fetch_add(1, Ordering::Relaxed);
+                    EPHEMERAL_PORT_START + count % (u16::MAX - EPHEMERAL_PORT_START)
+                })
+                .find(|&x| !stack.port_in_use(x))
+                .ok_or(ErrorKind::AddrInUse)?,
+            port if stack.port_in_use(port) => return Err(ErrorKind::AddrInUse.into()),
+            port => port,
+        };
+
+        let binding = Binding {
+            local: SocketAddrV4::new(ip, port),
+            received: VecDeque::new(),
+        };
+
+        let index = match stack.sockets.iter().position(Option::is_none) {
+            Some(i) => i,
+            None => {
+                stack.sockets.push(None);
+                stack.sockets.len() - 1
+            }
+        };
+        stack.sockets[index] = Some(binding);
+
+        Ok(Handle(index))
+    })
+}
+
+/// Sends a datagram to `addr` from a UDP socket, see [`udp_bind`]. Datagrams to hosts
+/// whose Ethernet address is not known yet wait for it in the stack.
+pub fn udp_send_to(handle: Handle, data: &[u8], addr: SocketAddrV4) -> socket::Result<usize> {
+    if addr.port() == 0 || addr.ip().is_unspecified() {
+        return Err(ErrorKind::InvalidInput.into());
+    }
+
+    with_stack(|stack| {
+        let port = stack.binding(handle).local.port();
+        stack.send_udp(port, addr, DEFAULT_TTL, data)?;
+        Ok(data.len())
+    })
+}
+
+/// Takes the oldest datagram received on a UDP socket, cut short if it does not fit
+/// into `buf`.
+pub fn udp_recv_from(handle: Handle, buf: &mut [u8]) -> socket::Result<(usize, SocketAddrV4)> {
+    with_stack(|stack| {
+        let (from, data) = stack
+            .binding(handle)
+            .received
+            .pop_front()
+            .ok_or(ErrorKind::WouldBlock)?;
+        let len = data.len().min(buf.len());
+
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len, from))
+    })
+}
+
+pub fn udp_local_addr(handle: Handle) -> socket::Result<SocketAddrV4> {
+    with_stack(|stack| Ok(stack.binding(handle).local))
+}
+
+/// Closes a socket once its owner is gone, dropping what it did not receive.
+pub fn release(handle: Handle) {
+    let _ = with_stack(|stack| {
+        stack.sockets[handle.0] = None;
+        Ok(())
+    });
+}
+
+/// UDP socket talking to one server, e.g. a TFTP server.
+struct UdpPeer {
+    socket: UdpSocket,
+    server: Ipv4Addr,
+}
+
+impl tftp::Datagram for UdpPeer {
+    fn send_to(&mut self, port: u16, data: &[u8]) -> bool {
+        self.socket.send_to(data, (self.server, port)).is_ok()
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8], timeout: Duration) -> Option<(usize, u16)> {
+        let deadline = timer::uptime() + timeout;
+
+        // Datagrams from anyone but the server are dropped.
+        loop {
+            let left = deadline
+                .checked_sub(timer::uptime())
+                .filter(|x| !x.is_zero())?;
+            self.socket.set_read_timeout(Some(left)).ok()?;
+
+            let (len, from) = self.socket.recv_from(buf).ok()?;
+            match from {
+                core::net::SocketAddr::V4(x) if *x.ip() == self.server => {
+                    return Some((len, x.port()));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn arp_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    let (neighbors, pending) = STACK
+        .lock()
+        .as_ref()
+        .map(|x| (x.arp, x.pending.len()))
+        .ok_or(CommandError::Failed("network stack is not running"))?;
+
+    let now = timer::uptime();
+    for neighbor in neighbors.iter().flatten().filter(|x| x.expires > now) {
+        writeln!(
+            out,
+            "{: <15} {} expires in {}s",
+            neighbor.ip,
+            neighbor.mac,
+            (neighbor.expires - now).as_secs()
+        )?;
+    }
+
+    writeln!(
+        out,
+        "{pending} datagrams waiting, {} unresolved, {} frames dropped",
+        UNRESOLVED.load(Ordering::Relaxed),
+        DROPPED.load(Ordering::Relaxed)
+    )?;
+
+    Ok(())
+}
+
+/// Brings up the stack on the network interface with the address from
+/// [`net::ipv4_config`], and fetches the files named on the kernel command line from
+/// the TFTP server, if one is given.
+///
+/// The stack answers ARP requests for its address, takes IPv4 packets for it, its
+/// network's broadcast address and the mDNS group, and hands their UDP datagrams to
+/// the sockets of socket.rs. Queries to the mDNS port are answered by [`mdns::respond`].
+pub fn init() {
+    let _init = INIT.start();
+    net::INIT.require("net_stack");
+
+    let Some(mac) = net::with_nic(|nic| nic.mac_address()) else {
+        log!("net_stack::init(): no network interface");
+        return;
+    };
+
+    let config = net::ipv4_config();
+    let address = Ipv4Addr::from(config.address);
+
+    *STACK.lock() = Some(Stack {
+        mac,
+        config,
+        arp: [None; ARP_CACHE_SIZE],
+        pending: VecDeque::new(),
+        retry_armed: false,
+        sockets: Vec::new(),
+    });
+
+    net::set_receiver(Some(receive));
+
+    assert!(
+        heap::register_reclaimer(reclaim),
+        "net_stack::init(): failed to register the reclaimer"
+    );
+
+    let group = MacAddress::multicast(Ipv4Addr::from(mdns::MDNS_GROUP));
+    match net::with_nic(|nic| nic.set_multicast_filter(&[group])) {
+        Some(Ok(())) | Some(Err(NicError::Unsupported)) | None => {}
+        Some(Err(e)) => log!("net_stack::init(): cannot receive mDNS queries: {e:?}"),
+    }
+
+    mdns::set_address(Some(config.address));
+
+    if let Some(stack) = STACK.lock().as_mut() {
+        // Tell the network who has the address, then who we are.
+        stack.send_arp(ARP_REQUEST, MacAddress([0; 6]), address);
+
+        let mut announcement = [0u8; MAX_UDP_PAYLOAD];
+        if let Some(len) = mdns::announcement(&mut announcement) {
+            let group = SocketAddrV4::new(mdns::MDNS_GROUP.into(), mdns::MDNS_PORT);
+            let _ = stack.send_udp(mdns::MDNS_PORT, group, MDNS_TTL, &announcement[..len]);
+        }
+    }
+
+    assert!(
+        shell::register(Command {
+            name: "arp",
+            usage: "",
+            help: "show the ARP cache of the network stack",
+            run: arp_command,
+        }),
+        "net_stack::init(): failed to register arp"
+    );
+
+    log!(
+        "net_stack::init(): {address}/{} [ \x1b[0;32mOK\x1b[0m ]",
+        config.prefix_len
+    );
+
+    if let Some(server) = tftp::server() {
+        let peer = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map(|socket| UdpPeer {
+            socket,
+            server: server.into(),
+        });
+
+        let failed = peer.map_or(1, |mut peer| tftp::fetch_all(&mut peer));
+        if failed > 0 {
+            log!("net_stack::init(): {failed} files could not be fetched");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUR_MAC: MacAddress = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    const PEER_MAC: MacAddress = MacAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+    const OUR_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+    const PEER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+
+    // A stack on 10.0.2.15/24 with a UDP socket bound to port 7.
+    fn stack() -> Stack {
+        Stack {
+            mac: OUR_MAC,
+            config: Ipv4Config {
+                address: OUR_IP.octets(),
+                prefix_len: 24,
+                gateway: Some(PEER_IP.octets()),
+            },
+            arp: [None; ARP_CACHE_SIZE],
+            pending: VecDeque::new(),
+            retry_armed: false,
+            sockets: vec![Some(Binding {
+                local: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 7),
+                received: VecDeque::new(),
+            })],
+        }
+    }
+
+    fn received(stack: &Stack) -> Vec<(SocketAddrV4, Vec<u8>)> {
+        let binding = stack.sockets[0].as_ref().unwrap();
+        binding.received.iter().cloned().collect()
+    }
+
+    fn udp_datagram(source: Ipv4Addr, destination: Ipv4Addr, data: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::new();
+        datagram.extend_from_slice(&1234u16.to_be_bytes());
+        datagram.extend_from_slice(&7u16.to_be_bytes());
+        datagram.extend_from_slice(&((UDP_HEADER_SIZE + data.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(data);
+
+        let checksum = udp_checksum(source, destination, &[&datagram]);
+        datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+        datagram
+    }
+
+    fn ipv4_packet(source: Ipv4Addr, destination: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&((IPV4_HEADER_SIZE + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(&IP_FLAG_DF.to_be_bytes());
+        packet.extend_from_slice(&[DEFAULT_TTL, IP_PROTOCOL_UDP, 0, 0]);
+        packet.extend_from_slice(&source.octets());
+        packet.extend_from_slice(&destination.octets());
+
+        let checksum = checksum(&packet);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn arp_packet(operation: u16, sender: (MacAddress, Ipv4Addr), target_ip: Ipv4Addr) -> Vec<u8> {
+        let mut packet = vec![0, 1, 0x08, 0x00, 6, 4];
+        packet.extend_from_slice(&operation.to_be_bytes());
+        packet.extend_from_slice(&sender.0 .0);
+        packet.extend_from_slice(&sender.1.octets());
+        packet.extend_from_slice(&[0; 6]);
+        packet.extend_from_slice(&target_ip.octets());
+        packet
+    }
+
+    #[test]
+    fn checksum_matches_known_values() {
+        // The example of RFC 1071, section 3.
+        assert_eq!(
+            checksum(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]),
+            !0xddf2
+        );
+
+        let mut header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(checksum(&header), 0xb861);
+
+        header[10..12].copy_from_slice(&0xb861u16.to_be_bytes());
+        assert_eq!(checksum(&header), 0);
+
+        // An odd byte at the end is padded with zero.
+        assert_eq!(checksum(&[0x01]), !0x0100);
+        assert_eq!(checksum(&[]), 0xffff);
+    }
+
+    #[test]
+    fn udp_checksum_covers_pseudo_header() {
+        let datagram = udp_datagram(PEER_IP, OUR_IP, b"odd");
+        assert_eq!(udp_checksum(PEER_IP, OUR_IP, &[&datagram]), 0);
+
+        // Split into parts the same way send_udp() does.
+        let (header, data) = datagram.split_at(UDP_HEADER_SIZE);
+        assert_eq!(udp_checksum(PEER_IP, OUR_IP, &[header, data]), 0);
+
+        // The addresses are part of the sum.
+        assert_ne!(
+            udp_checksum(PEER_IP, Ipv4Addr::new(10, 0, 2, 16), &[&datagram]),
+            0
+        );
+    }
+
+    #[test]
+    fn delivers_udp_to_bound_socket() {
+        let mut stack = stack();
+        let from = SocketAddrV4::new(PEER_IP, 1234);
+
+        let datagram = udp_datagram(PEER_IP, OUR_IP, b"hello");
+        stack.receive_ipv4(&ipv4_packet(PEER_IP, OUR_IP, &datagram));
+
+        // Broadcasts to the network are for us too.
+        let broadcast = Ipv4Addr::new(10, 0, 2, 255);
+        let datagram = udp_datagram(PEER_IP, broadcast, b"all");
+        stack.receive_ipv4(&ipv4_packet(PEER_IP, broadcast, &datagram));
+
+        // Without a checksum, which is optional over IPv4.
+        let mut datagram = udp_datagram(PEER_IP, OUR_IP, b"unchecked");
+        datagram[6..8].copy_from_slice(&[0, 0]);
+        stack.receive_ipv4(&ipv4_packet(PEER_IP, OUR_IP, &datagram));
+
+        assert_eq!(
+            received(&stack),
+            [
+                (from, b"hello".to_vec()),
+                (from, b"all".to_vec()),
+                (from, b"unchecked".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_trailing_bytes() {
+        let mut stack = stack();
+
+        // Ethernet pads short frames, the lengths in the headers tell where data ends.
+        let datagram = udp_datagram(PEER_IP, OUR_IP, b"short");
+        let mut packet = ipv4_packet(PEER_IP, OUR_IP, &datagram);
+        packet.extend_from_slice(&[0; 20]);
+        stack.receive_ipv4(&packet);
+
+        assert_eq!(
+            received(&stack),
+            [(SocketAddrV4::new(PEER_IP, 1234), b"short".to_vec())]
+        );
+    }
+
+    #[test]
+    fn drops_malformed_packets() {
+        let mut stack = stack();
+        let datagram = udp_datagram(PEER_IP, OUR_IP, b"data");
+        let packet = ipv4_packet(PEER_IP, OUR_IP, &datagram);
+
+        let mut bad_checksum = packet.clone();
+        bad_checksum[8] -= 1;
+
+        let mut not_ipv4 = packet.clone();
+        not_ipv4[0] = 0x65;
+
+        let mut fragment = packet.clone();
+        fragment[6..8].copy_from_slice(&0x2000u16.to_be_bytes());
+        let checksum = checksum(&{
+            let mut header = fragment[..IPV4_HEADER_SIZE].to_vec();
+            header[10..12].fill(0);
+            header
+        });
+        fragment[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        let mut bad_udp_checksum = datagram.clone();
+        bad_udp_checksum[UDP_HEADER_SIZE] ^= 1;
+
+        let mut long_udp = datagram.clone();
+        long_udp[4..6].copy_from_slice(&100u16.to_be_bytes());
+
+        let packets = [
+            bad_checksum,
+            not_ipv4,
+            fragment,
+            packet[..packet.len() - 1].to_vec(),
+            packet[..IPV4_HEADER_SIZE - 1].to_vec(),
+            ipv4_packet(PEER_IP, OUR_IP, &bad_udp_checksum),
+            ipv4_packet(PEER_IP, OUR_IP, &long_udp),
+            ipv4_packet(PEER_IP, OUR_IP, &datagram[..UDP_HEADER_SIZE - 1]),
+            // Someone else's.
+            ipv4_packet(PEER_IP, Ipv4Addr::new(10, 0, 2, 16), &datagram),
+        ];
+
+        for packet in packets {
+            stack.receive_ipv4(&packet);
+        }
+
+        assert!(received(&stack).is_empty());
+    }
+
+    #[test]
+    fn learns_from_arp_replies() {
+        let mut stack = stack();
+
+        stack.receive_arp(&arp_packet(ARP_REPLY, (PEER_MAC, PEER_IP), OUR_IP));
+        assert_eq!(stack.lookup(PEER_IP), Some(PEER_MAC));
+    }
+
+    #[test]
+    fn ignores_unusable_arp_packets() {
+        let mut stack = stack();
+        let other_ip = Ipv4Addr::new(10, 0, 2, 3);
+
+        let mut not_ethernet = arp_packet(ARP_REPLY, (PEER_MAC, PEER_IP), OUR_IP);
+        not_ethernet[1] = 6;
+
+        let packets = [
+            not_ethernet,
+            arp_packet(ARP_REPLY, (PEER_MAC, PEER_IP), OUR_IP)[..ARP_PACKET_SIZE - 1].to_vec(),
+            // Nobody has a multicast or unspecified address.
+            arp_packet(ARP_REPLY, (BROADCAST_MAC, PEER_IP), OUR_IP),
+            arp_packet(ARP_REPLY, (PEER_MAC, Ipv4Addr::UNSPECIFIED), OUR_IP),
+            // Neighbors we never talked to are not learned from packets for others.
+            arp_packet(ARP_REQUEST, (PEER_MAC, PEER_IP), other_ip),
+        ];
+
+        for packet in packets {
+            stack.receive_arp(&packet);
+        }
+
+        assert!(stack.arp.iter().all(Option::is_none));
+    }
+}
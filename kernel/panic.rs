@@ -1,11 +1,104 @@
-use crate::print;
+use crate::debug;
+use crate::emergency_print;
+use crate::log;
+use crate::multiboot;
+use crate::power;
 
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
 
 use x86_64::instructions;
-use x86_64::instructions::port::PortWriteOnly;
 
-#[panic_handler]
+/// Exit status QEMU is asked for by `panic=exit` without a code.
+const DEFAULT_EXIT_CODE: u8 = 1;
+
+/// What the machine does once a panic was reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicAction {
+    /// Stop the processor, leaving the machine up for a debugger.
+    Halt,
+    /// Turn the machine off, which ends QEMU.
+    PowerOff,
+    /// Reset the machine.
+    Reboot,
+    /// End QEMU with this exit code, for automated runs, see [`power::qemu_exit`].
+    Exit(u8),
+}
+
+impl PanicAction {
+    /// Parses `halt`, `poweroff`, `reboot`, `exit` or `exit,<code>`.
+    fn parse(s: &str) -> Option<Self> {
+        match s.split_once(',') {
+            Some(("exit", code)) => code.parse().ok().map(Self::Exit),
+            Some(_) => None,
+            None => match s {
+                "halt" => Some(Self::Halt),
+                "poweroff" => Some(Self::PowerOff),
+                "reboot" => Some(Self::Reboot),
+                "exit" => Some(Self::Exit(DEFAULT_EXIT_CODE)),
+                _ => None,
+            },
+        }
+    }
+
+    fn encode(self) -> (u8, u8) {
+        match self {
+            Self::Halt => (0, 0),
+            Self::PowerOff => (1, 0),
+            Self::Reboot => (2, 0),
+            Self::Exit(code) => (3, code),
+        }
+    }
+
+    fn decode(kind: u8, code: u8) -> Self {
+        match kind {
+            0 => Self::Halt,
+            2 => Self::Reboot,
+            3 => Self::Exit(code),
+            _ => Self::PowerOff,
+        }
+    }
+}
+
+/// Called with the panic after it was reported and before the panic action, e.g. to
+/// flush application state. Runs with interrupts disabled and must not take locks.
+pub type PanicHook = fn(&PanicInfo);
+
+// Panic action, stored as the two halves of [`PanicAction::encode`] since the panic
+// handler cannot take locks.
+static ACTION_KIND: AtomicU8 = AtomicU8::new(1);
+static ACTION_CODE: AtomicU8 = AtomicU8::new(0);
+
+// Registered panic hook, 0 if there is none.
+static HOOK: AtomicUsize = AtomicUsize::new(0);
+
+// Set by the first panic, so a nested panic halts instead of reporting it again.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Returns what the machine does after a panic.
+pub fn action() -> PanicAction {
+    PanicAction::decode(
+        ACTION_KIND.load(Ordering::Relaxed),
+        ACTION_CODE.load(Ordering::Relaxed),
+    )
+}
+
+/// Changes what the machine does after a panic.
+pub fn set_action(action: PanicAction) {
+    let (kind, code) = action.encode();
+    ACTION_CODE.store(code, Ordering::Relaxed);
+    ACTION_KIND.store(kind, Ordering::Relaxed);
+}
+
+/// Registers a hook called on panic. Returns false if one is registered already.
+pub fn set_hook(hook: PanicHook) -> bool {
+    HOOK.compare_exchange(0, hook as usize, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+}
+
+// Host builds link the standard library, which brings its own panic handler.
+#[cfg_attr(not(any(test, fuzzing)), panic_handler)]
+#[cfg_attr(any(test, fuzzing), allow(dead_code))]
 fn panic(info: &PanicInfo) -> ! {
     const ANSI_FOREGROUND_RED: &str = "\x1b[31m";
     const ANSI_FOREGROUND_CYAN: &str = "\x1b[36m";
@@ -13,28 +106,58 @@ fn panic(info: &PanicInfo) -> ! {
 
     instructions::interrupts::disable();
 
+    // Reporting the panic or the hook may panic again, which must not recurse.
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        emergency_print!("panicked while panicking, halting\n");
+        power::halt();
+    }
+
     // print!("\x1bc");
-    print!("{ANSI_FOREGROUND_RED}[        panic]{ANSI_CLEAR} ");
+    emergency_print!("{ANSI_FOREGROUND_RED}[        panic]{ANSI_CLEAR} ");
 
     if let Some(location) = info.location() {
-        print!("{ANSI_FOREGROUND_CYAN}");
-        print!(
+        emergency_print!("{ANSI_FOREGROUND_CYAN}");
+        emergency_print!(
             "{0: <20} | line {1: <5} | {ANSI_CLEAR} ",
             location.file(),
             location.line()
         );
     }
 
-    if let Some(msg) = info.message() {
-        print!("{}\n", format_args!("{}", msg));
-    } else if let Some(payload) = info.payload().downcast_ref::<&'static str>() {
-        print!("{}\n", payload);
+    emergency_print!("{}\n", info.message());
+
+    emergency_print!("backtrace:\n{}", debug::backtrace());
+
+    let hook = HOOK.load(Ordering::Acquire);
+    if hook != 0 {
+        let hook: PanicHook = unsafe { core::mem::transmute(hook) };
+        hook(info);
     }
 
-    unsafe {
-        PortWriteOnly::new(0x604).write(0x2000u16);
-        loop {
-            instructions::hlt();
+    match action() {
+        PanicAction::Halt => power::halt(),
+        PanicAction::PowerOff => power::power_off(),
+        PanicAction::Reboot => power::reset(),
+        PanicAction::Exit(code) => power::qemu_exit(code),
+    }
+}
+
+/// Initializes the panic handler.
+///
+/// A panic powers the machine off unless `panic=<action>` on the kernel command line
+/// says otherwise: `halt` to keep it up for a debugger, `reboot`, or `exit` to end
+/// QEMU with a failure exit code, optionally given as `exit,<code>`. Applications can
+/// change the action with [`set_action`] and run code of their own with [`set_hook`].
+pub fn init() {
+    for value in multiboot::arg("panic") {
+        match PanicAction::parse(value) {
+            Some(action) => set_action(action),
+            None => log!("panic::init(): ignoring malformed panic={value}"),
         }
     }
+
+    log!(
+        "panic::init(): {:?} on panic [ \x1b[0;32mOK\x1b[0m ]",
+        action()
+    );
 }
@@ -1,6 +1,14 @@
+use crate::critical::IrqMutex;
+use crate::initcall::InitGuard;
 use crate::log;
+use crate::multiboot;
+use crate::shell;
+use crate::shell::{Command as ShellCommand, CommandError};
+use crate::trap;
+use crate::trap::IrqHandler;
 use alloc::vec::Vec;
 use bitflags::bitflags;
+use core::fmt::Write;
 use spin::Mutex;
 use x86_64::instructions::port::Port;
 
@@ -11,7 +19,69 @@ pub const PCI_CONFIG_ADDRESS_PORT: u16 = 0xCF8;
 pub const PCI_CONFIG_DATA_PORT: u16 = 0xCFC;
 
 // List of all valid PCI devices.
-static mut PCI_DEVICES: Mutex<Vec<DeviceConfig>> = Mutex::new(Vec::new());
+static PCI_DEVICES: Mutex<Vec<DeviceConfig>> = Mutex::new(Vec::new());
+
+// Initialization of the list of PCI devices.
+pub static INIT: InitGuard = InitGuard::new("pci");
+
+// Held while the buses are scanned, so rescans do not race each other.
+static RESCAN_LOCK: Mutex<()> = Mutex::new(());
+
+/// Maximum number of device listeners.
+const MAX_LISTENERS: usize = 8;
+
+// Drivers waiting for devices to appear or go away.
+static LISTENERS: Mutex<[Option<DeviceListener>; MAX_LISTENERS]> =
+    Mutex::new([None; MAX_LISTENERS]);
+
+/// Maximum number of entries of the interrupt routing table.
+const MAX_ROUTES: usize = 32;
+
+/// Maximum number of device interrupt handlers, several can share a line.
+const MAX_INTX_HANDLERS: usize = 16;
+
+/// Number of legacy interrupt pins, INTA# to INTD#.
+const INTX_PINS: u8 = 4;
+
+/// Number of interrupt lines of the PICs, the only global system interrupts that can be
+/// used without an I/O APIC.
+const PIC_LINES: u32 = 16;
+
+// Where the interrupt pins of the slots on the root bus are wired, as the platform
+// describes it.
+static ROUTES: Mutex<[Option<Route>; MAX_ROUTES]> = Mutex::new([None; MAX_ROUTES]);
+
+// Handlers of device interrupts and the line each is on, called from interrupt context.
+static INTX_HANDLERS: IrqMutex<[Option<(u8, IrqHandler)>; MAX_INTX_HANDLERS]> =
+    IrqMutex::new([None; MAX_INTX_HANDLERS]);
+
+/// Vendor ID read from a function that does not exist.
+const NO_VENDOR: u16 = 0xFFFF;
+
+/// Bit of the header type set by devices with more than one function.
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 0x80;
+
+/// Header type of PCI-to-PCI bridges.
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+/// The offsets in bytes to the expansion ROM BAR of ordinary devices and bridges.
+const EXPANSION_ROM_OFFSET: u8 = 0x30;
+const BRIDGE_EXPANSION_ROM_OFFSET: u8 = 0x38;
+
+/// Header type of ordinary devices.
+const HEADER_TYPE_DEVICE: u8 = 0x00;
+
+// The offsets in bytes to the primary, secondary and subordinate bus numbers of a
+// bridge.
+const PRIMARY_BUS_OFFSET: u8 = 0x18;
+const SECONDARY_BUS_OFFSET: u8 = 0x19;
+const SUBORDINATE_BUS_OFFSET: u8 = 0x1A;
+
+/// The offset in bytes to the memory window a bridge forwards to its secondary bus.
+const MEMORY_WINDOW_OFFSET: u8 = 0x20;
+
+/// Bridge memory windows are 1 MiB aligned.
+const MEMORY_WINDOW_GRANULARITY: u32 = 1 << 20;
 
 /// The offset in bytes to BAR0 within PCI configuration space.
 const BAR0_OFFSET: u8 = 0x10;
@@ -19,6 +89,28 @@ const BAR0_OFFSET: u8 = 0x10;
 /// ID for vendor-specific PCI capabilities.
 pub const PCI_CAP_ID_VNDR: u8 = 0x09;
 
+/// Size in bytes of the configuration space of a device function.
+pub const CONFIG_SPACE_SIZE: usize = 256;
+
+// Offsets in bytes of the fields of the header common to all types.
+const VENDOR_ID_OFFSET: u8 = 0x00;
+const DEVICE_ID_OFFSET: u8 = 0x02;
+const COMMAND_OFFSET: u8 = 0x04;
+const STATUS_OFFSET: u8 = 0x06;
+const REVISION_OFFSET: u8 = 0x08;
+const PROG_IF_OFFSET: u8 = 0x09;
+const SUBCLASS_OFFSET: u8 = 0x0A;
+const CLASS_OFFSET: u8 = 0x0B;
+const HEADER_TYPE_OFFSET: u8 = 0x0E;
+const INTERRUPT_LINE_OFFSET: u8 = 0x3C;
+const INTERRUPT_PIN_OFFSET: u8 = 0x3D;
+
+/// The offset in bytes to the pointer to the first capability.
+const CAPABILITIES_POINTER_OFFSET: u8 = 0x34;
+
+/// Capabilities can only be placed after the standard header.
+const CAPABILITIES_START: usize = 0x40;
+
 bitflags! {
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
     pub struct Status: u16 {
@@ -58,7 +150,6 @@ pub struct DeviceConfig {
     pub device_id: u16,
     pub vendor_id: u16,
     pub command: Command,
-    pub status: Status,
     pub revision: u8,
     pub prog_if: u8,
     pub subclass: u8,
@@ -67,50 +158,36 @@ pub struct DeviceConfig {
     pub base_addresses: [u32; 6],
     pub interrupt_pin: u8,
     pub interrupt_line: u8,
+    /// Sizes of the memory BARs in bytes, 0 for the others and the top halves of 64-bit
+    /// BARs. Only found by bus scans, since sizing a BAR briefly moves it.
+    pub bar_sizes: [u32; 6],
 }
 
 impl DeviceConfig {
+    /// Reads the configuration of a device function. Functions that do not exist have
+    /// a vendor ID of `0xFFFF`.
     pub fn new(bus: u8, device: u8, function: u8) -> Self {
-        use bit_field::BitField;
-
-        // TODO(kosinw): Assume for now all devices are header type 0x0
-        let mut register = ConfigRegister::new(bus, device, function, 0x00);
-        let data = register.read();
-
-        let vendor_id = data.get_bits(0..16) as u16;
-        let device_id = data.get_bits(16..32) as u16;
-
-        let mut register = ConfigRegister::new(bus, device, function, 0x04);
-        let data = register.read();
+        let register = |offset| ConfigRegister::new(bus, device, function, offset);
 
-        let command = Command::from_bits_truncate(data.get_bits(0..16) as u16);
-        let status = Status::from_bits_truncate(data.get_bits(16..32) as u16);
-
-        let mut register = ConfigRegister::new(bus, device, function, 0x08);
-        let data = register.read();
-
-        let revision = data.get_bits(0..8) as u8;
-        let prog_if = data.get_bits(8..16) as u8;
-        let subclass = data.get_bits(16..24) as u8;
-        let class = data.get_bits(24..32) as u8;
-
-        let mut register = ConfigRegister::new(bus, device, function, 0x0C);
-        let data = register.read();
-
-        let header_type = data.get_bits(16..24) as u8;
-
-        let mut register = ConfigRegister::new(bus, device, function, 0x3C);
-        let data = register.read();
-
-        let interrupt_line = data.get_bits(0..8) as u8;
-        let interrupt_pin = data.get_bits(8..16) as u8;
+        let vendor_id = register(VENDOR_ID_OFFSET).read_u16();
+        let device_id = register(DEVICE_ID_OFFSET).read_u16();
+        let command = Command::from_bits_truncate(register(COMMAND_OFFSET).read_u16());
+        let revision = register(REVISION_OFFSET).read_u8();
+        let prog_if = register(PROG_IF_OFFSET).read_u8();
+        let subclass = register(SUBCLASS_OFFSET).read_u8();
+        let class = register(CLASS_OFFSET).read_u8();
+        let header_type = register(HEADER_TYPE_OFFSET).read_u8();
+        let interrupt_line = register(INTERRUPT_LINE_OFFSET).read_u8();
+        let interrupt_pin = register(INTERRUPT_PIN_OFFSET).read_u8();
 
         let mut base_addresses = [0u32; 6];
 
-        for (i, ba) in base_addresses.iter_mut().enumerate() {
-            let offset = (BAR0_OFFSET as usize + i * 4) as u8;
-            let mut register = ConfigRegister::new(bus, device, function, offset);
-            *ba = register.read();
+        for (i, ba) in base_addresses
+            .iter_mut()
+            .enumerate()
+            .take(Self::bar_count(header_type))
+        {
+            *ba = register(BAR0_OFFSET + 4 * i as u8).read();
         }
 
         Self {
@@ -120,7 +197,6 @@ impl DeviceConfig {
             device_id,
             vendor_id,
             command,
-            status,
             revision,
             prog_if,
             subclass,
@@ -129,24 +205,116 @@ impl DeviceConfig {
             base_addresses,
             interrupt_line,
             interrupt_pin,
+            bar_sizes: [0; 6],
+        }
+    }
+
+    /// Finds the sizes of the memory BARs, with decoding turned off meanwhile so the
+    /// device does not answer at the addresses written during sizing.
+    fn size_bars(&mut self) {
+        let command = self.config_read_u16(COMMAND_OFFSET);
+        self.config_write_u16(
+            COMMAND_OFFSET,
+            command & !(Command::IO_SPACE | Command::MEMORY_SPACE).bits(),
+        );
+
+        let mut index = 0;
+        while index < Self::bar_count(self.header_type) {
+            let offset = BAR0_OFFSET + 4 * index as u8;
+            let old = self.base_addresses[index];
+
+            // 64-bit BARs take two slots.
+            let slots = if old & 0x7 == 0x4 { 2 } else { 1 };
+
+            if old & 0x1 == 0 {
+                self.config_write_word(offset, 0xffffffff);
+                let size_mask = self.config_read_word(offset) & 0xfffffff0;
+                self.config_write_word(offset, old);
+                self.bar_sizes[index] = (!size_mask).wrapping_add(1) & size_mask;
+            }
+
+            index += slots;
         }
+
+        self.config_write_u16(COMMAND_OFFSET, command);
     }
 
-    pub fn capabilities(&self) -> Option<CapabilityIter> {
+    /// Returns the number of base address registers of a header type. Bridges only
+    /// have two, their bus numbers and windows sit where the others would be.
+    fn bar_count(header_type: u8) -> usize {
+        match header_type & !HEADER_TYPE_MULTI_FUNCTION {
+            HEADER_TYPE_DEVICE => 6,
+            HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        }
+    }
+
+    /// Checks whether this and `other` are the same function of the same device in the
+    /// same slot.
+    pub fn is_same_device(&self, other: &DeviceConfig) -> bool {
+        (self.bus, self.device, self.function) == (other.bus, other.device, other.function)
+            && (self.vendor_id, self.device_id) == (other.vendor_id, other.device_id)
+    }
+
+    /// Reads the bus numbers and memory window if this is a PCI-to-PCI bridge.
+    pub fn bridge(&self) -> Option<Bridge> {
         use bit_field::BitField;
 
-        if self.status.contains(Status::CAPABILITIES_LIST) {
-            let caps_offset = (self.config_read_word(0x34).get_bits(2..8) << 2) as u8;
+        if self.header_type & !HEADER_TYPE_MULTI_FUNCTION != HEADER_TYPE_BRIDGE {
+            return None;
+        }
+
+        let window = self.config_read_word(MEMORY_WINDOW_OFFSET);
 
-            Some(CapabilityIter {
-                bus: self.bus,
-                device: self.device,
-                function: self.function,
-                next_capability_offset: Some(caps_offset),
-            })
-        } else {
-            None
+        // Bits 4 to 15 of either half are bits 20 to 31 of the address, the limit is
+        // the last byte of its megabyte.
+        let base = (window.get_bits(4..16)) << 20;
+        let limit = (window.get_bits(20..32) << 20) | (MEMORY_WINDOW_GRANULARITY - 1);
+
+        Some(Bridge {
+            primary: self.config_read_u8(PRIMARY_BUS_OFFSET),
+            secondary: self.config_read_u8(SECONDARY_BUS_OFFSET),
+            subordinate: self.config_read_u8(SUBORDINATE_BUS_OFFSET),
+            memory_window: (base < limit).then_some((base, limit)),
+        })
+    }
+
+    /// Returns the bus behind this device if it is a PCI-to-PCI bridge.
+    pub fn secondary_bus(&self) -> Option<u8> {
+        self.bridge().map(|x| x.secondary)
+    }
+
+    /// Returns the address the expansion ROM of the device is placed at, or `None` if it
+    /// has none or it was not given an address. The ROM only shows up there while it is
+    /// enabled by bit 0 of the register.
+    pub fn expansion_rom(&self) -> Option<u32> {
+        let offset = match self.header_type & !HEADER_TYPE_MULTI_FUNCTION {
+            HEADER_TYPE_DEVICE => EXPANSION_ROM_OFFSET,
+            HEADER_TYPE_BRIDGE => BRIDGE_EXPANSION_ROM_OFFSET,
+            _ => return None,
+        };
+
+        let address = self.config_read_word(offset) & 0xfffff800;
+        (address != 0).then_some(address)
+    }
+
+    /// Sets the primary, secondary and subordinate bus numbers of a bridge.
+    fn set_bus_numbers(&self, primary: u8, secondary: u8, subordinate: u8) {
+        self.config_write_u8(PRIMARY_BUS_OFFSET, primary);
+        self.config_write_u8(SECONDARY_BUS_OFFSET, secondary);
+        self.config_write_u8(SUBORDINATE_BUS_OFFSET, subordinate);
+    }
+
+    /// Reads the whole configuration space of the device function, so that it can be
+    /// parsed with [`capabilities`] and [`read_u32`].
+    pub fn read_config_space(&self) -> [u8; CONFIG_SPACE_SIZE] {
+        let mut config = [0u8; CONFIG_SPACE_SIZE];
+
+        for (i, word) in config.as_chunks_mut::<4>().0.iter_mut().enumerate() {
+            *word = self.config_read_word((i * 4) as u8).to_le_bytes();
         }
+
+        config
     }
 
     /// Reads word from PCI configuration space.
@@ -159,14 +327,32 @@ impl DeviceConfig {
         ConfigRegister::new(self.bus, self.device, self.function, offset).write(word);
     }
 
+    /// Reads a byte from PCI configuration space.
+    pub fn config_read_u8(&self, offset: u8) -> u8 {
+        ConfigRegister::new(self.bus, self.device, self.function, offset).read_u8()
+    }
+
+    /// Reads a 16-bit field from PCI configuration space, `offset` must be even.
+    pub fn config_read_u16(&self, offset: u8) -> u16 {
+        ConfigRegister::new(self.bus, self.device, self.function, offset).read_u16()
+    }
+
+    /// Writes a byte to PCI configuration space, leaving the rest of its word alone.
+    pub fn config_write_u8(&self, offset: u8, value: u8) {
+        ConfigRegister::new(self.bus, self.device, self.function, offset).write_u8(value);
+    }
+
+    /// Writes a 16-bit field to PCI configuration space, leaving the rest of its word
+    /// alone. `offset` must be even.
+    pub fn config_write_u16(&self, offset: u8, value: u16) {
+        ConfigRegister::new(self.bus, self.device, self.function, offset).write_u16(value);
+    }
+
     /// Enables PCI bus mastering (first-party DMA) for this device.
     pub fn enable_bus_mastering(&mut self) {
-        use bit_field::BitField;
-
-        let mut register = ConfigRegister::new(self.bus, self.device, self.function, 0x04);
-        let mut data = register.read();
-        data.set_bit(2, true);
-        register.write(data);
+        let command = self.config_read_u16(COMMAND_OFFSET) | Command::BUS_MASTER.bits();
+        self.config_write_u16(COMMAND_OFFSET, command);
+        self.command = Command::from_bits_truncate(command);
     }
 
     /// Returns the approriate base adddress region.
@@ -194,7 +380,7 @@ impl DeviceConfig {
             // Memory space
             let mut address = u64::from(old & 0xfffffff0);
             let prefetchable = old.get_bit(3);
-            let memory_bar_type = old.get_bits(1..2) as u8;
+            let memory_bar_type = old.get_bits(1..3) as u8;
 
             if memory_bar_type == 0x2 {
                 if bar_index >= 5 {
@@ -215,12 +401,66 @@ impl DeviceConfig {
     }
 }
 
+/// Bus numbers and forwarding window of a PCI-to-PCI bridge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bridge {
+    /// Bus the bridge sits on.
+    pub primary: u8,
+    /// Bus directly behind the bridge.
+    pub secondary: u8,
+    /// Highest bus behind the bridge, including behind bridges on its secondary bus.
+    pub subordinate: u8,
+    /// First and last address of the memory the bridge forwards to its secondary bus,
+    /// or `None` if it forwards none.
+    pub memory_window: Option<(u32, u32)>,
+}
+
+/// Reads the little endian word at `offset` of a configuration space, or `None` if it
+/// does not lie within `config`.
+pub fn read_u32(config: &[u8], offset: usize) -> Option<u32> {
+    let bytes = config.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// Reads the little endian 16-bit field at `offset` of a configuration space, or `None`
+/// if it does not lie within `config`.
+pub fn read_u16(config: &[u8], offset: usize) -> Option<u16> {
+    let bytes = config.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// Returns an iterator over the capability list of a configuration space read with
+/// [`DeviceConfig::read_config_space`].
+///
+/// Only the bytes in `config` are looked at, so a device reporting garbage can at
+/// worst end the list early. Pointers into the standard header or past the end of
+/// `config` end the list, as does a list longer than fits into `config`, which can
+/// only be a cycle.
+pub fn capabilities(config: &[u8]) -> CapabilityIter<'_> {
+    let status = read_u16(config, STATUS_OFFSET as usize).unwrap_or(0);
+
+    let next_capability_offset =
+        if Status::from_bits_truncate(status).contains(Status::CAPABILITIES_LIST) {
+            config
+                .get(CAPABILITIES_POINTER_OFFSET as usize)
+                .map(|x| x & !0x3)
+        } else {
+            None
+        };
+
+    CapabilityIter {
+        config,
+        next_capability_offset,
+        remaining: config.len().saturating_sub(CAPABILITIES_START) / 4,
+    }
+}
+
 #[derive(Debug)]
-pub struct CapabilityIter {
-    bus: u8,
-    device: u8,
-    function: u8,
+pub struct CapabilityIter<'a> {
+    config: &'a [u8],
     next_capability_offset: Option<u8>,
+    // Number of capabilities that fit into what is left of the configuration space.
+    remaining: usize,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -233,20 +473,20 @@ pub struct CapabilityInfo {
     pub private_header: u16,
 }
 
-impl Iterator for CapabilityIter {
+impl Iterator for CapabilityIter<'_> {
     type Item = CapabilityInfo;
 
     fn next(&mut self) -> Option<Self::Item> {
-        use bit_field::BitField;
-
-        let offset = self.next_capability_offset?;
+        let offset = self.next_capability_offset.take()? as usize;
 
-        let mut register = ConfigRegister::new(self.bus, self.device, self.function, offset);
-        let capability_header = register.read();
+        if offset < CAPABILITIES_START || self.remaining == 0 {
+            return None;
+        }
 
-        let id = capability_header.get_bits(0..8) as u8;
-        let next_offset = capability_header.get_bits(8..16) as u8;
-        let private_header = capability_header.get_bits(16..32) as u16;
+        let id = *self.config.get(offset)?;
+        let next_offset = *self.config.get(offset + 1)? & !0x3;
+        let private_header = read_u16(self.config, offset + 2)?;
+        self.remaining -= 1;
 
         self.next_capability_offset = if next_offset == 0 {
             None
@@ -255,7 +495,7 @@ impl Iterator for CapabilityIter {
         };
 
         Some(CapabilityInfo {
-            offset,
+            offset: offset as u8,
             id,
             private_header,
         })
@@ -288,10 +528,18 @@ impl BaseAddressRegister {
     }
 }
 
+/// A register in the configuration space of a device function, reached through the
+/// configuration address and data ports.
+///
+/// Bytes and 16-bit fields are accessed through the matching byte of the data port,
+/// so writing one does not write back the rest of its word. That matters next to
+/// registers whose bits are cleared by writing ones, like the status register.
 pub struct ConfigRegister {
     addr_port: Port<u32>,
     data_port: Port<u32>,
     addr: u32,
+    // Byte within the word, for the narrower accesses.
+    byte: u16,
 }
 
 impl ConfigRegister {
@@ -304,6 +552,45 @@ impl ConfigRegister {
                 | ((device as u32) << 11)
                 | ((function as u32) << 8)
                 | ((offset as u32) & 0xFC),
+            byte: (offset & 0x3) as u16,
+        }
+    }
+
+    pub fn read_u8(&mut self) -> u8 {
+        unsafe {
+            self.addr_port.write(self.addr);
+            Port::<u8>::new(PCI_CONFIG_DATA_PORT + self.byte).read()
+        }
+    }
+
+    pub fn read_u16(&mut self) -> u16 {
+        assert!(
+            self.byte & 0x1 == 0,
+            "pci::ConfigRegister::read_u16(): unaligned offset"
+        );
+
+        unsafe {
+            self.addr_port.write(self.addr);
+            Port::<u16>::new(PCI_CONFIG_DATA_PORT + self.byte).read()
+        }
+    }
+
+    pub fn write_u8(&mut self, v: u8) {
+        unsafe {
+            self.addr_port.write(self.addr);
+            Port::<u8>::new(PCI_CONFIG_DATA_PORT + self.byte).write(v);
+        }
+    }
+
+    pub fn write_u16(&mut self, v: u16) {
+        assert!(
+            self.byte & 0x1 == 0,
+            "pci::ConfigRegister::write_u16(): unaligned offset"
+        );
+
+        unsafe {
+            self.addr_port.write(self.addr);
+            Port::<u16>::new(PCI_CONFIG_DATA_PORT + self.byte).write(v);
         }
     }
 
@@ -322,13 +609,236 @@ impl ConfigRegister {
     }
 }
 
-fn add_device(device: &DeviceConfig) {
-    unsafe {
-        PCI_DEVICES.lock().push(*device);
+/// Whether a device appeared or went away, see [`register_listener`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Added,
+    Removed,
+}
+
+/// Called when a device appears or goes away, e.g. to bind a driver to a device
+/// hot-added with QEMU's `device_add`.
+pub type DeviceListener = fn(&DeviceConfig, DeviceEvent);
+
+// State of a scan of the buses.
+struct Scan<'a> {
+    devices: Vec<DeviceConfig>,
+    // Devices found by the previous scan, whose BARs must not be moved for sizing since
+    // drivers may be using them.
+    known: &'a [DeviceConfig],
+    // Buses visited so far, bit n for bus n.
+    visited: [u64; 4],
+    // Highest bus number visited so far.
+    last_bus: u8,
+}
+
+impl Scan<'_> {
+    // Marks a bus as visited, returns false if it was already.
+    fn visit(&mut self, bus: u8) -> bool {
+        let (word, bit) = (bus as usize >> 6, bus & 63);
+        let new = self.visited[word] & (1 << bit) == 0;
+        self.visited[word] |= 1 << bit;
+        self.last_bus = self.last_bus.max(bus);
+        new
+    }
+
+    fn check_function(&mut self, mut device: DeviceConfig) {
+        match self.known.iter().find(|x| x.is_same_device(&device)) {
+            Some(known) => device.bar_sizes = known.bar_sizes,
+            None => device.size_bars(),
+        }
+
+        self.devices.push(device);
+
+        let Some(bridge) = device.bridge() else {
+            return;
+        };
+
+        if bridge.secondary == 0 {
+            self.check_unnumbered_bridge(device);
+            return;
+        }
+
+        // A bridge claiming a bus we have seen is misconfigured and would make us go in
+        // circles.
+        if self.visit(bridge.secondary) {
+            let first = self.devices.len();
+            self.check_bus(bridge.secondary);
+            assign_resources(&bridge, &mut self.devices[first..]);
+        }
     }
 
+    // Numbers a bridge the firmware left alone, giving it the next free bus and every
+    // bus found behind it.
+    fn check_unnumbered_bridge(&mut self, device: DeviceConfig) {
+        let Some(secondary) = self.last_bus.checked_add(1) else {
+            log!(
+                "pci::scan(): out of bus numbers for bridge at {:02x}:{:02x}.{}",
+                device.bus,
+                device.device,
+                device.function
+            );
+            return;
+        };
+
+        // Let configuration cycles for any bus below reach the secondary bus until we
+        // know how many there are.
+        device.set_bus_numbers(device.bus, secondary, 0xFF);
+        self.visit(secondary);
+        self.check_bus(secondary);
+        device.set_bus_numbers(device.bus, secondary, self.last_bus);
+
+        log!(
+            "pci::scan(): numbered buses {secondary:02x}-{:02x} behind bridge at {:02x}:{:02x}.{}",
+            self.last_bus,
+            device.bus,
+            device.device,
+            device.function
+        );
+    }
+
+    fn check_device(&mut self, bus: u8, device: u8) {
+        let potential_device = DeviceConfig::new(bus, device, 0);
+
+        if potential_device.vendor_id == NO_VENDOR {
+            return;
+        }
+
+        self.check_function(potential_device);
+
+        // Is this a multi function device?
+        if potential_device.header_type & HEADER_TYPE_MULTI_FUNCTION != 0 {
+            for function in 1u8..8u8 {
+                let potential_device = DeviceConfig::new(bus, device, function);
+                if potential_device.vendor_id != NO_VENDOR {
+                    self.check_function(potential_device);
+                }
+            }
+        }
+    }
+
+    fn check_bus(&mut self, bus: u8) {
+        for device in 0u8..32u8 {
+            self.check_device(bus, device);
+        }
+    }
+}
+
+// Places the memory BARs the firmware left unassigned on the secondary bus of a bridge
+// in the free part of its memory window, after every BAR already there, and refreshes
+// the configurations of the devices it changed.
+fn assign_resources(bridge: &Bridge, devices: &mut [DeviceConfig]) {
+    let Some((base, limit)) = bridge.memory_window else {
+        return;
+    };
+
+    let (base, end) = (base as u64, limit as u64 + 1);
+    let children = || devices.iter().filter(|x| x.bus == bridge.secondary);
+    let bars = |device: &DeviceConfig| {
+        let device = *device;
+        (0..6)
+            .filter(move |&i| device.bar_sizes[i] != 0)
+            .map(move |i| {
+                (
+                    device,
+                    i,
+                    device.base_addresses[i] & 0xfffffff0,
+                    device.bar_sizes[i],
+                )
+            })
+    };
+
+    let mut next = children()
+        .flat_map(bars)
+        .filter(|&(_, _, address, _)| address != 0)
+        .map(|(_, _, address, size)| address as u64 + size as u64)
+        .filter(|&x| x > base && x <= end)
+        .fold(base, u64::max);
+
+    // Largest first, so alignment wastes the least of the window.
+    let mut unassigned: Vec<_> = children()
+        .flat_map(bars)
+        .filter(|&(_, _, address, _)| address == 0)
+        .collect();
+    unassigned.sort_unstable_by_key(|&(_, _, _, size)| core::cmp::Reverse(size));
+
+    if unassigned.is_empty() {
+        return;
+    }
+
+    for (device, index, _, size) in unassigned {
+        let address = (next + size as u64 - 1) & !(size as u64 - 1);
+
+        if address + size as u64 > end {
+            log!(
+                "pci::assign_resources(): no room for BAR{index} of {:02x}:{:02x}.{} in bridge window",
+                device.bus,
+                device.device,
+                device.function
+            );
+            continue;
+        }
+
+        let offset = BAR0_OFFSET + 4 * index as u8;
+        let old = device.base_addresses[index];
+        device.config_write_word(offset, (old & 0xf) | address as u32);
+
+        // The window lies below 4 GiB, so the top half of a 64-bit BAR is zero.
+        if old & 0x7 == 0x4 {
+            device.config_write_word(offset + 4, 0);
+        }
+
+        let command = device.config_read_u16(COMMAND_OFFSET);
+        device.config_write_u16(COMMAND_OFFSET, command | Command::MEMORY_SPACE.bits());
+
+        log!(
+            "pci::assign_resources(): BAR{index} of {:02x}:{:02x}.{} at {address:#x}",
+            device.bus,
+            device.device,
+            device.function
+        );
+
+        next = address + size as u64;
+    }
+
+    for device in devices.iter_mut().filter(|x| x.bus == bridge.secondary) {
+        let bar_sizes = device.bar_sizes;
+        *device = DeviceConfig::new(device.bus, device.device, device.function);
+        device.bar_sizes = bar_sizes;
+    }
+}
+
+// Finds every device reachable from the host bridges, following bridges to the buses
+// behind them instead of probing all 256 buses.
+fn scan(known: &[DeviceConfig]) -> Vec<DeviceConfig> {
+    let mut scan = Scan {
+        devices: Vec::new(),
+        known,
+        visited: [0; 4],
+        last_bus: 0,
+    };
+
+    // With more than one host bridge, function n of the first is the host bridge of
+    // bus n.
+    let host = DeviceConfig::new(0, 0, 0);
+    let roots = if host.header_type & HEADER_TYPE_MULTI_FUNCTION == 0 {
+        0..1
+    } else {
+        0..8
+    };
+
+    for function in roots {
+        if DeviceConfig::new(0, 0, function).vendor_id != NO_VENDOR && scan.visit(function) {
+            scan.check_bus(function);
+        }
+    }
+
+    scan.devices
+}
+
+fn log_device(device: &DeviceConfig, caller: &str, what: &str) {
     log!(
-        "pci::init(): Bus: {:02X} | Device: {:02X} | Function: {:02X} | [{:04X}:{:04X}]",
+        "{caller}: {what}Bus: {:02X} | Device: {:02X} | Function: {:02X} | [{:04X}:{:04X}]",
         device.bus,
         device.device,
         device.function,
@@ -337,37 +847,289 @@ fn add_device(device: &DeviceConfig) {
     );
 }
 
-fn check_device(bus: u8, device: u8) {
-    let potential_device = DeviceConfig::new(bus, device, 0);
+fn notify(device: &DeviceConfig, event: DeviceEvent) {
+    // Copy the listeners out so that a listener is free to register another one.
+    let listeners = *LISTENERS.lock();
 
-    if potential_device.vendor_id == 0xFFFFu16 {
-        return;
+    for listener in listeners.iter().flatten() {
+        listener(device, event);
     }
+}
 
-    add_device(&potential_device);
+/// Registers a listener that is called whenever [`rescan`] finds a device appeared or
+/// went away. Returns false if there is no room.
+///
+/// The listener is called right away for every device already present, so a driver
+/// can bind to all its devices from the listener alone.
+pub fn register_listener(listener: DeviceListener) -> bool {
+    // A rescan must not slip in between the listener going in and it seeing the
+    // devices, or it would miss a change.
+    let _rescan = RESCAN_LOCK.lock();
 
-    // Is this a multi function device?
-    if potential_device.header_type & 0x80 != 0 {
-        for function in 1u8..8u8 {
-            let potential_device = DeviceConfig::new(bus, device, function);
-            if potential_device.vendor_id != 0xFFFFu16 {
-                add_device(&potential_device);
-            }
+    {
+        let mut listeners = LISTENERS.lock();
+        let Some(slot) = listeners.iter_mut().find(|x| x.is_none()) else {
+            return false;
+        };
+        *slot = Some(listener);
+    }
+
+    let devices = PCI_DEVICES.lock().clone();
+    for device in &devices {
+        listener(device, DeviceEvent::Added);
+    }
+
+    true
+}
+
+/// Scans the PCI buses again, e.g. after a device was hot-added with QEMU's
+/// `device_add`, and tells the listeners about every device that appeared or went
+/// away. Returns the number of such changes.
+pub fn rescan() -> usize {
+    INIT.require("pci::rescan()");
+
+    let _rescan = RESCAN_LOCK.lock();
+    let old = PCI_DEVICES.lock().clone();
+    let devices = scan(&old);
+    *PCI_DEVICES.lock() = devices.clone();
+
+    let mut changes = 0;
+
+    for device in old
+        .iter()
+        .filter(|x| !devices.iter().any(|y| y.is_same_device(x)))
+    {
+        log_device(device, "pci::rescan()", "removed ");
+        notify(device, DeviceEvent::Removed);
+        changes += 1;
+    }
+
+    for device in devices
+        .iter()
+        .filter(|x| !old.iter().any(|y| y.is_same_device(x)))
+    {
+        log_device(device, "pci::rescan()", "added ");
+        notify(device, DeviceEvent::Added);
+        changes += 1;
+    }
+
+    changes
+}
+
+/// Calls `f` with the configuration of every PCI device.
+pub fn for_each_device(f: impl FnMut(&DeviceConfig)) {
+    PCI_DEVICES.lock().iter().for_each(f);
+}
+
+/// Finds the first PCI device configuration matching `f`.
+pub fn find(mut f: impl FnMut(&DeviceConfig) -> bool) -> Option<DeviceConfig> {
+    PCI_DEVICES.lock().iter().find(|&device| f(device)).copied()
+}
+
+/// Wiring of an interrupt pin of a slot on the root bus to a global system interrupt,
+/// e.g. from the `_PRT` of the host bridge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub slot: u8,
+    /// Pin from 1 for INTA# to 4 for INTD#.
+    pub pin: u8,
+    pub gsi: u32,
+}
+
+/// Adds an entry to the interrupt routing table, replacing the one for the same slot
+/// and pin. Returns false if the table is full.
+pub fn add_route(route: Route) -> bool {
+    let mut routes = ROUTES.lock();
+
+    let slot = routes
+        .iter()
+        .position(|x| x.is_some_and(|x| (x.slot, x.pin) == (route.slot, route.pin)))
+        .or_else(|| routes.iter().position(|x| x.is_none()));
+
+    match slot {
+        Some(i) => {
+            routes[i] = Some(route);
+            true
         }
+        None => false,
+    }
+}
+
+impl Route {
+    /// Parses `<slot>:<pin>=<gsi>` with the pin given as `A` to `D`, e.g. `3:A=11`.
+    fn parse(s: &str) -> Option<Self> {
+        let (slot, rest) = s.split_once(':')?;
+        let (pin, gsi) = rest.split_once('=')?;
+
+        let pin = match pin {
+            "A" => 1,
+            "B" => 2,
+            "C" => 3,
+            "D" => 4,
+            _ => return None,
+        };
+
+        Some(Self {
+            slot: slot.parse().ok().filter(|&x| x < 32)?,
+            pin,
+            gsi: gsi.parse().ok()?,
+        })
+    }
+}
+
+/// Follows the interrupt pin of a device through the bridges between it and the root
+/// bus. Returns the slot and pin the interrupt arrives at on the root bus.
+///
+/// Each bridge rotates the pins of the devices behind it by their slot number, see
+/// 9.1 of the PCI-to-PCI Bridge Architecture Specification.
+fn swizzle(devices: &[DeviceConfig], device: &DeviceConfig) -> Option<(u8, u8)> {
+    if !(1..=INTX_PINS).contains(&device.interrupt_pin) {
+        return None;
+    }
+
+    let (mut bus, mut slot, mut pin) = (device.bus, device.device, device.interrupt_pin);
+
+    // Every bridge takes us up one bus, a longer chain can only be a cycle.
+    for _ in 0..=u8::MAX {
+        let Some(bridge) = devices.iter().find(|x| x.secondary_bus() == Some(bus)) else {
+            return Some((slot, pin));
+        };
+
+        pin = (pin - 1 + slot) % INTX_PINS + 1;
+        (bus, slot) = (bridge.bus, bridge.device);
     }
+
+    None
 }
 
-fn check_bus(bus: u8) {
-    for device in 0u8..32u8 {
-        check_device(bus, device);
+fn route(devices: &[DeviceConfig], device: &DeviceConfig) -> Option<u8> {
+    let (slot, pin) = swizzle(devices, device)?;
+    let route = ROUTES
+        .lock()
+        .iter()
+        .flatten()
+        .find(|x| (x.slot, x.pin) == (slot, pin))
+        .copied();
+
+    match route {
+        Some(route) if route.gsi < PIC_LINES => Some(route.gsi as u8),
+        Some(_) => None,
+        // Firmware routed the interrupts for the PICs, trust it without a table.
+        None => (device.interrupt_line < PIC_LINES as u8).then_some(device.interrupt_line),
     }
 }
 
-/// Finds PCI device configuration given vendor and device ID.
-pub fn find_device(vendor_id: u16, device_id: u16) -> Option<DeviceConfig> {
-    unsafe { PCI_DEVICES.lock().iter() }
-        .find(|&&device| device.vendor_id == vendor_id && device.device_id == device_id)
-        .copied()
+/// Returns the IRQ line the legacy INTx interrupt of a device arrives on, or `None` if
+/// it has no interrupt pin or the interrupt cannot be routed.
+///
+/// The pin is followed through the bridges to the root bus and looked up in the table
+/// filled by [`add_route`]. Without an entry, the interrupt line firmware wrote into
+/// the device is used. Only the lines of the PICs can be used until there is an I/O
+/// APIC driver.
+pub fn irq(device: &DeviceConfig) -> Option<u8> {
+    let devices = PCI_DEVICES.lock();
+    route(&devices, device)
+}
+
+fn intx_interrupt(irq: u8) {
+    let handlers = INTX_HANDLERS.with(|x| *x);
+
+    for (_, handler) in handlers.iter().flatten().filter(|(x, _)| *x == irq) {
+        handler(irq);
+    }
+}
+
+/// Registers the handler of the legacy INTx interrupt of a device and unmasks it.
+/// Returns the IRQ line, or `None` if the interrupt cannot be routed, the line is
+/// taken by something other than a PCI device, or there is no room.
+///
+/// Devices can share a line, in which case all their handlers are called and each has
+/// to check whether its device raised the interrupt, e.g. by reading its ISR status.
+pub fn register_irq_handler(device: &DeviceConfig, handler: IrqHandler) -> Option<u8> {
+    let irq = irq(device)?;
+
+    let first = INTX_HANDLERS.with(|handlers| {
+        let first = !handlers.iter().flatten().any(|(x, _)| *x == irq);
+        let slot = handlers.iter_mut().find(|x| x.is_none())?;
+        *slot = Some((irq, handler));
+        Some(first)
+    })?;
+
+    if first {
+        trap::set_level_triggered(irq);
+
+        if !trap::register_irq_handler(irq, intx_interrupt) {
+            unregister_irq_handler(irq, handler);
+            return None;
+        }
+    }
+
+    let command = device.config_read_u16(COMMAND_OFFSET) & !Command::INTERRUPT_DISABLE.bits();
+    device.config_write_u16(COMMAND_OFFSET, command);
+
+    Some(irq)
+}
+
+/// Removes a handler registered with [`register_irq_handler`], masking the line once no
+/// device uses it anymore. Returns false if it was not registered for `irq`.
+pub fn unregister_irq_handler(irq: u8, handler: IrqHandler) -> bool {
+    let (found, last) = INTX_HANDLERS.with(|handlers| {
+        let Some(slot) = handlers
+            .iter_mut()
+            .find(|x| x.is_some_and(|(x, y)| x == irq && y as usize == handler as usize))
+        else {
+            return (false, false);
+        };
+
+        *slot = None;
+        (true, !handlers.iter().flatten().any(|(x, _)| *x == irq))
+    });
+
+    if last {
+        trap::unregister_irq_handler(irq, intx_interrupt);
+    }
+
+    found
+}
+
+fn lspci_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    match args {
+        [] => {}
+        ["rescan"] => writeln!(out, "{} changes", rescan())?,
+        _ => return Err(CommandError::Usage),
+    }
+
+    let devices = PCI_DEVICES.lock().clone();
+    for device in &devices {
+        write!(
+            out,
+            "{:02x}:{:02x}.{} [{:04x}:{:04x}] class {:02x}{:02x}{:02x} rev {:02x}",
+            device.bus,
+            device.device,
+            device.function,
+            device.vendor_id,
+            device.device_id,
+            device.class,
+            device.subclass,
+            device.prog_if,
+            device.revision
+        )?;
+
+        if let Some(irq) = route(&devices, device) {
+            write!(out, " irq {irq}")?;
+        }
+
+        match device.bridge() {
+            Some(bridge) => writeln!(
+                out,
+                " bridge to buses {:02x}-{:02x}",
+                bridge.secondary, bridge.subordinate
+            )?,
+            None => writeln!(out)?,
+        }
+    }
+
+    Ok(())
 }
 
 /// Initializes the PCI (Peripheral Component Interconnect) subsystem in the kernel.
@@ -375,11 +1137,49 @@ pub fn find_device(vendor_id: u16, device_id: u16) -> Option<DeviceConfig> {
 /// This function initializes the PCI subsystem, scans for PCI devices, and performs necessary
 /// setup to enable communication with PCI-connected devices. It sets up data structures and
 /// configurations needed for interacting with PCI devices in the system.
+///
+/// Without ACPI there is no `_PRT` to read the interrupt routing from, so entries are
+/// given on the kernel command line as `pci.route=<slot>:<pin>=<gsi>`, e.g.
+/// `pci.route=3:A=11`, see [`add_route`].
+///
+/// Only the buses reachable from the host bridges are scanned, by following PCI-to-PCI
+/// bridges. The device list is kept until [`rescan`] picks up hot-added or removed
+/// devices, which drivers hear about through [`register_listener`].
 pub fn init() {
+    let _init = INIT.start();
+    crate::heap::INIT.require("pci");
+
+    for value in multiboot::arg("pci.route") {
+        match Route::parse(value) {
+            Some(route) if add_route(route) => {}
+            Some(_) => {
+                log!("pci::init(): interrupt routing table is full, ignoring pci.route={value}")
+            }
+            None => log!("pci::init(): ignoring malformed pci.route={value}"),
+        }
+    }
+
     log!("pci::init(): enumerating PCI bus...");
-    // Enumerate over all busses and find all PCI devices.
-    for bus in 0u8..=255u8 {
-        check_bus(bus);
+    {
+        let _rescan = RESCAN_LOCK.lock();
+        let devices = scan(&[]);
+
+        for device in &devices {
+            log_device(device, "pci::init()", "");
+        }
+
+        *PCI_DEVICES.lock() = devices;
     }
+
+    assert!(
+        shell::register(ShellCommand {
+            name: "lspci",
+            usage: "[rescan]",
+            help: "list the PCI devices, scanning the buses again first if asked to",
+            run: lspci_command,
+        }),
+        "pci::init(): failed to register lspci"
+    );
+
     log!("pci::init(): successfully enumerated PCI bus [ \x1b[0;32mOK\x1b[0m ]");
 }
@@ -0,0 +1,258 @@
+use core::fmt;
+use core::fmt::Write;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+use crate::acpi;
+use crate::arch::{Arch, Current};
+use crate::log;
+use crate::shell;
+use crate::shell::{Command, CommandError};
+
+/// Maximum number of shutdown hooks.
+const MAX_SHUTDOWN_HOOKS: usize = 16;
+
+/// Command port of the 8042 keyboard controller.
+const KBD_COMMAND_PORT: u16 = 0x64;
+
+/// Keyboard controller command that pulses the reset line of the processor.
+const KBD_PULSE_RESET: u8 = 0xFE;
+
+/// ACPI PM1a control ports of QEMU's q35 and older i440fx machines, with the value that
+/// enters the S5 (soft off) sleep state.
+const ACPI_PM1A_CONTROL_PORTS: [u16; 2] = [0x604, 0xB004];
+const ACPI_SLEEP_S5: u16 = 0x2000;
+
+/// Port of QEMU's `isa-debug-exit` device, which ends QEMU with exit status
+/// `(value << 1) | 1` when written to.
+const QEMU_EXIT_PORT: u16 = 0xF4;
+
+/// What happens to the machine after the shutdown hooks ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownKind {
+    Reboot,
+    PowerOff,
+}
+
+/// Called before the machine reboots or powers off, e.g. to stop devices from doing DMA
+/// into memory the next kernel owns.
+pub type ShutdownHook = fn(ShutdownKind);
+
+// Hooks run at shutdown, with the name they are logged under.
+static SHUTDOWN_HOOKS: Mutex<[Option<(&'static str, ShutdownHook)>; MAX_SHUTDOWN_HOOKS]> =
+    Mutex::new([None; MAX_SHUTDOWN_HOOKS]);
+
+/// Registers a hook run at shutdown. Returns false if the table is full.
+///
+/// Hooks run in the reverse order they were registered in, so a driver is shut down
+/// before the subsystems it was built on.
+pub fn register_shutdown_hook(name: &'static str, hook: ShutdownHook) -> bool {
+    let mut hooks = SHUTDOWN_HOOKS.lock();
+    let Some(slot) = hooks.iter_mut().find(|x| x.is_none()) else {
+        return false;
+    };
+
+    *slot = Some((name, hook));
+    true
+}
+
+/// Removes a hook, e.g. when its driver is unloaded. Returns false if it was not
+/// registered.
+pub fn unregister_shutdown_hook(hook: ShutdownHook) -> bool {
+    let mut hooks = SHUTDOWN_HOOKS.lock();
+    let Some(slot) = hooks
+        .iter_mut()
+        .find(|x| x.is_some_and(|(_, x)| x as usize == hook as usize))
+    else {
+        return false;
+    };
+
+    *slot = None;
+    true
+}
+
+fn run_shutdown_hooks(kind: ShutdownKind) {
+    // Hooks may unregister themselves, so run them on a copy of the table.
+    let hooks = *SHUTDOWN_HOOKS.lock();
+
+    for (name, hook) in hooks.iter().rev().flatten() {
+        log!("power::shutdown(): running shutdown hook {name}");
+        hook(kind);
+    }
+}
+
+/// Runs the shutdown hooks and resets the machine.
+pub fn reboot() -> ! {
+    run_shutdown_hooks(ShutdownKind::Reboot);
+    log!("power::reboot(): restarting the machine");
+    reset()
+}
+
+/// Resets the machine right away, without running the shutdown hooks, e.g. after a
+/// panic when nothing else can be trusted anymore.
+pub fn reset() -> ! {
+    interrupts::disable();
+
+    unsafe {
+        Port::new(KBD_COMMAND_PORT).write(KBD_PULSE_RESET);
+
+        // Without a keyboard controller, triple fault with an empty IDT instead.
+        static EMPTY_IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
+        EMPTY_IDT.load();
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+
+    halt()
+}
+
+/// Runs the shutdown hooks and turns the machine off.
+pub fn poweroff() -> ! {
+    run_shutdown_hooks(ShutdownKind::PowerOff);
+    log!("power::poweroff(): powering off the machine");
+    power_off()
+}
+
+/// Turns the machine off right away, without running the shutdown hooks.
+pub fn power_off() -> ! {
+    interrupts::disable();
+    acpi::enter_s5();
+
+    // Without the FADT, try where QEMU puts the PM1a control register.
+    for port in ACPI_PM1A_CONTROL_PORTS {
+        unsafe { Port::new(port).write(ACPI_SLEEP_S5) };
+    }
+
+    halt()
+}
+
+/// Ends QEMU with exit status `(code << 1) | 1`, without running the shutdown hooks,
+/// so scripts can tell how a run went. Needs QEMU to be started with
+/// `-device isa-debug-exit,iobase=0xf4,iosize=0x04`, otherwise the machine is
+/// powered off instead.
+pub fn qemu_exit(code: u8) -> ! {
+    interrupts::disable();
+
+    unsafe { Port::new(QEMU_EXIT_PORT).write(code as u32) };
+    power_off()
+}
+
+/// Outcome of an application, turned into an exit status by [`exit`].
+pub trait Termination {
+    /// Returns the exit status, 0 for success.
+    fn report(self) -> u8;
+}
+
+impl Termination for () {
+    fn report(self) -> u8 {
+        0
+    }
+}
+
+impl Termination for u8 {
+    fn report(self) -> u8 {
+        self
+    }
+}
+
+impl<T: Termination, E: fmt::Debug> Termination for Result<T, E> {
+    fn report(self) -> u8 {
+        match self {
+            Ok(x) => x.report(),
+            Err(err) => {
+                log!("power::exit(): application failed: {err:?}");
+                1
+            }
+        }
+    }
+}
+
+/// Runs the shutdown hooks and ends the machine with the exit status of `status`.
+///
+/// Success powers the machine off, so QEMU exits with status 0. Any other status `n`
+/// ends QEMU through [`qemu_exit`] with status `(n << 1) | 1`, which is never 0, so
+/// scripts running applications can tell the two apart.
+pub fn exit(status: impl Termination) -> ! {
+    let code = status.report();
+
+    run_shutdown_hooks(ShutdownKind::PowerOff);
+    log!("power::exit(): exiting with status {code}");
+
+    if code == 0 {
+        power_off()
+    } else {
+        qemu_exit(code)
+    }
+}
+
+/// Stops the processor for good.
+pub fn halt() -> ! {
+    Current::halt()
+}
+
+fn reboot_command(args: &[&str], _out: &mut dyn Write) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    reboot()
+}
+
+fn poweroff_command(args: &[&str], _out: &mut dyn Write) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    poweroff()
+}
+
+fn exit_command(args: &[&str], _out: &mut dyn Write) -> Result<(), CommandError> {
+    let code = match args {
+        [] => 0,
+        [code] => code.parse().map_err(|_| CommandError::Usage)?,
+        _ => return Err(CommandError::Usage),
+    };
+
+    exit(code)
+}
+
+/// Initializes the shutdown path.
+///
+/// Subsystems that leave hardware in a state the next kernel or the firmware cannot
+/// cope with, like devices doing DMA, register a hook with [`register_shutdown_hook`].
+/// The `reboot`, `poweroff` and `exit` shell commands run the hooks before resetting
+/// or turning off the machine.
+pub fn init() {
+    let commands = [
+        Command {
+            name: "reboot",
+            usage: "",
+            help: "shut down devices and restart the machine",
+            run: reboot_command,
+        },
+        Command {
+            name: "poweroff",
+            usage: "",
+            help: "shut down devices and turn the machine off",
+            run: poweroff_command,
+        },
+        Command {
+            name: "exit",
+            usage: "[<status>]",
+            help: "shut down devices and end the machine with an exit status",
+            run: exit_command,
+        },
+    ];
+
+    for command in commands {
+        assert!(
+            shell::register(command),
+            "power::init(): failed to register {}",
+            command.name
+        );
+    }
+
+    log!("power::init(): shutdown hooks enabled [ \x1b[0;32mOK\x1b[0m ]");
+}
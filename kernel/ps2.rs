@@ -0,0 +1,222 @@
+use crate::console;
+use crate::console::ansi::Key;
+use crate::log;
+use crate::trap;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// IRQ line of the first PS/2 port, where the keyboard is.
+const IRQ_KEYBOARD: u8 = 1;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+
+const CONFIG_KEYBOARD_INTERRUPT: u8 = 1 << 0;
+const CONFIG_KEYBOARD_CLOCK_DISABLED: u8 = 1 << 4;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+/// How often to poll the status register before giving up on the controller.
+const POLL_LIMIT: usize = 100_000;
+
+/// Prefix of the scan codes of the keys added after the original keyboard.
+const SCANCODE_EXTENDED: u8 = 0xE0;
+/// Bit set in the scan code sent when a key is released.
+const SCANCODE_RELEASED: u8 = 0x80;
+
+const SCANCODE_LEFT_SHIFT: u8 = 0x2A;
+const SCANCODE_RIGHT_SHIFT: u8 = 0x36;
+const SCANCODE_CTRL: u8 = 0x1D;
+const SCANCODE_CAPS_LOCK: u8 = 0x3A;
+
+// Characters of scan code set 1, indexed by scan code, 0 for keys that type nothing.
+// Backspace and enter send what a serial terminal would.
+const NORMAL: &[u8] = b"\0\x1b1234567890-=\x7f\tqwertyuiop[]\r\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const SHIFTED: &[u8] = b"\0\x1b!@#$%^&*()_+\x7f\tQWERTYUIOP{}\r\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+/// Decoder of scan code set 1, which the controller translates every keyboard to.
+#[derive(Debug)]
+struct Keyboard {
+    // The previous byte was the extended prefix.
+    extended: bool,
+    // Number of shift keys held down.
+    shift: u8,
+    ctrl: bool,
+    caps_lock: bool,
+}
+
+impl Keyboard {
+    const fn new() -> Self {
+        Self {
+            extended: false,
+            shift: 0,
+            ctrl: false,
+            caps_lock: false,
+        }
+    }
+
+    /// Feeds one scan code, returning a key once one was pressed.
+    fn feed(&mut self, scancode: u8) -> Option<Key> {
+        if scancode == SCANCODE_EXTENDED {
+            self.extended = true;
+            return None;
+        }
+
+        let extended = core::mem::take(&mut self.extended);
+        let released = scancode & SCANCODE_RELEASED != 0;
+        let code = scancode & !SCANCODE_RELEASED;
+
+        match (extended, code) {
+            (false, SCANCODE_LEFT_SHIFT | SCANCODE_RIGHT_SHIFT) => {
+                self.shift = if released {
+                    self.shift.saturating_sub(1)
+                } else {
+                    self.shift.saturating_add(1).min(2)
+                };
+                return None;
+            }
+            (_, SCANCODE_CTRL) => {
+                self.ctrl = !released;
+                return None;
+            }
+            _ if released => return None,
+            (false, SCANCODE_CAPS_LOCK) => {
+                self.caps_lock = !self.caps_lock;
+                return None;
+            }
+            _ => {}
+        }
+
+        if extended {
+            return match code {
+                0x1C => Some(Key::Char(b'\r')),
+                0x47 => Some(Key::Home),
+                0x48 => Some(Key::Up),
+                0x4B => Some(Key::Left),
+                0x4D => Some(Key::Right),
+                0x4F => Some(Key::End),
+                0x50 => Some(Key::Down),
+                0x53 => Some(Key::Delete),
+                _ => None,
+            };
+        }
+
+        let table = if self.shift > 0 { SHIFTED } else { NORMAL };
+        let mut ch = table.get(code as usize).copied().filter(|&x| x != 0)?;
+
+        if self.caps_lock && ch.is_ascii_alphabetic() {
+            ch ^= 0x20;
+        }
+
+        if self.ctrl && ch.is_ascii_alphabetic() {
+            ch = console::uart::ctrl(ch.to_ascii_uppercase());
+        }
+
+        Some(Key::Char(ch))
+    }
+}
+
+static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard::new());
+
+fn status() -> u8 {
+    unsafe { Port::new(STATUS_PORT).read() }
+}
+
+// Waits until the controller has a byte for us, returns false if it never does.
+fn wait_output() -> bool {
+    (0..POLL_LIMIT).any(|_| status() & STATUS_OUTPUT_FULL != 0)
+}
+
+// Waits until the controller can take a byte, returns false if it never can.
+fn wait_input() -> bool {
+    (0..POLL_LIMIT).any(|_| status() & STATUS_INPUT_FULL == 0)
+}
+
+fn command(command: u8) -> bool {
+    wait_input() && {
+        unsafe { Port::new(COMMAND_PORT).write(command) };
+        true
+    }
+}
+
+fn read_data() -> Option<u8> {
+    wait_output().then(|| unsafe { Port::new(DATA_PORT).read() })
+}
+
+fn write_data(data: u8) -> bool {
+    wait_input() && {
+        unsafe { Port::new(DATA_PORT).write(data) };
+        true
+    }
+}
+
+// Handles the keyboard interrupt, passing the keys pressed on to the console as the
+// bytes a serial terminal would send for them.
+fn interrupt(_irq: u8) {
+    let mut keyboard = KEYBOARD.lock();
+
+    while status() & STATUS_OUTPUT_FULL != 0 {
+        let scancode: u8 = unsafe { Port::new(DATA_PORT).read() };
+
+        let Some(key) = keyboard.feed(scancode) else {
+            continue;
+        };
+
+        match key {
+            Key::Char(ch) => console::receive(&[ch]),
+            Key::Up => console::receive(b"\x1b[A"),
+            Key::Down => console::receive(b"\x1b[B"),
+            Key::Right => console::receive(b"\x1b[C"),
+            Key::Left => console::receive(b"\x1b[D"),
+            Key::Home => console::receive(b"\x1b[H"),
+            Key::End => console::receive(b"\x1b[F"),
+            Key::Delete => console::receive(b"\x1b[3~"),
+        }
+    }
+}
+
+/// Initializes the PS/2 keyboard, so the console takes input from the QEMU display
+/// window as well as from the serial port.
+///
+/// The controller is set up to interrupt on key presses and to translate them to scan
+/// code set 1, which is all the keyboard decoder understands. Machines without a
+/// controller are left alone.
+pub fn init() {
+    // Reads of a port nothing answers return all ones.
+    if status() == 0xFF {
+        log!("ps2::init(): no controller found, skipping");
+        return;
+    }
+
+    // Throw away whatever the keyboard sent before.
+    while status() & STATUS_OUTPUT_FULL != 0 {
+        let _: u8 = unsafe { Port::new(DATA_PORT).read() };
+    }
+
+    let Some(config) = command(COMMAND_READ_CONFIG).then(read_data).flatten() else {
+        log!("ps2::init(): controller does not respond, skipping");
+        return;
+    };
+
+    let config =
+        (config | CONFIG_KEYBOARD_INTERRUPT | CONFIG_TRANSLATION) & !CONFIG_KEYBOARD_CLOCK_DISABLED;
+
+    if !command(COMMAND_WRITE_CONFIG) || !write_data(config) {
+        log!("ps2::init(): controller does not respond, skipping");
+        return;
+    }
+
+    // The serial console may have been moved to the same line.
+    if !trap::register_irq_handler(IRQ_KEYBOARD, interrupt) {
+        log!("ps2::init(): irq {IRQ_KEYBOARD} is taken, skipping");
+        return;
+    }
+
+    log!("ps2::init(): keyboard on irq {IRQ_KEYBOARD} [ \x1b[0;32mOK\x1b[0m ]");
+}
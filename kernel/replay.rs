@@ -0,0 +1,315 @@
+use alloc::vec::Vec;
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::critical::IrqMutex;
+use crate::log;
+use crate::multiboot;
+use crate::mux;
+use crate::sched;
+use crate::sched::Priority;
+use crate::shell;
+use crate::shell::{Command, CommandError};
+use crate::timer;
+
+/// Size of the buffer input is recorded into.
+const LOG_SIZE: usize = 64 * 1024;
+
+// What happens to input, see [`Mode`].
+static MODE: AtomicU8 = AtomicU8::new(Mode::Off as u8);
+
+// Recorded input, one event per line.
+static LOG: IrqMutex<InputLog> = IrqMutex::new(InputLog {
+    data: [0; LOG_SIZE],
+    len: 0,
+});
+
+// Number of events that did not fit into the log.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+// Receive path of the network driver, fed with replayed frames.
+static FRAME_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Receives a replayed network frame, as if it came from the device.
+pub type FrameHandler = fn(&[u8]);
+
+/// Device input comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Console,
+    Network,
+}
+
+impl Source {
+    fn name(self) -> &'static str {
+        match self {
+            Source::Console => "console",
+            Source::Network => "net",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Source> {
+        [Source::Console, Source::Network]
+            .into_iter()
+            .find(|x| x.name() == name)
+    }
+}
+
+/// Whether input is recorded or replayed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Mode {
+    /// Input is passed on untouched.
+    Off = 0,
+    /// Input is passed on and recorded into the log.
+    Record = 1,
+    /// Input is dropped and the recording given as a boot module is replayed instead.
+    Replay = 2,
+}
+
+impl Mode {
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Off => "off",
+            Mode::Record => "record",
+            Mode::Replay => "replay",
+        }
+    }
+}
+
+struct InputLog {
+    data: [u8; LOG_SIZE],
+    len: usize,
+}
+
+impl Write for InputLog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > LOG_SIZE {
+            return Err(fmt::Error);
+        }
+
+        self.data[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// A line of the log: `<microseconds since boot> <source> <data in hex>`.
+struct Event<'a> {
+    time_us: u64,
+    source: Source,
+    data: &'a [u8],
+}
+
+impl fmt::Display for Event<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} ", self.time_us, self.source.name())?;
+        for byte in self.data {
+            write!(f, "{byte:02x}")?;
+        }
+        writeln!(f)
+    }
+}
+
+fn parse_event(line: &str, data: &mut Vec<u8>) -> Option<(u64, Source)> {
+    let mut fields = line.split_ascii_whitespace();
+    let time_us = fields.next()?.parse().ok()?;
+    let source = Source::from_name(fields.next()?)?;
+    let hex = fields.next()?;
+
+    if fields.next().is_some() {
+        return None;
+    }
+
+    data.clear();
+    for pair in hex.as_bytes().chunks(2) {
+        let pair = core::str::from_utf8(pair).ok().filter(|x| x.len() == 2)?;
+        data.push(u8::from_str_radix(pair, 16).ok()?);
+    }
+
+    Some((time_us, source))
+}
+
+/// Returns whether input is recorded or replayed.
+pub fn mode() -> Mode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => Mode::Record,
+        2 => Mode::Replay,
+        _ => Mode::Off,
+    }
+}
+
+/// Sets the receive path replayed network frames are handed to, replacing the
+/// previous one.
+pub fn set_frame_handler(handler: Option<FrameHandler>) {
+    FRAME_HANDLER.store(handler.map_or(0, |x| x as usize), Ordering::Release);
+}
+
+/// Passes input received from a device through the recorder. Returns false if the
+/// input has to be dropped because recorded input is replayed instead.
+///
+/// Drivers call this for every chunk of input before handing it on.
+pub fn input(source: Source, data: &[u8]) -> bool {
+    match mode() {
+        Mode::Off => true,
+        Mode::Record => {
+            record(source, data);
+            true
+        }
+        Mode::Replay => false,
+    }
+}
+
+fn record(source: Source, data: &[u8]) {
+    let event = Event {
+        time_us: timer::uptime().as_micros() as u64,
+        source,
+        data,
+    };
+
+    LOG.with(|log| {
+        let len = log.len;
+
+        // Keep only whole lines, so the log can always be replayed.
+        if write!(log, "{event}").is_err() {
+            log.len = len;
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Feeds the events of a recording to the console and the network driver, each at
+/// the time it was recorded at.
+fn replay(recording: &'static [u8]) {
+    let mut data = Vec::new();
+    let mut count = 0;
+
+    for (i, line) in recording.split(|&x| x == b'\n').enumerate() {
+        let line = core::str::from_utf8(line).unwrap_or("").trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((time_us, source)) = parse_event(line, &mut data) else {
+            log!("replay::replay(): ignoring malformed line {}", i + 1);
+            continue;
+        };
+
+        let now = timer::uptime().as_micros() as u64;
+        if time_us > now {
+            timer::sleep(Duration::from_micros(time_us - now));
+        }
+
+        match source {
+            Source::Console => mux::input(data.iter().copied()),
+            Source::Network => match FRAME_HANDLER.load(Ordering::Acquire) {
+                0 => log!("replay::replay(): no network driver, dropping frame"),
+                handler => {
+                    let handler: FrameHandler = unsafe { core::mem::transmute(handler) };
+                    handler(&data);
+                }
+            },
+        }
+
+        count += 1;
+    }
+
+    log!("replay::replay(): replayed {count} events [ \x1b[0;32mOK\x1b[0m ]");
+}
+
+/// Writes the recording, copying it out of the log a piece at a time so that input
+/// is not held up while the output is slow.
+fn dump(out: &mut dyn Write) -> fmt::Result {
+    let mut chunk = [0u8; 256];
+    let mut offset = 0;
+
+    loop {
+        let len = LOG.with(|log| {
+            let len = (log.len - offset).min(chunk.len());
+            chunk[..len].copy_from_slice(&log.data[offset..offset + len]);
+            len
+        });
+
+        if len == 0 {
+            return Ok(());
+        }
+
+        // The log only ever holds ASCII.
+        out.write_str(core::str::from_utf8(&chunk[..len]).unwrap_or(""))?;
+        offset += len;
+    }
+}
+
+fn replay_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    match args {
+        [] => writeln!(
+            out,
+            "mode {}, {} bytes recorded, {} events dropped",
+            mode().name(),
+            LOG.with(|log| log.len),
+            DROPPED.load(Ordering::Relaxed)
+        )?,
+        ["dump"] => dump(out)?,
+        _ => return Err(CommandError::Usage),
+    }
+
+    Ok(())
+}
+
+/// Initializes recording and replay of input.
+///
+/// With `replay=record` on the kernel command line, everything typed on the console
+/// and every frame the network driver receives is recorded with the time it arrived.
+/// The `replay dump` shell command prints the recording, one event per line. Booting
+/// with `replay=play` and that recording as the first boot module (the `-initrd`
+/// option of QEMU) feeds the same input to the shell and the network stack at the
+/// same times, ignoring the real devices, so a test sees identical input on every run.
+pub fn init() {
+    for value in multiboot::arg("replay") {
+        let mode = match value {
+            "record" => Mode::Record,
+            "play" => Mode::Replay,
+            _ => {
+                log!("replay::init(): ignoring malformed replay={value}");
+                continue;
+            }
+        };
+
+        MODE.store(mode as u8, Ordering::Relaxed);
+    }
+
+    if mode() == Mode::Replay {
+        match multiboot::info().and_then(|x| x.modules().first()) {
+            Some(module) => {
+                let recording = module.data();
+                sched::spawn("replay", Priority::Normal, move || replay(recording));
+            }
+            None => {
+                log!("replay::init(): no recording given as boot module, not replaying");
+                MODE.store(Mode::Off as u8, Ordering::Relaxed);
+            }
+        }
+    }
+
+    assert!(
+        shell::register(Command {
+            name: "replay",
+            usage: "[dump]",
+            help: "show the input recorder or print the recording",
+            run: replay_command,
+        }),
+        "replay::init(): failed to register shell command"
+    );
+
+    log!(
+        "replay::init(): input {} [ \x1b[0;32mOK\x1b[0m ]",
+        match mode() {
+            Mode::Off => "passed through",
+            Mode::Record => "recorded",
+            Mode::Replay => "replayed",
+        }
+    );
+}
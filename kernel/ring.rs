@@ -0,0 +1,189 @@
+/// What a [`RingBuffer`] does with a byte pushed while it is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// The oldest byte is discarded to make room, for input where the latest matters.
+    DropOldest,
+    /// The new byte is discarded, for input that must stay in order without gaps.
+    Reject,
+}
+
+/// Fixed-size FIFO of bytes.
+///
+/// Every byte value is stored as is, the buffer keeps its own count instead of using
+/// a sentinel. Bytes lost to overflow are counted.
+#[derive(Debug)]
+pub struct RingBuffer<const N: usize> {
+    data: [u8; N],
+    // Index of the oldest byte.
+    head: usize,
+    len: usize,
+    overflow: Overflow,
+    dropped: u64,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    /// Creates an empty buffer that handles overflow according to `overflow`.
+    pub const fn new(overflow: Overflow) -> Self {
+        Self {
+            data: [0; N],
+            head: 0,
+            len: 0,
+            overflow,
+            dropped: 0,
+        }
+    }
+
+    /// Gets the number of bytes the buffer can hold.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Gets the number of bytes waiting to be popped.
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub const fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Gets the number of bytes lost because the buffer was full.
+    pub const fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Appends a byte. Returns false if a byte was lost because the buffer was full,
+    /// either this one or the oldest depending on the overflow policy.
+    pub fn push(&mut self, ch: u8) -> bool {
+        let full = self.is_full();
+
+        if full {
+            self.dropped += 1;
+
+            match self.overflow {
+                Overflow::Reject => return false,
+                Overflow::DropOldest => {
+                    self.pop();
+                }
+            }
+        }
+
+        self.data[(self.head + self.len) % N] = ch;
+        self.len += 1;
+        !full
+    }
+
+    /// Removes and returns the oldest byte.
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let ch = self.data[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(ch)
+    }
+
+    /// Returns the bytes waiting to be popped, oldest first, without removing them.
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..self.len).map(|i| self.data[(self.head + i) % N])
+    }
+
+    /// Discards all bytes and returns how many there were.
+    pub fn clear(&mut self) -> usize {
+        let len = self.len;
+        self.head = 0;
+        self.len = 0;
+        len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Overflow, RingBuffer};
+
+    fn contents<const N: usize>(ring: &RingBuffer<N>) -> Vec<u8> {
+        ring.iter().collect()
+    }
+
+    #[test]
+    fn wraps_around_to_index_0() {
+        let mut ring = RingBuffer::<4>::new(Overflow::Reject);
+
+        for ch in 0..3 {
+            assert!(ring.push(ch));
+            assert_eq!(ring.pop(), Some(ch));
+        }
+
+        // The oldest byte is in the last slot, the ones after it wrap around to index 0.
+        assert_eq!(ring.head, 3);
+        for ch in [1, 2, 3, 4] {
+            assert!(ring.push(ch));
+        }
+        assert!(ring.is_full());
+        assert_eq!(ring.data, [2, 3, 4, 1]);
+        assert_eq!(contents(&ring), [1, 2, 3, 4]);
+
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.head, 0);
+        assert!(ring.push(5));
+        assert_eq!(contents(&ring), [2, 3, 4, 5]);
+        assert_eq!(ring.dropped(), 0);
+    }
+
+    #[test]
+    fn stores_zero_bytes() {
+        let mut ring = RingBuffer::<3>::new(Overflow::Reject);
+
+        assert!(ring.push(0));
+        assert!(ring.push(b'a'));
+        assert!(ring.push(0));
+        assert_eq!(ring.len(), 3);
+        assert_eq!(contents(&ring), [0, b'a', 0]);
+        assert_eq!(ring.pop(), Some(0));
+        assert_eq!(ring.pop(), Some(b'a'));
+        assert_eq!(ring.pop(), Some(0));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn reject_keeps_the_oldest_bytes() {
+        let mut ring = RingBuffer::<3>::new(Overflow::Reject);
+
+        for ch in 1..=3 {
+            assert!(ring.push(ch));
+        }
+        assert!(!ring.push(4));
+        assert!(!ring.push(5));
+        assert_eq!(contents(&ring), [1, 2, 3]);
+        assert_eq!(ring.dropped(), 2);
+
+        assert_eq!(ring.pop(), Some(1));
+        assert!(ring.push(6));
+        assert_eq!(contents(&ring), [2, 3, 6]);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest_bytes() {
+        let mut ring = RingBuffer::<3>::new(Overflow::DropOldest);
+
+        for ch in 1..=3 {
+            assert!(ring.push(ch));
+        }
+        assert!(!ring.push(4));
+        assert!(!ring.push(5));
+        assert_eq!(contents(&ring), [3, 4, 5]);
+        assert_eq!(ring.len(), ring.capacity());
+        assert_eq!(ring.dropped(), 2);
+
+        assert_eq!(ring.clear(), 3);
+        assert_eq!(ring.pop(), None);
+        assert!(ring.push(6));
+        assert_eq!(contents(&ring), [6]);
+    }
+}
@@ -0,0 +1,748 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::cpu;
+use crate::cpu::{CpuState, CPU_COUNT};
+use crate::critical::IrqMutex;
+use crate::debug;
+use crate::debug::Backtrace;
+use crate::idle;
+use crate::log;
+use crate::shell;
+use crate::shell::{Command, CommandError};
+use crate::tracepoint;
+use crate::trap;
+
+/// Size of the kernel stack given to every thread.
+pub const THREAD_STACK_SIZE: usize = 4096 * 4;
+
+/// Number of distinct priority levels.
+const PRIORITY_LEVELS: usize = 3;
+
+// Run queues, blocked threads and pending wakeups shared by all processors.
+static SCHEDULER: IrqMutex<Scheduler> = IrqMutex::new(Scheduler::new());
+
+// Saved stack pointer of the scheduler loop on each processor.
+static mut SCHEDULER_RSP: [u64; CPU_COUNT] = [0; CPU_COUNT];
+
+// Thread currently running on each processor. Only ever touched by the owning processor.
+static mut CURRENT: [Option<Box<Thread>>; CPU_COUNT] = {
+    const NONE: Option<Box<Thread>> = None;
+    [NONE; CPU_COUNT]
+};
+
+// Number of times a thread was switched to.
+static CONTEXT_SWITCHES: AtomicU64 = AtomicU64::new(0);
+
+// Number of task-local keys handed a slot so far.
+static LOCAL_KEYS: AtomicUsize = AtomicUsize::new(0);
+
+/// How often the task-local values of an exiting thread are dropped, for destructors
+/// that use other task-local values and so bring them back.
+const LOCAL_TEARDOWN_ROUNDS: usize = 4;
+
+extern "C" {
+    fn swtch(old: *mut u64, new: u64);
+}
+
+/// Unique identifier of a kernel thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+/// Scheduling priority of a kernel thread.
+///
+/// A runnable thread is never picked while a higher priority thread that may run on
+/// the same processor is runnable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Priority {
+    /// Batch work that only runs when nothing else is runnable.
+    Low = 0,
+    /// Default priority.
+    Normal = 1,
+    /// Latency-sensitive work such as network polling.
+    High = 2,
+}
+
+impl Priority {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "low" => Some(Self::Low),
+            "normal" => Some(Self::Normal),
+            "high" => Some(Self::High),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::Normal => "normal",
+            Self::High => "high",
+        }
+    }
+}
+
+/// Set of processors a thread is allowed to run on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuSet(u64);
+
+impl CpuSet {
+    /// Every processor.
+    pub const fn all() -> Self {
+        Self(u64::MAX)
+    }
+
+    /// Only the given processor.
+    pub const fn single(id: usize) -> Self {
+        Self(1 << id)
+    }
+
+    /// Adds a processor to the set.
+    pub const fn with(self, id: usize) -> Self {
+        Self(self.0 | Self::single(id).0)
+    }
+
+    /// Checks whether a processor is part of the set.
+    pub const fn contains(&self, id: usize) -> bool {
+        self.0 & (1 << id) != 0
+    }
+
+    /// Parses `all` or a comma separated list of processors, e.g. `0,2`.
+    fn parse(s: &str) -> Option<Self> {
+        if s == "all" {
+            return Some(Self::all());
+        }
+
+        s.split(',').try_fold(Self(0), |set, id| {
+            let id = id.parse().ok().filter(|&x| x < CPU_COUNT)?;
+            Some(set.with(id))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ThreadState {
+    Runnable,
+    Running,
+    Blocked,
+    Exited,
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    priority: Priority,
+    affinity: CpuSet,
+    state: ThreadState,
+    rsp: u64,
+    _stack: Vec<u8>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    // Task-local values, by slot of their key. Each is boxed so that it stays put when
+    // the vector grows.
+    locals: Vec<Option<Box<dyn Any + Send>>>,
+}
+
+struct Scheduler {
+    next_id: u64,
+    run_queues: [VecDeque<Box<Thread>>; PRIORITY_LEVELS],
+    blocked: BTreeMap<ThreadId, Box<Thread>>,
+    pending_wakeups: BTreeSet<ThreadId>,
+    // Threads that have not exited, wherever they are.
+    live: BTreeSet<ThreadId>,
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        Self {
+            next_id: 1,
+            run_queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            blocked: BTreeMap::new(),
+            pending_wakeups: BTreeSet::new(),
+            live: BTreeSet::new(),
+        }
+    }
+
+    fn enqueue(&mut self, mut thread: Box<Thread>) {
+        thread.state = ThreadState::Runnable;
+        self.run_queues[thread.priority as usize].push_back(thread);
+    }
+
+    /// Removes the highest priority thread that is allowed to run on `cpu`.
+    fn pick(&mut self, cpu: usize) -> Option<Box<Thread>> {
+        for queue in self.run_queues.iter_mut().rev() {
+            if let Some(i) = queue.iter().position(|x| x.affinity.contains(cpu)) {
+                return queue.remove(i);
+            }
+        }
+
+        None
+    }
+
+    /// Removes a runnable or blocked thread so that it can be modified.
+    fn take(&mut self, id: ThreadId) -> Option<Box<Thread>> {
+        for queue in self.run_queues.iter_mut() {
+            if let Some(i) = queue.iter().position(|x| x.id == id) {
+                return queue.remove(i);
+            }
+        }
+
+        self.blocked.remove(&id)
+    }
+
+    /// Puts back a thread removed with [`Scheduler::take`].
+    fn restore(&mut self, thread: Box<Thread>) {
+        if thread.state == ThreadState::Blocked {
+            self.blocked.insert(thread.id, thread);
+        } else {
+            self.enqueue(thread);
+        }
+    }
+}
+
+// All scheduler state may be touched from interrupt handlers through [`wake`].
+fn with_scheduler<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    SCHEDULER.with(f)
+}
+
+fn cpu_id() -> usize {
+    unsafe { cpu::current().id() }
+}
+
+/// Spawns a new kernel thread with the given priority that may run on any processor.
+pub fn spawn<F>(name: &'static str, priority: Priority, f: F) -> ThreadId
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_on(name, priority, CpuSet::all(), f)
+}
+
+/// Spawns a new kernel thread restricted to the processors in `affinity`.
+pub fn spawn_on<F>(name: &'static str, priority: Priority, affinity: CpuSet, f: F) -> ThreadId
+where
+    F: FnOnce() + Send + 'static,
+{
+    let stack = vec![0u8; THREAD_STACK_SIZE];
+
+    // Build an initial frame that swtch() can return into: six callee-saved
+    // registers, then thread_start() as the return address and a fake return
+    // address for thread_start() itself so its stack is aligned like after a call.
+    let top = (stack.as_ptr() as u64 + THREAD_STACK_SIZE as u64) & !0xf;
+    let rsp = top - 8 * 8;
+
+    unsafe {
+        let frame = rsp as *mut u64;
+        for i in 0..6 {
+            frame.add(i).write(0);
+        }
+        frame
+            .add(6)
+            .write(thread_start as extern "C" fn() -> ! as usize as u64);
+        frame.add(7).write(0);
+    }
+
+    let id = with_scheduler(|s| {
+        let id = ThreadId(s.next_id);
+        s.next_id += 1;
+        s.live.insert(id);
+
+        s.enqueue(Box::new(Thread {
+            id,
+            name,
+            priority,
+            affinity,
+            state: ThreadState::Runnable,
+            rsp,
+            _stack: stack,
+            entry: Some(Box::new(f)),
+            locals: Vec::new(),
+        }));
+
+        id
+    });
+
+    kick(affinity);
+    id
+}
+
+/// Wakes the idle processors other than this one that may run a thread with the given
+/// affinity.
+fn kick(affinity: CpuSet) {
+    let this = cpu_id();
+
+    for id in (0..CPU_COUNT).filter(|&x| x != this && affinity.contains(x)) {
+        if cpu::state(id) == CpuState::Online {
+            idle::wake(id);
+        }
+    }
+}
+
+extern "C" fn thread_start() -> ! {
+    let entry = unsafe {
+        CURRENT[cpu_id()]
+            .as_mut()
+            .and_then(|x| x.entry.take())
+            .expect("sched::thread_start(): thread has no entry point")
+    };
+
+    entry();
+    exit();
+}
+
+/// Returns the identifier of the thread running on this processor, if any.
+pub fn current() -> Option<ThreadId> {
+    unsafe { CURRENT[cpu_id()].as_ref().map(|x| x.id) }
+}
+
+/// Switches from the current thread back into the scheduler loop of this processor.
+fn switch_to_scheduler(state: ThreadState) {
+    let id = cpu_id();
+
+    unsafe {
+        let Some(thread) = CURRENT[id].as_mut() else {
+            return;
+        };
+
+        thread.state = state;
+        swtch(&mut thread.rsp, SCHEDULER_RSP[id]);
+    }
+}
+
+/// Gives up the processor to other runnable threads.
+///
+/// Does nothing when called outside of a kernel thread.
+pub fn yield_now() {
+    switch_to_scheduler(ThreadState::Running);
+}
+
+/// Blocks the current thread until [`wake`] is called for it.
+///
+/// Wakeups behave like a permit: if [`wake`] was called since the thread last
+/// blocked, this returns immediately.
+pub fn block() {
+    switch_to_scheduler(ThreadState::Blocked);
+}
+
+/// Makes a blocked thread runnable again. Safe to call from interrupt handlers.
+///
+/// Threads that have exited are ignored.
+pub fn wake(id: ThreadId) {
+    let affinity = with_scheduler(|s| {
+        let thread = s.blocked.remove(&id);
+        let affinity = thread.as_ref().map(|x| x.affinity);
+
+        match thread {
+            Some(thread) => s.enqueue(thread),
+            None if s.live.contains(&id) => {
+                s.pending_wakeups.insert(id);
+            }
+            None => {}
+        }
+
+        affinity
+    });
+
+    if let Some(affinity) = affinity {
+        kick(affinity);
+    }
+}
+
+/// Terminates the current thread. A pending [`wake`] for it is dropped, later ones are
+/// ignored.
+///
+/// Its task-local values are dropped first, while it still runs, so their destructors
+/// may block or use other task-local values.
+pub fn exit() -> ! {
+    for _ in 0..LOCAL_TEARDOWN_ROUNDS {
+        let Some(thread) = (unsafe { CURRENT[cpu_id()].as_mut() }) else {
+            break;
+        };
+
+        let locals = core::mem::take(&mut thread.locals);
+        if locals.iter().all(|x| x.is_none()) {
+            break;
+        }
+
+        drop(locals);
+    }
+
+    switch_to_scheduler(ThreadState::Exited);
+    unreachable!("sched::exit(): exited thread was scheduled again");
+}
+
+/// Error returned when a task-local value is used outside of a kernel thread, which
+/// includes interrupt handlers, as they do not run on behalf of the thread they
+/// interrupted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessError;
+
+/// Key of a task-local value, declared with [`task_local!`](crate::task_local).
+///
+/// Every kernel thread has its own value, created by the initializer the first time the
+/// thread uses it and dropped when the thread exits. Values are only ever touched by
+/// their thread, so per-task state like an RNG or a logging context needs no locks,
+/// but must be `Send` as the thread may move between processors. Values are shared
+/// references, use `Cell` or `RefCell` for ones that change.
+pub struct LocalKey<T: Send + 'static> {
+    init: fn() -> T,
+    // Index into the values of a thread plus one, or zero until the key is first used.
+    slot: AtomicUsize,
+}
+
+impl<T: Send + 'static> LocalKey<T> {
+    #[doc(hidden)]
+    pub const fn new(init: fn() -> T) -> Self {
+        Self {
+            init,
+            slot: AtomicUsize::new(0),
+        }
+    }
+
+    fn slot(&self) -> usize {
+        let slot = self.slot.load(Ordering::Acquire);
+        if slot != 0 {
+            return slot - 1;
+        }
+
+        // Another processor may hand out a slot at the same time, the first one wins.
+        let new = LOCAL_KEYS.fetch_add(1, Ordering::Relaxed) + 1;
+        match self
+            .slot
+            .compare_exchange(0, new, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => new - 1,
+            Err(slot) => slot - 1,
+        }
+    }
+
+    // Returns the value of the current thread, if it has one already.
+    fn existing(&'static self) -> Result<Option<*const T>, AccessError> {
+        if trap::in_interrupt() {
+            return Err(AccessError);
+        }
+
+        let value = unsafe { CURRENT[cpu_id()].as_ref() }
+            .ok_or(AccessError)?
+            .locals
+            .get(self.slot())
+            .and_then(|x| x.as_ref())
+            .map(|x| x.as_ref() as *const dyn Any as *const T);
+
+        Ok(value)
+    }
+
+    // Returns the value of the current thread, creating it if need be.
+    fn get(&'static self) -> Result<*const T, AccessError> {
+        if let Some(value) = self.existing()? {
+            return Ok(value);
+        }
+
+        // The initializer may use other task-local values, so the thread must not be
+        // borrowed while it runs.
+        let value: Box<dyn Any + Send> = Box::new((self.init)());
+        let thread = unsafe { CURRENT[cpu_id()].as_mut() }.ok_or(AccessError)?;
+        let slot = self.slot();
+
+        if thread.locals.len() <= slot {
+            thread.locals.resize_with(slot + 1, || None);
+        }
+
+        let value = thread.locals[slot].insert(value);
+        Ok(value.as_ref() as *const dyn Any as *const T)
+    }
+
+    /// Calls `f` with the value of the current thread, or returns an error outside of
+    /// a kernel thread.
+    pub fn try_with<R>(&'static self, f: impl FnOnce(&T) -> R) -> Result<R, AccessError> {
+        // The value is boxed and only dropped when the thread exits, so the reference
+        // stays valid even if `f` uses other keys or the thread moves.
+        let value = self.get()?;
+        Ok(f(unsafe { &*value }))
+    }
+
+    /// Calls `f` with the value of the current thread if it has one already, without
+    /// creating it. Unlike [`LocalKey::try_with`] this never allocates, so it may be
+    /// used on paths the allocator itself takes, like logging.
+    pub fn try_with_existing<R>(&'static self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        let value = self.existing().ok()??;
+        Some(f(unsafe { &*value }))
+    }
+
+    /// Calls `f` with the value of the current thread.
+    ///
+    /// Panics when called outside of a kernel thread.
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        self.try_with(f)
+            .expect("sched::LocalKey::with(): not called from a kernel thread")
+    }
+}
+
+/// Declares task-local values, each a [`LocalKey`] created by its initializer the
+/// first time a thread uses it:
+///
+/// ```ignore
+/// task_local! {
+///     static REQUESTS: Cell<u64> = Cell::new(0);
+/// }
+///
+/// REQUESTS.with(|x| x.set(x.get() + 1));
+/// ```
+#[macro_export]
+macro_rules! task_local {
+    () => {};
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => {
+        $(#[$attr])*
+        $vis static $name: $crate::sched::LocalKey<$t> = $crate::sched::LocalKey::new({
+            fn init() -> $t {
+                $init
+            }
+            init
+        });
+        $crate::task_local!($($rest)*);
+    };
+}
+
+/// Changes the priority of a thread. Returns false if the thread does not exist.
+pub fn set_priority(id: ThreadId, priority: Priority) -> bool {
+    update(id, |x| x.priority = priority)
+}
+
+/// Changes the set of processors a thread may run on. Returns false if the thread
+/// does not exist.
+pub fn set_affinity(id: ThreadId, affinity: CpuSet) -> bool {
+    update(id, |x| x.affinity = affinity)
+}
+
+fn update(id: ThreadId, f: impl FnOnce(&mut Thread)) -> bool {
+    let cpu = cpu_id();
+
+    if let Some(thread) = unsafe { CURRENT[cpu].as_mut() }.filter(|x| x.id == id) {
+        f(thread);
+        return true;
+    }
+
+    with_scheduler(|s| match s.take(id) {
+        Some(mut thread) => {
+            f(&mut thread);
+            s.restore(thread);
+            true
+        }
+        None => false,
+    })
+}
+
+/// Returns the number of runnable threads that may run on this processor.
+pub fn runnable_count() -> usize {
+    let id = cpu_id();
+    with_scheduler(|s| {
+        s.run_queues
+            .iter()
+            .flatten()
+            .filter(|x| x.affinity.contains(id))
+            .count()
+    })
+}
+
+/// Checks whether any thread that may run on this processor is runnable.
+pub fn has_runnable() -> bool {
+    let id = cpu_id();
+    with_scheduler(|s| {
+        s.run_queues
+            .iter()
+            .flatten()
+            .any(|x| x.affinity.contains(id))
+    })
+}
+
+/// Returns the number of runnable and blocked threads on all processors.
+pub fn thread_counts() -> (usize, usize) {
+    with_scheduler(|s| (s.run_queues.iter().map(|x| x.len()).sum(), s.blocked.len()))
+}
+
+/// Returns the call stacks of the current thread and of every thread that is not
+/// running, with their identifier and name.
+///
+/// Threads that are not running are stopped inside `swtch()`, whose saved registers
+/// end with the frame pointer and return address of its caller, so their backtrace
+/// starts there. Threads running on other processors are left out.
+pub fn backtraces() -> Vec<(ThreadId, &'static str, Backtrace)> {
+    let mut result = Vec::new();
+
+    if let Some(thread) = unsafe { CURRENT[cpu_id()].as_ref() } {
+        result.push((thread.id, thread.name, debug::backtrace()));
+    }
+
+    with_scheduler(|s| {
+        let threads = s.run_queues.iter().flatten().chain(s.blocked.values());
+
+        for thread in threads {
+            let backtrace = unsafe { Backtrace::from_frame_pointer(thread.rsp + 5 * 8) };
+            result.push((thread.id, thread.name, backtrace));
+        }
+    });
+
+    result
+}
+
+/// Returns the number of times a thread was switched to since boot.
+pub fn context_switches() -> u64 {
+    CONTEXT_SWITCHES.load(Ordering::Relaxed)
+}
+
+/// Runs the highest priority thread allowed on this processor until it yields,
+/// blocks or exits.
+///
+/// Returns false if there was no runnable thread, in which case the caller should
+/// idle the processor.
+pub fn schedule() -> bool {
+    let id = cpu_id();
+
+    let Some(mut thread) = with_scheduler(|s| s.pick(id)) else {
+        return false;
+    };
+
+    thread.state = ThreadState::Running;
+    let rsp = thread.rsp;
+    CONTEXT_SWITCHES.fetch_add(1, Ordering::Relaxed);
+    tracepoint!(
+        "sched::switch",
+        "cpu {id} -> thread {:?} ({})",
+        thread.id,
+        thread.name
+    );
+
+    unsafe {
+        CURRENT[id] = Some(thread);
+        swtch(core::ptr::addr_of_mut!(SCHEDULER_RSP[id]), rsp);
+    }
+
+    let thread = unsafe { CURRENT[id].take() }.expect("sched::schedule(): lost current thread");
+
+    match thread.state {
+        ThreadState::Running | ThreadState::Runnable => with_scheduler(|s| s.enqueue(thread)),
+        ThreadState::Blocked => with_scheduler(|s| {
+            // A wakeup may have raced with the thread blocking.
+            if s.pending_wakeups.remove(&thread.id) {
+                s.enqueue(thread);
+            } else {
+                s.blocked.insert(thread.id, thread);
+            }
+        }),
+        ThreadState::Exited => {
+            // Wakeups may have come in while the thread was exiting.
+            with_scheduler(|s| {
+                s.live.remove(&thread.id);
+                s.pending_wakeups.remove(&thread.id);
+            });
+            drop(thread);
+        }
+    }
+
+    true
+}
+
+fn ps_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    let describe = |x: &Thread| (x.id, x.name, x.priority, x.affinity, x.state);
+    let mut threads: Vec<_> = unsafe { CURRENT[cpu_id()].as_deref() }
+        .map(describe)
+        .into_iter()
+        .collect();
+
+    with_scheduler(|s| {
+        let others = s.run_queues.iter().flatten().chain(s.blocked.values());
+        threads.extend(others.map(|x| describe(x)));
+    });
+
+    writeln!(
+        out,
+        "{:>5} {:<16} {:<8} {:<18} state",
+        "id", "name", "priority", "cpus"
+    )?;
+    for (id, name, priority, affinity, state) in threads {
+        writeln!(
+            out,
+            "{:>5} {name:<16} {:<8} {:<#18x} {state:?}",
+            id.0,
+            priority.name(),
+            affinity.0
+        )?;
+    }
+
+    Ok(())
+}
+
+fn parse_thread(s: &str) -> Result<ThreadId, CommandError> {
+    s.parse().map(ThreadId).map_err(|_| CommandError::Usage)
+}
+
+fn nice_command(args: &[&str], _out: &mut dyn Write) -> Result<(), CommandError> {
+    let [id, priority] = args else {
+        return Err(CommandError::Usage);
+    };
+
+    let priority = Priority::parse(priority).ok_or(CommandError::Usage)?;
+
+    match set_priority(parse_thread(id)?, priority) {
+        true => Ok(()),
+        false => Err(CommandError::Failed("no such thread")),
+    }
+}
+
+fn taskset_command(args: &[&str], _out: &mut dyn Write) -> Result<(), CommandError> {
+    let [id, cpus] = args else {
+        return Err(CommandError::Usage);
+    };
+
+    let affinity = CpuSet::parse(cpus).ok_or(CommandError::Usage)?;
+
+    match set_affinity(parse_thread(id)?, affinity) {
+        true => Ok(()),
+        false => Err(CommandError::Failed("no such thread")),
+    }
+}
+
+/// Initializes the kernel thread scheduler.
+///
+/// Threads are scheduled cooperatively: a thread runs until it yields, blocks or
+/// exits. Each processor runs [`schedule`] from its idle loop.
+pub fn init() {
+    let commands = [
+        Command {
+            name: "ps",
+            usage: "",
+            help: "list the kernel threads",
+            run: ps_command,
+        },
+        Command {
+            name: "nice",
+            usage: "<thread> low|normal|high",
+            help: "change the priority of a kernel thread",
+            run: nice_command,
+        },
+        Command {
+            name: "taskset",
+            usage: "<thread> all|<cpu>[,<cpu>...]",
+            help: "change the processors a kernel thread may run on",
+            run: taskset_command,
+        },
+    ];
+
+    for command in commands {
+        assert!(
+            shell::register(command),
+            "sched::init(): failed to register {}",
+            command.name
+        );
+    }
+
+    log!("sched::init(): scheduler initialized [ \x1b[0;32mOK\x1b[0m ]");
+}
@@ -0,0 +1,356 @@
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::fmt;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::console;
+use crate::console::Console;
+use crate::emergency_print;
+use crate::heap;
+use crate::log;
+use crate::logger;
+use crate::logger::{Level, Style, Timestamp};
+use crate::panic;
+use crate::sched;
+use crate::sched::Priority;
+use crate::tty::ReadError;
+
+/// Maximum number of shell commands.
+const MAX_COMMANDS: usize = 48;
+
+/// Maximum length of a command line.
+const LINE_SIZE: usize = 256;
+
+const PROMPT: &str = "lithium> ";
+
+// Registered commands, looked up by name.
+static COMMANDS: Mutex<[Option<Command>; MAX_COMMANDS]> = Mutex::new([None; MAX_COMMANDS]);
+
+// Number of times the heap ran out, and the size of the last request it could not
+// satisfy, so that a command that failed for lack of memory can be told apart.
+static OUT_OF_MEMORY: AtomicU64 = AtomicU64::new(0);
+static OUT_OF_MEMORY_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// A shell command.
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    /// Usage shown by `help`, without the command name.
+    pub usage: &'static str,
+    pub help: &'static str,
+    /// Runs the command with the words following its name.
+    pub run: fn(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError>,
+}
+
+/// Reasons a command line could not be run.
+#[derive(Debug)]
+pub enum CommandError {
+    /// No command with the given name is registered.
+    UnknownCommand,
+    /// The arguments did not match the usage of the command.
+    Usage,
+    /// The command ran but failed.
+    Failed(&'static str),
+    /// Writing the output failed.
+    Output(fmt::Error),
+}
+
+impl From<fmt::Error> for CommandError {
+    fn from(e: fmt::Error) -> Self {
+        CommandError::Output(e)
+    }
+}
+
+/// Registers a command. Returns false if the table is full or the name is taken.
+pub fn register(command: Command) -> bool {
+    let mut commands = COMMANDS.lock();
+
+    if commands.iter().flatten().any(|x| x.name == command.name) {
+        return false;
+    }
+
+    match commands.iter_mut().find(|x| x.is_none()) {
+        Some(slot) => {
+            *slot = Some(command);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Runs a single command line, writing its output to `out`.
+///
+/// This is the entry point for both the interactive shell and the host control
+/// protocol, so every runtime knob is reachable from either.
+pub fn execute(line: &str, out: &mut dyn Write) -> Result<(), CommandError> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((&name, args)) = words.split_first() else {
+        return Ok(());
+    };
+
+    let command = COMMANDS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|x| x.name == name)
+        .copied()
+        .ok_or(CommandError::UnknownCommand)?;
+
+    let failures = OUT_OF_MEMORY.load(Ordering::Relaxed);
+    let result = logger::with_context("command", command.name, || (command.run)(args, out));
+
+    if OUT_OF_MEMORY.load(Ordering::Relaxed) != failures {
+        writeln!(
+            out,
+            "{}: out of memory, last failed request was {} bytes",
+            command.name,
+            OUT_OF_MEMORY_SIZE.load(Ordering::Relaxed)
+        )?;
+    }
+
+    match result {
+        Err(CommandError::Usage) => {
+            writeln!(out, "usage: {} {}", command.name, command.usage)?;
+            Err(CommandError::Usage)
+        }
+        result => result,
+    }
+}
+
+// Notes that the heap ran out, see heap::set_oom_hook(). Must not allocate.
+fn out_of_memory(layout: Layout) {
+    OUT_OF_MEMORY_SIZE.store(layout.size(), Ordering::Relaxed);
+    OUT_OF_MEMORY.fetch_add(1, Ordering::Relaxed);
+}
+
+// Names the command that was running when the kernel panicked, see panic::set_hook().
+fn report_panic(_info: &PanicInfo) {
+    if let Some(("command", name)) = logger::context() {
+        emergency_print!("while running shell command {name}\n");
+    }
+}
+
+fn help(_args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    let commands = *COMMANDS.lock();
+
+    for command in commands.iter().flatten() {
+        writeln!(out, "  {} {}", command.name, command.usage)?;
+        writeln!(out, "      {}", command.help)?;
+    }
+
+    Ok(())
+}
+
+fn parse_level(s: &str) -> Result<Level, CommandError> {
+    Level::parse(s).ok_or(CommandError::Failed(
+        "unknown level, expected error|warn|info|debug|trace",
+    ))
+}
+
+fn dmesg_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    let mut result = Ok(());
+    logger::for_each(|record| {
+        if result.is_ok() {
+            result = writeln!(
+                out,
+                "[{:>12.6}] {:<5} {}: {}",
+                record.timestamp,
+                record.level.as_str(),
+                logger::module_of(record.file),
+                record.message()
+            );
+        }
+    });
+    result?;
+
+    Ok(())
+}
+
+fn log_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            writeln!(out, "level: {}", logger::level().as_str())?;
+            logger::for_each_module_level(|module, level| {
+                let _ = writeln!(out, "  {module}: {}", level.as_str());
+            });
+            Ok(())
+        }
+        [level] => {
+            logger::set_level(parse_level(level)?);
+            Ok(())
+        }
+        [module, "default"] => {
+            logger::set_module_level(module, None);
+            Ok(())
+        }
+        [module, level] => {
+            if logger::set_module_level(module, Some(parse_level(level)?)) {
+                Ok(())
+            } else {
+                Err(CommandError::Failed("too many module levels"))
+            }
+        }
+        _ => Err(CommandError::Usage),
+    }
+}
+
+fn logfmt_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    let mut format = logger::format();
+    let on_off = |x: bool| if x { "on" } else { "off" };
+
+    match args {
+        [] => {
+            writeln!(out, "style:     {}", format.style.as_str())?;
+            writeln!(out, "color:     {}", on_off(format.color))?;
+            writeln!(out, "timestamp: {}", format.timestamp.as_str())?;
+            writeln!(out, "location:  {}", on_off(format.location))?;
+            return Ok(());
+        }
+        ["style", style] => {
+            format.style = Style::parse(style)
+                .ok_or(CommandError::Failed("unknown style, expected text|json"))?;
+        }
+        ["color", state @ ("on" | "off")] => format.color = *state == "on",
+        ["timestamp", timestamp] => {
+            format.timestamp = Timestamp::parse(timestamp).ok_or(CommandError::Failed(
+                "unknown timestamp, expected none|seconds|millis",
+            ))?;
+        }
+        ["location", state @ ("on" | "off")] => format.location = *state == "on",
+        _ => return Err(CommandError::Usage),
+    }
+
+    logger::set_format(format);
+    Ok(())
+}
+
+fn trace_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            logger::for_each_tracepoint(|name| {
+                let _ = writeln!(out, "  {name}");
+            });
+            Ok(())
+        }
+        [name, state @ ("on" | "off")] => {
+            if logger::set_tracepoint(name, *state == "on") {
+                Ok(())
+            } else {
+                Err(CommandError::Failed("too many tracepoints"))
+            }
+        }
+        _ => Err(CommandError::Usage),
+    }
+}
+
+fn echo_command(args: &[&str], _out: &mut dyn Write) -> Result<(), CommandError> {
+    match args {
+        ["on"] => console::enable_echo(true),
+        ["off"] => console::enable_echo(false),
+        _ => return Err(CommandError::Usage),
+    }
+
+    Ok(())
+}
+
+fn shell() {
+    let mut line = [0u8; LINE_SIZE];
+
+    loop {
+        crate::print!("{PROMPT}");
+        let len = match console::read_line(&mut line) {
+            Ok(len) => len,
+            // The line was echoed as ^C, only the prompt is left to show.
+            Err(ReadError::Interrupted) => continue,
+            // There is no one else to hand the console to, so start over.
+            Err(ReadError::EndOfFile | ReadError::WouldBlock) => {
+                crate::println!();
+                continue;
+            }
+        };
+
+        let Ok(line) = core::str::from_utf8(&line[..len]) else {
+            crate::println!("error: invalid utf-8");
+            continue;
+        };
+
+        match execute(line, &mut Console) {
+            Ok(()) | Err(CommandError::Usage) => {}
+            Err(CommandError::UnknownCommand) => {
+                crate::println!("error: unknown command, try `help`")
+            }
+            Err(CommandError::Failed(msg)) => crate::println!("error: {msg}"),
+            Err(CommandError::Output(_)) => {}
+        }
+    }
+}
+
+/// Initializes the kernel shell.
+///
+/// The shell runs in its own kernel thread and reads commands line by line from the
+/// console. Subsystems add their own commands with [`register`].
+pub fn init() {
+    let commands = [
+        Command {
+            name: "help",
+            usage: "",
+            help: "list all commands",
+            run: help,
+        },
+        Command {
+            name: "log",
+            usage: "[<level> | <module> <level|default>]",
+            help: "show or change the global or per-module log level",
+            run: log_command,
+        },
+        Command {
+            name: "dmesg",
+            usage: "",
+            help: "show the log records still held in the ring buffer",
+            run: dmesg_command,
+        },
+        Command {
+            name: "logfmt",
+            usage: "[style|color|timestamp|location <value>]",
+            help: "show or change how log lines are printed on the console",
+            run: logfmt_command,
+        },
+        Command {
+            name: "trace",
+            usage: "[<tracepoint> on|off]",
+            help: "list enabled tracepoints or enable/disable one",
+            run: trace_command,
+        },
+        Command {
+            name: "echo",
+            usage: "on|off",
+            help: "toggle console echo",
+            run: echo_command,
+        },
+    ];
+
+    for command in commands {
+        assert!(
+            register(command),
+            "shell::init(): failed to register {}",
+            command.name
+        );
+    }
+
+    heap::set_oom_hook(Some(out_of_memory));
+    assert!(
+        panic::set_hook(report_panic),
+        "shell::init(): failed to set the panic hook"
+    );
+
+    sched::spawn("shell", Priority::Normal, shell);
+    log!("shell::init(): shell started [ \x1b[0;32mOK\x1b[0m ]");
+}
@@ -0,0 +1,558 @@
+use alloc::string::String;
+use core::fmt;
+use core::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use core::time::Duration;
+
+#[cfg(feature = "net-smoltcp")]
+use smoltcp::iface::SocketHandle as Handle;
+use spin::Mutex;
+
+#[cfg(feature = "net-smoltcp")]
+use crate::net_smoltcp as stack;
+#[cfg(not(feature = "net-smoltcp"))]
+use crate::net_stack as stack;
+#[cfg(not(feature = "net-smoltcp"))]
+use crate::net_stack::Handle;
+use crate::timer;
+
+/// Largest number of connections a [`TcpListener`] holds before they are accepted.
+#[cfg(feature = "net-smoltcp")]
+const LISTEN_BACKLOG: usize = 4;
+
+/// How long [`TcpStream::connect`] waits for the remote side, as long as BSD does.
+#[cfg(feature = "net-smoltcp")]
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(75);
+
+/// Kind of a socket error, named after the matching kind of `std::io::ErrorKind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The local port is taken by another socket.
+    AddrInUse,
+    /// The local address is not one of the interface.
+    AddrNotAvailable,
+    /// The remote side refused the connection, or it could not be made.
+    ConnectionRefused,
+    /// The remote side reset the connection.
+    ConnectionReset,
+    /// The socket is not connected.
+    NotConnected,
+    /// The writing half of the connection was shut down.
+    BrokenPipe,
+    /// An address could not be parsed, or is not an IPv4 address.
+    InvalidInput,
+    /// The timeout set on the socket elapsed.
+    TimedOut,
+    /// The operation would block on a nonblocking socket.
+    WouldBlock,
+    /// A datagram did not fit into the buffer of the socket.
+    OutOfMemory,
+    /// The network stack is not running.
+    NetworkDown,
+    /// There is no route to the remote side.
+    NetworkUnreachable,
+}
+
+/// Error of a socket operation, see [`Error::kind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error(ErrorKind);
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        self.0
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Self(kind)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self.0 {
+            ErrorKind::AddrInUse => "address in use",
+            ErrorKind::AddrNotAvailable => "address not available",
+            ErrorKind::ConnectionRefused => "connection refused",
+            ErrorKind::ConnectionReset => "connection reset",
+            ErrorKind::NotConnected => "not connected",
+            ErrorKind::BrokenPipe => "broken pipe",
+            ErrorKind::InvalidInput => "invalid input",
+            ErrorKind::TimedOut => "timed out",
+            ErrorKind::WouldBlock => "operation would block",
+            ErrorKind::OutOfMemory => "out of memory",
+            ErrorKind::NetworkDown => "network down",
+            ErrorKind::NetworkUnreachable => "network unreachable",
+        };
+        f.write_str(message)
+    }
+}
+
+/// Result of a socket operation, like `std::io::Result`.
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Halves of a [`TcpStream`] that [`TcpStream::shutdown`] closes, like
+/// `std::net::Shutdown`.
+#[cfg(feature = "net-smoltcp")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shutdown {
+    Read,
+    Write,
+    Both,
+}
+
+/// Values that are an IPv4 socket address, like `std::net::ToSocketAddrs`.
+///
+/// There is no name resolution, so strings must hold an address like `10.0.2.2:69`,
+/// and only IPv4 is supported.
+pub trait ToSocketAddrs {
+    fn to_socket_addr(&self) -> Result<SocketAddrV4>;
+}
+
+impl ToSocketAddrs for SocketAddrV4 {
+    fn to_socket_addr(&self) -> Result<SocketAddrV4> {
+        Ok(*self)
+    }
+}
+
+impl ToSocketAddrs for SocketAddr {
+    fn to_socket_addr(&self) -> Result<SocketAddrV4> {
+        match self {
+            SocketAddr::V4(x) => Ok(*x),
+            SocketAddr::V6(_) => Err(ErrorKind::InvalidInput.into()),
+        }
+    }
+}
+
+impl ToSocketAddrs for (Ipv4Addr, u16) {
+    fn to_socket_addr(&self) -> Result<SocketAddrV4> {
+        Ok(SocketAddrV4::new(self.0, self.1))
+    }
+}
+
+impl ToSocketAddrs for ([u8; 4], u16) {
+    fn to_socket_addr(&self) -> Result<SocketAddrV4> {
+        Ok(SocketAddrV4::new(self.0.into(), self.1))
+    }
+}
+
+impl ToSocketAddrs for str {
+    fn to_socket_addr(&self) -> Result<SocketAddrV4> {
+        self.parse().map_err(|_| ErrorKind::InvalidInput.into())
+    }
+}
+
+impl ToSocketAddrs for String {
+    fn to_socket_addr(&self) -> Result<SocketAddrV4> {
+        self.as_str().to_socket_addr()
+    }
+}
+
+impl<T: ToSocketAddrs + ?Sized> ToSocketAddrs for &T {
+    fn to_socket_addr(&self) -> Result<SocketAddrV4> {
+        (**self).to_socket_addr()
+    }
+}
+
+// Settings of a socket, changed through a shared reference like in std.
+#[derive(Debug, Clone, Copy, Default)]
+struct Options {
+    nonblocking: bool,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+/// Runs `f` again until it no longer fails with [`ErrorKind::WouldBlock`], for at most
+/// `timeout`, or only once on a nonblocking socket.
+fn block<T>(
+    nonblocking: bool,
+    timeout: Option<Duration>,
+    mut f: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let would_block = |x: &Result<T>| matches!(x, Err(e) if e.kind() == ErrorKind::WouldBlock);
+
+    let mut result = f();
+    if nonblocking || !would_block(&result) {
+        return result;
+    }
+
+    let waited = timer::wait_until(
+        || {
+            result = f();
+            !would_block(&result)
+        },
+        timeout.unwrap_or(Duration::MAX),
+    );
+
+    waited.map_err(|_| ErrorKind::TimedOut)?;
+    result
+}
+
+/// Rejects a timeout of zero, like std.
+fn check_timeout(timeout: Option<Duration>) -> Result<()> {
+    match timeout {
+        Some(x) if x.is_zero() => Err(ErrorKind::InvalidInput.into()),
+        _ => Ok(()),
+    }
+}
+
+/// UDP socket, like `std::net::UdpSocket`.
+pub struct UdpSocket {
+    handle: Handle,
+    options: Mutex<Options>,
+    // Where send() sends to and the only address recv() takes datagrams from.
+    peer: Mutex<Option<SocketAddrV4>>,
+}
+
+impl UdpSocket {
+    /// Creates a socket bound to `addr`, with port 0 for an ephemeral port.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<UdpSocket> {
+        Ok(Self {
+            handle: stack::udp_bind(addr.to_socket_addr()?)?,
+            options: Mutex::new(Options::default()),
+            peer: Mutex::new(None),
+        })
+    }
+
+    /// Receives a datagram and returns its length and where it came from. A datagram
+    /// longer than `buf` is cut short.
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let options = *self.options.lock();
+        let (len, from) = block(options.nonblocking, options.read_timeout, || {
+            stack::udp_recv_from(self.handle, buf)
+        })?;
+
+        Ok((len, from.into()))
+    }
+
+    /// Sends a datagram to `addr` and returns its length.
+    pub fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> Result<usize> {
+        let addr = addr.to_socket_addr()?;
+        let options = *self.options.lock();
+        block(options.nonblocking, options.write_timeout, || {
+            stack::udp_send_to(self.handle, buf, addr)
+        })
+    }
+
+    /// Sets the address [`UdpSocket::send`] sends to, and drops datagrams from anywhere
+    /// else from now on.
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> Result<()> {
+        *self.peer.lock() = Some(addr.to_socket_addr()?);
+        Ok(())
+    }
+
+    /// Sends a datagram to the address the socket is connected to.
+    pub fn send(&self, buf: &[u8]) -> Result<usize> {
+        let peer = self.peer_addr()?;
+        self.send_to(buf, peer)
+    }
+
+    /// Receives a datagram from the address the socket is connected to.
+    pub fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        let peer = self.peer_addr()?;
+
+        loop {
+            let (len, from) = self.recv_from(buf)?;
+            if from == peer {
+                return Ok(len);
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(stack::udp_local_addr(self.handle)?.into())
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        let peer = self.peer.lock().ok_or(ErrorKind::NotConnected)?;
+        Ok(peer.into())
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.options.lock().nonblocking = nonblocking;
+        Ok(())
+    }
+
+    /// Sets how long receiving blocks, `None` for as long as it takes.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        check_timeout(timeout)?;
+        self.options.lock().read_timeout = timeout;
+        Ok(())
+    }
+
+    /// Sets how long sending blocks while the send buffer is full.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        check_timeout(timeout)?;
+        self.options.lock().write_timeout = timeout;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> Result<Option<Duration>> {
+        Ok(self.options.lock().read_timeout)
+    }
+
+    pub fn write_timeout(&self) -> Result<Option<Duration>> {
+        Ok(self.options.lock().write_timeout)
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        stack::release(self.handle);
+    }
+}
+
+/// TCP connection, like `std::net::TcpStream`.
+///
+/// There is no `std::io`, so reading and writing are methods of the stream itself.
+#[cfg(feature = "net-smoltcp")]
+pub struct TcpStream {
+    handle: Handle,
+    options: Mutex<Options>,
+}
+
+#[cfg(feature = "net-smoltcp")]
+impl TcpStream {
+    fn new(handle: Handle) -> Self {
+        Self {
+            handle,
+            options: Mutex::new(Options::default()),
+        }
+    }
+
+    /// Connects to `addr`, giving up after [`CONNECT_TIMEOUT`].
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<TcpStream> {
+        Self::connect_within(addr.to_socket_addr()?, CONNECT_TIMEOUT)
+    }
+
+    /// Connects to `addr`, giving up after `timeout`.
+    pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> Result<TcpStream> {
+        check_timeout(Some(timeout))?;
+        Self::connect_within(addr.to_socket_addr()?, timeout)
+    }
+
+    fn connect_within(addr: SocketAddrV4, timeout: Duration) -> Result<TcpStream> {
+        let stream = Self::new(stack::tcp_connect(addr)?);
+        block(false, Some(timeout), || {
+            stack::tcp_poll_established(stream.handle)
+        })?;
+        Ok(stream)
+    }
+
+    /// Reads what was received, at most `buf.len()` bytes, and returns how many. Returns
+    /// 0 once the remote side closed the connection and everything was read.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let options = *self.options.lock();
+        block(options.nonblocking, options.read_timeout, || {
+            stack::tcp_recv(self.handle, buf)
+        })
+    }
+
+    /// Queues as much of `buf` as fits into the send buffer and returns how much.
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let options = *self.options.lock();
+        block(options.nonblocking, options.write_timeout, || {
+            stack::tcp_send(self.handle, buf)
+        })
+    }
+
+    /// Writes all of `buf`, waiting for room in the send buffer as needed.
+    pub fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            let len = self.write(buf)?;
+            buf = &buf[len..];
+        }
+
+        Ok(())
+    }
+
+    /// Does nothing, what was written is sent as soon as possible anyway.
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Closes one or both halves of the connection. Only closing the writing half is
+    /// seen by the remote side, which receives everything written before.
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        match how {
+            Shutdown::Read => Ok(()),
+            Shutdown::Write | Shutdown::Both => stack::tcp_close(self.handle),
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(stack::tcp_local_addr(self.handle)?.into())
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(stack::tcp_peer_addr(self.handle)?.into())
+    }
+
+    /// Sends small writes right away instead of combining them, by turning off Nagle's
+    /// algorithm.
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<()> {
+        stack::tcp_set_nodelay(self.handle, nodelay)
+    }
+
+    pub fn nodelay(&self) -> Result<bool> {
+        stack::tcp_nodelay(self.handle)
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.options.lock().nonblocking = nonblocking;
+        Ok(())
+    }
+
+    /// Sets how long reading blocks, `None` for as long as it takes.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        check_timeout(timeout)?;
+        self.options.lock().read_timeout = timeout;
+        Ok(())
+    }
+
+    /// Sets how long writing blocks while the send buffer is full.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        check_timeout(timeout)?;
+        self.options.lock().write_timeout = timeout;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> Result<Option<Duration>> {
+        Ok(self.options.lock().read_timeout)
+    }
+
+    pub fn write_timeout(&self) -> Result<Option<Duration>> {
+        Ok(self.options.lock().write_timeout)
+    }
+}
+
+#[cfg(feature = "net-smoltcp")]
+impl fmt::Write for TcpStream {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+#[cfg(feature = "net-smoltcp")]
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        stack::release(self.handle);
+    }
+}
+
+/// TCP socket listening for connections, like `std::net::TcpListener`.
+#[cfg(feature = "net-smoltcp")]
+pub struct TcpListener {
+    addr: SocketAddrV4,
+    // Sockets waiting for a connection each, which accept() replaces once connected.
+    backlog: Mutex<[Handle; LISTEN_BACKLOG]>,
+    options: Mutex<Options>,
+}
+
+#[cfg(feature = "net-smoltcp")]
+impl TcpListener {
+    /// Listens on `addr`, with port 0 for an ephemeral port.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<TcpListener> {
+        let first = stack::tcp_listen(addr.to_socket_addr()?)?;
+        let addr = match stack::tcp_local_addr(first) {
+            Ok(addr) => addr,
+            Err(e) => {
+                stack::release(first);
+                return Err(e);
+            }
+        };
+
+        let mut backlog = [first; LISTEN_BACKLOG];
+        for i in 1..LISTEN_BACKLOG {
+            match stack::tcp_relisten(addr) {
+                Ok(handle) => backlog[i] = handle,
+                Err(e) => {
+                    backlog[..i].iter().for_each(|&x| stack::release(x));
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(Self {
+            addr,
+            backlog: Mutex::new(backlog),
+            options: Mutex::new(Options::default()),
+        })
+    }
+
+    /// Waits for a connection and returns it with the address of the remote side.
+    pub fn accept(&self) -> Result<(TcpStream, SocketAddr)> {
+        let nonblocking = self.options.lock().nonblocking;
+        let handle = block(nonblocking, None, || self.take_connected())?;
+        let stream = TcpStream::new(handle);
+        let peer = stream.peer_addr()?;
+        Ok((stream, peer))
+    }
+
+    // Takes a socket of the backlog that got a connection, and listens with a new one in
+    // its place.
+    fn take_connected(&self) -> Result<Handle> {
+        let mut backlog = self.backlog.lock();
+
+        for slot in backlog.iter_mut() {
+            match stack::tcp_poll_established(*slot) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                // A connection reset before it was accepted leaves the socket closed.
+                Err(_) => {
+                    stack::release(*slot);
+                    *slot = stack::tcp_relisten(self.addr)?;
+                }
+                Ok(()) => {
+                    let listener = stack::tcp_relisten(self.addr)?;
+                    return Ok(core::mem::replace(slot, listener));
+                }
+            }
+        }
+
+        Err(ErrorKind::WouldBlock.into())
+    }
+
+    /// Returns an iterator over the connections as they are accepted.
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.addr.into())
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.options.lock().nonblocking = nonblocking;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "net-smoltcp")]
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        self.backlog.lock().iter().for_each(|&x| stack::release(x));
+    }
+}
+
+/// Iterator over the connections of a [`TcpListener`], see [`TcpListener::incoming`].
+#[cfg(feature = "net-smoltcp")]
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+}
+
+#[cfg(feature = "net-smoltcp")]
+impl Iterator for Incoming<'_> {
+    type Item = Result<TcpStream>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.listener.accept().map(|(stream, _)| stream))
+    }
+}
@@ -0,0 +1,275 @@
+use core::fmt;
+use core::fmt::Write;
+
+use spin::Mutex;
+
+use crate::io;
+use crate::log;
+use crate::logger;
+use crate::logger::Level;
+use crate::mux;
+use crate::shell;
+use crate::shell::{Command, CommandError};
+use crate::timer;
+
+/// Longest line forwarded as a single log record, longer lines are split.
+const LINE_SIZE: usize = 120;
+
+// Routing and line state of stdout and stderr.
+static STREAMS: Mutex<[StreamState; 2]> = Mutex::new([
+    StreamState::new(Route::Channel(mux::APP)),
+    StreamState::new(Route::Channel(mux::APP)),
+]);
+
+/// Output stream of the application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout = 0,
+    Stderr = 1,
+}
+
+impl Stream {
+    fn name(&self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// Where the output of a stream goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// A channel of the serial multiplexer.
+    Channel(usize),
+    /// The kernel log, one record per line, which also reaches the remote log sinks.
+    Log,
+    /// Nowhere.
+    Discard,
+}
+
+struct StreamState {
+    route: Route,
+    timestamps: bool,
+    // Whether the next byte written starts a new line.
+    line_start: bool,
+    // Partial line waiting for its newline when routed to the log.
+    line: [u8; LINE_SIZE],
+    line_len: usize,
+}
+
+impl StreamState {
+    const fn new(route: Route) -> Self {
+        Self {
+            route,
+            timestamps: false,
+            line_start: true,
+            line: [0; LINE_SIZE],
+            line_len: 0,
+        }
+    }
+
+    fn write(&mut self, stream: Stream, s: &str) {
+        match self.route {
+            Route::Channel(channel) => {
+                for line in s.split_inclusive('\n') {
+                    if self.timestamps && self.line_start {
+                        let uptime = timer::uptime().as_secs_f64();
+                        mux::write(channel, format_args!("[{uptime: >13.6}] "));
+                    }
+
+                    mux::write(channel, format_args!("{line}"));
+                    self.line_start = line.ends_with('\n');
+                }
+            }
+            Route::Log => {
+                for &byte in s.as_bytes() {
+                    if byte == b'\n' {
+                        self.flush_line(stream);
+                        continue;
+                    }
+
+                    if self.line_len == LINE_SIZE {
+                        self.flush_line(stream);
+                    }
+
+                    self.line[self.line_len] = byte;
+                    self.line_len += 1;
+                }
+            }
+            Route::Discard => {}
+        }
+    }
+
+    /// Hands a partial line routed to the log on as a record of its own.
+    fn flush(&mut self, stream: Stream) {
+        if self.route == Route::Log && self.line_len > 0 {
+            self.flush_line(stream);
+        }
+    }
+
+    fn flush_line(&mut self, stream: Stream) {
+        let level = match stream {
+            Stream::Stdout => Level::Info,
+            Stream::Stderr => Level::Warn,
+        };
+
+        let line = core::str::from_utf8(&self.line[..self.line_len]).unwrap_or("<invalid utf-8>");
+        logger::record(stream.name(), 0, level, format_args!("{line}"));
+        self.line_len = 0;
+    }
+}
+
+/// Handle to one of the application output streams.
+#[derive(Debug, Clone, Copy)]
+pub struct Handle(Stream);
+
+impl Write for Handle {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        STREAMS.lock()[self.0 as usize].write(self.0, s);
+        Ok(())
+    }
+}
+
+impl io::Write for Handle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::write_lossy(self, buf).map_err(|_| io::ErrorKind::Other)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        STREAMS.lock()[self.0 as usize].flush(self.0);
+        Ok(())
+    }
+}
+
+/// Returns a handle to the standard output of the application.
+pub fn stdout() -> Handle {
+    Handle(Stream::Stdout)
+}
+
+/// Returns a handle to the standard error of the application.
+pub fn stderr() -> Handle {
+    Handle(Stream::Stderr)
+}
+
+/// Changes where a stream goes. A partial line routed to the log is flushed first.
+pub fn set_route(stream: Stream, route: Route) {
+    let mut streams = STREAMS.lock();
+    let state = &mut streams[stream as usize];
+
+    state.flush(stream);
+    state.route = route;
+    state.line_start = true;
+}
+
+/// Returns where a stream goes.
+pub fn route(stream: Stream) -> Route {
+    STREAMS.lock()[stream as usize].route
+}
+
+/// Enables or disables timestamps at the start of every line written to a serial
+/// channel. Log records always carry their own timestamp.
+pub fn set_timestamps(stream: Stream, enabled: bool) {
+    STREAMS.lock()[stream as usize].timestamps = enabled;
+}
+
+fn stdio_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    let parse_stream = |s: &str| match s {
+        "stdout" => Ok(Stream::Stdout),
+        "stderr" => Ok(Stream::Stderr),
+        _ => Err(CommandError::Usage),
+    };
+
+    match args {
+        [] => {
+            for stream in [Stream::Stdout, Stream::Stderr] {
+                writeln!(out, "{}: {:?}", stream.name(), route(stream))?;
+            }
+            Ok(())
+        }
+        [stream, "log"] => {
+            set_route(parse_stream(stream)?, Route::Log);
+            Ok(())
+        }
+        [stream, "discard"] => {
+            set_route(parse_stream(stream)?, Route::Discard);
+            Ok(())
+        }
+        [stream, "write", words @ ..] => {
+            let mut handle = match parse_stream(stream)? {
+                Stream::Stdout => stdout(),
+                Stream::Stderr => stderr(),
+            };
+
+            for (i, word) in words.iter().enumerate() {
+                let separator = if i == 0 { "" } else { " " };
+                write!(handle, "{separator}{word}")?;
+            }
+            writeln!(handle)?;
+            Ok(())
+        }
+        [stream, "timestamps", state @ ("on" | "off")] => {
+            set_timestamps(parse_stream(stream)?, *state == "on");
+            Ok(())
+        }
+        [stream, channel] => match channel.parse::<usize>() {
+            Ok(channel) if channel < mux::CHANNEL_COUNT => {
+                set_route(parse_stream(stream)?, Route::Channel(channel));
+                Ok(())
+            }
+            _ => Err(CommandError::Usage),
+        },
+        _ => Err(CommandError::Usage),
+    }
+}
+
+/// Initializes the application output streams.
+///
+/// Application output is kept apart from kernel logs: by default both streams go to
+/// the application channel of the serial multiplexer without timestamps, and each can
+/// be routed on its own to another channel, the kernel log or nowhere.
+pub fn init() {
+    assert!(
+        shell::register(Command {
+            name: "stdio",
+            usage: "[stdout|stderr <channel>|log|discard|timestamps on|off|write <text>]",
+            help: "show or change where application output goes",
+            run: stdio_command,
+        }),
+        "stdio::init(): failed to register shell command"
+    );
+
+    log!(
+        "stdio::init(): application output on channel {} [ \x1b[0;32mOK\x1b[0m ]",
+        mux::APP
+    );
+}
+
+#[macro_export]
+macro_rules! app_print {
+    ($($args:tt)*) => ({
+        use core::fmt::Write;
+        let _ = $crate::stdio::stdout().write_fmt(format_args!($($args)*));
+    })
+}
+
+#[macro_export]
+macro_rules! app_println {
+    () => ($crate::app_print!("\n"));
+    ($($arg:tt)*) => ($crate::app_print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! app_eprint {
+    ($($args:tt)*) => ({
+        use core::fmt::Write;
+        let _ = $crate::stdio::stderr().write_fmt(format_args!($($args)*));
+    })
+}
+
+#[macro_export]
+macro_rules! app_eprintln {
+    () => ($crate::app_eprint!("\n"));
+    ($($arg:tt)*) => ($crate::app_eprint!("{}\n", format_args!($($arg)*)));
+}
@@ -0,0 +1,161 @@
+use core::net::SocketAddrV4;
+#[cfg(feature = "net-smoltcp")]
+use core::time::Duration;
+
+#[cfg(feature = "net-smoltcp")]
+use spin::Mutex;
+use spin::Once;
+
+use crate::log;
+use crate::logger;
+use crate::logger::{Framing, LogTransport};
+use crate::multiboot;
+#[cfg(feature = "net-smoltcp")]
+use crate::sched;
+#[cfg(feature = "net-smoltcp")]
+use crate::sched::Priority;
+#[cfg(feature = "net-smoltcp")]
+use crate::socket::TcpStream;
+use crate::socket::UdpSocket;
+#[cfg(feature = "net-smoltcp")]
+use crate::timer;
+
+/// How long to wait before connecting to the collector again.
+#[cfg(feature = "net-smoltcp")]
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How long shipping a record waits for room in the send buffer before the connection
+/// is given up on.
+#[cfg(feature = "net-smoltcp")]
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Ships records to a collector as datagrams.
+static UDP: UdpTransport = UdpTransport(Once::new());
+
+// Ships records to a collector over a connection.
+#[cfg(feature = "net-smoltcp")]
+static TCP: TcpTransport = TcpTransport(Mutex::new(None));
+
+struct UdpTransport(Once<UdpSocket>);
+
+impl LogTransport for UdpTransport {
+    fn send(&self, data: &[u8]) -> bool {
+        self.0.get().is_some_and(|x| x.send(data).is_ok())
+    }
+}
+
+// The connection, none until it is made or after it failed. A record cut short by a
+// failure is sent again in full on the next connection.
+#[cfg(feature = "net-smoltcp")]
+struct TcpTransport(Mutex<Option<TcpStream>>);
+
+#[cfg(feature = "net-smoltcp")]
+impl LogTransport for TcpTransport {
+    fn send(&self, data: &[u8]) -> bool {
+        let mut stream = self.0.lock();
+        let Some(connection) = stream.as_mut() else {
+            return false;
+        };
+
+        let sent = connection.write_all(data).is_ok();
+        if !sent {
+            *stream = None;
+        }
+
+        sent
+    }
+}
+
+/// Parses `[udp:|tcp:]<ip>:<port>`, UDP if the protocol is left out.
+fn parse_collector(s: &str) -> Option<(Framing, SocketAddrV4)> {
+    let (framing, addr) = match s.split_once(':') {
+        Some(("udp", addr)) => (Framing::SyslogUdp, addr),
+        Some(("tcp", addr)) => (Framing::SyslogTcp, addr),
+        _ => (Framing::SyslogUdp, s),
+    };
+
+    Some((framing, addr.parse().ok()?))
+}
+
+// Connects to the collector, and again whenever the connection failed.
+#[cfg(feature = "net-smoltcp")]
+fn keep_connected(collector: SocketAddrV4) {
+    let mut failed = false;
+
+    loop {
+        // Never wait for the lock, a record may be stuck sending with it held.
+        if TCP.0.try_lock().is_none_or(|x| x.is_some()) {
+            timer::sleep(RECONNECT_DELAY);
+            continue;
+        }
+
+        match TcpStream::connect(collector) {
+            Ok(stream) => {
+                let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+                *TCP.0.lock() = Some(stream);
+                failed = false;
+
+                // Logging ships what was held back while there was no connection.
+                log!("syslog::keep_connected(): connected to {collector}");
+            }
+            Err(e) => {
+                if !failed {
+                    log!("syslog::keep_connected(): failed to connect to {collector}: {e}");
+                }
+
+                failed = true;
+                timer::sleep(RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+/// Initializes shipping the log to a syslog collector once the network is up.
+///
+/// `log.syslog=[udp:|tcp:]<ip>:<port>` on the kernel command line names the collector,
+/// which gets RFC 5424 messages as UDP datagrams or, with the smoltcp network stack,
+/// over TCP with RFC 6587 octet counting. Records logged before, as far as the log ring
+/// buffer still holds them, are shipped first.
+pub fn init() {
+    let Some(value) = multiboot::arg("log.syslog").next() else {
+        return;
+    };
+
+    let Some((framing, collector)) = parse_collector(value) else {
+        log!("syslog::init(): ignoring malformed log.syslog={value}");
+        return;
+    };
+
+    let transport: &'static dyn LogTransport = match framing {
+        Framing::SyslogUdp => {
+            let socket = match UdpSocket::bind(([0, 0, 0, 0], 0)) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    log!("syslog::init(): failed to create socket: {e}");
+                    return;
+                }
+            };
+
+            let _ = socket.connect(collector);
+            UDP.0.call_once(|| socket);
+            &UDP
+        }
+        #[cfg(feature = "net-smoltcp")]
+        Framing::SyslogTcp => {
+            sched::spawn("syslog", Priority::Low, move || keep_connected(collector));
+            &TCP
+        }
+        #[cfg(not(feature = "net-smoltcp"))]
+        Framing::SyslogTcp => {
+            log!("syslog::init(): the network stack has no TCP, ignoring log.syslog={value}");
+            return;
+        }
+    };
+
+    assert!(
+        logger::add_sink(transport, framing),
+        "syslog::init(): failed to add log sink"
+    );
+
+    log!("syslog::init(): shipping the log to {collector} [ \x1b[0;32mOK\x1b[0m ]");
+}
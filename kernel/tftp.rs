@@ -0,0 +1,492 @@
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::net::Ipv4Addr;
+use core::time::Duration;
+
+use spin::Mutex;
+
+use crate::io;
+use crate::log;
+use crate::multiboot;
+use crate::shell;
+use crate::shell::{Command, CommandError};
+use crate::stdio;
+
+/// UDP port TFTP servers listen on for requests.
+pub const TFTP_PORT: u16 = 69;
+
+/// Size of a full data block, a shorter one ends the transfer.
+const BLOCK_SIZE: usize = 512;
+
+/// How long to wait for the server before sending the last packet again.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often a packet is sent before the server is given up on.
+const MAX_RETRIES: usize = 5;
+
+/// Largest file fetched at boot, so a bad server cannot use up the heap.
+const MAX_FILE_SIZE: usize = 16 << 20;
+
+/// Maximum number of files fetched at boot.
+const MAX_FILES: usize = 8;
+
+// Opcodes, see RFC 1350.
+const OP_RRQ: u16 = 1;
+const OP_DATA: u16 = 3;
+const OP_ACK: u16 = 4;
+const OP_ERROR: u16 = 5;
+
+/// Name of a file to fetch at boot and, once fetched, its contents.
+type File = (&'static str, Option<&'static [u8]>);
+
+// Files named on the kernel command line.
+static FILES: Mutex<[Option<File>; MAX_FILES]> = Mutex::new([None; MAX_FILES]);
+
+// IPv4 address of the server named on the kernel command line.
+static SERVER: Mutex<Option<[u8; 4]>> = Mutex::new(None);
+
+/// Datagram socket talking to one TFTP server.
+pub trait Datagram {
+    /// Sends a datagram to `port` of the server. Returns false if it could not be sent.
+    fn send_to(&mut self, port: u16, data: &[u8]) -> bool;
+
+    /// Waits at most `timeout` for a datagram from the server and returns its length
+    /// and the port it came from.
+    fn recv_from(&mut self, buf: &mut [u8], timeout: Duration) -> Option<(usize, u16)>;
+}
+
+/// Reasons a file could not be fetched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TftpError {
+    /// The file name does not fit into a request.
+    InvalidName,
+    /// The server stopped answering.
+    TimedOut,
+    /// The server reported an error with this code, e.g. 1 for a missing file.
+    Server(u16),
+    /// The file is larger than the caller allowed.
+    TooLarge,
+}
+
+fn read_request(name: &str) -> Option<Vec<u8>> {
+    if name.is_empty() || name.contains('\0') || name.len() > BLOCK_SIZE - 10 {
+        return None;
+    }
+
+    let mut packet = Vec::with_capacity(name.len() + 10);
+    packet.extend_from_slice(&OP_RRQ.to_be_bytes());
+    packet.extend_from_slice(name.as_bytes());
+    packet.extend_from_slice(b"\0octet\0");
+    Some(packet)
+}
+
+fn ack(block: u16) -> [u8; 4] {
+    let [a, b] = OP_ACK.to_be_bytes();
+    let [c, d] = block.to_be_bytes();
+    [a, b, c, d]
+}
+
+/// Fetches a file of at most `max_size` bytes from the server behind `socket` in octet
+/// mode.
+///
+/// The server answers from a port of its own for the transfer, datagrams from other
+/// ports are ignored. Lost packets are sent again after a second, as often as
+/// [`MAX_RETRIES`] allows.
+pub fn fetch(
+    socket: &mut impl Datagram,
+    name: &str,
+    max_size: usize,
+) -> Result<Vec<u8>, TftpError> {
+    let request = read_request(name).ok_or(TftpError::InvalidName)?;
+    let mut buf = [0u8; 4 + BLOCK_SIZE];
+    let mut data = Vec::new();
+
+    // The packet to send again if the server does not answer, and where to.
+    let mut last: Vec<u8> = request;
+    let mut port = TFTP_PORT;
+    let mut server_port = None;
+    let mut block: u16 = 1;
+    let mut retries = 0;
+
+    socket.send_to(port, &last);
+
+    loop {
+        let Some((len, from)) = socket.recv_from(&mut buf, TIMEOUT) else {
+            retries += 1;
+            if retries == MAX_RETRIES {
+                return Err(TftpError::TimedOut);
+            }
+
+            socket.send_to(port, &last);
+            continue;
+        };
+
+        if len < 4 || server_port.is_some_and(|x| x != from) {
+            continue;
+        }
+
+        let opcode = u16::from_be_bytes([buf[0], buf[1]]);
+        let number = u16::from_be_bytes([buf[2], buf[3]]);
+
+        match opcode {
+            OP_ERROR => return Err(TftpError::Server(number)),
+            OP_DATA if number == block => {
+                server_port = Some(from);
+                port = from;
+                retries = 0;
+
+                let payload = &buf[4..len];
+                if data.len() + payload.len() > max_size {
+                    return Err(TftpError::TooLarge);
+                }
+
+                data.extend_from_slice(payload);
+                last = ack(block).to_vec();
+                socket.send_to(port, &last);
+
+                if payload.len() < BLOCK_SIZE {
+                    return Ok(data);
+                }
+
+                block = block.wrapping_add(1);
+            }
+            // The server did not see our acknowledgement and sent the block again.
+            OP_DATA if number == block.wrapping_sub(1) => {
+                socket.send_to(port, &ack(number));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Fetches every file named on the kernel command line that was not fetched yet and
+/// keeps it for [`file`]. Returns the number of files that could not be fetched.
+///
+/// Called by the network stack once it can reach the server, before control is
+/// handed to the application.
+pub fn fetch_all(socket: &mut impl Datagram) -> usize {
+    let names: Vec<&'static str> = FILES
+        .lock()
+        .iter()
+        .flatten()
+        .filter(|(_, data)| data.is_none())
+        .map(|&(name, _)| name)
+        .collect();
+
+    let mut failed = 0;
+
+    for name in names {
+        match fetch(socket, name, MAX_FILE_SIZE) {
+            Ok(data) => {
+                log!("tftp::fetch_all(): fetched {name}, {} bytes", data.len());
+
+                // Files live as long as the kernel, like boot modules.
+                let data: &'static [u8] = data.leak();
+                let mut files = FILES.lock();
+                if let Some(slot) = files.iter_mut().flatten().find(|(x, _)| *x == name) {
+                    slot.1 = Some(data);
+                }
+            }
+            Err(e) => {
+                log!("tftp::fetch_all(): failed to fetch {name}: {e:?}");
+                failed += 1;
+            }
+        }
+    }
+
+    failed
+}
+
+/// Returns the contents of a file fetched at boot, or `None` if it was not named on the
+/// kernel command line or could not be fetched.
+pub fn file(name: &str) -> Option<&'static [u8]> {
+    FILES
+        .lock()
+        .iter()
+        .flatten()
+        .find(|(x, _)| *x == name)
+        .and_then(|&(_, data)| data)
+}
+
+/// Returns the server given on the kernel command line as `tftp.server=<address>`, which
+/// the network stack fetches the files from.
+pub fn server() -> Option<[u8; 4]> {
+    *SERVER.lock()
+}
+
+fn tftp_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            // Output may take a while, so copy the list rather than hold the lock.
+            let files = *FILES.lock();
+            for (name, data) in files.iter().flatten() {
+                match data {
+                    Some(data) => writeln!(out, "{name}: {} bytes", data.len())?,
+                    None => writeln!(out, "{name}: not fetched")?,
+                }
+            }
+            Ok(())
+        }
+        ["cat", name] => {
+            let data = file(name).ok_or(CommandError::Failed("no such file"))?;
+            io::write_lossy(out, data)?;
+            Ok(())
+        }
+        ["cat", name, stream] => {
+            let mut data = file(name).ok_or(CommandError::Failed("no such file"))?;
+            let mut handle = match *stream {
+                "stdout" => stdio::stdout(),
+                "stderr" => stdio::stderr(),
+                _ => return Err(CommandError::Usage),
+            };
+
+            // Files do not have to end with a newline.
+            let copied = io::copy(&mut data, &mut handle)
+                .and_then(|copied| io::Write::flush(&mut handle).map(|_| copied))
+                .map_err(|_| CommandError::Failed("failed to write the file"))?;
+            writeln!(out, "{copied} bytes written to {stream}")?;
+            Ok(())
+        }
+        _ => Err(CommandError::Usage),
+    }
+}
+
+/// Initializes the TFTP client with the files to fetch at boot, each given on the
+/// kernel command line as `tftp.file=<name>`, and the server to fetch them from.
+///
+/// The files are fetched by [`fetch_all`] once the network is up, and applications
+/// find their configuration and data with [`file`] instead of having it baked into the
+/// image.
+pub fn init() {
+    let mut files = FILES.lock();
+    let mut count = 0;
+
+    for value in multiboot::arg("tftp.server") {
+        match value.parse::<Ipv4Addr>() {
+            Ok(server) => *SERVER.lock() = Some(server.octets()),
+            Err(_) => log!("tftp::init(): ignoring malformed tftp.server={value}"),
+        }
+    }
+
+    for name in multiboot::arg("tftp.file") {
+        if read_request(name).is_none() {
+            log!("tftp::init(): ignoring malformed tftp.file={name}");
+            continue;
+        }
+
+        match files.iter_mut().find(|x| x.is_none()) {
+            Some(slot) => {
+                *slot = Some((name, None));
+                count += 1;
+            }
+            None => log!("tftp::init(): too many files, ignoring tftp.file={name}"),
+        }
+    }
+
+    assert!(
+        shell::register(Command {
+            name: "tftp",
+            usage: "[cat <file> [stdout|stderr]]",
+            help: "list the files fetched at boot or print one, optionally to application output",
+            run: tftp_command,
+        }),
+        "tftp::init(): failed to register shell command"
+    );
+
+    log!("tftp::init(): {count} files to fetch [ \x1b[0;32mOK\x1b[0m ]");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// Port the fake server answers from.
+    const SERVER_PORT: u16 = 1069;
+
+    /// TFTP server sending one file, without any network.
+    struct Server {
+        file: Vec<u8>,
+        // Absolute number of the last block sent, which wraps around in the packets.
+        sent: usize,
+        // Packets on their way to the client, with the port they come from.
+        incoming: VecDeque<(u16, Vec<u8>)>,
+        // Block numbers the client acknowledged, in order.
+        acks: Vec<u16>,
+        // Whether every block arrives twice, as if the first acknowledgement was lost.
+        duplicate: bool,
+        // Number of requests lost before one reaches the server.
+        lost_requests: usize,
+        // Error code answering the request instead of the file.
+        error: Option<u16>,
+    }
+
+    impl Server {
+        fn new(file: Vec<u8>) -> Self {
+            Self {
+                file,
+                sent: 0,
+                incoming: VecDeque::new(),
+                acks: Vec::new(),
+                duplicate: false,
+                lost_requests: 0,
+                error: None,
+            }
+        }
+
+        // Number of blocks of the file, the last one shorter than BLOCK_SIZE.
+        fn blocks(&self) -> usize {
+            self.file.len() / BLOCK_SIZE + 1
+        }
+
+        fn send_block(&mut self, index: usize) {
+            let start = (index - 1) * BLOCK_SIZE;
+            let end = (start + BLOCK_SIZE).min(self.file.len());
+
+            let mut packet = OP_DATA.to_be_bytes().to_vec();
+            packet.extend_from_slice(&(index as u16).to_be_bytes());
+            packet.extend_from_slice(&self.file[start..end]);
+
+            if self.duplicate {
+                self.incoming.push_back((SERVER_PORT, packet.clone()));
+            }
+            self.incoming.push_back((SERVER_PORT, packet));
+            self.sent = index;
+        }
+    }
+
+    impl Datagram for Server {
+        fn send_to(&mut self, port: u16, data: &[u8]) -> bool {
+            let opcode = u16::from_be_bytes([data[0], data[1]]);
+
+            match opcode {
+                OP_RRQ => {
+                    assert_eq!(port, TFTP_PORT);
+                    assert_eq!(&data[2..], b"boot.img\0octet\0");
+
+                    if self.lost_requests > 0 {
+                        self.lost_requests -= 1;
+                    } else if let Some(code) = self.error {
+                        let mut packet = OP_ERROR.to_be_bytes().to_vec();
+                        packet.extend_from_slice(&code.to_be_bytes());
+                        packet.extend_from_slice(b"File not found\0");
+                        self.incoming.push_back((SERVER_PORT, packet));
+                    } else {
+                        self.send_block(1);
+                    }
+                }
+                OP_ACK => {
+                    assert_eq!(port, SERVER_PORT);
+                    let block = u16::from_be_bytes([data[2], data[3]]);
+                    self.acks.push(block);
+
+                    if block == self.sent as u16 && self.sent < self.blocks() {
+                        self.send_block(self.sent + 1);
+                    }
+                }
+                _ => panic!("unexpected opcode {opcode}"),
+            }
+
+            true
+        }
+
+        fn recv_from(&mut self, buf: &mut [u8], _timeout: Duration) -> Option<(usize, u16)> {
+            let (port, packet) = self.incoming.pop_front()?;
+            buf[..packet.len()].copy_from_slice(&packet);
+            Some((packet.len(), port))
+        }
+    }
+
+    fn file(len: usize) -> Vec<u8> {
+        (0..len).map(|x| (x * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn fetches_every_block() {
+        let mut server = Server::new(file(1300));
+
+        assert_eq!(
+            fetch(&mut server, "boot.img", MAX_FILE_SIZE),
+            Ok(file(1300))
+        );
+        assert_eq!(server.acks, [1, 2, 3]);
+    }
+
+    #[test]
+    fn ends_with_an_empty_block() {
+        // A file of whole blocks needs one more without data to end it.
+        let mut server = Server::new(file(2 * BLOCK_SIZE));
+
+        assert_eq!(
+            fetch(&mut server, "boot.img", MAX_FILE_SIZE),
+            Ok(file(2 * BLOCK_SIZE))
+        );
+        assert_eq!(server.acks, [1, 2, 3]);
+    }
+
+    #[test]
+    fn acknowledges_duplicate_data_again() {
+        let mut server = Server::new(file(1300));
+        server.duplicate = true;
+
+        // Every block is stored once and every copy is acknowledged, except the copy of
+        // the last one, which comes after the transfer ended.
+        assert_eq!(
+            fetch(&mut server, "boot.img", MAX_FILE_SIZE),
+            Ok(file(1300))
+        );
+        assert_eq!(server.acks, [1, 1, 2, 2, 3]);
+    }
+
+    #[test]
+    fn block_numbers_wrap_around() {
+        // Block 65535 is followed by block 0, and a duplicate of it is still recognized.
+        let len = 65536 * BLOCK_SIZE + 100;
+        let mut server = Server::new(file(len));
+        server.duplicate = true;
+
+        assert_eq!(fetch(&mut server, "boot.img", len), Ok(file(len)));
+        assert_eq!(server.acks[2 * 65535 - 2..], [65535, 65535, 0, 0, 1]);
+    }
+
+    #[test]
+    fn refuses_files_that_are_too_large() {
+        let mut server = Server::new(file(1300));
+
+        assert_eq!(
+            fetch(&mut server, "boot.img", 1000),
+            Err(TftpError::TooLarge)
+        );
+        assert_eq!(server.acks, [1]);
+    }
+
+    #[test]
+    fn sends_lost_requests_again() {
+        let mut server = Server::new(file(100));
+        server.lost_requests = MAX_RETRIES - 1;
+        assert_eq!(fetch(&mut server, "boot.img", MAX_FILE_SIZE), Ok(file(100)));
+
+        let mut server = Server::new(file(100));
+        server.lost_requests = MAX_RETRIES;
+        assert_eq!(
+            fetch(&mut server, "boot.img", MAX_FILE_SIZE),
+            Err(TftpError::TimedOut)
+        );
+    }
+
+    #[test]
+    fn reports_server_errors() {
+        let mut server = Server::new(Vec::new());
+        server.error = Some(1);
+
+        assert_eq!(
+            fetch(&mut server, "boot.img", MAX_FILE_SIZE),
+            Err(TftpError::Server(1))
+        );
+        assert_eq!(
+            fetch(&mut server, "", MAX_FILE_SIZE),
+            Err(TftpError::InvalidName)
+        );
+    }
+}
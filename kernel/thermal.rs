@@ -0,0 +1,210 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use raw_cpuid::native_cpuid::CpuIdReaderNative;
+use raw_cpuid::CpuId;
+use spin::Mutex;
+use x86_64::registers::model_specific::Msr;
+
+use crate::cpu;
+use crate::log;
+use crate::metrics;
+use crate::metrics::{Metric, MetricKind, Sample};
+
+/// Counts at a fixed rate while the processor runs, see the Intel SDM 18.7.2.
+const IA32_MPERF: u32 = 0xE7;
+/// Counts at the actual clock rate while the processor runs.
+const IA32_APERF: u32 = 0xE8;
+/// Intel only, holds the maximum non-turbo ratio in bits 15:8.
+const MSR_PLATFORM_INFO: u32 = 0xCE;
+/// Thermal status of the core.
+const IA32_THERM_STATUS: u32 = 0x19C;
+/// Intel only, holds the temperature at which throttling starts in bits 23:16.
+const MSR_TEMPERATURE_TARGET: u32 = 0x1A2;
+/// Thermal status of the package.
+const IA32_PACKAGE_THERM_STATUS: u32 = 0x1B1;
+
+// Bits of the thermal status registers.
+const THERM_STATUS: u64 = 1 << 0;
+const THERM_READING_VALID: u64 = 1 << 31;
+const THERM_READING_SHIFT: u64 = 16;
+const THERM_READING_MASK: u64 = 0x7F;
+
+/// Bus clock the ratios of `MSR_PLATFORM_INFO` are multiplied with.
+const BUS_CLOCK_HZ: u64 = 100_000_000;
+
+/// Temperature throttling starts at when `MSR_TEMPERATURE_TARGET` cannot be read.
+const DEFAULT_TJ_MAX: u64 = 100;
+
+// Temperature in degrees Celsius at which the processor starts throttling.
+static TJ_MAX: AtomicU64 = AtomicU64::new(DEFAULT_TJ_MAX);
+
+// Maximum non-turbo frequency, the rate MPERF counts at.
+static BASE_HZ: AtomicU64 = AtomicU64::new(0);
+
+// Whether the package thermal status register exists.
+static PACKAGE_THERMAL: AtomicBool = AtomicBool::new(false);
+
+// APERF and MPERF at the last sample, the frequency is averaged since then.
+static LAST_PERF: Mutex<(u64, u64)> = Mutex::new((0, 0));
+
+/// Reads the temperature out of a thermal status register, which holds how many
+/// degrees it is below the throttling temperature.
+fn temperature(msr: u32) -> u64 {
+    let status = unsafe { Msr::new(msr).read() };
+
+    if status & THERM_READING_VALID == 0 {
+        return 0;
+    }
+
+    let below = (status >> THERM_READING_SHIFT) & THERM_READING_MASK;
+    TJ_MAX.load(Ordering::Relaxed).saturating_sub(below)
+}
+
+fn core_temperature() -> u64 {
+    temperature(IA32_THERM_STATUS)
+}
+
+fn package_temperature() -> u64 {
+    temperature(IA32_PACKAGE_THERM_STATUS)
+}
+
+/// Returns 1 while the processor throttles because it is too hot.
+fn throttling() -> u64 {
+    let mut status = unsafe { Msr::new(IA32_THERM_STATUS).read() } & THERM_STATUS;
+
+    if PACKAGE_THERMAL.load(Ordering::Relaxed) {
+        status |= unsafe { Msr::new(IA32_PACKAGE_THERM_STATUS).read() } & THERM_STATUS;
+    }
+
+    status
+}
+
+fn base_frequency() -> u64 {
+    BASE_HZ.load(Ordering::Relaxed)
+}
+
+/// Returns the average frequency the processor ran at since the last sample, from
+/// how much faster APERF counted than MPERF.
+fn frequency() -> u64 {
+    let aperf = unsafe { Msr::new(IA32_APERF).read() };
+    let mperf = unsafe { Msr::new(IA32_MPERF).read() };
+
+    let (last_aperf, last_mperf) = core::mem::replace(&mut *LAST_PERF.lock(), (aperf, mperf));
+    let (aperf, mperf) = (
+        aperf.wrapping_sub(last_aperf),
+        mperf.wrapping_sub(last_mperf),
+    );
+
+    if mperf == 0 {
+        return base_frequency();
+    }
+
+    (base_frequency() as u128 * aperf as u128 / mperf as u128) as u64
+}
+
+/// Returns the maximum non-turbo frequency: the processor frequency leaf if present,
+/// then `MSR_PLATFORM_INFO` on Intel, then the TSC frequency, which matches it on
+/// processors with an invariant TSC.
+fn detect_base_frequency(cpuid: &CpuId<CpuIdReaderNative>, intel: bool) -> u64 {
+    if let Some(mhz) = cpuid
+        .get_processor_frequency_info()
+        .map(|x| x.processor_base_frequency() as u64)
+        .filter(|&x| x != 0)
+    {
+        return mhz * 1_000_000;
+    }
+
+    if intel {
+        let ratio = (unsafe { Msr::new(MSR_PLATFORM_INFO).read() } >> 8) & 0xFF;
+        if ratio != 0 {
+            return ratio * BUS_CLOCK_HZ;
+        }
+    }
+
+    unsafe { cpu::current().get_frequency() }
+}
+
+/// Initializes thermal and frequency reporting.
+///
+/// Long benchmarks slow down when the processor throttles, which is invisible from
+/// inside the kernel otherwise. This registers metrics for the core and package
+/// temperature, whether the processor throttles and the frequency it actually runs
+/// at, each only if CPUID says the MSRs behind it exist. Hypervisors usually hide
+/// them, in which case nothing is registered.
+pub fn init() {
+    let cpuid: CpuId<CpuIdReaderNative> = CpuId::new();
+    let Some(info) = cpuid.get_thermal_power_info() else {
+        log!("thermal::init(): no thermal and power management leaf, disabled");
+        return;
+    };
+
+    let intel = cpuid
+        .get_vendor_info()
+        .is_some_and(|x| x.as_str() == "GenuineIntel");
+
+    let mut available = [None; 5];
+
+    if info.has_dts() {
+        if intel {
+            let target = (unsafe { Msr::new(MSR_TEMPERATURE_TARGET).read() } >> 16) & 0xFF;
+            if target != 0 {
+                TJ_MAX.store(target, Ordering::Relaxed);
+            }
+        }
+
+        PACKAGE_THERMAL.store(info.has_ptm(), Ordering::Relaxed);
+
+        available[0] = Some(Metric {
+            name: "lithium_cpu_temperature_celsius",
+            help: "Temperature of the core.",
+            kind: MetricKind::Gauge,
+            sample: Sample::Value(core_temperature),
+        });
+        available[1] = info.has_ptm().then_some(Metric {
+            name: "lithium_cpu_package_temperature_celsius",
+            help: "Temperature of the processor package.",
+            kind: MetricKind::Gauge,
+            sample: Sample::Value(package_temperature),
+        });
+        available[2] = Some(Metric {
+            name: "lithium_cpu_throttling",
+            help: "Whether the processor throttles because it is too hot.",
+            kind: MetricKind::Gauge,
+            sample: Sample::Value(throttling),
+        });
+    }
+
+    if info.has_hw_coord_feedback() {
+        BASE_HZ.store(detect_base_frequency(&cpuid, intel), Ordering::Relaxed);
+        *LAST_PERF.lock() = unsafe { (Msr::new(IA32_APERF).read(), Msr::new(IA32_MPERF).read()) };
+
+        available[3] = Some(Metric {
+            name: "lithium_cpu_base_frequency_hertz",
+            help: "Maximum frequency of the processor without turbo.",
+            kind: MetricKind::Gauge,
+            sample: Sample::Value(base_frequency),
+        });
+        available[4] = Some(Metric {
+            name: "lithium_cpu_frequency_hertz",
+            help: "Average frequency of the processor since the last scrape.",
+            kind: MetricKind::Gauge,
+            sample: Sample::Value(frequency),
+        });
+    }
+
+    let mut count = 0;
+    for metric in available.into_iter().flatten() {
+        assert!(
+            metrics::register(metric),
+            "thermal::init(): failed to register {}",
+            metric.name
+        );
+        count += 1;
+    }
+
+    log!(
+        "thermal::init(): {count} metrics, Tj max {} C, base {} MHz [ \x1b[0;32mOK\x1b[0m ]",
+        TJ_MAX.load(Ordering::Relaxed),
+        base_frequency() / 1_000_000
+    );
+}
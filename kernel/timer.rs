@@ -0,0 +1,485 @@
+use alloc::collections::BinaryHeap;
+use core::cmp::Ordering as CmpOrdering;
+use core::fmt::Write;
+use core::hint;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use raw_cpuid::native_cpuid::CpuIdReaderNative;
+use raw_cpuid::CpuId;
+use spin::Once;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::PortWriteOnly;
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+use crate::boot;
+use crate::cpu;
+use crate::cpu::CpuState;
+use crate::critical::{CriticalSection, IrqMutex};
+use crate::hypervisor;
+use crate::idle;
+use crate::log;
+use crate::memory;
+use crate::mmio::Region;
+use crate::multiboot;
+use crate::sched;
+use crate::sched::ThreadId;
+use crate::shell;
+use crate::shell::{Command, CommandError};
+use crate::tracepoint;
+use crate::trap;
+use crate::workqueue;
+use crate::workqueue::Work;
+
+/// Frequency of the periodic tick.
+pub const TICK_HZ: u64 = 100;
+
+/// Input clock of the 8253/8254 programmable interval timer.
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+const PIT_CHANNEL0_DATA: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+
+/// Channel 0, lobyte/hibyte access, mode 0 (interrupt on terminal count).
+const PIT_CMD_ONESHOT: u8 = 0x30;
+/// Channel 0, lobyte/hibyte access, mode 2 (rate generator).
+const PIT_CMD_PERIODIC: u8 = 0x34;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const IA32_TSC_DEADLINE_MSR: u32 = 0x6E0;
+
+/// Size of the local APIC register page.
+const LAPIC_SIZE: u64 = 0x1000;
+
+const LAPIC_EOI: u64 = 0xB0;
+const LAPIC_SVR: u64 = 0xF0;
+const LAPIC_LVT_TIMER: u64 = 0x320;
+
+const LAPIC_SVR_ENABLE: u32 = 1 << 8;
+const LAPIC_LVT_TSC_DEADLINE: u32 = 0b10 << 17;
+
+// Software timers ordered by deadline.
+static TIMERS: IrqMutex<TimerState> = IrqMutex::new(TimerState::new());
+
+// Whether the periodic tick may be stopped.
+static TICKLESS: AtomicBool = AtomicBool::new(true);
+
+// Whether one-shot deadlines are programmed through the TSC-deadline LAPIC timer.
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+
+// Virtual address of the local APIC registers.
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+// Number of periodic ticks delivered.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// Paravirtual clock reading and uptime, both in nanoseconds, when the uptime was first
+// taken from the paravirtual clock.
+static PV_CLOCK_BASE: Once<(u64, u64)> = Once::new();
+
+/// What happens when a timer expires. All actions are safe in interrupt context.
+#[derive(Clone, Copy)]
+pub enum TimerAction {
+    /// Wake a blocked kernel thread.
+    Wake(ThreadId),
+    /// Schedule deferred work.
+    Work(&'static Work),
+    /// Only raise the interrupt, e.g. to end a sleep in [`halt_until`].
+    Interrupt,
+}
+
+/// The condition waited for did not hold before the timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+/// Handle used to cancel a timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+struct TimerEntry {
+    deadline: u64,
+    id: TimerId,
+    action: TimerAction,
+}
+
+// BinaryHeap is a max-heap, so order entries by reverse deadline.
+impl Ord for TimerEntry {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (other.deadline, other.id.0).cmp(&(self.deadline, self.id.0))
+    }
+}
+
+impl PartialOrd for TimerEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for TimerEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for TimerEntry {}
+
+struct TimerState {
+    next_id: u64,
+    timers: BinaryHeap<TimerEntry>,
+    tick_running: bool,
+}
+
+impl TimerState {
+    const fn new() -> Self {
+        Self {
+            next_id: 0,
+            timers: BinaryHeap::new(),
+            tick_running: false,
+        }
+    }
+
+    fn next_deadline(&self) -> Option<u64> {
+        self.timers.peek().map(|x| x.deadline)
+    }
+}
+
+/// Returns the current value of the timestamp counter.
+fn now() -> u64 {
+    unsafe { cpu::current().get_timestamp() }
+}
+
+/// Converts a duration into timestamp counter cycles.
+pub fn duration_to_cycles(duration: Duration) -> u64 {
+    let hz = unsafe { cpu::current().get_frequency() } as u128;
+    (duration.as_nanos() * hz / 1_000_000_000) as u64
+}
+
+/// Converts timestamp counter cycles into a duration.
+pub fn cycles_to_duration(cycles: u64) -> Duration {
+    let hz = unsafe { cpu::current().get_frequency() } as u128;
+    Duration::from_nanos((cycles as u128 * 1_000_000_000 / hz) as u64)
+}
+
+/// Returns the time since the kernel was entered.
+///
+/// This is what log lines are stamped with. Until the boot processor is initialized
+/// the timestamp counter frequency is unknown, so the uptime is counted in periodic
+/// ticks instead, which is zero that early. Once the hypervisor provides a paravirtual
+/// clock the uptime follows it, since it keeps counting correctly when the guest is
+/// migrated or the timestamp counter frequency was guessed wrong.
+pub fn uptime() -> Duration {
+    if cpu::state(0) == CpuState::Offline {
+        return Duration::from_micros(ticks() * 1_000_000 / TICK_HZ);
+    }
+
+    let tsc_uptime = || cycles_to_duration(now().saturating_sub(boot::start_timestamp()));
+
+    let Some(ns) = hypervisor::clock_ns() else {
+        return tsc_uptime();
+    };
+
+    let (base, offset) = *PV_CLOCK_BASE.call_once(|| (ns, tsc_uptime().as_nanos() as u64));
+    Duration::from_nanos(offset + ns.saturating_sub(base))
+}
+
+/// Blocks the current thread for at least `duration`.
+///
+/// Outside of a kernel thread, e.g. while the boot processor brings up subsystems,
+/// there is nothing to switch to and this spins instead.
+pub fn sleep(duration: Duration) {
+    let deadline = now() + duration_to_cycles(duration);
+
+    let Some(thread) = sched::current() else {
+        while now() < deadline {
+            hint::spin_loop();
+        }
+        return;
+    };
+
+    // Other wakeups may end the block early, so go back to sleep until the deadline.
+    while now() < deadline {
+        let id = add_at(deadline, TimerAction::Wake(thread));
+        sched::block();
+        cancel(id);
+    }
+}
+
+/// Waits until `cond` holds, giving up once `timeout` has elapsed.
+///
+/// Called from a kernel thread with interrupts enabled, other threads run between
+/// checks. Otherwise, e.g. under a lock taken with interrupts disabled, this only
+/// pauses the processor, so it is safe to use on the console and in early boot.
+pub fn wait_until(mut cond: impl FnMut() -> bool, timeout: Duration) -> Result<(), TimedOut> {
+    let deadline = uptime().saturating_add(timeout);
+
+    loop {
+        if cond() {
+            return Ok(());
+        }
+
+        if uptime() >= deadline {
+            return Err(TimedOut);
+        }
+
+        if interrupts::are_enabled() && sched::current().is_some() {
+            sched::yield_now();
+        }
+
+        hint::spin_loop();
+    }
+}
+
+/// Like [`wait_until`], but sleeps in the idle state between checks, for conditions
+/// that change in interrupt handlers.
+///
+/// Interrupts are enabled when this function returns.
+pub fn halt_until(mut cond: impl FnMut() -> bool, timeout: Duration) -> Result<(), TimedOut> {
+    let deadline = now() + duration_to_cycles(timeout);
+
+    // In tickless mode nothing else may end the sleep once the timeout is up.
+    let id = add_at(deadline, TimerAction::Interrupt);
+
+    let result = loop {
+        // Check with interrupts disabled so that a wakeup is never missed.
+        interrupts::disable();
+
+        if cond() {
+            break Ok(());
+        }
+
+        if now() >= deadline {
+            break Err(TimedOut);
+        }
+
+        idle::enter();
+    };
+
+    interrupts::enable();
+    cancel(id);
+    result
+}
+
+/// Returns the number of periodic ticks delivered since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Allows or forbids stopping the periodic tick.
+pub fn set_tickless(enabled: bool) {
+    TICKLESS.store(enabled, Ordering::Relaxed);
+
+    if !enabled {
+        TIMERS.with(|state| {
+            if !state.tick_running {
+                start_tick(state);
+            }
+        });
+    }
+}
+
+/// Arms a timer that fires `action` once `delay` has elapsed.
+pub fn add(delay: Duration, action: TimerAction) -> TimerId {
+    add_at(now() + duration_to_cycles(delay), action)
+}
+
+/// Arms a timer that fires `action` once the timestamp counter reaches `deadline`.
+fn add_at(deadline: u64, action: TimerAction) -> TimerId {
+    TIMERS.with(|state| {
+        let id = TimerId(state.next_id);
+        state.next_id += 1;
+
+        let earliest = state.next_deadline().is_none_or(|x| deadline < x);
+        state.timers.push(TimerEntry {
+            deadline,
+            id,
+            action,
+        });
+
+        // Without a periodic tick, nobody would notice the new deadline.
+        if earliest && !state.tick_running {
+            program_oneshot(deadline);
+        }
+
+        id
+    })
+}
+
+/// Cancels a timer that has not fired yet.
+pub fn cancel(id: TimerId) {
+    TIMERS.with(|state| state.timers.retain(|x| x.id != id));
+}
+
+/// Starts or stops the periodic tick depending on how many threads are runnable.
+///
+/// With a single runnable thread there is nothing to time-slice, so in tickless mode
+/// the tick is stopped and only the next timer deadline is programmed.
+pub fn update_tick(runnable: usize) {
+    if !TICKLESS.load(Ordering::Relaxed) {
+        return;
+    }
+
+    TIMERS.with(|state| {
+        if runnable <= 1 && state.tick_running {
+            stop_tick(state);
+        } else if runnable > 1 && !state.tick_running {
+            start_tick(state);
+        }
+    });
+}
+
+fn start_tick(state: &mut TimerState) {
+    if TSC_DEADLINE.load(Ordering::Relaxed) {
+        unsafe { Msr::new(IA32_TSC_DEADLINE_MSR).write(0) };
+    }
+
+    pit_program(PIT_CMD_PERIODIC, (PIT_FREQUENCY / TICK_HZ) as u16);
+    state.tick_running = true;
+}
+
+fn stop_tick(state: &mut TimerState) {
+    // Writing the mode 0 command without a count halts channel 0.
+    unsafe { PortWriteOnly::new(PIT_COMMAND).write(PIT_CMD_ONESHOT) };
+    state.tick_running = false;
+
+    if let Some(deadline) = state.next_deadline() {
+        program_oneshot(deadline);
+    }
+}
+
+fn pit_program(command: u8, count: u16) {
+    unsafe {
+        PortWriteOnly::new(PIT_COMMAND).write(command);
+        let mut data = PortWriteOnly::new(PIT_CHANNEL0_DATA);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+    }
+}
+
+/// Programs a single interrupt at the given timestamp counter value.
+fn program_oneshot(deadline: u64) {
+    if TSC_DEADLINE.load(Ordering::Relaxed) {
+        unsafe { Msr::new(IA32_TSC_DEADLINE_MSR).write(deadline) };
+        return;
+    }
+
+    // The PIT can only count 16 bits, far deadlines are reached in several steps.
+    let hz = unsafe { cpu::current().get_frequency() };
+    let cycles = deadline.saturating_sub(now()) as u128;
+    let count = (cycles * PIT_FREQUENCY as u128 / hz as u128).clamp(1, u16::MAX as u128);
+    pit_program(PIT_CMD_ONESHOT, count as u16);
+}
+
+fn lapic_write(offset: u64, value: u32) {
+    let base = LAPIC_BASE.load(Ordering::Relaxed);
+    unsafe { Region::new(base, LAPIC_SIZE) }.write(offset, value);
+}
+
+/// Acknowledges a local APIC interrupt.
+pub fn lapic_end_of_interrupt() {
+    if !hypervisor::pv_eoi_handled() {
+        lapic_write(LAPIC_EOI, 0);
+    }
+}
+
+/// Handles both the periodic tick and one-shot deadline interrupts.
+pub fn interrupt(cs: CriticalSection) {
+    let mut state = TIMERS.lock(cs);
+
+    if state.tick_running {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let now = now();
+    while state.next_deadline().is_some_and(|x| x <= now) {
+        let timer = state.timers.pop().unwrap();
+        tracepoint!("timer::expire", "timer {:?} expired", timer.id);
+
+        match timer.action {
+            TimerAction::Wake(thread) => sched::wake(thread),
+            TimerAction::Work(work) => {
+                workqueue::schedule(work);
+            }
+            TimerAction::Interrupt => {}
+        }
+    }
+
+    if !state.tick_running {
+        if let Some(deadline) = state.next_deadline() {
+            program_oneshot(deadline);
+        }
+    }
+}
+
+fn uptime_command(args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+    if !args.is_empty() {
+        return Err(CommandError::Usage);
+    }
+
+    let uptime = uptime();
+    writeln!(
+        out,
+        "up {}.{:06} s, {} ticks",
+        uptime.as_secs(),
+        uptime.subsec_micros(),
+        ticks()
+    )?;
+    Ok(())
+}
+
+/// Initializes the timer subsystem.
+///
+/// The periodic tick comes from the PIT on IRQ0, which the trap handler dispatches here
+/// directly instead of through the IRQ handler table. When the processor supports it,
+/// one-shot deadlines in tickless mode are programmed with the TSC-deadline mode of
+/// the local APIC timer; otherwise the PIT is switched into one-shot mode instead.
+/// Threads wait on these deadlines with [`sleep`]. `timer.tickless=off` on the kernel
+/// command line keeps the tick running, e.g. to rule it out when chasing a hang.
+pub fn init() {
+    let cpuid: CpuId<CpuIdReaderNative> = CpuId::new();
+    let tsc_deadline = cpuid
+        .get_feature_info()
+        .is_some_and(|x| x.has_tsc_deadline());
+
+    if tsc_deadline {
+        let base = unsafe { Msr::new(IA32_APIC_BASE_MSR).read() } & 0xFFFF_F000;
+        LAPIC_BASE.store(
+            memory::phys_to_virt(PhysAddr::new(base)).as_u64(),
+            Ordering::Relaxed,
+        );
+
+        lapic_write(LAPIC_SVR, LAPIC_SVR_ENABLE | trap::TRAP_SPURIOUS as u32);
+        lapic_write(
+            LAPIC_LVT_TIMER,
+            LAPIC_LVT_TSC_DEADLINE | trap::TRAP_LAPIC_TIMER as u32,
+        );
+        TSC_DEADLINE.store(true, Ordering::Relaxed);
+
+        log!("timer::init(): using TSC-deadline for one-shot timers");
+    } else {
+        log!("timer::init(): using PIT for one-shot timers");
+    }
+
+    TIMERS.with(start_tick);
+    trap::enable_irq(trap::IRQ_TIMER);
+
+    for value in multiboot::arg("timer.tickless") {
+        match value {
+            "on" => set_tickless(true),
+            "off" => set_tickless(false),
+            _ => log!("timer::init(): ignoring malformed timer.tickless={value}"),
+        }
+    }
+
+    assert!(
+        shell::register(Command {
+            name: "uptime",
+            usage: "",
+            help: "show the time since boot",
+            run: uptime_command,
+        }),
+        "timer::init(): failed to register shell command"
+    );
+
+    log!("timer::init(): periodic tick at {TICK_HZ} Hz [ \x1b[0;32mOK\x1b[0m ]");
+}
@@ -0,0 +1,186 @@
+use crate::initcall::InitGuard;
+use crate::log;
+use crate::lowmem;
+use crate::lowmem::LowRegion;
+use core::fmt;
+use core::mem::{align_of, size_of};
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+// Code for an application processor started before there is anything for it to do, e.g.
+// by firmware or a stray startup IPI: count the arrival at PARK_ARRIVALS and halt with
+// interrupts disabled.
+//
+//      cli
+//      mov ax, <segment>
+//      mov ds, ax
+//      lock inc word [PARK_ARRIVALS]
+//  .1: hlt
+//      jmp .1
+#[rustfmt::skip]
+const PARK_CODE: [u8; 18] = [
+    0xfa,
+    0xb8, 0x00, 0x00,
+    0x8e, 0xd8,
+    0xf0, 0xff, 0x06, 0x10, 0x00,
+    0xf4,
+    0xeb, 0xfd,
+    0x90, 0x90,
+    0x00, 0x00,
+];
+
+// Offset of the number of processors that reached the parking code.
+const PARK_ARRIVALS: usize = 0x10;
+
+// The parking trampoline, kept installed for as long as the kernel runs.
+static PARKING: Mutex<Option<Trampoline>> = Mutex::new(None);
+
+// Initialization of the parking trampoline.
+pub static INIT: InitGuard = InitGuard::new("trampoline");
+
+/// Largest trampoline, all of which must be reachable from one real mode segment.
+pub const MAX_TRAMPOLINE_SIZE: usize = 0x1_0000;
+
+/// Place in a trampoline that depends on where it is copied to.
+///
+/// Trampolines are assembled as if they started at address zero, e.g. with `org 0`,
+/// and every relocation names the offset of the field to fix up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relocation {
+    /// A 16-bit field set to the real mode segment of the trampoline, e.g. for loading
+    /// the data segment registers.
+    Segment(usize),
+}
+
+impl Relocation {
+    // Whether the field lies within code of `size` bytes.
+    fn fits(&self, size: usize) -> bool {
+        match *self {
+            Self::Segment(offset) => offset.checked_add(2).is_some_and(|x| x <= size),
+        }
+    }
+}
+
+/// Reason a trampoline could not be installed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrampolineError {
+    /// The code is larger than [`MAX_TRAMPOLINE_SIZE`].
+    TooLarge(usize),
+    /// There was no low memory left for the code.
+    OutOfMemory,
+    /// A relocation does not lie within the code.
+    BadRelocation(Relocation),
+}
+
+impl fmt::Display for TrampolineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge(size) => write!(f, "{size} bytes do not fit in a real mode segment"),
+            Self::OutOfMemory => write!(f, "out of low memory"),
+            Self::BadRelocation(x) => write!(f, "relocation {x:?} is outside of the code"),
+        }
+    }
+}
+
+/// Code copied below 1 MiB and relocated there, for a processor to run in real mode,
+/// e.g. an application processor after a startup IPI. Freed when dropped, which must
+/// not happen while a processor still runs it.
+#[derive(Debug)]
+pub struct Trampoline {
+    region: LowRegion,
+    size: usize,
+}
+
+impl Trampoline {
+    /// Copies `code` to low memory and applies `relocations` to the copy.
+    pub fn install(code: &[u8], relocations: &[Relocation]) -> Result<Self, TrampolineError> {
+        if code.len() > MAX_TRAMPOLINE_SIZE {
+            return Err(TrampolineError::TooLarge(code.len()));
+        }
+
+        if let Some(&x) = relocations.iter().find(|x| !x.fits(code.len())) {
+            return Err(TrampolineError::BadRelocation(x));
+        }
+
+        let mut region = lowmem::allocate(code.len()).ok_or(TrampolineError::OutOfMemory)?;
+        let segment = region.segment();
+        let copy = region.as_mut_slice();
+
+        copy[..code.len()].copy_from_slice(code);
+
+        for relocation in relocations {
+            match *relocation {
+                Relocation::Segment(offset) => {
+                    copy[offset..offset + 2].copy_from_slice(&segment.to_le_bytes());
+                }
+            }
+        }
+
+        Ok(Self {
+            region,
+            size: code.len(),
+        })
+    }
+
+    pub fn start_address(&self) -> PhysAddr {
+        self.region.start_address()
+    }
+
+    /// Gets the vector of a startup IPI that starts a processor here, the number of the
+    /// page the trampoline starts at.
+    pub fn startup_vector(&self) -> u8 {
+        // Pages from the pool are below 1 MiB, so the page number fits.
+        (self.start_address().as_u64() >> 12) as u8
+    }
+
+    /// Gets the real mode segment the trampoline starts at, with offset zero.
+    pub fn segment(&self) -> u16 {
+        self.region.segment()
+    }
+
+    /// Reads a field at `offset` that the code may have written, e.g. to signal that a
+    /// processor started. Panics unless the field lies within the code and is aligned.
+    pub fn read<T: Copy>(&mut self, offset: usize) -> T {
+        let ptr = self.field::<T>(offset);
+        unsafe { ptr.read_volatile() }
+    }
+
+    // Checks that a `T` at `offset` is within the code and aligned, and returns where it
+    // is in the direct map.
+    fn field<T>(&mut self, offset: usize) -> *mut T {
+        assert!(
+            offset + size_of::<T>() <= self.size && offset.is_multiple_of(align_of::<T>()),
+            "trampoline::field(): no {} byte field at offset {offset:#x}",
+            size_of::<T>()
+        );
+
+        self.region.as_mut_slice()[offset..].as_mut_ptr().cast()
+    }
+}
+
+/// Gets the startup vector of the parking trampoline and the number of processors that
+/// have halted there, or `None` if it could not be installed.
+pub fn parked() -> Option<(u8, u16)> {
+    let mut parking = PARKING.lock();
+    let parking = parking.as_mut()?;
+    Some((parking.startup_vector(), parking.read(PARK_ARRIVALS)))
+}
+
+/// Installs the code application processors park in until the kernel starts them itself,
+/// so that its page is set aside before the heap can take all of low memory.
+pub fn init() {
+    let _init = INIT.start();
+    lowmem::INIT.require("trampoline");
+
+    match Trampoline::install(&PARK_CODE, &[Relocation::Segment(2)]) {
+        Ok(trampoline) => {
+            log!(
+                "trampoline::init(): parking at vector {:#04x}, segment {:#06x} [ \x1b[0;32mOK\x1b[0m ]",
+                trampoline.startup_vector(),
+                trampoline.segment()
+            );
+            *PARKING.lock() = Some(trampoline);
+        }
+        Err(e) => log!("trampoline::init(): cannot install the parking code: {e}"),
+    }
+}
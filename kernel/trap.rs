@@ -1,38 +1,411 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
 use x86_64::instructions::interrupts;
-use x86_64::instructions::port::PortWriteOnly;
-use x86_64::set_general_handler;
-use x86_64::structures::idt::ExceptionVector;
-use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::instructions::port::{Port, PortWriteOnly};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{Entry, ExceptionVector, HandlerFunc, PageFaultErrorCode};
+use x86_64::VirtAddr;
 
 use crate::console;
 use crate::cpu;
+use crate::critical::{self, CriticalSection, IrqMutex};
+use crate::debug;
+use crate::emergency_print;
+use crate::heap;
+use crate::initcall::InitGuard;
 use crate::log;
+use crate::mce;
+use crate::timer;
+use crate::{assert_offset, assert_size};
 
 const IO_PIC1_COMMAND: u16 = 0x20;
 const IO_PIC1_DATA: u16 = 0x21;
 const IO_PIC2_COMMAND: u16 = 0xA0;
 const IO_PIC2_DATA: u16 = 0xA1;
 
+/// Edge/level control registers of the PICs, bit n for IRQ n, see 4.1.8 of the PIIX4
+/// datasheet.
+const IO_ELCR1: u16 = 0x4D0;
+const IO_ELCR2: u16 = 0x4D1;
+
 pub const TRAP_IRQ0: u8 = 0x20;
+pub const TRAP_LAPIC_TIMER: u8 = 0x40;
+pub const TRAP_SPURIOUS: u8 = 0xFF;
+pub const IRQ_TIMER: u8 = 0;
 pub const IRQ_SLAVE: u8 = 2;
-pub const IRQ_COM1: u8 = 4;
 
 const CMD_END_OF_INTERRUPT: u8 = 0x20;
 
-/// Handles traps raised in kernel space.
-fn kerneltrap(_stack_frame: InterruptStackFrame, index: u8, _error_code: Option<u64>) {
+extern "C" {
+    // Entry points of every vector, see trapasm.S.
+    static trap_vectors: [u64; 256];
+}
+
+/// Interrupted context, saved on the stack by the entry code in trapasm.S.
+///
+/// Handlers may change any field and execution resumes with the changed registers,
+/// except for `fs` and `gs`, which are only saved since loading them would clear the
+/// segment bases.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TrapFrame {
+    pub ds: u64,
+    pub es: u64,
+    pub fs: u64,
+    pub gs: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    /// Vector of the trap.
+    pub vector: u64,
+    /// Error code pushed by the processor, zero for traps without one.
+    pub error_code: u64,
+    // Pushed by the processor.
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// The entry code in trapasm.S builds the frame on the stack.
+assert_size!(TrapFrame, 0xD0);
+assert_offset!(TrapFrame, r15, 0x20);
+assert_offset!(TrapFrame, rax, 0x90);
+assert_offset!(TrapFrame, vector, 0x98);
+assert_offset!(TrapFrame, error_code, 0xA0);
+assert_offset!(TrapFrame, rip, 0xA8);
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let registers = [
+            ("rax", self.rax),
+            ("rbx", self.rbx),
+            ("rcx", self.rcx),
+            ("rdx", self.rdx),
+            ("rsi", self.rsi),
+            ("rdi", self.rdi),
+            ("rbp", self.rbp),
+            ("rsp", self.rsp),
+            ("r8", self.r8),
+            ("r9", self.r9),
+            ("r10", self.r10),
+            ("r11", self.r11),
+            ("r12", self.r12),
+            ("r13", self.r13),
+            ("r14", self.r14),
+            ("r15", self.r15),
+            ("rip", self.rip),
+            ("rflags", self.rflags),
+            ("cs", self.cs),
+            ("ss", self.ss),
+        ];
+
+        for (i, (name, value)) in registers.iter().enumerate() {
+            write!(f, "{name:>6} {value:#018x}")?;
+            if i % 4 == 3 {
+                writeln!(f)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Number of vectors reserved for exceptions.
+const EXCEPTION_COUNT: u8 = 32;
+
+/// Returns the name of one of the exception vectors.
+fn exception_name(vector: u8) -> &'static str {
+    match vector {
+        0 => "divide error",
+        1 => "debug exception",
+        2 => "non-maskable interrupt",
+        3 => "breakpoint",
+        4 => "overflow",
+        5 => "bound range exceeded",
+        6 => "invalid opcode",
+        7 => "device not available",
+        8 => "double fault",
+        9 => "coprocessor segment overrun",
+        10 => "invalid tss",
+        11 => "segment not present",
+        12 => "stack segment fault",
+        13 => "general protection fault",
+        14 => "page fault",
+        16 => "x87 floating point exception",
+        17 => "alignment check",
+        18 => "machine check",
+        19 => "simd floating point exception",
+        20 => "virtualization exception",
+        21 => "control protection exception",
+        28 => "hypervisor injection exception",
+        29 => "vmm communication exception",
+        30 => "security exception",
+        _ => "reserved exception",
+    }
+}
+
+/// Reports an exception the kernel cannot recover from and panics.
+///
+/// The interrupted registers and the call stack they belong to are printed before the
+/// panic, whose own backtrace only reaches back to the trap handler. Nothing here takes
+/// a lock, the exception may have interrupted any lock holder.
+fn exception(frame: &TrapFrame) -> ! {
+    let name = exception_name(frame.vector as u8);
+
+    emergency_print!(
+        "trap::exception(): {name} (vector {}, error code {:#x}) at {:#016x}\n",
+        frame.vector,
+        frame.error_code,
+        frame.rip
+    );
+
+    if frame.vector == ExceptionVector::Page as u64 {
+        let flags = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+        emergency_print!(
+            "trap::exception(): accessing {:#016x}, {flags:?}\n",
+            Cr2::read().as_u64()
+        );
+    }
+
+    let backtrace = unsafe { debug::Backtrace::from_frame_pointer(frame.rbp) };
+    emergency_print!("{frame}interrupted backtrace:\n{backtrace}");
+
+    panic!("trap::kerneltrap(): {name} at {:#016x}", frame.rip);
+}
+
+// Number of traps handled per vector.
+static TRAP_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Calls `f` with the vector and count of every vector that has been raised.
+pub fn for_each_count(f: &mut dyn FnMut(u64, u64)) {
+    for (vector, count) in TRAP_COUNTS.iter().enumerate() {
+        let count = count.load(Ordering::Relaxed);
+        if count != 0 {
+            f(vector as u64, count);
+        }
+    }
+}
+
+// Number of interrupts each processor is in the middle of handling.
+static INTERRUPT_DEPTH: [AtomicUsize; cpu::CPU_COUNT] =
+    [const { AtomicUsize::new(0) }; cpu::CPU_COUNT];
+
+/// Whether the current processor is handling an interrupt, as opposed to running the
+/// thread or the scheduler it interrupted.
+pub fn in_interrupt() -> bool {
+    INTERRUPT_DEPTH[unsafe { cpu::current() }.id()].load(Ordering::Relaxed) != 0
+}
+
+/// Maximum number of subsystems that can listen for NMIs, like a watchdog or a profiler.
+const MAX_NMI_HANDLERS: usize = 4;
+
+/// Stack space at the bottom of the NMI stack used by nested NMIs.
+const NMI_NESTED_STACK_SIZE: u64 = 1024;
+
+/// Subsystem handler of NMIs. Returns whether the NMI was caused by the subsystem.
+pub type NmiHandler = fn(&TrapFrame) -> bool;
+
+// Registered NMI handlers. The NMI handler can interrupt any lock holder, so these are
+// plain function pointers stored atomically instead of a locked table.
+static NMI_HANDLERS: [AtomicUsize; MAX_NMI_HANDLERS] =
+    [const { AtomicUsize::new(0) }; MAX_NMI_HANDLERS];
+
+// Whether a processor is handling an NMI.
+static NMI_ACTIVE: [AtomicBool; cpu::CPU_COUNT] =
+    [const { AtomicBool::new(false) }; cpu::CPU_COUNT];
+
+// Whether another NMI arrived while a processor was handling one.
+static NMI_PENDING: [AtomicBool; cpu::CPU_COUNT] =
+    [const { AtomicBool::new(false) }; cpu::CPU_COUNT];
+
+/// Registers a handler that is called on every NMI. Returns false if there is no room.
+pub fn register_nmi_handler(handler: NmiHandler) -> bool {
+    NMI_HANDLERS.iter().any(|slot| {
+        slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    })
+}
+
+/// Number of IRQ lines of the two cascaded PICs.
+const IRQ_COUNT: usize = 16;
+
+// Initialization of the IDT and the PICs.
+pub static INIT: InitGuard = InitGuard::new("trap");
+
+// Lines masked at the PICs, bit n for IRQ n. The PICs are shared by all CPUs.
+static IRQ_MASK: IrqMutex<u16> = IrqMutex::new(0xffff);
+
+/// Driver handler of a device interrupt, called with the IRQ line.
+pub type IrqHandler = fn(u8);
+
+// Handlers of the IRQ lines drivers claimed, stored atomically since they are read in
+// interrupt context.
+static IRQ_HANDLERS: [AtomicUsize; IRQ_COUNT] = [const { AtomicUsize::new(0) }; IRQ_COUNT];
+
+/// Registers the handler of a device interrupt and unmasks the line. Returns false if
+/// the line is taken or reserved for the kernel.
+pub fn register_irq_handler(irq: u8, handler: IrqHandler) -> bool {
+    if irq as usize >= IRQ_COUNT || [IRQ_TIMER, IRQ_SLAVE, console::irq()].contains(&irq) {
+        return false;
+    }
+
+    let registered = IRQ_HANDLERS[irq as usize]
+        .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok();
+
+    if registered {
+        enable_irq(irq);
+    }
+
+    registered
+}
+
+/// Masks the line and removes the handler of a device interrupt, e.g. when its driver
+/// shuts down. Returns false if `handler` was not registered for `irq`.
+pub fn unregister_irq_handler(irq: u8, handler: IrqHandler) -> bool {
+    let Some(slot) = IRQ_HANDLERS.get(irq as usize) else {
+        return false;
+    };
+
+    if slot.load(Ordering::Acquire) != handler as usize {
+        return false;
+    }
+
+    // Mask the line first so the handler is not running once this returns.
+    critical::with(|_| {
+        disable_irq(irq);
+        slot.compare_exchange(handler as usize, 0, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    })
+}
+
+fn handle_irq(irq: u8) {
+    let handler = IRQ_HANDLERS[irq as usize].load(Ordering::Acquire);
+    if handler != 0 {
+        let handler: IrqHandler = unsafe { core::mem::transmute(handler) };
+        handler(irq);
+    }
+}
+
+/// Handles non-maskable interrupts on their own stack.
+///
+/// The CPU blocks NMIs until the next IRET, but an exception taken by the NMI handler
+/// returns with IRET as well and unblocks them early. A nested NMI would then start at
+/// the top of the same stack and overwrite the frame of the one being handled. To avoid
+/// that, the stack entry is moved to a small area at the bottom of the stack while an
+/// NMI is handled, and nested NMIs only mark themselves pending there, to be handled
+/// by the outer one before it returns.
+fn nmi_handler(frame: &mut TrapFrame) {
+    // Only the NMI stack entry is changed, which nothing else touches after boot.
+    let cpu = unsafe { &mut *cpu::current_nmi() };
+    let id = cpu.id();
+    let ist = cpu::NMI_IST_INDEX as usize;
+
+    if NMI_ACTIVE[id].swap(true, Ordering::Acquire) {
+        NMI_PENDING[id].store(true, Ordering::Release);
+        return;
+    }
+
+    let top = cpu::nmi_stack_top(id);
+    cpu.tss.interrupt_stack_table[ist] = top - cpu::NMI_STACK_SIZE + NMI_NESTED_STACK_SIZE;
+
+    loop {
+        handle_nmi(frame);
+
+        if !NMI_PENDING[id].swap(false, Ordering::AcqRel) {
+            break;
+        }
+    }
+
+    cpu.tss.interrupt_stack_table[ist] = top;
+    NMI_ACTIVE[id].store(false, Ordering::Release);
+}
+
+fn handle_nmi(frame: &TrapFrame) {
+    // Hardware errors come first, so a handler claiming every NMI cannot hide one.
+    let status: u8 = unsafe { Port::new(0x61).read() };
+    if status & 0xC0 != 0 {
+        panic!("trap::handle_nmi(): hardware error, system control port is {status:#04x}");
+    }
+
+    for slot in NMI_HANDLERS.iter() {
+        let handler = slot.load(Ordering::Acquire);
+        if handler == 0 {
+            continue;
+        }
+
+        let handler: NmiHandler = unsafe { core::mem::transmute(handler) };
+        if handler(frame) {
+            return;
+        }
+    }
+
+    emergency_print!(
+        "trap::handle_nmi(): ignoring unknown NMI at {:#016x}\n",
+        frame.rip
+    );
+}
+
+/// Handles traps raised in kernel space. Called from the entry code in trapasm.S.
+#[no_mangle]
+extern "C" fn kerneltrap(frame: &mut TrapFrame) {
     // log!("trap::kerneltrap(): hello from trap handler!");
+    let index = frame.vector as u8;
+    TRAP_COUNTS[index as usize].fetch_add(1, Ordering::Relaxed);
+
+    // Interrupt gates clear IF, and it stays clear until the handler returns.
+    let cs = unsafe { CriticalSection::new() };
+
+    // Unlike exceptions, interrupts only arrive once the processor is initialized.
+    let depth = (index >= EXCEPTION_COUNT).then(|| {
+        let depth = &INTERRUPT_DEPTH[unsafe { cpu::current() }.id()];
+        depth.fetch_add(1, Ordering::Relaxed);
+        depth
+    });
+
     match index {
-        x if x == ExceptionVector::GeneralProtection as u8 => {
-            panic!("trap::kerneltrap(): general protection fault")
+        x if x == ExceptionVector::NonMaskableInterrupt as u8 => nmi_handler(frame),
+        x if x == ExceptionVector::Breakpoint as u8 => debug::breakpoint(frame),
+        x if x == ExceptionVector::MachineCheck as u8 => mce::handle(frame),
+        x if x < EXCEPTION_COUNT => exception(frame),
+        x if x == (IRQ_TIMER + TRAP_IRQ0) => {
+            timer::interrupt(cs);
+            end_of_interrupt(x);
         }
-        x if x == ExceptionVector::Page as u8 => panic!("trap::kerneltrap(): page fault"),
-        x if x == (IRQ_COM1 + TRAP_IRQ0) => {
-            console::interrupt();
+        x if x == (console::irq() + TRAP_IRQ0) => {
+            console::interrupt(cs);
             end_of_interrupt(x);
         }
+        x if (TRAP_IRQ0..TRAP_IRQ0 + IRQ_COUNT as u8).contains(&x) => {
+            handle_irq(x - TRAP_IRQ0);
+            end_of_interrupt(x);
+        }
+        TRAP_LAPIC_TIMER => {
+            timer::interrupt(cs);
+            timer::lapic_end_of_interrupt();
+        }
+        TRAP_SPURIOUS => {}
         _ => panic!("trap::kerneltrap(): unknown trap kind {}", index),
     }
+
+    if let Some(depth) = depth {
+        depth.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 bitflags::bitflags! {
@@ -70,23 +443,51 @@ fn end_of_interrupt(v: u8) {
     }
 }
 
-/// Sets the IRQ enable mask.
-fn set_irq_mask(mask: u16) {
+/// Writes the IRQ mask to both PICs.
+fn write_irq_mask(mask: u16) {
     unsafe {
         let mut master_data_port = PortWriteOnly::new(IO_PIC1_DATA);
         let mut slave_data_port = PortWriteOnly::new(IO_PIC2_DATA);
 
-        let cpu = cpu::current_mut();
-        cpu.irq_mask = mask;
         master_data_port.write((mask & 0xff) as u8);
         slave_data_port.write((mask >> 8) as u8);
     }
 }
 
+/// Changes the IRQ mask with `f` and writes the result to the PICs.
+fn update_irq_mask(f: impl FnOnce(u16) -> u16) {
+    IRQ_MASK.with(|mask| {
+        *mask = f(*mask);
+        write_irq_mask(*mask);
+    });
+}
+
 /// Enables the IRQ.
 pub fn enable_irq(irq: u8) {
-    let cpu = unsafe { cpu::current() };
-    set_irq_mask(cpu.irq_mask & !(1 << irq));
+    update_irq_mask(|mask| mask & !(1 << irq));
+}
+
+/// Disables the IRQ.
+pub fn disable_irq(irq: u8) {
+    update_irq_mask(|mask| mask | (1 << irq));
+}
+
+/// Makes the PICs treat an IRQ line as level triggered, as PCI interrupts are, so that
+/// an interrupt of one device is not lost while another one on the same line still
+/// holds it.
+pub fn set_level_triggered(irq: u8) {
+    // The timer, keyboard, cascade, RTC and FPU lines must stay edge triggered.
+    if irq as usize >= IRQ_COUNT || [0, 1, 2, 8, 13].contains(&irq) {
+        return;
+    }
+
+    let port = if irq < 8 { IO_ELCR1 } else { IO_ELCR2 };
+
+    critical::with(|_| unsafe {
+        let mut elcr = Port::<u8>::new(port);
+        let value = elcr.read();
+        elcr.write(value | 1 << (irq % 8));
+    });
 }
 
 /// Initializes the PIC8259A interrupt controller.
@@ -97,10 +498,6 @@ fn enable_pic8259a() {
         let mut slave_data_port = PortWriteOnly::new(IO_PIC2_DATA);
         let mut slave_command_port = PortWriteOnly::new(IO_PIC2_COMMAND);
 
-        // Setup all interrupts but IRQ slave line to be masked.
-        let cpu = cpu::current_mut();
-        cpu.irq_mask = !(1 << IRQ_SLAVE);
-
         // Mask all interrupts.
         master_data_port.write(0xFFu8);
         slave_data_port.write(0xFFu8);
@@ -125,6 +522,10 @@ fn enable_pic8259a() {
         // ICW4: some other configuration stuff
         slave_data_port.write(ICW4::MODE_8086.bits());
     }
+
+    // Initialization cleared the masks, so mask everything but the cascade again.
+    // Drivers enable their lines once they can handle them.
+    update_irq_mask(|_| !(1 << IRQ_SLAVE));
 }
 
 /// Initializes the trap handling mechanism for the kernel.
@@ -134,17 +535,57 @@ fn enable_pic8259a() {
 /// exceptions, interrupts, and other asynchronous events, are essential for the correct
 /// operation of the kernel.
 pub fn init() {
-    // First we set up our general purpose kernel trap handler.
+    // First we point every vector at the entry code, which calls [`kerneltrap`].
     use x86_64::instructions::tables::sidt;
-    let cpu = unsafe { cpu::current_mut() };
-    set_general_handler!(&mut cpu.idt, kerneltrap);
+
+    let _init = INIT.start();
+    heap::INIT.require("trap");
+
+    // Double faults and machine checks can happen on an overflowed or corrupt stack, so
+    // they get stacks of their own.
+    let exception_stacks = [
+        (ExceptionVector::Double, cpu::DOUBLE_FAULT_IST_INDEX),
+        (ExceptionVector::MachineCheck, cpu::MACHINE_CHECK_IST_INDEX),
+    ];
+
+    for (vector, index) in exception_stacks {
+        let top = heap::alloc_guarded_stack(cpu::EXCEPTION_STACK_SIZE)
+            .expect("trap::init(): could not allocate exception stack");
+
+        if let Err(err) = cpu::set_interrupt_stack(index, top, cpu::EXCEPTION_STACK_SIZE) {
+            panic!("trap::init(): stack for {vector:?} is unusable: {err:?}");
+        }
+    }
 
     log!(
         "trap::init(): previous IDT is located at {:016p}",
         sidt().base.as_ptr::<u8>()
     );
 
-    cpu.idt.load();
+    let mut cpu = cpu::current_mut();
+
+    // The entries are declared with different handler types, but all have the same
+    // layout and the entry code handles every kind of vector.
+    let entries = &mut cpu.idt as *mut _ as *mut Entry<HandlerFunc>;
+    for (vector, &address) in unsafe { trap_vectors.iter() }.enumerate() {
+        unsafe {
+            let entry = &mut *entries.add(vector);
+            let options = entry.set_handler_addr(VirtAddr::new(address));
+
+            // NMIs get their own stack, see [`nmi_handler`].
+            if vector == ExceptionVector::NonMaskableInterrupt as usize {
+                options.set_stack_index(cpu::NMI_IST_INDEX);
+            }
+
+            if let Some(&(_, index)) = exception_stacks.iter().find(|x| x.0 as usize == vector) {
+                options.set_stack_index(index);
+            }
+        }
+    }
+
+    // The IDT lives in the per-cpu data structure, which is never moved or freed.
+    unsafe { cpu.idt.load_unsafe() };
+    drop(cpu);
 
     log!(
         "trap::init(): current IDT is located at {:016p}",
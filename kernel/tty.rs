@@ -0,0 +1,553 @@
+use core::fmt;
+use core::fmt::Write;
+use core::time::Duration;
+
+use bitflags::bitflags;
+use spin::Mutex;
+
+use crate::console::ansi;
+use crate::console::uart;
+use crate::io;
+use crate::mux;
+use crate::ring::{Overflow, RingBuffer};
+use crate::sched;
+use crate::sched::ThreadId;
+use crate::timer;
+use crate::workqueue;
+
+/// Size of the buffer of input waiting to be read and of the line being edited.
+const INPUT_BUFFER_SIZE: usize = 256;
+
+/// Longest a reader outside a kernel thread halts before checking for input again.
+const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+const CTRL_C: u8 = uart::ctrl(b'C');
+const CTRL_D: u8 = uart::ctrl(b'D');
+const CTRL_U: u8 = uart::ctrl(b'U');
+
+bitflags! {
+    /// Line discipline modes of a terminal.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Mode: u8 {
+        /// Cooked mode: input is edited a line at a time and only handed to readers
+        /// once the line is complete. Without it, input is raw bytes.
+        const CANONICAL = 1 << 0;
+        /// Input is echoed back to the terminal.
+        const ECHO = 1 << 1;
+    }
+}
+
+impl Mode {
+    /// Byte-at-a-time input without echo, e.g. for full-screen applications.
+    pub const RAW: Mode = Mode::empty();
+    /// Line-edited input with echo, what an interactive shell wants.
+    pub const COOKED: Mode = Mode::CANONICAL.union(Mode::ECHO);
+}
+
+/// Requests understood by [`Tty::ioctl`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    /// Returns the current mode bits.
+    GetMode,
+    /// Replaces the mode, returns the previous mode bits.
+    SetMode(Mode),
+    /// Discards pending input including a partially edited line, returns the number
+    /// of bytes discarded.
+    FlushInput,
+    /// Returns the number of bytes that can be read without blocking.
+    InputAvailable,
+    /// Returns the number of bytes that can still be buffered before input is dropped.
+    InputSpace,
+    /// Returns the number of bytes dropped because readers fell behind.
+    InputDropped,
+}
+
+/// Errors returned by [`Tty::ioctl`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtyError {
+    /// The mode contains bits that are not defined.
+    InvalidMode,
+}
+
+/// Reasons a read from a terminal returned no input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadError {
+    /// End-of-file (^D) was typed at the start of a line.
+    EndOfFile,
+    /// The line was cancelled with ^C.
+    Interrupted,
+    /// No input is available and the read was not allowed to block.
+    WouldBlock,
+}
+
+/// Line discipline of a terminal: turns received bytes into input for readers,
+/// applying line editing and echo depending on the mode.
+struct LineDiscipline {
+    mode: Mode,
+    // Input waiting to be read.
+    buffer: RingBuffer<INPUT_BUFFER_SIZE>,
+    // Line currently being edited in canonical mode, not yet visible to readers.
+    line: [u8; INPUT_BUFFER_SIZE],
+    line_len: usize,
+    cursor: usize,
+    escape: ansi::Parser,
+    output: fn(fmt::Arguments),
+}
+
+/// Writes echo output to the terminal a line discipline belongs to.
+struct Echo(fn(fmt::Arguments));
+
+impl Write for Echo {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        (self.0)(format_args!("{s}"));
+        Ok(())
+    }
+}
+
+impl LineDiscipline {
+    const fn new(output: fn(fmt::Arguments)) -> Self {
+        Self {
+            mode: Mode::CANONICAL,
+            buffer: RingBuffer::new(Overflow::Reject),
+            line: [0; INPUT_BUFFER_SIZE],
+            line_len: 0,
+            cursor: 0,
+            escape: ansi::Parser::new(),
+            output,
+        }
+    }
+
+    /// Returns a writer for echo output, or `None` if echo is disabled.
+    fn echo(&self) -> Option<Echo> {
+        self.mode.contains(Mode::ECHO).then_some(Echo(self.output))
+    }
+
+    fn available(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn push(&mut self, ch: u8) {
+        // Drop input when readers fall too far behind, rather than the start of a line
+        // they have not read yet.
+        self.buffer.push(ch);
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        self.buffer.pop()
+    }
+
+    /// Handles one received byte.
+    fn input(&mut self, ch: u8) {
+        if self.mode.contains(Mode::CANONICAL) {
+            if let Some(key) = self.escape.feed(ch) {
+                self.key(key);
+            }
+        } else {
+            self.push(ch);
+            if let Some(mut echo) = self.echo() {
+                let _ = echo.write_char(ch as char);
+            }
+        }
+    }
+
+    /// Redraws the line from the cursor to its end, blanking `erased` trailing
+    /// columns, and moves the terminal cursor back to where it was.
+    fn redraw_tail(&self, erased: usize) {
+        let Some(mut echo) = self.echo() else {
+            return;
+        };
+
+        let tail = &self.line[self.cursor..self.line_len];
+        let _ = echo.write_str(core::str::from_utf8(tail).unwrap_or(""));
+        for _ in 0..erased {
+            let _ = echo.write_char(' ');
+        }
+        ansi::move_left(&mut echo, tail.len() + erased);
+    }
+
+    fn insert(&mut self, ch: u8) {
+        if self.line_len == self.line.len() {
+            return;
+        }
+
+        self.line
+            .copy_within(self.cursor..self.line_len, self.cursor + 1);
+        self.line[self.cursor] = ch;
+        self.line_len += 1;
+        self.cursor += 1;
+
+        if let Some(mut echo) = self.echo() {
+            let _ = echo.write_char(ch as char);
+        }
+        self.redraw_tail(0);
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.move_left();
+            self.delete();
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor == self.line_len {
+            return;
+        }
+
+        self.line
+            .copy_within(self.cursor + 1..self.line_len, self.cursor);
+        self.line_len -= 1;
+        self.redraw_tail(1);
+    }
+
+    fn move_left(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            if let Some(mut echo) = self.echo() {
+                ansi::move_left(&mut echo, 1);
+            }
+        }
+    }
+
+    fn move_right(&mut self) {
+        if self.cursor < self.line_len {
+            self.cursor += 1;
+            if let Some(mut echo) = self.echo() {
+                ansi::move_right(&mut echo, 1);
+            }
+        }
+    }
+
+    fn home(&mut self) {
+        if let Some(mut echo) = self.echo() {
+            ansi::move_left(&mut echo, self.cursor);
+        }
+        self.cursor = 0;
+    }
+
+    fn end(&mut self) {
+        if let Some(mut echo) = self.echo() {
+            ansi::move_right(&mut echo, self.line_len - self.cursor);
+        }
+        self.cursor = self.line_len;
+    }
+
+    fn kill_line(&mut self) {
+        self.home();
+        self.line_len = 0;
+        if let Some(mut echo) = self.echo() {
+            ansi::erase_to_end_of_line(&mut echo);
+        }
+    }
+
+    /// Makes the edited line visible to readers, followed by `terminator`.
+    fn commit(&mut self, terminator: u8) {
+        if let Some(mut echo) = self.echo() {
+            ansi::move_right(&mut echo, self.line_len - self.cursor);
+            if terminator == b'\n' {
+                let _ = echo.write_char('\n');
+            }
+        }
+
+        let line = self.line;
+        for &ch in line[..self.line_len].iter().chain([terminator].iter()) {
+            self.push(ch);
+        }
+
+        self.line_len = 0;
+        self.cursor = 0;
+    }
+
+    /// Discards the edited line and pending input, and tells readers the line was
+    /// cancelled.
+    fn cancel(&mut self) {
+        if let Some(mut echo) = self.echo() {
+            ansi::move_right(&mut echo, self.line_len - self.cursor);
+            let _ = echo.write_str("^C\n");
+        }
+
+        self.buffer.clear();
+        self.line_len = 0;
+        self.cursor = 0;
+        self.push(CTRL_C);
+    }
+
+    /// Moves input into `line` up to the end of a line, `len` bytes of which were
+    /// taken before. Returns `None` if the input ran out first.
+    fn take_line(&mut self, line: &mut [u8], len: &mut usize) -> Option<Result<usize, ReadError>> {
+        while let Some(ch) = self.pop() {
+            match ch {
+                b'\n' => return Some(Ok(*len)),
+                CTRL_D if *len == 0 => return Some(Err(ReadError::EndOfFile)),
+                CTRL_D => return Some(Ok(*len)),
+                CTRL_C => return Some(Err(ReadError::Interrupted)),
+                _ if *len < line.len() => {
+                    line[*len] = ch;
+                    *len += 1;
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    /// Takes one byte of input, as [`Tty::try_read_char`] describes.
+    fn take_char(&mut self) -> Result<u8, ReadError> {
+        let ch = self.pop().ok_or(ReadError::WouldBlock)?;
+
+        match ch {
+            CTRL_D if self.mode.contains(Mode::CANONICAL) => Err(ReadError::EndOfFile),
+            CTRL_C if self.mode.contains(Mode::CANONICAL) => Err(ReadError::Interrupted),
+            _ => Ok(ch),
+        }
+    }
+
+    fn key(&mut self, key: ansi::Key) {
+        match key {
+            ansi::Key::Char(b'\r' | b'\n') => self.commit(b'\n'),
+            ansi::Key::Char(CTRL_C) => self.cancel(),
+            ansi::Key::Char(CTRL_D) => self.commit(CTRL_D),
+            ansi::Key::Char(CTRL_U) => self.kill_line(),
+            ansi::Key::Char(uart::BACKSPACE | uart::DELETE) => self.backspace(),
+            ansi::Key::Char(b'\x00') => {}
+            ansi::Key::Char(ch) => self.insert(ch),
+            ansi::Key::Left => self.move_left(),
+            ansi::Key::Right => self.move_right(),
+            ansi::Key::Home => self.home(),
+            ansi::Key::End => self.end(),
+            ansi::Key::Delete => self.delete(),
+            ansi::Key::Up | ansi::Key::Down => {}
+        }
+    }
+}
+
+/// A terminal: a line discipline over some output, read by kernel threads.
+pub struct Tty {
+    ldisc: Mutex<LineDiscipline>,
+    // Thread blocked waiting for input.
+    reader: Mutex<Option<ThreadId>>,
+}
+
+impl Tty {
+    /// Creates a terminal that echoes and writes through `output`.
+    pub const fn new(output: fn(fmt::Arguments)) -> Self {
+        Self {
+            ldisc: Mutex::new(LineDiscipline::new(output)),
+            reader: Mutex::new(None),
+        }
+    }
+
+    /// Feeds received bytes through the line discipline and wakes a blocked reader.
+    ///
+    /// Must be called from thread context, echo output may take a while.
+    pub fn input(&self, data: impl IntoIterator<Item = u8>) {
+        {
+            let mut ldisc = self.ldisc.lock();
+            for ch in data {
+                ldisc.input(ch);
+            }
+        }
+
+        if let Some(reader) = self.reader.lock().take() {
+            sched::wake(reader);
+        }
+    }
+
+    /// Blocks the calling thread until input is available.
+    ///
+    /// Outside a kernel thread, like on the boot processor before it enters the
+    /// scheduler, there is nothing to block, so the processor halts until an interrupt
+    /// queues input and processes it itself.
+    fn wait(&self) {
+        loop {
+            let Some(reader) = sched::current() else {
+                let _ = timer::halt_until(workqueue::pending, WAIT_INTERVAL);
+                workqueue::flush();
+
+                if self.ldisc.lock().available() > 0 {
+                    return;
+                }
+
+                continue;
+            };
+
+            // Register before checking so input arriving in between is not missed.
+            *self.reader.lock() = Some(reader);
+
+            if self.ldisc.lock().available() > 0 {
+                return;
+            }
+
+            sched::block();
+        }
+    }
+
+    /// Reads input into `buf` and returns the number of bytes read, blocking until
+    /// at least one byte is available.
+    ///
+    /// In canonical mode at most one line is returned, including its newline. An
+    /// end-of-file (^D) or cancelled line (^C) at the start of a line returns 0.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        if buf.is_empty() {
+            return 0;
+        }
+
+        self.wait();
+
+        let mut ldisc = self.ldisc.lock();
+        let canonical = ldisc.mode.contains(Mode::CANONICAL);
+        let mut len = 0;
+
+        while len < buf.len() {
+            let Some(ch) = ldisc.pop() else {
+                break;
+            };
+
+            if canonical && (ch == CTRL_D || ch == CTRL_C) {
+                break;
+            }
+
+            buf[len] = ch;
+            len += 1;
+
+            if canonical && ch == b'\n' {
+                break;
+            }
+        }
+
+        len
+    }
+
+    /// Reads one line into `line`, without the trailing newline, and returns its
+    /// length. Blocks the calling thread until a complete line is available.
+    ///
+    /// Bytes that do not fit into `line` are discarded. An end-of-file (^D) at the
+    /// start of a line and a line cancelled with ^C are returned as errors.
+    pub fn read_line(&self, line: &mut [u8]) -> Result<usize, ReadError> {
+        let mut len = 0;
+
+        loop {
+            self.wait();
+
+            if let Some(result) = self.ldisc.lock().take_line(line, &mut len) {
+                return result;
+            }
+        }
+    }
+
+    /// Like [`read_line`](Self::read_line), but returns [`ReadError::WouldBlock`]
+    /// instead of blocking if no complete line is available.
+    pub fn try_read_line(&self, line: &mut [u8]) -> Result<usize, ReadError> {
+        let mut ldisc = self.ldisc.lock();
+
+        let complete = ldisc
+            .buffer
+            .iter()
+            .any(|ch| matches!(ch, b'\n' | CTRL_C | CTRL_D));
+
+        if !complete {
+            return Err(ReadError::WouldBlock);
+        }
+
+        let mut len = 0;
+        ldisc
+            .take_line(line, &mut len)
+            .unwrap_or(Err(ReadError::WouldBlock))
+    }
+
+    /// Reads one byte of input, blocking until one is available.
+    ///
+    /// In canonical mode, bytes only become available once their line is complete,
+    /// and end-of-file (^D) and cancelled lines (^C) are returned as errors.
+    pub fn read_char(&self) -> Result<u8, ReadError> {
+        loop {
+            self.wait();
+
+            match self.ldisc.lock().take_char() {
+                Err(ReadError::WouldBlock) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Like [`read_char`](Self::read_char), but returns [`ReadError::WouldBlock`]
+    /// instead of blocking if no input is available.
+    pub fn try_read_char(&self) -> Result<u8, ReadError> {
+        self.ldisc.lock().take_char()
+    }
+
+    /// Returns the current mode.
+    pub fn mode(&self) -> Mode {
+        self.ldisc.lock().mode
+    }
+
+    /// Changes the mode. A partially edited line is handed to readers when leaving
+    /// canonical mode.
+    pub fn set_mode(&self, mode: Mode) {
+        let mut ldisc = self.ldisc.lock();
+
+        if ldisc.mode.contains(Mode::CANONICAL) && !mode.contains(Mode::CANONICAL) {
+            let line = ldisc.line;
+            for &ch in &line[..ldisc.line_len] {
+                ldisc.push(ch);
+            }
+            ldisc.line_len = 0;
+            ldisc.cursor = 0;
+        }
+
+        ldisc.mode = mode;
+    }
+
+    /// Performs a control request on the terminal, in the spirit of `ioctl(2)`.
+    pub fn ioctl(&self, request: Request) -> Result<u64, TtyError> {
+        match request {
+            Request::GetMode => Ok(self.mode().bits() as u64),
+            Request::SetMode(mode) => {
+                if Mode::from_bits(mode.bits()).is_none() {
+                    return Err(TtyError::InvalidMode);
+                }
+
+                let previous = self.mode();
+                self.set_mode(mode);
+                Ok(previous.bits() as u64)
+            }
+            Request::FlushInput => {
+                let mut ldisc = self.ldisc.lock();
+                let discarded = ldisc.buffer.clear() + ldisc.line_len;
+                ldisc.line_len = 0;
+                ldisc.cursor = 0;
+                Ok(discarded as u64)
+            }
+            Request::InputAvailable => Ok(self.ldisc.lock().available() as u64),
+            Request::InputSpace => {
+                let ldisc = self.ldisc.lock();
+                Ok((ldisc.buffer.capacity() - ldisc.available()) as u64)
+            }
+            Request::InputDropped => Ok(self.ldisc.lock().buffer.dropped()),
+        }
+    }
+}
+
+impl io::Read for &Tty {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(Tty::read(self, buf))
+    }
+}
+
+impl io::Write for &Tty {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut out = Echo(self.ldisc.lock().output);
+        io::write_lossy(&mut out, buf).map_err(|_| io::ErrorKind::Other)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the terminal of the kernel shell on the serial console.
+pub fn console() -> &'static Tty {
+    mux::channel(mux::SHELL)
+}
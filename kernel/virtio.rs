@@ -0,0 +1,493 @@
+use alloc::boxed::Box;
+use core::time::Duration;
+
+use bitflags::bitflags;
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use crate::arch::{Arch, Current};
+use crate::inspect::parse_u64;
+use crate::log;
+use crate::multiboot;
+use crate::pci;
+use crate::power;
+use crate::power::ShutdownKind;
+use crate::timer;
+use crate::virtio_legacy::LegacyTransport;
+use crate::virtio_mmio::MmioTransport;
+use crate::virtio_pci::PciTransport;
+
+/// How long a device may take to finish a reset.
+const RESET_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// PCI vendor id of virtio devices.
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+
+/// PCI device ids of transitional devices, which also have the legacy interface.
+const TRANSITIONAL_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103F;
+
+/// PCI device ids of modern devices, the virtio device id plus 0x1040.
+const MODERN_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1040..=0x107F;
+
+/// Offset of the subsystem device id in PCI configuration space.
+const PCI_SUBSYSTEM_ID_OFFSET: u8 = 0x2E;
+
+/// Feature bit: the device follows the virtio 1.0 specification instead of the legacy
+/// interface.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Maximum number of virtio-mmio devices given on the command line.
+const MAX_MMIO_DEVICES: usize = 8;
+
+bitflags! {
+    /// `device_status`, see 2.1 "Device Status Field".
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct DeviceStatus: u8 {
+        const ACKNOWLEDGE        = 1;
+        const DRIVER             = 2;
+        const DRIVER_OK          = 4;
+        const FEATURES_OK        = 8;
+        const DEVICE_NEEDS_RESET = 64;
+        const FAILED             = 128;
+    }
+}
+
+bitflags! {
+    /// Reasons for an interrupt, read from the ISR status or interrupt status register.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct InterruptStatus: u32 {
+        const QUEUE  = 1;
+        const CONFIG = 2;
+    }
+}
+
+/// Kinds of virtio devices, see 5 "Device Types".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Network,
+    Block,
+    Console,
+    Entropy,
+    Balloon,
+    Scsi,
+    Gpu,
+    Input,
+    Socket,
+    Other(u32),
+}
+
+impl From<u32> for DeviceType {
+    fn from(id: u32) -> Self {
+        match id {
+            1 => Self::Network,
+            2 => Self::Block,
+            3 => Self::Console,
+            4 => Self::Entropy,
+            5 => Self::Balloon,
+            8 => Self::Scsi,
+            16 => Self::Gpu,
+            18 => Self::Input,
+            19 => Self::Socket,
+            x => Self::Other(x),
+        }
+    }
+}
+
+/// Errors returned by virtio transports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Nothing that looks like a virtio device is at the address.
+    NoDevice,
+    /// The device speaks a version of the transport that is not supported.
+    UnsupportedVersion(u32),
+    /// The device did not accept the features the driver asked for.
+    FeaturesRejected,
+    /// The queue does not exist on the device.
+    QueueUnavailable,
+    /// The queue was already set up.
+    QueueInUse,
+    /// The queue is larger than the device supports.
+    QueueTooLarge,
+    /// The device only supports queues of the given size.
+    QueueSizeMismatch(u16),
+    /// The rings are not laid out the way the device expects.
+    InvalidLayout,
+    /// The device did not finish resetting in time.
+    ResetTimeout,
+}
+
+/// Way of talking to a virtio device, independent of the bus it sits on.
+///
+/// Drivers bring the device up with [`Transport::negotiate`], set up their queues and
+/// finish with [`Transport::finish_init`], see 3.1 "Device Initialization".
+pub trait Transport {
+    /// Returns the kind of device.
+    fn device_type(&self) -> DeviceType;
+
+    /// Returns the IRQ line the device interrupts on.
+    fn irq(&self) -> u8;
+
+    /// Returns the features the device offers.
+    fn device_features(&mut self) -> u64;
+
+    /// Tells the device which of its features the driver uses.
+    fn set_driver_features(&mut self, features: u64);
+
+    fn status(&self) -> DeviceStatus;
+
+    fn set_status(&mut self, status: DeviceStatus);
+
+    /// Returns the largest size of `queue`, zero if the queue does not exist.
+    fn max_queue_size(&mut self, queue: u16) -> u16;
+
+    /// Hands the rings of `queue` to the device, as returned by the virtqueue.
+    fn setup_queue(
+        &mut self,
+        queue: u16,
+        size: u16,
+        desc: PhysAddr,
+        avail: PhysAddr,
+        used: PhysAddr,
+    ) -> Result<(), Error>;
+
+    /// Takes `queue` away from the device, after which its rings can be freed.
+    fn disable_queue(&mut self, queue: u16);
+
+    /// Tells the device there are new buffers in `queue`.
+    fn notify(&mut self, queue: u16);
+
+    /// Acknowledges an interrupt and returns what caused it.
+    fn ack_interrupt(&mut self) -> InterruptStatus;
+
+    /// Returns the counter that changes whenever the device changes its configuration.
+    fn config_generation(&self) -> u32;
+
+    fn read_config_u8(&self, offset: usize) -> u8;
+
+    fn read_config_u16(&self, offset: usize) -> u16;
+
+    fn read_config_u32(&self, offset: usize) -> u32;
+
+    /// Reads a 64-bit configuration field, retrying if the device changed it halfway.
+    fn read_config_u64(&self, offset: usize) -> u64 {
+        loop {
+            let generation = self.config_generation();
+            let low = self.read_config_u32(offset) as u64;
+            let high = self.read_config_u32(offset + 4) as u64;
+
+            if self.config_generation() == generation {
+                return high << 32 | low;
+            }
+        }
+    }
+
+    /// Resets the device and waits until it is done, letting other threads run while
+    /// the device takes its time.
+    fn reset(&mut self) -> Result<(), Error> {
+        self.set_status(DeviceStatus::empty());
+        timer::wait_until(|| self.status().is_empty(), RESET_TIMEOUT)
+            .map_err(|_| Error::ResetTimeout)
+    }
+
+    /// Resets the device and agrees on the features both sides support, returning them.
+    ///
+    /// Legacy devices do not offer `VIRTIO_F_VERSION_1` and skip the `FEATURES_OK`
+    /// handshake, as the legacy interface has none.
+    fn negotiate(&mut self, supported: u64) -> Result<u64, Error> {
+        self.reset()?;
+        self.set_status(DeviceStatus::ACKNOWLEDGE);
+        self.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+
+        let features = self.device_features() & (supported | VIRTIO_F_VERSION_1);
+        self.set_driver_features(features);
+
+        if features & VIRTIO_F_VERSION_1 != 0 {
+            self.set_status(self.status() | DeviceStatus::FEATURES_OK);
+
+            if !self.status().contains(DeviceStatus::FEATURES_OK) {
+                self.set_status(self.status() | DeviceStatus::FAILED);
+                return Err(Error::FeaturesRejected);
+            }
+        }
+
+        Ok(features)
+    }
+
+    /// Tells the device the driver is ready, after its queues are set up.
+    fn finish_init(&mut self) {
+        self.set_status(self.status() | DeviceStatus::DRIVER_OK);
+    }
+
+    /// Stops the device when its driver goes away, e.g. from a shutdown hook.
+    ///
+    /// The reset stops the device from using the rings and raising interrupts, and the
+    /// first `queues` queues are disabled so the next driver finds them unconfigured.
+    /// Afterwards the driver reclaims its buffers with
+    /// [`crate::virtqueue::VirtQueue::reclaim`] and unregisters its IRQ handler.
+    fn shutdown(&mut self, queues: u16) -> Result<(), Error> {
+        let result = self.reset();
+
+        for queue in 0..queues {
+            self.disable_queue(queue);
+        }
+
+        result
+    }
+}
+
+/// Lets drivers pick the transport at run time, e.g. the modern PCI interface with a
+/// fallback to the legacy one.
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn device_type(&self) -> DeviceType {
+        (**self).device_type()
+    }
+
+    fn irq(&self) -> u8 {
+        (**self).irq()
+    }
+
+    fn device_features(&mut self) -> u64 {
+        (**self).device_features()
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        (**self).set_driver_features(features)
+    }
+
+    fn status(&self) -> DeviceStatus {
+        (**self).status()
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        (**self).set_status(status)
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        (**self).max_queue_size(queue)
+    }
+
+    fn setup_queue(
+        &mut self,
+        queue: u16,
+        size: u16,
+        desc: PhysAddr,
+        avail: PhysAddr,
+        used: PhysAddr,
+    ) -> Result<(), Error> {
+        (**self).setup_queue(queue, size, desc, avail, used)
+    }
+
+    fn disable_queue(&mut self, queue: u16) {
+        (**self).disable_queue(queue)
+    }
+
+    fn notify(&mut self, queue: u16) {
+        (**self).notify(queue)
+    }
+
+    fn ack_interrupt(&mut self) -> InterruptStatus {
+        (**self).ack_interrupt()
+    }
+
+    fn config_generation(&self) -> u32 {
+        (**self).config_generation()
+    }
+
+    fn read_config_u8(&self, offset: usize) -> u8 {
+        (**self).read_config_u8(offset)
+    }
+
+    fn read_config_u16(&self, offset: usize) -> u16 {
+        (**self).read_config_u16(offset)
+    }
+
+    fn read_config_u32(&self, offset: usize) -> u32 {
+        (**self).read_config_u32(offset)
+    }
+}
+
+/// Whether a virtio PCI device is transitional, see 4.1.2 "PCI Device Discovery".
+pub fn is_transitional(device: &pci::DeviceConfig) -> bool {
+    device.vendor_id == VIRTIO_VENDOR_ID && TRANSITIONAL_DEVICE_IDS.contains(&device.device_id)
+}
+
+/// Returns the kind of a virtio PCI device, `None` if it is not one.
+///
+/// Transitional devices have their kind in the subsystem device id instead.
+pub fn pci_device_type(device: &pci::DeviceConfig) -> Option<DeviceType> {
+    if device.vendor_id != VIRTIO_VENDOR_ID {
+        return None;
+    }
+
+    match device.device_id {
+        x if MODERN_DEVICE_IDS.contains(&x) => Some(DeviceType::from((x - 0x1040) as u32)),
+        x if TRANSITIONAL_DEVICE_IDS.contains(&x) => {
+            let subsystem = device.config_read_u16(PCI_SUBSYSTEM_ID_OFFSET);
+            Some(DeviceType::from(subsystem as u32))
+        }
+        _ => None,
+    }
+}
+
+/// Finds the first virtio PCI device of kind `device_type`, modern or transitional.
+pub fn find_pci(device_type: DeviceType) -> Option<pci::DeviceConfig> {
+    pci::find(|x| pci_device_type(x) == Some(device_type))
+}
+
+/// Location of a virtio-mmio device, which unlike PCI devices cannot be discovered by
+/// probing and has to be given on the command line.
+#[derive(Debug, Clone, Copy)]
+pub struct MmioDevice {
+    pub base: PhysAddr,
+    pub size: u64,
+    pub irq: u8,
+}
+
+impl MmioDevice {
+    /// Parses a device in the format of Linux, `<size>@<base>:<irq>[:<id>]`, where the
+    /// size takes an optional `K`, `M` or `G` suffix. The id is ignored.
+    fn parse(s: &str) -> Option<Self> {
+        let (size, rest) = s.split_once('@')?;
+        let mut rest = rest.split(':');
+        let base = parse_u64(rest.next()?)?;
+        let irq = rest.next()?.parse().ok()?;
+
+        let (size, shift) = match size.as_bytes().last()? {
+            b'K' | b'k' => (&size[..size.len() - 1], 10),
+            b'M' | b'm' => (&size[..size.len() - 1], 20),
+            b'G' | b'g' => (&size[..size.len() - 1], 30),
+            _ => (size, 0),
+        };
+        let size = parse_u64(size)?.checked_shl(shift)?;
+
+        Some(Self {
+            base: PhysAddr::try_new(base).ok()?,
+            size,
+            irq,
+        })
+    }
+}
+
+// Probed virtio-mmio devices, and whether a driver claimed them.
+static MMIO_DEVICES: Mutex<[Option<(DeviceType, MmioDevice, bool)>; MAX_MMIO_DEVICES]> =
+    Mutex::new([None; MAX_MMIO_DEVICES]);
+
+/// Claims the first virtio-mmio device of kind `device_type` for a driver.
+pub fn take_mmio(device_type: DeviceType) -> Option<MmioTransport> {
+    let mut devices = MMIO_DEVICES.lock();
+    let (_, device, claimed) = devices
+        .iter_mut()
+        .flatten()
+        .find(|(ty, _, claimed)| *ty == device_type && !*claimed)?;
+
+    *claimed = true;
+    unsafe { MmioTransport::new(*device) }.ok()
+}
+
+/// Resets every virtio device, as a last resort for drivers that did not shut down
+/// their own devices. PCI devices are reset through their modern interface if they
+/// have one.
+fn reset_devices(_kind: ShutdownKind) {
+    let devices = *MMIO_DEVICES.lock();
+    for (_, device, _) in devices.iter().flatten() {
+        if let Ok(mut transport) = unsafe { MmioTransport::new(*device) } {
+            if transport.reset().is_err() {
+                log!(
+                    "virtio::reset_devices(): device at {:#x} did not reset",
+                    device.base.as_u64()
+                );
+            }
+        }
+    }
+
+    pci::for_each_device(|device| {
+        let result = match PciTransport::new(*device) {
+            Ok(mut transport) => Some(transport.reset()),
+            Err(_) => LegacyTransport::new(*device).ok().map(|mut x| x.reset()),
+        };
+
+        if let Some(Err(_)) = result {
+            log!(
+                "virtio::reset_devices(): device {:02x}:{:02x}.{} did not reset",
+                device.bus,
+                device.device,
+                device.function
+            );
+        }
+    });
+}
+
+/// Initializes the virtio transports.
+///
+/// PCI devices are found by drivers on the PCI bus. Lightweight virtual machine
+/// monitors without a PCI bus instead attach virtio-mmio devices, which are given on
+/// the kernel command line with `virtio_mmio.device=<size>@<base>:<irq>` arguments,
+/// one per device. Machines with fixed virtio-mmio slots, like the RISC-V QEMU `virt`
+/// machine, have every slot probed as well. Each device is probed here and kept until
+/// a driver claims it with [`take_mmio`].
+///
+/// Drivers shut their devices down from their own shutdown hooks. The hook registered
+/// here runs after them and resets any virtio device still running, so the next kernel
+/// does not find devices writing into its memory.
+pub fn init() {
+    let mut devices = MMIO_DEVICES.lock();
+    let mut count = 0;
+
+    let slots = Current::virtio_mmio_slots().iter().filter_map(|x| {
+        Some(MmioDevice {
+            base: PhysAddr::try_new(x.base).ok()?,
+            size: x.size,
+            irq: x.irq,
+        })
+    });
+
+    for device in slots {
+        // Most slots are empty.
+        let Ok(transport) = (unsafe { MmioTransport::new(device) }) else {
+            continue;
+        };
+
+        if count == MAX_MMIO_DEVICES {
+            log!("virtio::init(): too many virtio-mmio devices, ignoring the remaining slots");
+            break;
+        }
+
+        log!(
+            "virtio::init(): found {:?} device in slot at {:#x} using irq {}",
+            transport.device_type(),
+            device.base.as_u64(),
+            device.irq
+        );
+        devices[count] = Some((transport.device_type(), device, false));
+        count += 1;
+    }
+
+    for arg in multiboot::arg("virtio_mmio.device") {
+        let Some(device) = MmioDevice::parse(arg) else {
+            log!("virtio::init(): ignoring malformed virtio_mmio.device={arg}");
+            continue;
+        };
+
+        match unsafe { MmioTransport::new(device) } {
+            Ok(transport) if count < MAX_MMIO_DEVICES => {
+                log!(
+                    "virtio::init(): found {:?} device at {:#x} using irq {}",
+                    transport.device_type(),
+                    device.base.as_u64(),
+                    device.irq
+                );
+                devices[count] = Some((transport.device_type(), device, false));
+                count += 1;
+            }
+            Ok(_) => log!("virtio::init(): too many virtio-mmio devices, ignoring {arg}"),
+            Err(e) => log!("virtio::init(): ignoring virtio-mmio device {arg}: {e:?}"),
+        }
+    }
+
+    assert!(
+        power::register_shutdown_hook("virtio", reset_devices),
+        "virtio::init(): failed to register shutdown hook"
+    );
+
+    log!("virtio::init(): found {count} virtio-mmio devices [ \x1b[0;32mOK\x1b[0m ]");
+}
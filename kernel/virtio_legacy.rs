@@ -0,0 +1,178 @@
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+use crate::pci;
+use crate::virtio::{self, DeviceStatus, DeviceType, Error, InterruptStatus, Transport};
+
+/// Alignment of the used ring, see 2.7.2 "Legacy Interfaces: A Note on Virtqueue Layout".
+const QUEUE_ALIGN: u64 = 4096;
+
+// Register offsets in the I/O BAR, see 4.1.4.8 "Legacy Interfaces: A Note on PCI Device
+// Layout".
+const DEVICE_FEATURES: u16 = 0x00;
+const DRIVER_FEATURES: u16 = 0x04;
+const QUEUE_PFN: u16 = 0x08;
+const QUEUE_SIZE: u16 = 0x0c;
+const QUEUE_SELECT: u16 = 0x0e;
+const QUEUE_NOTIFY: u16 = 0x10;
+const DEVICE_STATUS: u16 = 0x12;
+const ISR_STATUS: u16 = 0x13;
+// Device configuration follows, as long as MSI-X is not enabled.
+const CONFIG: u16 = 0x14;
+
+/// virtio over the I/O BAR of a legacy or transitional PCI device.
+///
+/// The legacy interface has 32 feature bits, a fixed queue size chosen by the device
+/// and the rings of a queue in one contiguous allocation, which is how
+/// [`crate::virtqueue::VirtQueue`] lays them out.
+#[derive(Debug)]
+pub struct LegacyTransport {
+    pci: pci::DeviceConfig,
+    port_base: u16,
+    device_type: DeviceType,
+}
+
+impl LegacyTransport {
+    /// Takes over a transitional virtio device with an I/O BAR.
+    pub fn new(mut device: pci::DeviceConfig) -> Result<Self, Error> {
+        if !virtio::is_transitional(&device) {
+            return Err(Error::NoDevice);
+        }
+
+        let Some(pci::BaseAddressRegister::IO { address, .. }) = device.base_address_region(0)
+        else {
+            return Err(Error::NoDevice);
+        };
+
+        let device_type = virtio::pci_device_type(&device).ok_or(Error::NoDevice)?;
+
+        // Enable PCI bus mastering to allow the device to do DMA.
+        device.enable_bus_mastering();
+
+        Ok(Self {
+            pci: device,
+            port_base: address as u16,
+            device_type,
+        })
+    }
+
+    fn read_u8(&self, offset: u16) -> u8 {
+        unsafe { Port::new(self.port_base + offset).read() }
+    }
+
+    fn read_u16(&self, offset: u16) -> u16 {
+        unsafe { Port::new(self.port_base + offset).read() }
+    }
+
+    fn read_u32(&self, offset: u16) -> u32 {
+        unsafe { Port::new(self.port_base + offset).read() }
+    }
+
+    fn write_u8(&mut self, offset: u16, value: u8) {
+        unsafe { Port::new(self.port_base + offset).write(value) }
+    }
+
+    fn write_u16(&mut self, offset: u16, value: u16) {
+        unsafe { Port::new(self.port_base + offset).write(value) }
+    }
+
+    fn write_u32(&mut self, offset: u16, value: u32) {
+        unsafe { Port::new(self.port_base + offset).write(value) }
+    }
+}
+
+impl Transport for LegacyTransport {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn irq(&self) -> u8 {
+        self.pci.interrupt_line
+    }
+
+    fn device_features(&mut self) -> u64 {
+        self.read_u32(DEVICE_FEATURES) as u64
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        self.write_u32(DRIVER_FEATURES, features as u32);
+    }
+
+    fn status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_retain(self.read_u8(DEVICE_STATUS))
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.write_u8(DEVICE_STATUS, status.bits());
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        self.write_u16(QUEUE_SELECT, queue);
+        self.read_u16(QUEUE_SIZE)
+    }
+
+    fn setup_queue(
+        &mut self,
+        queue: u16,
+        size: u16,
+        desc: PhysAddr,
+        avail: PhysAddr,
+        used: PhysAddr,
+    ) -> Result<(), Error> {
+        self.write_u16(QUEUE_SELECT, queue);
+
+        if self.read_u32(QUEUE_PFN) != 0 {
+            return Err(Error::QueueInUse);
+        }
+
+        match self.read_u16(QUEUE_SIZE) {
+            0 => return Err(Error::QueueUnavailable),
+            x if x != size => return Err(Error::QueueSizeMismatch(x)),
+            _ => {}
+        }
+
+        // The device finds the rings from the descriptor table alone.
+        let avail_end = avail + (6 + 2 * size as u64);
+        if !desc.is_aligned(QUEUE_ALIGN)
+            || avail != desc + 16 * size as u64
+            || used != avail_end.align_up(QUEUE_ALIGN)
+        {
+            return Err(Error::InvalidLayout);
+        }
+
+        let pfn = u32::try_from(desc.as_u64() / QUEUE_ALIGN).map_err(|_| Error::InvalidLayout)?;
+        self.write_u32(QUEUE_PFN, pfn);
+        Ok(())
+    }
+
+    fn disable_queue(&mut self, queue: u16) {
+        self.write_u16(QUEUE_SELECT, queue);
+        self.write_u32(QUEUE_PFN, 0);
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.write_u16(QUEUE_NOTIFY, queue);
+    }
+
+    fn ack_interrupt(&mut self) -> InterruptStatus {
+        // Reading the register acknowledges the interrupt.
+        InterruptStatus::from_bits_retain(self.read_u8(ISR_STATUS) as u32)
+    }
+
+    /// The legacy interface has no generation counter, so this is always zero.
+    fn config_generation(&self) -> u32 {
+        0
+    }
+
+    fn read_config_u8(&self, offset: usize) -> u8 {
+        self.read_u8(CONFIG + offset as u16)
+    }
+
+    fn read_config_u16(&self, offset: usize) -> u16 {
+        self.read_u16(CONFIG + offset as u16)
+    }
+
+    fn read_config_u32(&self, offset: usize) -> u32 {
+        self.read_u32(CONFIG + offset as u16)
+    }
+}
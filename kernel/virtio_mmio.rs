@@ -0,0 +1,203 @@
+use x86_64::PhysAddr;
+
+use crate::arch::{Current, Mmu};
+use crate::mmio::Region;
+use crate::virtio::{DeviceStatus, DeviceType, Error, InterruptStatus, MmioDevice, Transport};
+
+/// Value of the magic register, "virt" in little endian.
+const MAGIC: u32 = 0x74726976;
+
+/// Version of the transport without the legacy interface.
+const VERSION: u32 = 2;
+
+/// Device id of a slot without a device behind it.
+const NO_DEVICE: u32 = 0;
+
+// Register offsets, see 4.2.2 "MMIO Device Register Layout".
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION_REG: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const CONFIG_GENERATION: u64 = 0x0fc;
+const CONFIG: u64 = 0x100;
+
+/// virtio over memory mapped registers, see 4.2 "Virtio Over MMIO".
+///
+/// Only version 2 of the transport is supported, version 1 is the legacy interface.
+#[derive(Debug)]
+pub struct MmioTransport {
+    device: MmioDevice,
+    // Registers in the direct map, like the local APIC.
+    regs: Region,
+    device_type: DeviceType,
+}
+
+impl MmioTransport {
+    /// Probes the registers of `device`.
+    ///
+    /// # Safety
+    /// `device` must describe memory mapped registers and not RAM.
+    pub unsafe fn new(device: MmioDevice) -> Result<Self, Error> {
+        if device.size < CONFIG {
+            return Err(Error::NoDevice);
+        }
+
+        let Some(va) = Current::map_device(device.base.as_u64(), device.size) else {
+            return Err(Error::NoDevice);
+        };
+
+        let mut transport = Self {
+            device,
+            regs: Region::new(va, device.size),
+            device_type: DeviceType::Other(NO_DEVICE),
+        };
+
+        if transport.read(MAGIC_VALUE) != MAGIC {
+            return Err(Error::NoDevice);
+        }
+
+        match transport.read(VERSION_REG) {
+            VERSION => {}
+            x => return Err(Error::UnsupportedVersion(x)),
+        }
+
+        match transport.read(DEVICE_ID) {
+            NO_DEVICE => Err(Error::NoDevice),
+            x => {
+                transport.device_type = DeviceType::from(x);
+                Ok(transport)
+            }
+        }
+    }
+
+    fn read(&self, offset: u64) -> u32 {
+        self.regs.read(offset)
+    }
+
+    fn write(&mut self, offset: u64, value: u32) {
+        self.regs.write(offset, value)
+    }
+
+    fn write_u64(&mut self, low: u64, high: u64, value: u64) {
+        self.write(low, value as u32);
+        self.write(high, (value >> 32) as u32);
+    }
+
+    fn read_config<T: Copy>(&self, offset: usize) -> T {
+        self.regs.read(CONFIG + offset as u64)
+    }
+}
+
+impl Transport for MmioTransport {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn irq(&self) -> u8 {
+        self.device.irq
+    }
+
+    fn device_features(&mut self) -> u64 {
+        self.write(DEVICE_FEATURES_SEL, 0);
+        let low = self.read(DEVICE_FEATURES) as u64;
+        self.write(DEVICE_FEATURES_SEL, 1);
+        let high = self.read(DEVICE_FEATURES) as u64;
+        high << 32 | low
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        self.write(DRIVER_FEATURES_SEL, 0);
+        self.write(DRIVER_FEATURES, features as u32);
+        self.write(DRIVER_FEATURES_SEL, 1);
+        self.write(DRIVER_FEATURES, (features >> 32) as u32);
+    }
+
+    fn status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_retain(self.read(STATUS) as u8)
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.write(STATUS, status.bits() as u32);
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        self.write(QUEUE_SEL, queue as u32);
+        self.read(QUEUE_NUM_MAX).min(u16::MAX as u32) as u16
+    }
+
+    fn setup_queue(
+        &mut self,
+        queue: u16,
+        size: u16,
+        desc: PhysAddr,
+        avail: PhysAddr,
+        used: PhysAddr,
+    ) -> Result<(), Error> {
+        self.write(QUEUE_SEL, queue as u32);
+
+        if self.read(QUEUE_READY) != 0 {
+            return Err(Error::QueueInUse);
+        }
+
+        match self.read(QUEUE_NUM_MAX) {
+            0 => return Err(Error::QueueUnavailable),
+            x if x < size as u32 => return Err(Error::QueueTooLarge),
+            _ => {}
+        }
+
+        self.write(QUEUE_NUM, size as u32);
+        self.write_u64(QUEUE_DESC_LOW, QUEUE_DESC_HIGH, desc.as_u64());
+        self.write_u64(QUEUE_DRIVER_LOW, QUEUE_DRIVER_HIGH, avail.as_u64());
+        self.write_u64(QUEUE_DEVICE_LOW, QUEUE_DEVICE_HIGH, used.as_u64());
+        self.write(QUEUE_READY, 1);
+        Ok(())
+    }
+
+    fn disable_queue(&mut self, queue: u16) {
+        self.write(QUEUE_SEL, queue as u32);
+        self.write(QUEUE_READY, 0);
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.write(QUEUE_NOTIFY, queue as u32);
+    }
+
+    fn ack_interrupt(&mut self) -> InterruptStatus {
+        let status = self.read(INTERRUPT_STATUS);
+        self.write(INTERRUPT_ACK, status);
+        InterruptStatus::from_bits_retain(status)
+    }
+
+    fn config_generation(&self) -> u32 {
+        self.read(CONFIG_GENERATION)
+    }
+
+    fn read_config_u8(&self, offset: usize) -> u8 {
+        self.read_config(offset)
+    }
+
+    fn read_config_u16(&self, offset: usize) -> u16 {
+        self.read_config(offset)
+    }
+
+    fn read_config_u32(&self, offset: usize) -> u32 {
+        self.read_config(offset)
+    }
+}
@@ -0,0 +1,351 @@
+use core::mem::{offset_of, size_of};
+
+use x86_64::PhysAddr;
+
+use crate::arch::{Current, Mmu};
+use crate::assert_size;
+use crate::mmio::{ReadOnly, Region, Volatile};
+use crate::pci;
+use crate::register_block;
+use crate::virtio::{self, DeviceStatus, DeviceType, Error, InterruptStatus, Transport};
+
+/// Common configuration.
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+/// Notifications.
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+/// ISR Status.
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+/// Device specific configuration.
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+/// Number of queues whose notification offset is remembered, the most a driver can set
+/// up.
+const MAX_QUEUES: usize = 16;
+
+register_block! {
+    /// `virtio_pci_cap` as laid out in configuration space, see 4.1.4 "Virtio Structure
+    /// PCI Capabilities".
+    struct VirtioPciCap {
+        0x00 => cap_vndr: ReadOnly<u8>,
+        0x01 => cap_next: ReadOnly<u8>,
+        0x02 => cap_len: ReadOnly<u8>,
+        0x03 => cfg_type: ReadOnly<u8>,
+        0x04 => bar: ReadOnly<u8>,
+        0x05 => id: ReadOnly<u8>,
+        0x06 => padding: [ReadOnly<u8>; 2],
+        0x08 => offset: ReadOnly<u32>,
+        0x0c => length: ReadOnly<u32>,
+    }
+}
+
+register_block! {
+    /// `virtio_pci_notify_cap`, see 4.1.4.4 "Notification structure layout".
+    struct VirtioPciNotifyCap {
+        0x00 => cap: VirtioPciCap,
+        /// Multiplier for `queue_notify_off`.
+        0x10 => notify_off_multiplier: ReadOnly<u32>,
+    }
+}
+
+assert_size!(VirtioPciCap, 0x10);
+assert_size!(VirtioPciNotifyCap, 0x14);
+
+/// `virtio_pci_cap`, see section 4.1.4 Virtio Structure PCI Capabilities
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VirtioPciCapability {
+    bar: u8,
+    offset: u32,
+    length: u32,
+}
+
+impl VirtioPciCapability {
+    /// Reads a `virtio_pci_cap` out of the configuration space. Returns `None` if it is
+    /// too short or does not fit into `config`.
+    pub fn parse(config: &[u8], capability: pci::CapabilityInfo) -> Option<VirtioPciCapability> {
+        use bit_field::BitField;
+
+        let cap_len = capability.private_header.get_bits(0..8) as usize;
+        let offset = capability.offset as usize;
+
+        if cap_len < size_of::<VirtioPciCap>() || offset + cap_len > config.len() {
+            return None;
+        }
+
+        Some(VirtioPciCapability {
+            bar: config[offset + offset_of!(VirtioPciCap, bar)],
+            offset: pci::read_u32(config, offset + offset_of!(VirtioPciCap, offset))?,
+            length: pci::read_u32(config, offset + offset_of!(VirtioPciCap, length))?,
+        })
+    }
+
+    /// Maps the structure in the memory BAR it points into. Returns `None` if the BAR
+    /// is not a memory BAR, the structure does not fit into it, or it cannot be mapped.
+    fn map(&self, device: &mut pci::DeviceConfig) -> Option<Region> {
+        let (address, size) = device.base_address_region(self.bar)?.region()?;
+        let (offset, length) = (self.offset as u64, self.length as u64);
+
+        if length == 0 || offset + length > size as u64 {
+            return None;
+        }
+
+        // The BAR was assigned to the device, so it is device memory.
+        let va = unsafe { Current::map_device(address + offset, length)? };
+
+        Some(unsafe { Region::new(va, length) })
+    }
+}
+
+/// The structures of a virtio device its configuration space points to.
+#[derive(Clone, Debug, Default)]
+pub struct VirtioCapabilities {
+    pub common: Option<VirtioPciCapability>,
+    pub notify: Option<VirtioPciCapability>,
+    /// Multiplier for `queue_notify_off`, from the notification capability.
+    pub notify_off_multiplier: u32,
+    pub isr: Option<VirtioPciCapability>,
+    pub config: Option<VirtioPciCapability>,
+}
+
+impl VirtioCapabilities {
+    /// Finds the capabilities in a configuration space read with
+    /// [`pci::DeviceConfig::read_config_space`]. The first capability of every kind is
+    /// the one to use, see 4.1.4.
+    pub fn parse(config_space: &[u8]) -> Self {
+        use bit_field::BitField;
+
+        let mut capabilities = Self::default();
+
+        for capability in pci::capabilities(config_space) {
+            if capability.id != pci::PCI_CAP_ID_VNDR {
+                continue;
+            }
+
+            let Some(info) = VirtioPciCapability::parse(config_space, capability) else {
+                continue;
+            };
+
+            match capability.private_header.get_bits(8..16) as u8 {
+                VIRTIO_PCI_CAP_COMMON_CFG if capabilities.common.is_none() => {
+                    capabilities.common = Some(info)
+                }
+                VIRTIO_PCI_CAP_NOTIFY_CFG if capabilities.notify.is_none() => {
+                    capabilities.notify = Some(info);
+                    capabilities.notify_off_multiplier = pci::read_u32(
+                        config_space,
+                        capability.offset as usize
+                            + offset_of!(VirtioPciNotifyCap, notify_off_multiplier),
+                    )
+                    .unwrap_or(0);
+                }
+                VIRTIO_PCI_CAP_ISR_CFG if capabilities.isr.is_none() => {
+                    capabilities.isr = Some(info)
+                }
+                VIRTIO_PCI_CAP_DEVICE_CFG if capabilities.config.is_none() => {
+                    capabilities.config = Some(info)
+                }
+                _ => {}
+            }
+        }
+
+        capabilities
+    }
+}
+
+register_block! {
+    /// `virtio_pci_common_cfg`, see 4.1.4.3 "Common configuration structure layout".
+    struct VirtioPciCommonCfg {
+        0x00 => device_feature_select: Volatile<u32>,
+        0x04 => device_feature: ReadOnly<u32>,
+        0x08 => driver_feature_select: Volatile<u32>,
+        0x0c => driver_feature: Volatile<u32>,
+        0x10 => msix_config: Volatile<u16>,
+        0x12 => num_queues: ReadOnly<u16>,
+        0x14 => device_status: Volatile<u8>,
+        0x15 => config_generation: ReadOnly<u8>,
+        0x16 => queue_select: Volatile<u16>,
+        0x18 => queue_size: Volatile<u16>,
+        0x1a => queue_msix_vector: Volatile<u16>,
+        0x1c => queue_enable: Volatile<u16>,
+        0x1e => queue_notify_off: ReadOnly<u16>,
+        0x20 => queue_desc: Volatile<u64>,
+        0x28 => queue_driver: Volatile<u64>,
+        0x30 => queue_device: Volatile<u64>,
+    }
+}
+
+assert_size!(VirtioPciCommonCfg, 0x38);
+
+/// virtio over the memory BARs of a modern PCI device, see 4.1 "Virtio Over PCI Bus".
+///
+/// The device describes where its registers are with vendor specific capabilities,
+/// each pointing into one of its BARs, which are reached through the direct map.
+#[derive(Debug)]
+pub struct PciTransport {
+    pci: pci::DeviceConfig,
+    device_type: DeviceType,
+    common: Region,
+    notify: Region,
+    notify_off_multiplier: u32,
+    isr: Region,
+    // Empty if the device has no configuration of its own.
+    config: Region,
+    // Offset of the notification address of every queue set up, in multiples of
+    // notify_off_multiplier.
+    notify_offsets: [u16; MAX_QUEUES],
+}
+
+impl PciTransport {
+    /// Takes over a virtio PCI device through its modern interface. Returns
+    /// [`Error::NoDevice`] for devices that only have the legacy interface.
+    pub fn new(mut device: pci::DeviceConfig) -> Result<Self, Error> {
+        let device_type = virtio::pci_device_type(&device).ok_or(Error::NoDevice)?;
+
+        let VirtioCapabilities {
+            common: Some(common),
+            notify: Some(notify),
+            notify_off_multiplier,
+            isr: Some(isr),
+            config,
+        } = VirtioCapabilities::parse(&device.read_config_space())
+        else {
+            return Err(Error::NoDevice);
+        };
+
+        let common = common.map(&mut device).ok_or(Error::NoDevice)?;
+        let notify = notify.map(&mut device).ok_or(Error::NoDevice)?;
+        let isr = isr.map(&mut device).ok_or(Error::NoDevice)?;
+        let config = match config {
+            Some(x) => x.map(&mut device).ok_or(Error::NoDevice)?,
+            None => unsafe { Region::new(0, 0) },
+        };
+
+        if common.size() < size_of::<VirtioPciCommonCfg>() as u64 || isr.size() == 0 {
+            return Err(Error::NoDevice);
+        }
+
+        // Enable PCI bus mastering to allow the device to do DMA.
+        device.enable_bus_mastering();
+
+        Ok(Self {
+            pci: device,
+            device_type,
+            common,
+            notify,
+            notify_off_multiplier,
+            isr,
+            config,
+            notify_offsets: [0; MAX_QUEUES],
+        })
+    }
+
+    fn common(&self) -> &VirtioPciCommonCfg {
+        self.common.block(0)
+    }
+}
+
+impl Transport for PciTransport {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn irq(&self) -> u8 {
+        self.pci.interrupt_line
+    }
+
+    fn device_features(&mut self) -> u64 {
+        let common = self.common();
+        common.device_feature_select.write(0);
+        let low = common.device_feature.read() as u64;
+        common.device_feature_select.write(1);
+        let high = common.device_feature.read() as u64;
+        high << 32 | low
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        let common = self.common();
+        common.driver_feature_select.write(0);
+        common.driver_feature.write(features as u32);
+        common.driver_feature_select.write(1);
+        common.driver_feature.write((features >> 32) as u32);
+    }
+
+    fn status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_retain(self.common().device_status.read())
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.common().device_status.write(status.bits());
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u16 {
+        let common = self.common();
+        common.queue_select.write(queue);
+        common.queue_size.read()
+    }
+
+    fn setup_queue(
+        &mut self,
+        queue: u16,
+        size: u16,
+        desc: PhysAddr,
+        avail: PhysAddr,
+        used: PhysAddr,
+    ) -> Result<(), Error> {
+        if queue as usize >= MAX_QUEUES {
+            return Err(Error::QueueUnavailable);
+        }
+
+        let common = self.common();
+        common.queue_select.write(queue);
+
+        if common.queue_enable.read() != 0 {
+            return Err(Error::QueueInUse);
+        }
+
+        match common.queue_size.read() {
+            0 => return Err(Error::QueueUnavailable),
+            x if x < size => return Err(Error::QueueTooLarge),
+            _ => {}
+        }
+
+        common.queue_size.write(size);
+        common.queue_desc.write(desc.as_u64());
+        common.queue_driver.write(avail.as_u64());
+        common.queue_device.write(used.as_u64());
+        let notify_off = common.queue_notify_off.read();
+        common.queue_enable.write(1);
+
+        self.notify_offsets[queue as usize] = notify_off;
+        Ok(())
+    }
+
+    /// Modern devices have no way to disable a single queue, the reset in
+    /// [`Transport::shutdown`] disables all of them.
+    fn disable_queue(&mut self, _queue: u16) {}
+
+    fn notify(&mut self, queue: u16) {
+        let offset = self.notify_offsets[queue as usize] as u64 * self.notify_off_multiplier as u64;
+        self.notify.write(offset, queue);
+    }
+
+    fn ack_interrupt(&mut self) -> InterruptStatus {
+        // Reading the register acknowledges the interrupt.
+        InterruptStatus::from_bits_retain(self.isr.read::<u8>(0) as u32)
+    }
+
+    fn config_generation(&self) -> u32 {
+        self.common().config_generation.read() as u32
+    }
+
+    fn read_config_u8(&self, offset: usize) -> u8 {
+        self.config.read(offset as u64)
+    }
+
+    fn read_config_u16(&self, offset: usize) -> u16 {
+        self.config.read(offset as u64)
+    }
+
+    fn read_config_u32(&self, offset: usize) -> u32 {
+        self.config.read(offset as u64)
+    }
+}
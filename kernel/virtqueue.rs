@@ -0,0 +1,457 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem::offset_of;
+use core::ptr;
+
+use x86_64::PhysAddr;
+
+use crate::arch::{Current, Mmu};
+use crate::assert_size;
+use crate::endian::{Le16, Le32, Le64};
+#[cfg(feature = "fault-injection")]
+use crate::fault;
+use crate::heap::DmaBox;
+use crate::mmio;
+
+/// Feature bit: the driver may use indirect descriptor tables.
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+
+/// Feature bit: notifications are suppressed with the event index fields.
+pub const VIRTIO_F_EVENT_IDX: u64 = 1 << 29;
+
+/// The buffer continues in the descriptor in `next`.
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// The buffer is written by the device instead of read.
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+/// The buffer is a table of descriptors.
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+/// Set by the driver when it does not want interrupts, without the event index.
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+/// Set by the device when it does not want notifications, without the event index.
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+
+/// `virtq_desc`, see 2.7.5 "The Virtqueue Descriptor Table".
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct Descriptor {
+    addr: Le64,
+    len: Le32,
+    flags: Le16,
+    next: Le16,
+}
+
+assert_size!(Descriptor, 16);
+
+/// `virtq_avail`, see 2.7.6 "The Virtqueue Available Ring".
+#[repr(C)]
+struct AvailRing<const N: usize> {
+    flags: Le16,
+    idx: Le16,
+    ring: [Le16; N],
+    // Only used with VIRTIO_F_EVENT_IDX.
+    used_event: Le16,
+}
+
+/// `virtq_used_elem`, see 2.7.8 "The Virtqueue Used Ring".
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct UsedElem {
+    id: Le32,
+    len: Le32,
+}
+
+assert_size!(UsedElem, 8);
+
+/// `virtq_used`, see 2.7.8 "The Virtqueue Used Ring".
+#[repr(C)]
+struct UsedRing<const N: usize> {
+    flags: Le16,
+    idx: Le16,
+    ring: [UsedElem; N],
+    // Only used with VIRTIO_F_EVENT_IDX.
+    avail_event: Le16,
+}
+
+/// Page aligned used ring.
+#[repr(C, align(4096))]
+struct AlignedUsedRing<const N: usize>(UsedRing<N>);
+
+/// The three parts of a queue in the layout of the legacy interface, see 2.7.2 "Legacy
+/// Interfaces: A Note on Virtqueue Layout". The available ring directly follows the
+/// descriptor table and the used ring starts on the next page, which modern devices
+/// accept as well.
+#[repr(C)]
+struct Rings<const N: usize> {
+    desc: [Descriptor; N],
+    avail: AvailRing<N>,
+    used: AlignedUsedRing<N>,
+}
+
+/// Physically contiguous memory handed to the device as part of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+}
+
+/// Errors returned when adding buffers to a virtqueue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// The request has no buffers.
+    Empty,
+    /// There are not enough free descriptors, wait for used buffers first.
+    Full,
+    /// The request has more buffers than the queue has entries.
+    TooLarge,
+}
+
+/// Split virtqueue of `N` entries, `N` being a power of two.
+///
+/// The driver adds chains of buffers to the available ring and the device returns them
+/// in the used ring. With `VIRTIO_F_INDIRECT_DESC` a request of several buffers takes a
+/// single entry of the descriptor table and points to a table of its own, so large
+/// scatter-gather requests do not exhaust the queue. With `VIRTIO_F_EVENT_IDX` both
+/// sides tell exactly at which index they want to be notified, which suppresses most
+/// notifications and interrupts under load.
+pub struct VirtQueue<const N: usize> {
+    rings: DmaBox<Rings<N>>,
+    // Indirect table of every request, by head descriptor.
+    indirect: Vec<Option<Box<[Descriptor]>>>,
+    indirect_enabled: bool,
+    event_idx_enabled: bool,
+    interrupts_enabled: bool,
+    free_head: u16,
+    num_free: usize,
+    // Our copy of the available index, which only the driver writes.
+    avail_idx: u16,
+    // Available index when the device was last notified.
+    notified_idx: u16,
+    // Next entry of the used ring to look at.
+    last_used_idx: u16,
+}
+
+impl<const N: usize> VirtQueue<N> {
+    /// Creates an empty queue using the features both sides agreed on.
+    pub fn new(features: u64) -> Self {
+        assert!(
+            N.is_power_of_two() && N <= 32768,
+            "virtqueue::new(): invalid queue size {N}"
+        );
+
+        let mut rings: DmaBox<Rings<N>> = unsafe { DmaBox::new_zeroed() };
+
+        // All descriptors start out in the free list.
+        for (i, x) in rings.desc.iter_mut().enumerate() {
+            x.next = Le16::new((i + 1) as u16);
+        }
+
+        Self {
+            rings,
+            indirect: (0..N).map(|_| None).collect(),
+            indirect_enabled: features & VIRTIO_F_INDIRECT_DESC != 0,
+            event_idx_enabled: features & VIRTIO_F_EVENT_IDX != 0,
+            interrupts_enabled: true,
+            free_head: 0,
+            num_free: N,
+            avail_idx: 0,
+            notified_idx: 0,
+            last_used_idx: 0,
+        }
+    }
+
+    /// Returns the number of entries of the queue.
+    pub const fn size(&self) -> usize {
+        N
+    }
+
+    /// Returns the physical addresses of the descriptor table, the available ring and
+    /// the used ring, to be given to the transport.
+    pub fn addresses(&self) -> (PhysAddr, PhysAddr, PhysAddr) {
+        let base = self.rings.phys_addr();
+        (
+            base,
+            base + offset_of!(Rings<N>, avail),
+            base + offset_of!(Rings<N>, used),
+        )
+    }
+
+    /// Returns the number of free descriptors.
+    pub fn num_free(&self) -> usize {
+        self.num_free
+    }
+
+    /// Checks whether a request of `count` buffers fits into the free descriptors, which
+    /// takes a single one if it goes into an indirect table.
+    pub fn can_add(&self, count: usize) -> bool {
+        if self.indirect_enabled && count > 1 {
+            self.num_free > 0
+        } else {
+            count <= self.num_free
+        }
+    }
+
+    /// Adds a request made of buffers the device reads followed by buffers it writes,
+    /// and returns the identifier it will come back with from [`VirtQueue::pop_used`].
+    ///
+    /// The device is not notified, see [`VirtQueue::should_notify`].
+    pub fn add(&mut self, outputs: &[Buffer], inputs: &[Buffer]) -> Result<u16, QueueError> {
+        let count = outputs.len() + inputs.len();
+
+        if count == 0 {
+            return Err(QueueError::Empty);
+        }
+
+        if count > N {
+            return Err(QueueError::TooLarge);
+        }
+
+        let head = if self.indirect_enabled && count > 1 {
+            self.add_indirect(outputs, inputs)?
+        } else {
+            self.add_direct(outputs, inputs)?
+        };
+
+        // Publish the descriptors before the ring entry, and the entry before the index.
+        let avail = &mut self.rings.avail;
+        mmio::write_barrier();
+        unsafe { ptr::write_volatile(&mut avail.ring[self.avail_idx as usize % N], head.into()) };
+        mmio::write_barrier();
+
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        unsafe { ptr::write_volatile(&mut avail.idx, self.avail_idx.into()) };
+
+        Ok(head)
+    }
+
+    fn add_direct(&mut self, outputs: &[Buffer], inputs: &[Buffer]) -> Result<u16, QueueError> {
+        let count = outputs.len() + inputs.len();
+        if count > self.num_free {
+            return Err(QueueError::Full);
+        }
+
+        let head = self.free_head;
+        let mut last = head;
+        let buffers = outputs
+            .iter()
+            .map(|x| (x, 0))
+            .chain(inputs.iter().map(|x| (x, VIRTQ_DESC_F_WRITE)));
+
+        for (i, (buffer, flags)) in buffers.enumerate() {
+            let index = self.free_head;
+            let desc = &mut self.rings.desc[index as usize];
+
+            self.free_head = desc.next.get();
+            desc.addr = buffer.addr.as_u64().into();
+            desc.len = buffer.len.into();
+            desc.flags = if i + 1 < count {
+                flags | VIRTQ_DESC_F_NEXT
+            } else {
+                flags
+            }
+            .into();
+
+            last = index;
+        }
+
+        // The free list continues after the chain, so detach the chain from it.
+        self.rings.desc[last as usize].next = Le16::new(0);
+        self.num_free -= count;
+        Ok(head)
+    }
+
+    fn add_indirect(&mut self, outputs: &[Buffer], inputs: &[Buffer]) -> Result<u16, QueueError> {
+        if self.num_free == 0 {
+            return Err(QueueError::Full);
+        }
+
+        let count = outputs.len() + inputs.len();
+        let buffers = outputs
+            .iter()
+            .map(|x| (x, 0))
+            .chain(inputs.iter().map(|x| (x, VIRTQ_DESC_F_WRITE)));
+
+        let table: Box<[Descriptor]> = buffers
+            .enumerate()
+            .map(|(i, (buffer, flags))| Descriptor {
+                addr: buffer.addr.as_u64().into(),
+                len: buffer.len.into(),
+                flags: if i + 1 < count {
+                    flags | VIRTQ_DESC_F_NEXT
+                } else {
+                    flags
+                }
+                .into(),
+                next: Le16::new((i + 1) as u16),
+            })
+            .collect();
+
+        // Heap memory is physically contiguous, see [`crate::heap::alloc_aligned`].
+        let table_addr = Current::translate(table.as_ptr() as u64)
+            .expect("virtqueue::add_indirect(): indirect table is not mapped");
+
+        let head = self.free_head;
+        let desc = &mut self.rings.desc[head as usize];
+
+        self.free_head = desc.next.get();
+        desc.addr = table_addr.into();
+        desc.len = (core::mem::size_of_val(&*table) as u32).into();
+        desc.flags = VIRTQ_DESC_F_INDIRECT.into();
+        desc.next = Le16::new(0);
+
+        self.indirect[head as usize] = Some(table);
+        self.num_free -= 1;
+        Ok(head)
+    }
+
+    /// Checks whether the device wants to be notified of the requests added since the
+    /// last notification. The caller notifies it through the transport if so.
+    pub fn should_notify(&mut self) -> bool {
+        // Read the device's wishes only after publishing the new index.
+        mmio::full_barrier();
+
+        let old = self.notified_idx;
+        let new = self.avail_idx;
+        self.notified_idx = new;
+
+        if self.event_idx_enabled {
+            let event = unsafe { ptr::read_volatile(&self.rings.used.0.avail_event) }.get();
+            need_event(event, new, old)
+        } else {
+            let flags = unsafe { ptr::read_volatile(&self.rings.used.0.flags) }.get();
+            flags & VIRTQ_USED_F_NO_NOTIFY == 0
+        }
+    }
+
+    /// Checks whether the device returned requests that were not popped yet.
+    pub fn has_used(&self) -> bool {
+        let idx = unsafe { ptr::read_volatile(&self.rings.used.0.idx) }.get();
+        idx != self.last_used_idx
+    }
+
+    /// Returns the identifier of the next request the device is done with and the
+    /// number of bytes it wrote, and frees its descriptors.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+
+        #[cfg(feature = "fault-injection")]
+        fault::delay_completion();
+
+        // Read the entry only after seeing the index that covers it.
+        mmio::read_barrier();
+
+        let elem =
+            unsafe { ptr::read_volatile(&self.rings.used.0.ring[self.last_used_idx as usize % N]) };
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        self.free_chain(elem.id.get() as u16);
+
+        // Ask for an interrupt as soon as the next request is used.
+        if self.event_idx_enabled && self.interrupts_enabled {
+            unsafe {
+                ptr::write_volatile(&mut self.rings.avail.used_event, self.last_used_idx.into())
+            };
+        }
+
+        Some((elem.id.get() as u16, elem.len.get()))
+    }
+
+    fn free_chain(&mut self, head: u16) {
+        let mut index = head;
+
+        loop {
+            let desc = &mut self.rings.desc[index as usize];
+            let flags = desc.flags.get();
+            let next = desc.next.get();
+
+            desc.addr = Le64::new(0);
+            desc.len = Le32::new(0);
+            desc.flags = Le16::new(0);
+            self.num_free += 1;
+
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                self.indirect[index as usize] = None;
+            }
+
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                // Put the whole chain back at the front of the free list.
+                self.rings.desc[index as usize].next = self.free_head.into();
+                self.free_head = head;
+                return;
+            }
+
+            index = next;
+        }
+    }
+
+    /// Frees the descriptors of every request the device did not return and calls `f`
+    /// with their identifiers, so the driver can free their buffers. The queue is empty
+    /// afterwards, as if it was just created.
+    ///
+    /// # Safety
+    /// The device must have been reset or the queue disabled, otherwise it may still
+    /// write to the buffers.
+    pub unsafe fn reclaim(&mut self, mut f: impl FnMut(u16)) {
+        // Free descriptors have no address, and the ones that follow another one in a
+        // chain are not the head of a request.
+        let mut heads: Vec<bool> = self.rings.desc.iter().map(|x| x.addr.get() != 0).collect();
+        for desc in self.rings.desc.iter() {
+            if desc.addr.get() != 0 && desc.flags.get() & VIRTQ_DESC_F_NEXT != 0 {
+                heads[desc.next.get() as usize] = false;
+            }
+        }
+
+        for (head, _) in heads.into_iter().enumerate().filter(|(_, x)| *x) {
+            self.free_chain(head as u16);
+            f(head as u16);
+        }
+
+        let rings = &mut *self.rings;
+        ptr::write_bytes(&mut rings.avail, 0, 1);
+        ptr::write_bytes(&mut rings.used, 0, 1);
+
+        self.interrupts_enabled = true;
+        self.avail_idx = 0;
+        self.notified_idx = 0;
+        self.last_used_idx = 0;
+    }
+
+    /// Asks the device to interrupt when requests are used. Returns false if some
+    /// already were, in which case the caller should poll them instead of waiting.
+    pub fn enable_interrupts(&mut self) -> bool {
+        self.interrupts_enabled = true;
+
+        if self.event_idx_enabled {
+            unsafe {
+                ptr::write_volatile(&mut self.rings.avail.used_event, self.last_used_idx.into())
+            };
+        } else {
+            let flags = self.rings.avail.flags.get() & !VIRTQ_AVAIL_F_NO_INTERRUPT;
+            unsafe { ptr::write_volatile(&mut self.rings.avail.flags, flags.into()) };
+        }
+
+        mmio::full_barrier();
+        !self.has_used()
+    }
+
+    /// Asks the device not to interrupt, e.g. while polling. This is only a hint.
+    pub fn disable_interrupts(&mut self) {
+        self.interrupts_enabled = false;
+
+        // With the event index, move the event as far ahead as the index space allows.
+        if self.event_idx_enabled {
+            let event = self.last_used_idx.wrapping_add(0x8000);
+            unsafe { ptr::write_volatile(&mut self.rings.avail.used_event, event.into()) };
+        } else {
+            let flags = self.rings.avail.flags.get() | VIRTQ_AVAIL_F_NO_INTERRUPT;
+            unsafe { ptr::write_volatile(&mut self.rings.avail.flags, flags.into()) };
+        }
+    }
+}
+
+/// `vring_need_event()`, see 2.7.10 "Available Buffer Notification Suppression":
+/// whether moving an index from `old` to `new` crossed `event`.
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
@@ -0,0 +1,89 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Once;
+
+use crate::log;
+use crate::mpsc;
+use crate::sched;
+use crate::sched::{Priority, ThreadId};
+
+/// Maximum number of work items that can be pending at once.
+const QUEUE_CAPACITY: usize = 64;
+
+// Pending work items in FIFO order.
+static QUEUE: mpsc::Queue<&'static Work, QUEUE_CAPACITY> = mpsc::Queue::new();
+
+// Kernel thread that runs deferred work.
+static WORKER: Once<ThreadId> = Once::new();
+
+/// A unit of deferred work.
+///
+/// Work items are meant to live in statics so that interrupt handlers can schedule
+/// them without allocating. Scheduling an item that is already pending does nothing,
+/// so bursts of interrupts are coalesced into a single run.
+pub struct Work {
+    func: fn(),
+    pending: AtomicBool,
+}
+
+impl Work {
+    /// Creates a new work item that runs `func` on the worker thread.
+    pub const fn new(func: fn()) -> Self {
+        Self {
+            func,
+            pending: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Schedules a work item to run on the worker thread with interrupts enabled.
+///
+/// Safe to call from interrupt handlers. Returns false if the work item was already
+/// pending.
+pub fn schedule(work: &'static Work) -> bool {
+    if work.pending.swap(true, Ordering::AcqRel) {
+        return false;
+    }
+
+    assert!(
+        QUEUE.push(work).is_ok(),
+        "workqueue::schedule(): too many pending work items"
+    );
+
+    if let Some(&worker) = WORKER.get() {
+        sched::wake(worker);
+    }
+
+    true
+}
+
+/// Returns whether any work item is waiting to run.
+pub fn pending() -> bool {
+    !QUEUE.is_empty()
+}
+
+/// Runs all pending work items on the current thread.
+pub fn flush() {
+    while let Some(work) = QUEUE.pop() {
+        // Clear pending first so that the work can be scheduled again while it runs.
+        work.pending.store(false, Ordering::Release);
+        (work.func)();
+    }
+}
+
+fn worker() {
+    loop {
+        flush();
+        sched::block();
+    }
+}
+
+/// Initializes the deferred work subsystem.
+///
+/// Interrupt handlers should do the minimum amount of work needed to acknowledge
+/// their device and push the rest onto the work queue, where it runs in a kernel
+/// thread with interrupts enabled.
+pub fn init() {
+    WORKER.call_once(|| sched::spawn("workqueue", Priority::High, worker));
+    log!("workqueue::init(): worker thread started [ \x1b[0;32mOK\x1b[0m ]");
+}